@@ -0,0 +1,269 @@
+#![allow(dead_code)]
+use crate::mem::PhysAddr;
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+use lazy_static::lazy_static;
+
+/*
+  Just enough ACPI to find the processors. The RSDP is found by
+  scanning the BIOS areas for its signature, it points to the RSDT
+  (or the XSDT on ACPI 2.0+) which lists the other tables. The MADT,
+  signature "APIC", describes the interrupt controllers, including one
+  local APIC per processor. All tables are read through the physical
+  memory map.
+  References:
+  https://wiki.osdev.org/RSDP
+  https://wiki.osdev.org/RSDT
+  https://wiki.osdev.org/MADT
+*/
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+const EBDA_SEARCH_SIZE: u64 = 1024;
+const BIOS_AREA: (u64, u64) = (0xe0000, 0x100000);
+
+// MADT entry types
+const LOCAL_APIC: u8 = 0;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[repr(C, packed)]
+struct Rsdp {
+  signature: [u8; 8],
+  checksum: u8,
+  oem_id: [u8; 6],
+  revision: u8,
+  rsdt_addr: u32,
+  // the rest is only valid for revision 2 and later
+  length: u32,
+  xsdt_addr: u64,
+  extended_checksum: u8,
+  reserved: [u8; 3],
+}
+
+const RSDP_V1_SIZE: usize = 20;
+
+#[repr(C, packed)]
+struct SdtHeader {
+  signature: [u8; 4],
+  length: u32,
+  revision: u8,
+  checksum: u8,
+  oem_id: [u8; 6],
+  oem_table_id: [u8; 8],
+  oem_revision: u32,
+  creator_id: u32,
+  creator_revision: u32,
+}
+
+// Unsafe since the memory has to be mapped and not change
+unsafe fn phys_bytes(addr: u64, len: usize) -> &'static [u8] {
+  slice::from_raw_parts(PhysAddr::new(addr).to_virt().as_ptr(), len)
+}
+
+unsafe fn read_phys<T>(addr: u64) -> T {
+  ptr::read_unaligned(PhysAddr::new(addr).to_virt().as_ptr::<T>())
+}
+
+// Every table is valid if all of its bytes sum to zero
+fn checksum_valid(bytes: &[u8]) -> bool {
+  bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+  let mut buf = [0; 4];
+  buf.copy_from_slice(&bytes[offset..offset + 4]);
+  u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+  let mut buf = [0; 8];
+  buf.copy_from_slice(&bytes[offset..offset + 8]);
+  u64::from_le_bytes(buf)
+}
+
+// The RSDP is on a 16 byte boundary in either the first KiB of
+// the extended BIOS data area or the main BIOS area
+fn find_rsdp() -> Option<u64> {
+  let ebda = unsafe { read_phys::<u16>(EBDA_SEGMENT_PTR) } as u64 * 16;
+  let areas = [(ebda, ebda + EBDA_SEARCH_SIZE), BIOS_AREA];
+  areas
+    .iter()
+    .filter(|&&(start, _)| start != 0)
+    .flat_map(|&(start, end)| (start..end).step_by(16))
+    .find(|&addr| {
+      let bytes = unsafe { phys_bytes(addr, RSDP_V1_SIZE) };
+      bytes.starts_with(RSDP_SIGNATURE) && checksum_valid(bytes)
+    })
+}
+
+// Returns the physical address of the first valid table with signature
+fn find_table(signature: &[u8; 4]) -> Option<u64> {
+  let rsdp_addr = find_rsdp()?;
+  let rsdp = unsafe { read_phys::<Rsdp>(rsdp_addr) };
+  // the xsdt has 64 bit pointers, the rsdt 32 bit ones
+  let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_addr != 0 {
+    (rsdp.xsdt_addr, 8)
+  } else {
+    (rsdp.rsdt_addr as u64, 4)
+  };
+  let root_table = table_bytes(root)?;
+  let entries = &root_table[size_of::<SdtHeader>()..];
+  entries
+    .chunks_exact(entry_size)
+    .map(|entry| match entry_size {
+      8 => read_u64(entry, 0),
+      _ => read_u32(entry, 0) as u64,
+    })
+    .find(|&addr| match table_bytes(addr) {
+      Some(table) => table.starts_with(signature),
+      None => false,
+    })
+}
+
+// All bytes of the table at addr, if the checksum is valid
+fn table_bytes(addr: u64) -> Option<&'static [u8]> {
+  let header = unsafe { read_phys::<SdtHeader>(addr) };
+  let len = header.length as usize;
+  if len < size_of::<SdtHeader>() {
+    return None;
+  }
+  let bytes = unsafe { phys_bytes(addr, len) };
+  if checksum_valid(bytes) {
+    Some(bytes)
+  } else {
+    None
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+  pub acpi_id: u8,
+  pub apic_id: u8,
+}
+
+// Iterates over the (type, body) pairs of the MADT entries
+struct MadtEntries<'a>(&'a [u8]);
+
+impl<'a> Iterator for MadtEntries<'a> {
+  type Item = (u8, &'a [u8]);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.0.len() < 2 {
+      return None;
+    }
+    let (kind, len) = (self.0[0], self.0[1] as usize);
+    if len < 2 || len > self.0.len() {
+      return None; // malformed, stop here
+    }
+    let body = &self.0[2..len];
+    self.0 = &self.0[len..];
+    Some((kind, body))
+  }
+}
+
+pub struct Madt {
+  bytes: &'static [u8],
+}
+
+// The local APIC address and flags follow the header
+const MADT_ENTRIES_OFFSET: usize = size_of::<SdtHeader>() + 8;
+
+impl Madt {
+  fn entries(&self) -> MadtEntries<'static> {
+    MadtEntries(&self.bytes[MADT_ENTRIES_OFFSET..])
+  }
+
+  pub fn local_apic_addr(&self) -> PhysAddr {
+    let addr = self
+      .entries()
+      .find(|&(kind, body)| kind == LOCAL_APIC_ADDRESS_OVERRIDE && body.len() >= 10)
+      .map(|(_, body)| read_u64(body, 2))
+      .unwrap_or_else(|| read_u32(self.bytes, size_of::<SdtHeader>()) as u64);
+    PhysAddr::new(addr)
+  }
+
+  // Processors which are enabled or can be brought online
+  pub fn processors(&self) -> impl Iterator<Item = Processor> {
+    processors(self.entries())
+  }
+}
+
+fn processors(entries: MadtEntries<'_>) -> impl Iterator<Item = Processor> + '_ {
+  entries
+    .filter(|&(kind, body)| kind == LOCAL_APIC && body.len() >= 6)
+    .filter(|&(_, body)| read_u32(body, 2) & (PROCESSOR_ENABLED | PROCESSOR_ONLINE_CAPABLE) != 0)
+    .map(|(_, body)| Processor {
+      acpi_id: body[0],
+      apic_id: body[1],
+    })
+}
+
+lazy_static! {
+  static ref MADT: Option<Madt> = {
+    find_table(MADT_SIGNATURE)
+      .and_then(table_bytes)
+      .filter(|bytes| bytes.len() >= MADT_ENTRIES_OFFSET)
+      .map(|bytes| Madt { bytes })
+  };
+}
+
+pub fn madt() -> Option<&'static Madt> {
+  MADT.as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn size_check() {
+    assert_eq!(size_of::<Rsdp>(), 36);
+    assert_eq!(size_of::<SdtHeader>(), 36);
+  }
+
+  #[test_case]
+  fn checksum() {
+    assert!(checksum_valid(&[]));
+    assert!(checksum_valid(&[0x10, 0xf0]));
+    assert!(!checksum_valid(&[0x10, 0xef]));
+  }
+
+  #[test_case]
+  fn parse_processors() {
+    #[rustfmt::skip]
+    let entries = [
+      LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0,           // enabled
+      LOCAL_APIC, 8, 1, 2, 0, 0, 0, 0,           // disabled
+      1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0, // io apic
+      LOCAL_APIC, 8, 2, 4, 2, 0, 0, 0,           // online capable
+      LOCAL_APIC, 8, 3, 6, 1, 0,                 // truncated
+    ];
+    let mut cpus = processors(MadtEntries(&entries));
+    assert_eq!(
+      cpus.next(),
+      Some(Processor {
+        acpi_id: 0,
+        apic_id: 0,
+      })
+    );
+    assert_eq!(
+      cpus.next(),
+      Some(Processor {
+        acpi_id: 2,
+        apic_id: 4,
+      })
+    );
+    assert_eq!(cpus.next(), None);
+  }
+
+  #[test_case]
+  fn madt_found() {
+    let madt = madt().expect("no MADT");
+    assert_eq!(madt.local_apic_addr().as_u64(), 0xfee0_0000);
+    assert!(madt.processors().count() >= 1);
+  }
+}
@@ -1,42 +1,126 @@
 use crate::mem::page_table::page_map_addr;
 use crate::mem::VirtAddr;
+use crate::sync::IrqSafeMutex;
 use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
 use core::ptr::null_mut;
-use lazy_static::lazy_static;
-use spin::Mutex;
+
+/*
+  The kernel heap, a list of the free blocks sorted by address. An
+  allocation takes the first block which fits and leaves whatever
+  is in front of and behind it in the list. Freed blocks are merged
+  with their neighbours so the heap doesn't fall apart into pieces
+  too small to use. Every block starts with its size and the address
+  of the next one, so sizes and alignments are at least that big.
+  Reference: https://os.phil-opp.com/allocator-designs/
+*/
 
 const MB: usize = 0x10_0000;
 const HEAP_START_ADDR: usize = 0x4444_4400_0000;
 const HEAP_END_ADDR: usize = HEAP_START_ADDR + 2 * MB;
 
+const MIN_BLOCK: usize = size_of::<FreeBlock>();
+
+struct FreeBlock {
+  size: usize,
+  next: usize, // 0 at the end of the list
+}
+
 struct KernelHeapAllocator {
-  ptr: usize,
+  head: usize,
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+  (addr + align - 1) & !(align - 1)
+}
+
+// The size and alignment of the block used for an allocation
+fn block_layout(layout: Layout) -> (usize, usize) {
+  let align = layout.align().max(MIN_BLOCK);
+  (align_up(layout.size().max(MIN_BLOCK), MIN_BLOCK), align)
+}
+
+unsafe fn block(addr: usize) -> &'static mut FreeBlock {
+  &mut *(addr as *mut FreeBlock)
 }
 
 impl KernelHeapAllocator {
-  // stupid bump allocator tm
+  const fn new() -> Self {
+    Self { head: 0 }
+  }
+
   unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
-    if self.ptr + layout.size() > HEAP_END_ADDR {
-      return null_mut();
+    let (size, align) = block_layout(layout);
+    let mut prev = 0;
+    let mut addr = self.head;
+    while addr != 0 {
+      let free = block(addr);
+      let start = align_up(addr, align);
+      let end = start.saturating_add(size);
+      if end <= addr + free.size {
+        let next = free.next;
+        let tail = addr + free.size - end;
+        if tail > 0 {
+          *block(end) = FreeBlock { size: tail, next };
+          self.link(if start > addr { addr } else { prev }, end);
+        } else if start == addr {
+          self.link(prev, next);
+        }
+        // what is left in front stays where it is
+        if start > addr {
+          free.size = start - addr;
+        }
+        return start as *mut u8;
+      }
+      prev = addr;
+      addr = free.next;
     }
-    // align the ptr
-    let align = layout.align();
-    if self.ptr & (align - 1) != 0 {
-      self.ptr = (self.ptr & (align - 1)) + align;
+    null_mut()
+  }
+
+  unsafe fn free(&mut self, ptr: *mut u8, layout: Layout) {
+    let (size, _) = block_layout(layout);
+    self.add_free(ptr as usize, size);
+  }
+
+  // Puts a block back in the list, merged with the blocks around it
+  unsafe fn add_free(&mut self, addr: usize, size: usize) {
+    let mut prev = 0;
+    let mut next = self.head;
+    while next != 0 && next < addr {
+      prev = next;
+      next = block(next).next;
+    }
+    *block(addr) = FreeBlock { size, next };
+    if next != 0 && addr + size == next {
+      let after = block(next);
+      *block(addr) = FreeBlock {
+        size: size + after.size,
+        next: after.next,
+      };
+    }
+    if prev != 0 && prev + block(prev).size == addr {
+      let merged = block(addr);
+      let before = block(prev);
+      before.size += merged.size;
+      before.next = merged.next;
+    } else {
+      self.link(prev, addr);
     }
-    let res = self.ptr as *mut u8;
-    self.ptr += layout.size();
-    res
   }
 
-  unsafe fn free(&mut self, _layout: Layout) {}
+  // Makes next follow prev, or the head if prev is 0
+  unsafe fn link(&mut self, prev: usize, next: usize) {
+    if prev == 0 {
+      self.head = next;
+    } else {
+      block(prev).next = next;
+    }
+  }
 }
 
-lazy_static! {
-  static ref KERNEL_ALLOCATOR: Mutex<KernelHeapAllocator> = Mutex::new(KernelHeapAllocator {
-    ptr: HEAP_START_ADDR,
-  });
-}
+static KERNEL_ALLOCATOR: IrqSafeMutex<KernelHeapAllocator> =
+  IrqSafeMutex::new(KernelHeapAllocator::new());
 
 struct AllocatorWrapper;
 
@@ -44,8 +128,8 @@ unsafe impl GlobalAlloc for AllocatorWrapper {
   unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
     KERNEL_ALLOCATOR.lock().alloc(layout)
   }
-  unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
-    KERNEL_ALLOCATOR.lock().free(layout)
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    KERNEL_ALLOCATOR.lock().free(ptr, layout)
   }
 }
 
@@ -63,4 +147,48 @@ pub fn initialize() {
   for page_addr in (HEAP_START_ADDR..HEAP_END_ADDR).step_by(0x1000) {
     page_map_addr(VirtAddr::new(page_addr as u64));
   }
+  unsafe {
+    KERNEL_ALLOCATOR
+      .lock()
+      .add_free(HEAP_START_ADDR, HEAP_END_ADDR - HEAP_START_ADDR)
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use alloc::boxed::Box;
+  use alloc::vec::Vec;
+
+  #[test_case]
+  fn aligned() {
+    let odd = Box::new(1u8);
+    for &align in [8, 16, 64, 4096].iter() {
+      let layout = Layout::from_size_align(24, align).unwrap();
+      let ptr = unsafe { ALLOCATOR.alloc(layout) };
+      assert!(!ptr.is_null());
+      assert_eq!(ptr as usize % align, 0);
+      unsafe { ALLOCATOR.dealloc(ptr, layout) };
+    }
+    assert_eq!(*odd, 1);
+  }
+
+  #[test_case]
+  fn reuses_freed_memory() {
+    // many times the size of the heap in total
+    for i in 0..64 {
+      let v = vec![i as u8; MB / 2];
+      assert!(v.iter().all(|&b| b == i as u8));
+    }
+  }
+
+  #[test_case]
+  fn merges_freed_blocks() {
+    let mut blocks: Vec<Vec<u8>> = (0..3).map(|_| vec![0; MB / 2]).collect();
+    // out of order, so blocks get merged on both sides
+    blocks.swap(0, 1);
+    drop(blocks);
+    let big = vec![1u8; 3 * MB / 2];
+    assert_eq!(big.len(), 3 * MB / 2);
+  }
 }
@@ -0,0 +1,237 @@
+#![allow(dead_code)]
+use crate::interrupts::trap::TrapFrame;
+use crate::mem::page_table::translate_addr;
+use crate::mem::VirtAddr;
+use core::convert::TryInto;
+
+/*
+  Stack walking via frame pointers, the kernel is built with
+  -C force-frame-pointers=yes (see .cargo/config.toml). Each frame
+  starts with the caller's rbp followed by the return address.
+  Addresses are symbolized using the KSYMS table, which is filled in
+  by tools/embed_symbols.py before the kernel is booted.
+  References:
+  https://wiki.osdev.org/Stack_Trace
+  https://refspecs.linuxbase.org/elf/x86_64-abi-0.99.pdf (3.2.2)
+*/
+
+const MAX_FRAMES: usize = 32;
+const KSYMS_SIZE: usize = 512 * 1024;
+
+// Found by its name, this file is part of both the library and the
+// kernel binary so there can be two copies. Its own section keeps it
+// out of .bss, which takes no space in the binary.
+#[used]
+#[link_section = ".ksyms"]
+static mut KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+pub struct SymbolTable<'a> {
+  entries: &'a [u8],
+  names:   &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+  const ENTRY_SIZE: usize = 16;
+
+  pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+    if bytes.len() < 8 || &bytes[0..4] != b"KSYM" {
+      return None;
+    }
+    let count = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+    let names_start = 8 + count * Self::ENTRY_SIZE;
+    if names_start > bytes.len() {
+      return None;
+    }
+    Some(Self {
+      entries: &bytes[8..names_start],
+      names:   &bytes[names_start..],
+    })
+  }
+
+  fn len(&self) -> usize {
+    self.entries.len() / Self::ENTRY_SIZE
+  }
+
+  fn entry(&self, i: usize) -> (u64, u64, &'a str) {
+    let entry = &self.entries[i * Self::ENTRY_SIZE..(i + 1) * Self::ENTRY_SIZE];
+    let addr = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+    let size = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+    let name_offset = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+    let name = &self.names[name_offset.min(self.names.len())..];
+    let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    let name = core::str::from_utf8(&name[..name_len]).unwrap_or("<invalid utf-8>");
+    (addr, size, name)
+  }
+
+  // Returns the function containing addr and the offset into it
+  pub fn lookup(&self, addr: u64) -> Option<(&'a str, u64)> {
+    // binary search for the last symbol starting at or before addr
+    let (mut lo, mut hi) = (0, self.len());
+    while lo < hi {
+      let mid = (lo + hi) / 2;
+      if self.entry(mid).0 <= addr {
+        lo = mid + 1;
+      } else {
+        hi = mid;
+      }
+    }
+    if lo == 0 {
+      return None;
+    }
+    let (start, size, name) = self.entry(lo - 1);
+    if size != 0 && addr >= start + size {
+      return None;
+    }
+    Some((name, addr - start))
+  }
+}
+
+pub fn kernel_symbols() -> Option<SymbolTable<'static>> {
+  SymbolTable::parse(unsafe { &KSYMS })
+}
+
+#[inline(always)]
+pub fn current_rbp() -> u64 {
+  let rbp;
+  unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+  rbp
+}
+
+// Calls f with the return address of each frame, starting at rbp
+pub fn walk_frames(mut rbp: u64, mut f: impl FnMut(u64)) {
+  for _ in 0..MAX_FRAMES {
+    // don't fault while walking a corrupted stack, the saved rbp and
+    // the return address can be on different pages
+    let mapped = |addr: u64| translate_addr(VirtAddr::new(addr)).is_some();
+    if rbp == 0 || rbp % 8 != 0 || !mapped(rbp) || !mapped(rbp.wrapping_add(8)) {
+      break;
+    }
+    let frame = rbp as *const u64;
+    let return_addr = unsafe { *frame.add(1) };
+    if return_addr == 0 {
+      break;
+    }
+    f(return_addr);
+    rbp = unsafe { *frame };
+  }
+}
+
+// Calls f with the index, address and symbol of each frame. The
+// first address, e.g an exception's rip, is used as is while return
+// addresses point after the call so the call itself is used.
+pub fn for_each_frame(
+  first_addr: Option<u64>,
+  rbp: u64,
+  mut f: impl FnMut(usize, u64, Option<(&'static str, u64)>),
+) {
+  let symbols = kernel_symbols();
+  let mut i = 0;
+  let mut frame = |addr: u64| {
+    f(i, addr, symbols.as_ref().and_then(|s| s.lookup(addr)));
+    i += 1;
+  };
+  if let Some(addr) = first_addr {
+    frame(addr);
+  }
+  walk_frames(rbp, |addr| frame(addr - 1));
+}
+
+fn print_frames(first_addr: Option<u64>, rbp: u64) {
+  dbg!("Backtrace:");
+  for_each_frame(first_addr, rbp, |i, addr, symbol| match symbol {
+    Some((name, offset)) => dbg!("{:>4}: {:#018x} {}+{:#x}", i, addr, name, offset),
+    None => dbg!("{:>4}: {:#018x} <unknown>", i, addr),
+  });
+}
+
+// Prints the call stack of the caller
+#[inline(never)]
+pub fn print() {
+  print_frames(None, current_rbp());
+}
+
+// Prints the call stack of the interrupted code
+pub fn print_exception(frame: &TrapFrame) {
+  print_frames(Some(frame.frame.instruction_ptr), frame.rbp);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mem::page_table::page_map_addr;
+
+  #[inline(never)]
+  fn collect_frames(frames: &mut [u64; MAX_FRAMES]) -> usize {
+    let mut n = 0;
+    walk_frames(current_rbp(), |addr| {
+      frames[n] = addr;
+      n += 1;
+    });
+    n
+  }
+
+  #[inline(never)]
+  fn nested_collect_frames(frames: &mut [u64; MAX_FRAMES]) -> usize {
+    collect_frames(frames)
+  }
+
+  #[test_case]
+  fn walks_the_stack() {
+    let mut frames = [0; MAX_FRAMES];
+    let n = nested_collect_frames(&mut frames);
+    assert!(n >= 2);
+    let symbols = kernel_symbols().expect("no symbols embedded");
+    let (name, _) = symbols.lookup(frames[0] - 1).unwrap();
+    assert!(name.ends_with("nested_collect_frames"), "{}", name);
+    let (name, _) = symbols.lookup(frames[1] - 1).unwrap();
+    assert!(name.ends_with("walks_the_stack"), "{}", name);
+  }
+
+  #[test_case]
+  fn frame_across_pages() {
+    // the saved rbp is at the end of a mapped page and the return
+    // address on the unmapped one after it
+    let page = VirtAddr::new(0x4321_4321_3000); // random unmapped address
+    page_map_addr(page);
+    let rbp = page.as_u64() + 4096 - 8;
+    unsafe { *(rbp as *mut u64) = 0 };
+    let mut n = 0;
+    walk_frames(rbp, |_| n += 1);
+    assert_eq!(n, 0);
+  }
+
+  #[test_case]
+  fn symbol_table_lookup() {
+    let mut table = [0u8; 8 + 3 * 16 + 12];
+    table[0..4].copy_from_slice(b"KSYM");
+    table[4..8].copy_from_slice(&3u32.to_le_bytes());
+    let entries = [
+      (0x1000u64, 0x10u32, 0u32),
+      (0x2000, 0x100, 4),
+      (0x2100, 0, 8),
+    ];
+    for (i, &(addr, size, name)) in entries.iter().enumerate() {
+      let entry = &mut table[8 + i * 16..8 + (i + 1) * 16];
+      entry[0..8].copy_from_slice(&addr.to_le_bytes());
+      entry[8..12].copy_from_slice(&size.to_le_bytes());
+      entry[12..16].copy_from_slice(&name.to_le_bytes());
+    }
+    table[56..68].copy_from_slice(b"foo\0bar\0baz\0");
+
+    let symbols = SymbolTable::parse(&table).unwrap();
+    assert_eq!(symbols.lookup(0xfff), None);
+    assert_eq!(symbols.lookup(0x1000), Some(("foo", 0)));
+    assert_eq!(symbols.lookup(0x100f), Some(("foo", 0xf)));
+    assert_eq!(symbols.lookup(0x1010), None);
+    assert_eq!(symbols.lookup(0x2042), Some(("bar", 0x42)));
+    assert_eq!(symbols.lookup(0x2100), Some(("baz", 0)));
+    assert_eq!(symbols.lookup(0x9999), Some(("baz", 0x7899)));
+  }
+
+  #[test_case]
+  fn invalid_symbol_table() {
+    assert!(SymbolTable::parse(&[]).is_none());
+    assert!(SymbolTable::parse(&[0; 64]).is_none());
+    assert!(SymbolTable::parse(b"KSYM\xff\0\0\0").is_none());
+  }
+}
@@ -0,0 +1,230 @@
+#![allow(dead_code)]
+use core::arch::x86_64::{__cpuid, CpuidResult};
+use core::fmt;
+use lazy_static::lazy_static;
+
+/*
+  Feature detection via the cpuid instruction. The leaves are only
+  queried once, the first time info() or features() is called.
+  References:
+  https://wiki.osdev.org/CPUID
+  https://www.felixcloutier.com/x86/cpuid
+  https://en.wikipedia.org/wiki/CPUID
+*/
+
+bitflags::bitflags! {
+  pub struct Features: u64 {
+    const TSC           = 1 << 0;
+    const MSR           = 1 << 1;
+    const APIC          = 1 << 2;
+    const X2APIC        = 1 << 3;
+    const PGE           = 1 << 4; // global pages
+    const HUGE_PAGES_1G = 1 << 5;
+    const NX            = 1 << 6;
+    const SYSCALL       = 1 << 7;
+    const RDTSCP        = 1 << 8;
+    const INVARIANT_TSC = 1 << 9;
+    const PCID          = 1 << 10;
+    const INVPCID       = 1 << 11;
+    const SMEP          = 1 << 12;
+    const SMAP          = 1 << 13;
+    const FSGSBASE      = 1 << 14;
+    const RDRAND        = 1 << 15;
+    const RDSEED        = 1 << 16;
+    const FXSR          = 1 << 17;
+    const SSE           = 1 << 18;
+    const SSE2          = 1 << 19;
+    const SSE3          = 1 << 20;
+    const SSSE3         = 1 << 21;
+    const SSE4_1        = 1 << 22;
+    const SSE4_2        = 1 << 23;
+    const XSAVE         = 1 << 24;
+    const AVX           = 1 << 25;
+    const AVX2          = 1 << 26;
+    const HYPERVISOR    = 1 << 27;
+  }
+}
+
+const BASIC_FEATURES: u32 = 0x1;
+const EXTENDED_FEATURES: u32 = 0x7;
+const EXTENDED_LEAVES: u32 = 0x8000_0000;
+const EXTENDED_PROCESSOR_INFO: u32 = 0x8000_0001;
+const BRAND_STRING: u32 = 0x8000_0002; // through 0x8000_0004
+const ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+// (bit, feature) pairs for the registers of each leaf
+#[rustfmt::skip]
+const LEAF_1_EDX: [(u32, Features); 7] = [
+  (4, Features::TSC), (5, Features::MSR), (9, Features::APIC),
+  (13, Features::PGE), (24, Features::FXSR), (25, Features::SSE),
+  (26, Features::SSE2),
+];
+#[rustfmt::skip]
+const LEAF_1_ECX: [(u32, Features); 10] = [
+  (0, Features::SSE3), (9, Features::SSSE3), (17, Features::PCID),
+  (19, Features::SSE4_1), (20, Features::SSE4_2), (21, Features::X2APIC),
+  (26, Features::XSAVE), (28, Features::AVX), (30, Features::RDRAND),
+  (31, Features::HYPERVISOR),
+];
+#[rustfmt::skip]
+const LEAF_7_EBX: [(u32, Features); 6] = [
+  (0, Features::FSGSBASE), (5, Features::AVX2), (7, Features::SMEP),
+  (10, Features::INVPCID), (18, Features::RDSEED), (20, Features::SMAP),
+];
+#[rustfmt::skip]
+const LEAF_80000001_EDX: [(u32, Features); 4] = [
+  (11, Features::SYSCALL), (20, Features::NX), (26, Features::HUGE_PAGES_1G),
+  (27, Features::RDTSCP),
+];
+const LEAF_80000007_EDX: [(u32, Features); 1] = [(8, Features::INVARIANT_TSC)];
+
+fn decode(reg: u32, bits: &[(u32, Features)]) -> Features {
+  bits
+    .iter()
+    .filter(|&&(bit, _)| reg & (1 << bit) != 0)
+    .fold(Features::empty(), |features, &(_, feature)| {
+      features | feature
+    })
+}
+
+pub struct CpuInfo {
+  vendor: [u8; 12],
+  brand: [u8; 48],
+  max_leaf: u32,
+  max_extended_leaf: u32,
+  features: Features,
+}
+
+impl CpuInfo {
+  fn query() -> Self {
+    let cpuid = |leaf| unsafe { __cpuid(leaf) };
+    let CpuidResult { eax, ebx, ecx, edx } = cpuid(0);
+    let max_leaf = eax;
+    let mut vendor = [0; 12];
+    vendor[0..4].copy_from_slice(&ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&ecx.to_le_bytes());
+
+    let max_extended_leaf = cpuid(EXTENDED_LEAVES).eax;
+    let has_leaf = |leaf: u32| match leaf >= EXTENDED_LEAVES {
+      true => leaf <= max_extended_leaf,
+      false => leaf <= max_leaf,
+    };
+
+    let mut features = Features::empty();
+    if has_leaf(BASIC_FEATURES) {
+      let res = cpuid(BASIC_FEATURES);
+      features |= decode(res.edx, &LEAF_1_EDX) | decode(res.ecx, &LEAF_1_ECX);
+    }
+    if has_leaf(EXTENDED_FEATURES) {
+      // subleaf 0, __cpuid leaves ecx as 0
+      features |= decode(cpuid(EXTENDED_FEATURES).ebx, &LEAF_7_EBX);
+    }
+    if has_leaf(EXTENDED_PROCESSOR_INFO) {
+      features |= decode(cpuid(EXTENDED_PROCESSOR_INFO).edx, &LEAF_80000001_EDX);
+    }
+    if has_leaf(ADVANCED_POWER_MANAGEMENT) {
+      features |= decode(cpuid(ADVANCED_POWER_MANAGEMENT).edx, &LEAF_80000007_EDX);
+    }
+
+    let mut brand = [0; 48];
+    if has_leaf(BRAND_STRING + 2) {
+      for (i, chunk) in brand.chunks_mut(16).enumerate() {
+        let res = cpuid(BRAND_STRING + i as u32);
+        for (j, reg) in [res.eax, res.ebx, res.ecx, res.edx].iter().enumerate() {
+          chunk[j * 4..(j + 1) * 4].copy_from_slice(&reg.to_le_bytes());
+        }
+      }
+    }
+
+    Self {
+      vendor,
+      brand,
+      max_leaf,
+      max_extended_leaf,
+      features,
+    }
+  }
+
+  // e.g "GenuineIntel" or "AuthenticAMD"
+  pub fn vendor(&self) -> &str {
+    core::str::from_utf8(&self.vendor).unwrap_or("unknown")
+  }
+
+  // e.g "QEMU Virtual CPU version 2.5+", empty if not supported
+  pub fn brand(&self) -> &str {
+    let len = self.brand.iter().position(|&b| b == 0).unwrap_or(48);
+    core::str::from_utf8(&self.brand[..len])
+      .unwrap_or("unknown")
+      .trim()
+  }
+
+  pub fn features(&self) -> Features {
+    self.features
+  }
+
+  pub fn has(&self, features: Features) -> bool {
+    self.features.contains(features)
+  }
+}
+
+impl fmt::Display for CpuInfo {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{} ({})\nfeatures: {:?}",
+      self.brand(),
+      self.vendor(),
+      self.features
+    )
+  }
+}
+
+lazy_static! {
+  static ref CPU_INFO: CpuInfo = CpuInfo::query();
+}
+
+pub fn info() -> &'static CpuInfo {
+  &CPU_INFO
+}
+
+pub fn features() -> Features {
+  CPU_INFO.features
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn decode_bits() {
+    assert_eq!(decode(0, &LEAF_1_EDX), Features::empty());
+    assert_eq!(decode(1 << 4, &LEAF_1_EDX), Features::TSC);
+    assert_eq!(
+      decode((1 << 20) | (1 << 11) | 1, &LEAF_80000001_EDX),
+      Features::NX | Features::SYSCALL
+    );
+  }
+
+  #[test_case]
+  fn vendor_and_brand() {
+    let vendor = info().vendor();
+    assert_eq!(vendor.len(), 12);
+    assert!(vendor.bytes().all(|b| b.is_ascii_graphic()), "{}", vendor);
+    assert!(info().brand().is_ascii());
+  }
+
+  #[test_case]
+  fn long_mode_features() {
+    // every x86_64 cpu has these, and the kernel already uses them
+    let required = Features::TSC
+      | Features::MSR
+      | Features::APIC
+      | Features::FXSR
+      | Features::SSE
+      | Features::SSE2
+      | Features::SYSCALL;
+    assert!(features().contains(required), "{:?}", features());
+    assert!(info().has(Features::SSE | Features::SSE2));
+  }
+}
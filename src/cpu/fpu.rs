@@ -0,0 +1,413 @@
+#![allow(dead_code)]
+use super::regs::{Cr0, Cr0Flags, Cr4, Cr4Flags, Xcr0, Xcr0Flags};
+use super::{features, Features};
+use crate::interrupts;
+use core::arch::x86_64::__cpuid_count;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
+
+/*
+  The kernel itself is still built with soft-float (see the target
+  json), so it never touches the FPU/SSE registers behind anyone's
+  back and interrupt handlers cannot corrupt the state of the task
+  they interrupted. The registers belong to tasks, i.e user programs
+  and kernel code inside with_kernel_fpu.
+
+  Switching is lazy. switch_to only sets CR0.TS and the first FPU
+  instruction the new task executes raises #NM (device not available),
+  at which point the registers are saved to the previous owner and
+  loaded from the current task. Tasks which never use the FPU never
+  pay for saving it. Every cpu keeps track of whose state is in its
+  own registers.
+  References:
+  https://wiki.osdev.org/SSE#Adding_support
+  https://wiki.osdev.org/FPU
+  Intel SDM Vol. 1, 13 Managing State Using the XSAVE Feature Set
+*/
+
+// Large enough for the legacy area, the xsave header and the AVX state
+const FPU_STATE_SIZE: usize = 1024;
+const MXCSR_OFFSET: usize = 24;
+const MXCSR_MASK_OFFSET: usize = 28;
+const DEFAULT_FCW: u16 = 0x037f; // all exceptions masked
+const DEFAULT_MXCSR: u32 = 0x1f80; // all exceptions masked
+
+// Setting any other bit of MXCSR faults, as do reserved xsave header
+// bits. Cpus which leave the mask in the FXSAVE area 0 use this one.
+const DEFAULT_MXCSR_MASK: u32 = 0xffbf;
+const XSAVE_HEADER_OFFSET: usize = 512;
+const XSAVE_HEADER_SIZE: usize = 64;
+
+#[repr(C, align(64))]
+pub struct FpuState([u8; FPU_STATE_SIZE]);
+
+impl FpuState {
+  // The state after fninit, which is what new tasks start with. An
+  // all zero xsave header means every component is in its init state.
+  pub const fn new() -> Self {
+    let mut bytes = [0; FPU_STATE_SIZE];
+    bytes[0] = DEFAULT_FCW as u8;
+    bytes[1] = (DEFAULT_FCW >> 8) as u8;
+    bytes[MXCSR_OFFSET] = DEFAULT_MXCSR as u8;
+    bytes[MXCSR_OFFSET + 1] = (DEFAULT_MXCSR >> 8) as u8;
+    Self(bytes)
+  }
+
+  fn as_mut_ptr(&mut self) -> *mut u8 {
+    self.0.as_mut_ptr()
+  }
+
+  fn read_u32(&self, offset: usize) -> u32 {
+    let bytes = &self.0[offset..offset + 4];
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+  }
+
+  fn mxcsr(&self) -> u32 {
+    self.read_u32(MXCSR_OFFSET)
+  }
+
+  // Clears the bits which would make restoring a state that came from
+  // user memory fault. Only the components enabled in XCR0 are kept,
+  // in the standard format.
+  pub fn sanitize(&mut self) {
+    let mxcsr = self.mxcsr() & MXCSR_MASK.load(Ordering::Relaxed);
+    self.0[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&mxcsr.to_le_bytes());
+    if XSAVE_ENABLED.load(Ordering::Relaxed) {
+      let header = &mut self.0[XSAVE_HEADER_OFFSET..XSAVE_HEADER_OFFSET + XSAVE_HEADER_SIZE];
+      let mut state_bv = [0; 8];
+      state_bv.copy_from_slice(&header[..8]);
+      let state_bv = u64::from_le_bytes(state_bv) & Xcr0::read().bits();
+      header.iter_mut().for_each(|b| *b = 0);
+      header[..8].copy_from_slice(&state_bv.to_le_bytes());
+    }
+  }
+}
+
+static XSAVE_ENABLED: AtomicBool = AtomicBool::new(false);
+static MXCSR_MASK: AtomicU32 = AtomicU32::new(DEFAULT_MXCSR_MASK);
+crate::percpu! {
+  // Whose state is currently in the registers, null if nobody's
+  static OWNER: AtomicPtr<FpuState> = AtomicPtr::new(ptr::null_mut());
+}
+crate::percpu! {
+  // The state of the task running on the cpu, loaded on the next #NM
+  static CURRENT: AtomicPtr<FpuState> = AtomicPtr::new(ptr::null_mut());
+}
+static KERNEL_FPU_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Used until the scheduler hands out per task states
+static mut BOOT_STATE: FpuState = FpuState::new();
+
+fn owner() -> *mut FpuState {
+  OWNER.with(|owner| owner.load(Ordering::Relaxed))
+}
+
+fn set_owner(state: *mut FpuState) {
+  OWNER.with(|owner| owner.store(state, Ordering::Relaxed));
+}
+
+fn current() -> *mut FpuState {
+  CURRENT.with(|current| current.load(Ordering::Relaxed))
+}
+
+fn set_current(state: *mut FpuState) {
+  CURRENT.with(|current| current.store(state, Ordering::Relaxed));
+}
+
+unsafe fn save(state: *mut FpuState) {
+  let ptr = (*state).as_mut_ptr();
+  if XSAVE_ENABLED.load(Ordering::Relaxed) {
+    asm!("xsave64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+  } else {
+    asm!("fxsave64 [{}]", in(reg) ptr, options(nostack));
+  }
+}
+
+unsafe fn restore(state: *mut FpuState) {
+  let ptr = (*state).as_mut_ptr();
+  if XSAVE_ENABLED.load(Ordering::Relaxed) {
+    asm!("xrstor64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+  } else {
+    asm!("fxrstor64 [{}]", in(reg) ptr, options(nostack));
+  }
+}
+
+unsafe fn clear_task_switched() {
+  asm!("clts", options(nomem, nostack));
+}
+
+unsafe fn set_task_switched() {
+  Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED));
+}
+
+// Saves the live registers to their owner, TS has to be clear
+unsafe fn save_owner() {
+  let owner = OWNER.with(|owner| owner.swap(ptr::null_mut(), Ordering::Relaxed));
+  if !owner.is_null() {
+    save(owner);
+  }
+}
+
+pub fn initialize() {
+  let features = features();
+  assert!(
+    features.contains(Features::FXSR | Features::SSE | Features::SSE2),
+    "the cpu does not support SSE2"
+  );
+  enable();
+  if features.contains(Features::XSAVE) {
+    // the size of the xsave area for the enabled components
+    let size = unsafe { __cpuid_count(0xd, 0).ebx } as usize;
+    assert!(size <= FPU_STATE_SIZE, "xsave area too large: {}", size);
+    XSAVE_ENABLED.store(true, Ordering::Relaxed);
+  }
+  // the MXCSR bits this cpu supports
+  let mut state = FpuState::new();
+  with_kernel_fpu(|| unsafe {
+    asm!("fxsave64 [{}]", in(reg) state.as_mut_ptr(), options(nostack))
+  });
+  let mask = match state.read_u32(MXCSR_MASK_OFFSET) {
+    0 => DEFAULT_MXCSR_MASK,
+    mask => mask,
+  };
+  MXCSR_MASK.store(mask, Ordering::Relaxed);
+  unsafe { set_current(&mut BOOT_STATE) };
+}
+
+// The control register setup, which every cpu has to do for itself.
+// TS is left set so that the first FPU instruction traps.
+pub fn enable() {
+  let features = features();
+  unsafe {
+    Cr0::update(|flags| {
+      flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+      flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+    });
+    Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    if features.contains(Features::XSAVE) {
+      Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+      let mut components = Xcr0Flags::X87 | Xcr0Flags::SSE;
+      if features.contains(Features::AVX) {
+        components |= Xcr0Flags::AVX;
+      }
+      Xcr0::write(components);
+    }
+    set_task_switched();
+  }
+}
+
+// Called by the scheduler when switching to a task. The state has to
+// stay valid until another state is switched to and release is called.
+pub unsafe fn switch_to(state: *mut FpuState) {
+  set_current(state);
+  if owner() == state {
+    clear_task_switched(); // the registers are still ours
+  } else {
+    set_task_switched();
+  }
+}
+
+// Called before the memory of a state is freed, which may still be in
+// use on another cpu
+pub fn release(state: *mut FpuState) {
+  for slot in OWNER.iter().chain(CURRENT.iter()) {
+    let _ = slot.compare_exchange(state, ptr::null_mut(), Ordering::Relaxed, Ordering::Relaxed);
+  }
+}
+
+// Copies the state of the running task into state, from the registers
+// if it owns them. For tasks which continue where this one is, like a
+// forked process. Unsafe since interrupts have to be disabled.
+pub unsafe fn copy_current(state: &mut FpuState) {
+  let current = current();
+  if current.is_null() {
+    *state = FpuState::new();
+    return;
+  }
+  if owner() == current {
+    clear_task_switched();
+    save(current);
+  }
+  ptr::copy_nonoverlapping(current, state, 1);
+}
+
+// Puts the running task back into the init state, when it starts a new
+// program. Unsafe since interrupts have to be disabled.
+pub unsafe fn reset_current() {
+  load_current(&FpuState::new());
+}
+
+// Replaces the state of the running task with state, which has to be
+// valid, see sanitize. Unsafe since interrupts have to be disabled.
+pub unsafe fn load_current(state: &FpuState) {
+  let current = current();
+  if current.is_null() {
+    return;
+  }
+  ptr::copy_nonoverlapping(state, current, 1);
+  // the next FPU instruction loads the new state
+  if owner() == current {
+    set_owner(ptr::null_mut());
+    set_task_switched();
+  }
+}
+
+// #NM handler, hands the registers to the current task
+pub fn handle_device_not_available() {
+  unsafe {
+    clear_task_switched();
+    let current = current();
+    if owner() == current {
+      return;
+    }
+    save_owner();
+    if current.is_null() {
+      let mut init = FpuState::new();
+      restore(&mut init);
+    } else {
+      restore(current);
+      set_owner(current);
+    }
+  }
+}
+
+// Runs f with the FPU and SSE available to the kernel, for functions
+// built with #[target_feature(enable = "sse2")]. Interrupts are off
+// for the duration since the registers are not saved on preemption.
+pub fn with_kernel_fpu<R>(f: impl FnOnce() -> R) -> R {
+  let interrupts_enabled = interrupts::enabled();
+  interrupts::disable();
+  let nested = KERNEL_FPU_ACTIVE.swap(true, Ordering::Relaxed);
+  assert!(!nested, "with_kernel_fpu is not reentrant");
+  unsafe {
+    clear_task_switched();
+    save_owner();
+    let mut init = FpuState::new();
+    restore(&mut init);
+  }
+  let res = f();
+  // the task reloads its own state on its next FPU instruction
+  unsafe { set_task_switched() };
+  KERNEL_FPU_ACTIVE.store(false, Ordering::Relaxed);
+  if interrupts_enabled {
+    interrupts::enable();
+  }
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn read_xmm0() -> u64 {
+    let value;
+    unsafe { asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack)) };
+    value
+  }
+
+  fn write_xmm0(value: u64) {
+    unsafe { asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+  }
+
+  #[test_case]
+  fn sse_enabled() {
+    let cr0 = Cr0::read();
+    assert!(!cr0.contains(Cr0Flags::EMULATE_COPROCESSOR));
+    assert!(cr0.contains(Cr0Flags::MONITOR_COPROCESSOR));
+    assert!(Cr4::read().contains(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    if XSAVE_ENABLED.load(Ordering::Relaxed) {
+      assert!(Xcr0::read().contains(Xcr0Flags::X87 | Xcr0Flags::SSE));
+    }
+  }
+
+  #[test_case]
+  fn kernel_fpu_save_restore() {
+    let mut state = FpuState::new();
+    let value = with_kernel_fpu(|| {
+      write_xmm0(0x1234_5678);
+      unsafe { save(&mut state) };
+      write_xmm0(0);
+      unsafe { restore(&mut state) };
+      read_xmm0()
+    });
+    assert_eq!(value, 0x1234_5678);
+    assert_eq!(state.mxcsr(), DEFAULT_MXCSR);
+    assert!(Cr0::read().contains(Cr0Flags::TASK_SWITCHED));
+  }
+
+  #[test_case]
+  fn lazy_switching() {
+    let (mut a, mut b) = (FpuState::new(), FpuState::new());
+    unsafe {
+      // the first sse instruction raises #NM and loads a
+      switch_to(&mut a);
+      write_xmm0(1);
+      assert_eq!(owner(), &mut a as *mut _);
+      switch_to(&mut b);
+      assert!(Cr0::read().contains(Cr0Flags::TASK_SWITCHED));
+      assert_eq!(read_xmm0(), 0); // b starts in the init state
+      write_xmm0(2);
+      switch_to(&mut a);
+      assert_eq!(read_xmm0(), 1);
+      switch_to(&mut b);
+      assert_eq!(read_xmm0(), 2);
+      // switching back to the owner does not trap
+      switch_to(&mut b);
+      assert!(!Cr0::read().contains(Cr0Flags::TASK_SWITCHED));
+      release(&mut a);
+      release(&mut b);
+      switch_to(&mut BOOT_STATE);
+    }
+  }
+
+  #[test_case]
+  fn release_on_other_cpus() {
+    let mut state = FpuState::new();
+    let other = OWNER.iter().nth(1).unwrap();
+    other.store(&mut state, Ordering::Relaxed);
+    release(&mut state);
+    assert_eq!(other.load(Ordering::Relaxed), ptr::null_mut());
+  }
+
+  #[test_case]
+  fn copy_and_reset() {
+    let (mut a, mut b) = (FpuState::new(), FpuState::new());
+    unsafe {
+      switch_to(&mut a);
+      write_xmm0(7);
+      copy_current(&mut b);
+      switch_to(&mut b);
+      assert_eq!(read_xmm0(), 7);
+      reset_current();
+      assert!(Cr0::read().contains(Cr0Flags::TASK_SWITCHED));
+      assert_eq!(read_xmm0(), 0);
+      switch_to(&mut a);
+      assert_eq!(read_xmm0(), 7);
+      load_current(&b);
+      assert_eq!(read_xmm0(), 0);
+      release(&mut a);
+      release(&mut b);
+      switch_to(&mut BOOT_STATE);
+    }
+  }
+
+  #[test_case]
+  fn sanitize_clears_reserved_bits() {
+    let mut state = FpuState::new();
+    for b in state.0.iter_mut().skip(MXCSR_OFFSET).take(4) {
+      *b = 0xff;
+    }
+    for b in state
+      .0
+      .iter_mut()
+      .skip(XSAVE_HEADER_OFFSET)
+      .take(XSAVE_HEADER_SIZE)
+    {
+      *b = 0xff;
+    }
+    state.sanitize();
+    assert_eq!(state.mxcsr(), MXCSR_MASK.load(Ordering::Relaxed));
+    // restoring it does not fault
+    with_kernel_fpu(|| unsafe { restore(&mut state) });
+  }
+}
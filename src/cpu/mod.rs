@@ -0,0 +1,12 @@
+mod cpuid;
+pub mod fpu;
+pub mod regs;
+
+pub use cpuid::{features, info, CpuInfo, Features};
+
+// The timestamp counter, cycles since reset
+pub fn rdtsc() -> u64 {
+  let (low, high): (u32, u32);
+  unsafe { asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack)) };
+  ((high as u64) << 32) | low as u64
+}
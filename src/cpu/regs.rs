@@ -0,0 +1,317 @@
+#![allow(dead_code)]
+use crate::mem::{PhysAddr, VirtAddr};
+
+/*
+  Typed access to the control registers, rflags and model specific
+  registers. Writes are unsafe since they change how the cpu behaves,
+  e.g turning off paging or loading a bogus page table.
+  References:
+  https://wiki.osdev.org/CPU_Registers_x86-64
+  https://wiki.osdev.org/Model_Specific_Registers
+  Intel SDM Vol. 3A, 2.5 Control Registers
+  Intel SDM Vol. 4, Model-Specific Registers
+*/
+
+bitflags::bitflags! {
+  pub struct Cr0Flags: u64 {
+    const PROTECTED_MODE_ENABLE = 1 << 0;
+    const MONITOR_COPROCESSOR   = 1 << 1;
+    const EMULATE_COPROCESSOR   = 1 << 2;
+    const TASK_SWITCHED         = 1 << 3;
+    const EXTENSION_TYPE        = 1 << 4;
+    const NUMERIC_ERROR         = 1 << 5;
+    const WRITE_PROTECT         = 1 << 16;
+    const ALIGNMENT_MASK        = 1 << 18;
+    const NOT_WRITE_THROUGH     = 1 << 29;
+    const CACHE_DISABLE         = 1 << 30;
+    const PAGING                = 1 << 31;
+  }
+}
+
+bitflags::bitflags! {
+  pub struct Cr4Flags: u64 {
+    const VIRTUAL_8086_MODE_EXTENSIONS = 1 << 0;
+    const PROTECTED_MODE_VIRTUAL_INTERRUPTS = 1 << 1;
+    const TIMESTAMP_DISABLE            = 1 << 2;
+    const DEBUGGING_EXTENSIONS         = 1 << 3;
+    const PAGE_SIZE_EXTENSION          = 1 << 4;
+    const PHYSICAL_ADDRESS_EXTENSION   = 1 << 5;
+    const MACHINE_CHECK_EXCEPTION      = 1 << 6;
+    const PAGE_GLOBAL                  = 1 << 7;
+    const PERFORMANCE_MONITOR_COUNTER  = 1 << 8;
+    const OSFXSR                       = 1 << 9;
+    const OSXMMEXCPT_ENABLE            = 1 << 10;
+    const USER_MODE_INSTRUCTION_PREVENTION = 1 << 11;
+    const FSGSBASE                     = 1 << 16;
+    const PCID                         = 1 << 17;
+    const OSXSAVE                      = 1 << 18;
+    const SUPERVISOR_MODE_EXECUTION_PROTECTION = 1 << 20;
+    const SUPERVISOR_MODE_ACCESS_PREVENTION = 1 << 21;
+  }
+}
+
+bitflags::bitflags! {
+  pub struct EferFlags: u64 {
+    const SYSTEM_CALL_EXTENSIONS = 1 << 0;
+    const LONG_MODE_ENABLE       = 1 << 8;
+    const LONG_MODE_ACTIVE       = 1 << 10;
+    const NO_EXECUTE_ENABLE      = 1 << 11;
+    const SECURE_VIRTUAL_MACHINE_ENABLE = 1 << 12;
+    const FAST_FXSAVE_FXRSTOR    = 1 << 14;
+  }
+}
+
+bitflags::bitflags! {
+  pub struct RFlags: u64 {
+    const CARRY_FLAG          = 1 << 0;
+    const RESERVED            = 1 << 1; // always set
+    const PARITY_FLAG         = 1 << 2;
+    const AUXILIARY_CARRY_FLAG = 1 << 4;
+    const ZERO_FLAG           = 1 << 6;
+    const SIGN_FLAG           = 1 << 7;
+    const TRAP_FLAG           = 1 << 8;
+    const INTERRUPT_FLAG      = 1 << 9;
+    const DIRECTION_FLAG      = 1 << 10;
+    const OVERFLOW_FLAG       = 1 << 11;
+    const IOPL                = 3 << 12;
+    const NESTED_TASK         = 1 << 14;
+    const RESUME_FLAG         = 1 << 16;
+    const VIRTUAL_8086_MODE   = 1 << 17;
+    const ALIGNMENT_CHECK     = 1 << 18;
+    const VIRTUAL_INTERRUPT   = 1 << 19;
+    const VIRTUAL_INTERRUPT_PENDING = 1 << 20;
+    const ID                  = 1 << 21;
+  }
+}
+
+bitflags::bitflags! {
+  // State components enabled for xsave/xrstor
+  pub struct Xcr0Flags: u64 {
+    const X87 = 1 << 0;
+    const SSE = 1 << 1;
+    const AVX = 1 << 2;
+  }
+}
+
+pub struct Cr0;
+
+impl Cr0 {
+  pub fn read() -> Cr0Flags {
+    Cr0Flags::from_bits_truncate(Self::read_raw())
+  }
+
+  pub fn read_raw() -> u64 {
+    let value;
+    unsafe { asm!("mov {}, cr0", out(reg) value, options(nomem, nostack)) };
+    value
+  }
+
+  // Keeps the reserved bits as they are
+  pub unsafe fn write(flags: Cr0Flags) {
+    let reserved = Self::read_raw() & !Cr0Flags::all().bits();
+    Self::write_raw(reserved | flags.bits());
+  }
+
+  pub unsafe fn write_raw(value: u64) {
+    asm!("mov cr0, {}", in(reg) value, options(nostack));
+  }
+
+  pub unsafe fn update(f: impl FnOnce(&mut Cr0Flags)) {
+    let mut flags = Self::read();
+    f(&mut flags);
+    Self::write(flags);
+  }
+}
+
+// Holds the faulting address after a page fault
+pub struct Cr2;
+
+impl Cr2 {
+  pub fn read() -> VirtAddr {
+    let value;
+    unsafe { asm!("mov {}, cr2", out(reg) value, options(nomem, nostack)) };
+    VirtAddr::new(value)
+  }
+}
+
+// The physical address of the level four page table, the low
+// bits are flags or the PCID if enabled.
+pub struct Cr3;
+
+const CR3_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+impl Cr3 {
+  pub fn read() -> (PhysAddr, u64) {
+    let value = Self::read_raw();
+    (PhysAddr::new(value & CR3_ADDR_MASK), value & !CR3_ADDR_MASK)
+  }
+
+  pub fn read_raw() -> u64 {
+    let value;
+    unsafe { asm!("mov {}, cr3", out(reg) value, options(nomem, nostack)) };
+    value
+  }
+
+  // Unsafe since the new page table has to map the kernel
+  pub unsafe fn write(level_four_table: PhysAddr, flags: u64) {
+    assert!(level_four_table.is_page_aligned());
+    Self::write_raw(level_four_table.as_u64() | (flags & !CR3_ADDR_MASK));
+  }
+
+  pub unsafe fn write_raw(value: u64) {
+    asm!("mov cr3, {}", in(reg) value, options(nostack));
+  }
+}
+
+pub struct Cr4;
+
+impl Cr4 {
+  pub fn read() -> Cr4Flags {
+    Cr4Flags::from_bits_truncate(Self::read_raw())
+  }
+
+  pub fn read_raw() -> u64 {
+    let value;
+    unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack)) };
+    value
+  }
+
+  // Keeps the reserved bits as they are
+  pub unsafe fn write(flags: Cr4Flags) {
+    let reserved = Self::read_raw() & !Cr4Flags::all().bits();
+    Self::write_raw(reserved | flags.bits());
+  }
+
+  pub unsafe fn write_raw(value: u64) {
+    asm!("mov cr4, {}", in(reg) value, options(nostack));
+  }
+
+  pub unsafe fn update(f: impl FnOnce(&mut Cr4Flags)) {
+    let mut flags = Self::read();
+    f(&mut flags);
+    Self::write(flags);
+  }
+}
+
+// Only accessible once Cr4Flags::OSXSAVE is set
+pub struct Xcr0;
+
+impl Xcr0 {
+  pub fn read() -> Xcr0Flags {
+    let (low, high): (u32, u32);
+    unsafe {
+      asm!("xgetbv", in("ecx") 0, out("eax") low, out("edx") high, options(nomem, nostack))
+    };
+    Xcr0Flags::from_bits_truncate(((high as u64) << 32) | low as u64)
+  }
+
+  // X87 always has to be set and AVX requires SSE
+  pub unsafe fn write(flags: Xcr0Flags) {
+    let value = flags.bits();
+    asm!("xsetbv", in("ecx") 0, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack));
+  }
+}
+
+impl RFlags {
+  pub fn read() -> Self {
+    Self::from_bits_truncate(Self::read_raw())
+  }
+
+  pub fn read_raw() -> u64 {
+    let value;
+    unsafe { asm!("pushfq; pop {}", out(reg) value, options(nomem)) };
+    value
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msr(u32);
+
+impl Msr {
+  pub const fn new(index: u32) -> Self {
+    Self(index)
+  }
+
+  // Unsafe since reading a msr the cpu does not have is a #GP
+  pub unsafe fn read(self) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") self.0, out("eax") low, out("edx") high, options(nomem, nostack));
+    ((high as u64) << 32) | low as u64
+  }
+
+  pub unsafe fn write(self, value: u64) {
+    let (low, high) = (value as u32, (value >> 32) as u32);
+    asm!("wrmsr", in("ecx") self.0, in("eax") low, in("edx") high, options(nostack));
+  }
+}
+
+pub const IA32_APIC_BASE: Msr = Msr::new(0x1b);
+pub const IA32_PAT: Msr = Msr::new(0x277);
+pub const IA32_EFER: Msr = Msr::new(0xc000_0080);
+pub const IA32_STAR: Msr = Msr::new(0xc000_0081);
+pub const IA32_LSTAR: Msr = Msr::new(0xc000_0082);
+pub const IA32_FMASK: Msr = Msr::new(0xc000_0084);
+pub const IA32_FS_BASE: Msr = Msr::new(0xc000_0100);
+pub const IA32_GS_BASE: Msr = Msr::new(0xc000_0101);
+pub const IA32_KERNEL_GS_BASE: Msr = Msr::new(0xc000_0102);
+
+pub struct Efer;
+
+impl Efer {
+  pub fn read() -> EferFlags {
+    EferFlags::from_bits_truncate(unsafe { IA32_EFER.read() })
+  }
+
+  // Keeps the reserved bits as they are
+  pub unsafe fn write(flags: EferFlags) {
+    let reserved = IA32_EFER.read() & !EferFlags::all().bits();
+    IA32_EFER.write(reserved | flags.bits());
+  }
+
+  pub unsafe fn update(f: impl FnOnce(&mut EferFlags)) {
+    let mut flags = Self::read();
+    f(&mut flags);
+    Self::write(flags);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn long_mode_state() {
+    let cr0 = Cr0::read();
+    assert!(cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE | Cr0Flags::PAGING));
+    assert!(Cr4::read().contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION));
+    let efer = Efer::read();
+    assert!(efer.contains(EferFlags::LONG_MODE_ENABLE | EferFlags::LONG_MODE_ACTIVE));
+    assert!(RFlags::read().contains(RFlags::RESERVED));
+  }
+
+  #[test_case]
+  fn cr3_page_table() {
+    let (addr, _) = Cr3::read();
+    assert!(addr.is_page_aligned());
+    assert_eq!(addr.as_u64(), Cr3::read_raw() & CR3_ADDR_MASK);
+  }
+
+  #[test_case]
+  fn update_cr0() {
+    let before = Cr0::read();
+    unsafe { Cr0::update(|flags| flags.remove(Cr0Flags::WRITE_PROTECT)) };
+    assert!(!Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+    unsafe { Cr0::write(before) };
+    assert_eq!(Cr0::read(), before);
+  }
+
+  #[test_case]
+  fn msr_read_write() {
+    unsafe {
+      let before = IA32_KERNEL_GS_BASE.read();
+      IA32_KERNEL_GS_BASE.write(0x1234_5678_9abc);
+      assert_eq!(IA32_KERNEL_GS_BASE.read(), 0x1234_5678_9abc);
+      IA32_KERNEL_GS_BASE.write(before);
+    }
+  }
+}
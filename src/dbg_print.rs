@@ -1,4 +1,5 @@
 use crate::serial_port;
+use crate::sync::IrqSafeMutex;
 use core::fmt::{self, Write};
 
 // QEMU accepts debug output on the COM1 serial port
@@ -11,6 +12,10 @@ pub fn initialize() {
 
 struct DebugPrinter;
 
+// Interrupt handlers print as well, so the
+// printer must not be held with interrupts on.
+static PRINTER: IrqSafeMutex<DebugPrinter> = IrqSafeMutex::new(DebugPrinter);
+
 impl Write for DebugPrinter {
   fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
     for b in s.bytes() {
@@ -20,9 +25,23 @@ impl Write for DebugPrinter {
   }
 }
 
+// Bytes which need not be text, e.g. written by user programs
+pub fn write_bytes(bytes: &[u8]) {
+  let _printer = PRINTER.lock();
+  for &b in bytes {
+    serial_port::send(COM1, b);
+  }
+}
+
 #[doc(hidden)]
 pub fn __print(args: fmt::Arguments) {
-  DebugPrinter.write_fmt(args).unwrap();
+  PRINTER.lock().write_fmt(args).unwrap();
+}
+
+// Unsafe since it may interleave output. Only meant for
+// panic handlers, the panic might have occurred mid-print.
+pub unsafe fn force_unlock() {
+  PRINTER.force_unlock();
 }
 
 #[macro_export]
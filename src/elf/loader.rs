@@ -0,0 +1,315 @@
+use super::{Elf, ElfError, ProgramHeader, PF_W, PF_X, PROGRAM_HEADER_SIZE, PT_LOAD};
+use crate::mem::address_space::{AddressSpace, USER_END};
+use crate::mem::page_table::PageFlags;
+use crate::mem::VirtAddr;
+use crate::random;
+
+/*
+  Loads an executable into a fresh address space. Every PT_LOAD segment
+  is copied into newly mapped pages, with the rest of the segment after
+  the file contents (the bss) zeroed. The stack at the top of the user
+  range is laid out like the System V ABI expects it at the entry
+  point, from the stack pointer upwards:
+    argc
+    argv pointers, null terminated
+    envp pointers, null terminated
+    auxiliary vector, (type, value) pairs ending with AT_NULL
+    the argument and environment strings and 16 random bytes
+  References:
+  https://refspecs.linuxfoundation.org/elf/x86_64-abi-0.99.pdf (3.4.1)
+  https://lwn.net/Articles/631631/
+*/
+
+pub const STACK_TOP: u64 = USER_END;
+pub const STACK_SIZE: u64 = 0x10000;
+
+// The arguments may take up at most a quarter of the stack
+const MAX_ARGS_SIZE: usize = STACK_SIZE as usize / 4;
+const RANDOM_SIZE: usize = 16;
+
+// Auxiliary vector types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AUXV_LEN: usize = 13;
+
+pub struct Image {
+  pub space: AddressSpace,
+  pub entry: VirtAddr,
+  pub stack_pointer: VirtAddr,
+}
+
+pub fn load(data: &[u8], args: &[&[u8]], env: &[&[u8]]) -> Result<Image, ElfError> {
+  let elf = Elf::parse(data)?;
+  let mut space = AddressSpace::new().map_err(|_| ElfError::OutOfMemory)?;
+  for ph in elf.program_headers().filter(|ph| ph.kind == PT_LOAD) {
+    load_segment(&mut space, &elf, &ph)?;
+  }
+  let stack_pointer = setup_stack(&mut space, &elf, args, env)?;
+  Ok(Image {
+    space,
+    entry: VirtAddr::new(elf.entry()),
+    stack_pointer: VirtAddr::new(stack_pointer),
+  })
+}
+
+fn load_segment(space: &mut AddressSpace, elf: &Elf, ph: &ProgramHeader) -> Result<(), ElfError> {
+  // pages are always readable, PF_R is implied
+  let mut flags = PageFlags::empty();
+  if ph.flags & PF_W != 0 {
+    flags |= PageFlags::WRITABLE;
+  }
+  if ph.flags & PF_X == 0 {
+    flags |= PageFlags::NON_EXECUTABLE;
+  }
+  map_range(space, ph.vaddr, ph.memsz, flags)?;
+  // the range is mapped and inside the user range, this cannot fail
+  space.write(ph.vaddr, elf.segment_data(ph));
+  space.zero(ph.vaddr + ph.filesz, (ph.memsz - ph.filesz) as usize);
+  Ok(())
+}
+
+// Maps the pages of [start, start + len), none of which may be mapped
+// already since they would have different permissions. The pages
+// mapped before running out of memory are freed with the space.
+fn map_range(
+  space: &mut AddressSpace,
+  start: u64,
+  len: u64,
+  flags: PageFlags,
+) -> Result<(), ElfError> {
+  for page in (start & !0xfff..start + len).step_by(0x1000) {
+    let page = VirtAddr::new(page);
+    if space.translate(page).is_some() {
+      return Err(ElfError::OverlappingSegments);
+    }
+    space.map(page, flags).map_err(|_| ElfError::OutOfMemory)?;
+  }
+  Ok(())
+}
+
+fn setup_stack(
+  space: &mut AddressSpace,
+  elf: &Elf,
+  args: &[&[u8]],
+  env: &[&[u8]],
+) -> Result<u64, ElfError> {
+  let strings_size: usize = args.iter().chain(env).map(|s| s.len() + 1).sum();
+  let vector_len = 1 + args.len() + 1 + env.len() + 1 + 2 * AUXV_LEN;
+  if strings_size + RANDOM_SIZE + vector_len * 8 > MAX_ARGS_SIZE {
+    return Err(ElfError::ArgumentsTooLarge);
+  }
+  let flags = PageFlags::WRITABLE | PageFlags::NON_EXECUTABLE;
+  map_range(space, STACK_TOP - STACK_SIZE, STACK_SIZE, flags)?;
+
+  let mut random_bytes = [0; RANDOM_SIZE];
+  random::fill(&mut random_bytes);
+  let random_addr = STACK_TOP - RANDOM_SIZE as u64;
+  space.write(random_addr, &random_bytes);
+  let strings = random_addr - strings_size as u64;
+  let stack_pointer = (strings - vector_len as u64 * 8) & !0xf;
+
+  let mut stack = StackWriter {
+    space,
+    vector: stack_pointer,
+    strings,
+  };
+  stack.push(args.len() as u64);
+  args.iter().for_each(|arg| stack.push_string(arg));
+  stack.push(0);
+  env.iter().for_each(|var| stack.push_string(var));
+  stack.push(0);
+  let auxv = [
+    (AT_PHDR, elf.program_headers_addr().unwrap_or(0)),
+    (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+    (AT_PHNUM, elf.program_header_count() as u64),
+    (AT_PAGESZ, 0x1000),
+    (AT_BASE, 0), // there is no interpreter
+    (AT_FLAGS, 0),
+    (AT_ENTRY, elf.entry()),
+    (AT_UID, 0),
+    (AT_EUID, 0),
+    (AT_GID, 0),
+    (AT_EGID, 0),
+    (AT_SECURE, 0),
+    (AT_RANDOM, random_addr),
+  ];
+  for &(key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+    stack.push(key);
+    stack.push(value);
+  }
+  Ok(stack_pointer)
+}
+
+// Fills in the stack upwards, the vector from the stack pointer and
+// the strings from below the random bytes. All of it is mapped.
+struct StackWriter<'a> {
+  space:   &'a mut AddressSpace,
+  vector:  u64,
+  strings: u64,
+}
+
+impl<'a> StackWriter<'a> {
+  fn push(&mut self, value: u64) {
+    self.space.write(self.vector, &value.to_le_bytes());
+    self.vector += 8;
+  }
+
+  // Pushes a pointer to a null terminated copy of string
+  fn push_string(&mut self, string: &[u8]) {
+    let addr = self.strings;
+    self.space.write(addr, string);
+    self.space.write(addr + string.len() as u64, &[0]);
+    self.strings += string.len() as u64 + 1;
+    self.push(addr);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::cpu::{self, Features};
+  use crate::elf::test_elf::{self, BSS_SIZE, CODE, DATA_BYTES, TEXT};
+  use crate::elf::MAX_SEGMENT_SIZE;
+  use crate::mem::frame_allocator::FrameAllocator;
+  use crate::syscall::Errno;
+
+  fn read_u64(space: &AddressSpace, addr: u64) -> u64 {
+    let mut buf = [0; 8];
+    assert!(space.read(addr, &mut buf));
+    u64::from_le_bytes(buf)
+  }
+
+  fn read_string<'a>(space: &AddressSpace, addr: u64, buf: &'a mut [u8]) -> &'a [u8] {
+    assert!(space.read(addr, buf));
+    let len = buf.iter().position(|&b| b == 0).unwrap();
+    &buf[..len]
+  }
+
+  #[test_case]
+  fn maps_segments() {
+    let image = load(&test_elf::build(), &[], &[]).unwrap();
+    let space = &image.space;
+    assert_eq!(image.entry.as_u64(), TEXT);
+    let nx_supported = cpu::features().contains(Features::NX);
+
+    let text = space.page_entry(VirtAddr::new(TEXT)).unwrap();
+    assert!(text.user_accessible() && !text.writable() && !text.non_executable());
+    let mut code = [0; 2];
+    assert!(space.read(TEXT, &mut code));
+    assert_eq!(code, CODE);
+
+    let data_addr = test_elf::data_addr();
+    let data = space.page_entry(VirtAddr::new(data_addr)).unwrap();
+    assert!(data.user_accessible() && data.writable());
+    assert_eq!(data.non_executable(), nx_supported);
+    let mut data = [0; 16];
+    assert!(space.read(data_addr, &mut data));
+    assert_eq!(data, DATA_BYTES);
+
+    // the bss is zeroed and mapped up to the end
+    let bss = data_addr + DATA_BYTES.len() as u64;
+    let mut chunk = [0xff; 64];
+    for addr in (bss..bss + BSS_SIZE).step_by(chunk.len()) {
+      assert!(space.read(addr, &mut chunk));
+      assert!(chunk.iter().all(|&b| b == 0));
+    }
+    let after = (bss + BSS_SIZE + 0xfff) & !0xfff;
+    assert!(space.translate(VirtAddr::new(after)).is_none());
+  }
+
+  #[test_case]
+  fn stack_layout() {
+    let args: [&[u8]; 2] = [b"prog", b"-v"];
+    let env: [&[u8]; 1] = [b"HOME=/"];
+    let image = load(&test_elf::build(), &args, &env).unwrap();
+    let space = &image.space;
+    let sp = image.stack_pointer.as_u64();
+    assert_eq!(sp % 16, 0);
+    assert!(sp > STACK_TOP - STACK_SIZE && sp < STACK_TOP);
+
+    let mut buf = [0; 8];
+    assert_eq!(read_u64(space, sp), 2);
+    assert_eq!(
+      read_string(space, read_u64(space, sp + 8), &mut buf),
+      b"prog"
+    );
+    assert_eq!(
+      read_string(space, read_u64(space, sp + 16), &mut buf),
+      b"-v"
+    );
+    assert_eq!(read_u64(space, sp + 24), 0);
+    assert_eq!(
+      read_string(space, read_u64(space, sp + 32), &mut buf),
+      b"HOME=/"
+    );
+    assert_eq!(read_u64(space, sp + 40), 0);
+
+    let auxv = |key| {
+      (sp + 48..)
+        .step_by(16)
+        .map(|addr| (read_u64(space, addr), read_u64(space, addr + 8)))
+        .take_while(|&(k, _)| k != AT_NULL)
+        .find(|&(k, _)| k == key)
+        .map(|(_, value)| value)
+    };
+    assert_eq!(auxv(AT_ENTRY), Some(TEXT));
+    assert_eq!(auxv(AT_PAGESZ), Some(0x1000));
+    assert_eq!(auxv(AT_PHNUM), Some(2));
+    assert_eq!(auxv(AT_PHDR), Some(TEXT - 0x1000 + 64));
+    let random_addr = auxv(AT_RANDOM).unwrap();
+    assert_eq!(random_addr, STACK_TOP - RANDOM_SIZE as u64);
+  }
+
+  static HUGE_ARG: [u8; MAX_ARGS_SIZE] = [b'a'; MAX_ARGS_SIZE];
+
+  #[test_case]
+  fn arguments_too_large() {
+    let args: [&[u8]; 1] = [&HUGE_ARG];
+    let too_large = Some(ElfError::ArgumentsTooLarge);
+    assert_eq!(load(&test_elf::build(), &args, &[]).err(), too_large);
+    assert_eq!(load(&test_elf::build(), &[], &args).err(), too_large);
+  }
+
+  #[test_case]
+  fn out_of_memory() {
+    // a bss larger than the memory there is
+    let mut data = test_elf::build();
+    let data_segment = Elf::parse(&data).unwrap().program_headers().nth(1).unwrap();
+    let huge = ProgramHeader {
+      memsz: MAX_SEGMENT_SIZE,
+      ..data_segment
+    };
+    test_elf::write_program_header(&mut data, 1, huge);
+    let before = FrameAllocator::the().allocated();
+    let err = load(&data, &[], &[]).err();
+    assert_eq!(err, Some(ElfError::OutOfMemory));
+    assert_eq!(Errno::from(ElfError::OutOfMemory), Errno::ENOMEM);
+    assert_eq!(FrameAllocator::the().allocated(), before);
+  }
+
+  #[test_case]
+  fn overlapping_segments() {
+    // the data segment moved onto the page with the code
+    let mut data = test_elf::build();
+    let data_segment = Elf::parse(&data).unwrap().program_headers().nth(1).unwrap();
+    let moved = ProgramHeader {
+      vaddr: TEXT + data_segment.vaddr % 0x1000,
+      ..data_segment
+    };
+    test_elf::write_program_header(&mut data, 1, moved);
+    let err = load(&data, &[], &[]).err();
+    assert_eq!(err, Some(ElfError::OverlappingSegments));
+  }
+}
@@ -0,0 +1,466 @@
+#![allow(dead_code)]
+use crate::mem::address_space;
+use crate::syscall::Errno;
+
+mod loader;
+
+#[allow(unused_imports)] // not used by the kernel binary yet
+pub use loader::{load, Image, STACK_SIZE, STACK_TOP};
+
+/*
+  Parsing of ELF64 executables. Only statically linked x86_64
+  executables are supported, there is no dynamic linker and nothing is
+  relocated, so every loadable segment has to be linked to somewhere
+  in the user range of the address space (see address_space.rs).
+  Everything read from the file is validated up front, a malformed file
+  is an error and never a panic or an out of bounds access.
+  References:
+  https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+  https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.pheader.html
+  https://wiki.osdev.org/ELF
+*/
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+// More than there usually is memory to back, loading fails with
+// OutOfMemory then
+const MAX_SEGMENT_SIZE: u64 = 1 << 30;
+
+// Segment types
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+// Segment permissions
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+  Truncated,
+  BadMagic,
+  NotElf64,
+  NotLittleEndian,
+  BadVersion,
+  NotExecutable,
+  WrongMachine,
+  DynamicallyLinked,
+  BadProgramHeaders,
+  BadSegment,
+  NotInUserSpace,
+  OverlappingSegments,
+  NoLoadableSegments,
+  BadEntry,
+  ArgumentsTooLarge,
+  OutOfMemory,
+}
+
+impl From<ElfError> for Errno {
+  fn from(err: ElfError) -> Self {
+    match err {
+      ElfError::ArgumentsTooLarge => Errno::E2BIG,
+      ElfError::OutOfMemory => Errno::ENOMEM,
+      _ => Errno::ENOEXEC,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+  pub kind:   u32,
+  pub flags:  u32,
+  pub offset: u64,
+  pub vaddr:  u64,
+  pub filesz: u64,
+  pub memsz:  u64,
+  pub align:  u64,
+}
+
+impl ProgramHeader {
+  fn parse(bytes: &[u8]) -> Self {
+    Self {
+      kind:   read_u32(bytes, 0),
+      flags:  read_u32(bytes, 4),
+      offset: read_u64(bytes, 8),
+      vaddr:  read_u64(bytes, 16),
+      filesz: read_u64(bytes, 32),
+      memsz:  read_u64(bytes, 40),
+      align:  read_u64(bytes, 48),
+    }
+  }
+
+  fn validate(&self, file_len: usize) -> Result<(), ElfError> {
+    let file_end = self.offset.checked_add(self.filesz);
+    if !matches!(file_end, Some(end) if end <= file_len as u64)
+      || self.filesz > self.memsz
+      || self.memsz > MAX_SEGMENT_SIZE
+    {
+      return Err(ElfError::BadSegment);
+    }
+    // the virtual address has to match the file offset modulo the alignment
+    if self.align > 1
+      && (!self.align.is_power_of_two() || self.vaddr % self.align != self.offset % self.align)
+    {
+      return Err(ElfError::BadSegment);
+    }
+    if !address_space::is_user_range(self.vaddr, self.memsz) {
+      return Err(ElfError::NotInUserSpace);
+    }
+    Ok(())
+  }
+
+  pub fn contains(&self, addr: u64) -> bool {
+    addr >= self.vaddr && addr - self.vaddr < self.memsz
+  }
+}
+
+// A validated executable
+pub struct Elf<'a> {
+  data:     &'a [u8],
+  entry:    u64,
+  ph_start: usize,
+  ph_count: usize,
+}
+
+impl<'a> Elf<'a> {
+  pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+    if data.len() < HEADER_SIZE {
+      return Err(ElfError::Truncated);
+    }
+    if !data.starts_with(MAGIC) {
+      return Err(ElfError::BadMagic);
+    }
+    if data[4] != CLASS_64 {
+      return Err(ElfError::NotElf64);
+    }
+    if data[5] != DATA_LITTLE_ENDIAN {
+      return Err(ElfError::NotLittleEndian);
+    }
+    if data[6] != VERSION_CURRENT || read_u32(data, 20) != VERSION_CURRENT as u32 {
+      return Err(ElfError::BadVersion);
+    }
+    // position independent executables have type dynamic, they would
+    // need relocating
+    if read_u16(data, 16) != TYPE_EXECUTABLE {
+      return Err(ElfError::NotExecutable);
+    }
+    if read_u16(data, 18) != MACHINE_X86_64 {
+      return Err(ElfError::WrongMachine);
+    }
+
+    let ph_offset = read_u64(data, 32);
+    let ph_entry_size = read_u16(data, 54) as usize;
+    let ph_count = read_u16(data, 56) as usize;
+    if ph_entry_size != PROGRAM_HEADER_SIZE {
+      return Err(ElfError::BadProgramHeaders);
+    }
+    let ph_end = ph_offset.checked_add((ph_count * PROGRAM_HEADER_SIZE) as u64);
+    if !matches!(ph_end, Some(end) if end <= data.len() as u64) {
+      return Err(ElfError::Truncated);
+    }
+
+    let elf = Self {
+      data,
+      entry: read_u64(data, 24),
+      ph_start: ph_offset as usize,
+      ph_count,
+    };
+    elf.validate_segments()?;
+    Ok(elf)
+  }
+
+  fn validate_segments(&self) -> Result<(), ElfError> {
+    let mut loadable = 0;
+    let mut entry_executable = false;
+    for ph in self.program_headers() {
+      match ph.kind {
+        PT_INTERP | PT_DYNAMIC => return Err(ElfError::DynamicallyLinked),
+        PT_LOAD => {
+          ph.validate(self.data.len())?;
+          loadable += 1;
+          entry_executable |= ph.flags & PF_X != 0 && ph.contains(self.entry);
+        }
+        _ => {}
+      }
+    }
+    if loadable == 0 {
+      return Err(ElfError::NoLoadableSegments);
+    }
+    if !entry_executable {
+      return Err(ElfError::BadEntry);
+    }
+    Ok(())
+  }
+
+  pub fn entry(&self) -> u64 {
+    self.entry
+  }
+
+  pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+    let (data, start) = (self.data, self.ph_start);
+    (0..self.ph_count).map(move |i| ProgramHeader::parse(&data[start + i * PROGRAM_HEADER_SIZE..]))
+  }
+
+  pub fn program_header_count(&self) -> usize {
+    self.ph_count
+  }
+
+  // The address the program headers end up at once loaded, if any
+  // segment contains them
+  pub fn program_headers_addr(&self) -> Option<u64> {
+    let mut headers = self.program_headers();
+    if let Some(phdr) = headers.find(|ph| ph.kind == PT_PHDR) {
+      return Some(phdr.vaddr);
+    }
+    let start = self.ph_start as u64;
+    let size = (self.ph_count * PROGRAM_HEADER_SIZE) as u64;
+    self
+      .program_headers()
+      .filter(|ph| ph.kind == PT_LOAD)
+      .find(|ph| start >= ph.offset && start + size <= ph.offset + ph.filesz)
+      .map(|ph| ph.vaddr + (start - ph.offset))
+  }
+
+  // The bytes of a validated segment which are stored in the file
+  pub fn segment_data(&self, ph: &ProgramHeader) -> &'a [u8] {
+    &self.data[ph.offset as usize..(ph.offset + ph.filesz) as usize]
+  }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+  let mut buf = [0; 2];
+  buf.copy_from_slice(&bytes[offset..offset + 2]);
+  u16::from_le_bytes(buf)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+  let mut buf = [0; 4];
+  buf.copy_from_slice(&bytes[offset..offset + 4]);
+  u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+  let mut buf = [0; 8];
+  buf.copy_from_slice(&bytes[offset..offset + 8]);
+  u64::from_le_bytes(buf)
+}
+
+// Builds small executables in memory for the tests here and in loader.rs
+#[cfg(test)]
+pub(crate) mod test_elf {
+  use super::*;
+  use crate::mem::address_space::USER_START;
+
+  pub const TEXT: u64 = USER_START + 0x40_0000;
+  pub const DATA: u64 = USER_START + 0x60_0000;
+  pub const BSS_SIZE: u64 = 0x2000;
+  pub const CODE: &[u8] = &[0x0f, 0x0b]; // ud2
+  pub const DATA_BYTES: &[u8] = b"initialized data";
+  // nothing after the data
+  pub const SIZE: usize = DATA_OFFSET + DATA_BYTES.len();
+
+  const TEXT_OFFSET: usize = 0x1000;
+  const DATA_OFFSET: usize = 0x1200;
+
+  fn write(buf: &mut [u8], offset: usize, bytes: &[u8]) {
+    buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+  }
+
+  pub fn write_program_header(buf: &mut [u8], i: usize, ph: ProgramHeader) {
+    let offset = HEADER_SIZE + i * PROGRAM_HEADER_SIZE;
+    write(buf, offset, &ph.kind.to_le_bytes());
+    write(buf, offset + 4, &ph.flags.to_le_bytes());
+    write(buf, offset + 8, &ph.offset.to_le_bytes());
+    write(buf, offset + 16, &ph.vaddr.to_le_bytes());
+    write(buf, offset + 24, &ph.vaddr.to_le_bytes());
+    write(buf, offset + 32, &ph.filesz.to_le_bytes());
+    write(buf, offset + 40, &ph.memsz.to_le_bytes());
+    write(buf, offset + 48, &ph.align.to_le_bytes());
+  }
+
+  // A text segment with CODE at TEXT and a data segment with
+  // DATA_BYTES at DATA followed by BSS_SIZE bytes of bss
+  pub fn build() -> [u8; SIZE] {
+    build_with_code(CODE)
+  }
+
+  // Like build, with other code at the entry point
+  pub fn build_with_code(code: &[u8]) -> [u8; SIZE] {
+    assert!(TEXT_OFFSET + code.len() <= DATA_OFFSET, "code too long");
+    let mut buf = [0; SIZE];
+    write(&mut buf, 0, MAGIC);
+    buf[4] = CLASS_64;
+    buf[5] = DATA_LITTLE_ENDIAN;
+    buf[6] = VERSION_CURRENT;
+    write(&mut buf, 16, &TYPE_EXECUTABLE.to_le_bytes());
+    write(&mut buf, 18, &MACHINE_X86_64.to_le_bytes());
+    write(&mut buf, 20, &(VERSION_CURRENT as u32).to_le_bytes());
+    write(&mut buf, 24, &TEXT.to_le_bytes());
+    write(&mut buf, 32, &(HEADER_SIZE as u64).to_le_bytes());
+    write(&mut buf, 52, &(HEADER_SIZE as u16).to_le_bytes());
+    write(&mut buf, 54, &(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    write(&mut buf, 56, &2u16.to_le_bytes());
+    // the first page of the file, with the headers, is part of the text
+    write_program_header(&mut buf, 0, ProgramHeader {
+      kind:   PT_LOAD,
+      flags:  PF_R | PF_X,
+      offset: 0,
+      vaddr:  TEXT - TEXT_OFFSET as u64,
+      filesz: (TEXT_OFFSET + code.len()) as u64,
+      memsz:  (TEXT_OFFSET + code.len()) as u64,
+      align:  0x1000,
+    });
+    write_program_header(&mut buf, 1, ProgramHeader {
+      kind:   PT_LOAD,
+      flags:  PF_R | PF_W,
+      offset: DATA_OFFSET as u64,
+      vaddr:  DATA + DATA_OFFSET as u64 % 0x1000,
+      filesz: DATA_BYTES.len() as u64,
+      memsz:  DATA_BYTES.len() as u64 + BSS_SIZE,
+      align:  0x1000,
+    });
+    write(&mut buf, TEXT_OFFSET, code);
+    write(&mut buf, DATA_OFFSET, DATA_BYTES);
+    buf
+  }
+
+  pub fn data_addr() -> u64 {
+    DATA + DATA_OFFSET as u64 % 0x1000
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::test_elf::{self, TEXT};
+  use super::*;
+
+  fn parse_error(data: &[u8]) -> ElfError {
+    Elf::parse(data).err().expect("malformed file accepted")
+  }
+
+  #[test_case]
+  fn parse_valid() {
+    let data = test_elf::build();
+    let elf = Elf::parse(&data).unwrap();
+    assert_eq!(elf.entry(), TEXT);
+    assert_eq!(elf.program_header_count(), 2);
+    let mut headers = elf.program_headers();
+    let text = headers.next().unwrap();
+    assert_eq!(text.flags, PF_R | PF_X);
+    assert_eq!(elf.segment_data(&text)[0x1000..], *test_elf::CODE);
+    let data_segment = headers.next().unwrap();
+    assert_eq!(elf.segment_data(&data_segment), test_elf::DATA_BYTES);
+    assert!(headers.next().is_none());
+    // the headers are in the first page of the text segment
+    assert_eq!(elf.program_headers_addr(), Some(TEXT - 0x1000 + 64));
+  }
+
+  #[test_case]
+  fn header_errors() {
+    let valid = test_elf::build();
+    let mut data = valid;
+    data[0] = b'E';
+    assert_eq!(parse_error(&data), ElfError::BadMagic);
+    let mut data = valid;
+    data[4] = 1;
+    assert_eq!(parse_error(&data), ElfError::NotElf64);
+    let mut data = valid;
+    data[5] = 2;
+    assert_eq!(parse_error(&data), ElfError::NotLittleEndian);
+    let mut data = valid;
+    data[16] = 3; // a position independent executable
+    assert_eq!(parse_error(&data), ElfError::NotExecutable);
+    let mut data = valid;
+    data[18] = 3; // i386
+    assert_eq!(parse_error(&data), ElfError::WrongMachine);
+    let mut data = valid;
+    data[54] = 32;
+    assert_eq!(parse_error(&data), ElfError::BadProgramHeaders);
+    let mut data = valid;
+    data[56] = 0xff; // more program headers than fit in the file
+    assert_eq!(parse_error(&data), ElfError::Truncated);
+    let mut data = valid;
+    data[24..32].copy_from_slice(&test_elf::data_addr().to_le_bytes());
+    assert_eq!(parse_error(&data), ElfError::BadEntry);
+  }
+
+  #[test_case]
+  fn segment_errors() {
+    let valid = test_elf::build();
+    let text = Elf::parse(&valid)
+      .unwrap()
+      .program_headers()
+      .next()
+      .unwrap();
+    let with_text = |ph: ProgramHeader| {
+      let mut data = valid;
+      test_elf::write_program_header(&mut data, 0, ph);
+      parse_error(&data)
+    };
+    let beyond_file = ProgramHeader {
+      filesz: 0x2000,
+      memsz: 0x2000,
+      ..text
+    };
+    assert_eq!(with_text(beyond_file), ElfError::BadSegment);
+    let overflowing = ProgramHeader {
+      offset: u64::MAX,
+      ..text
+    };
+    assert_eq!(with_text(overflowing), ElfError::BadSegment);
+    let bigger_file_size = ProgramHeader {
+      memsz: 0x10,
+      ..text
+    };
+    assert_eq!(with_text(bigger_file_size), ElfError::BadSegment);
+    let misaligned = ProgramHeader {
+      vaddr: text.vaddr + 0x10,
+      ..text
+    };
+    assert_eq!(with_text(misaligned), ElfError::BadSegment);
+    let kernel = ProgramHeader {
+      vaddr: 0x20_0000,
+      ..text
+    };
+    assert_eq!(with_text(kernel), ElfError::NotInUserSpace);
+    let huge = ProgramHeader {
+      memsz: u64::MAX,
+      ..text
+    };
+    assert_eq!(with_text(huge), ElfError::BadSegment);
+    let end_of_user_space = address_space::USER_END - 0x1000;
+    let wrapping = ProgramHeader {
+      vaddr: end_of_user_space,
+      ..text
+    };
+    assert_eq!(with_text(wrapping), ElfError::NotInUserSpace);
+    let interp = ProgramHeader {
+      kind: PT_INTERP,
+      ..text
+    };
+    assert_eq!(with_text(interp), ElfError::DynamicallyLinked);
+    let not_executable = ProgramHeader {
+      flags: PF_R,
+      ..text
+    };
+    assert_eq!(with_text(not_executable), ElfError::BadEntry);
+  }
+
+  #[test_case]
+  fn truncated_files() {
+    let data = test_elf::build();
+    for len in 0..data.len() {
+      assert!(Elf::parse(&data[..len]).is_err());
+    }
+  }
+}
@@ -0,0 +1,606 @@
+#![allow(dead_code)]
+use crate::cpu::regs::{Cr0, Cr0Flags, RFlags};
+use crate::interrupts::trap::TrapFrame;
+use crate::mem::page_table;
+use crate::mem::VirtAddr;
+use crate::serial_port;
+use crate::sync::IrqSafeMutex;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/*
+  A stub for the GDB remote serial protocol on the COM2 serial port.
+  It takes over on int3 and debug exceptions and talks to the host
+  until told to continue or single-step. `cargo run` connects COM2
+  to tcp port 1234 (see Cargo.toml), then on the host:
+    gdb target/x86_64-os-target/debug/ax_os -ex "target remote :1234"
+  The kernel only stops once it hits an int3, e.g a
+  `unsafe { asm!("int3") }` placed where you want to start debugging.
+  Until then, int3 and single steps in user mode raise SIGTRAP.
+  References:
+  https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+  https://sourceware.org/gdb/onlinedocs/gdb/Packets.html
+  https://wiki.osdev.org/GDB
+*/
+
+const COM2: u16 = 0x2f8;
+const MAX_PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+
+const INT3: u8 = 0xcc;
+const TRAP_FLAG: u64 = RFlags::TRAP_FLAG.bits();
+const SIGTRAP: u8 = 5;
+
+// Set while gdb is connected, from the first stop until it detaches
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+pub fn initialize() {
+  serial_port::initialize(COM2);
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+  addr:     u64,
+  original: u8,
+}
+
+// Software breakpoints replace the first byte of an instruction with
+// int3 and put it back once removed.
+struct Breakpoints([Option<Breakpoint>; MAX_BREAKPOINTS]);
+
+static BREAKPOINTS: IrqSafeMutex<Breakpoints> =
+  IrqSafeMutex::new(Breakpoints([None; MAX_BREAKPOINTS]));
+
+impl Breakpoints {
+  fn contains(&self, addr: u64) -> bool {
+    self.0.iter().flatten().any(|bp| bp.addr == addr)
+  }
+
+  fn insert(&mut self, addr: u64) -> bool {
+    if self.contains(addr) {
+      return true;
+    }
+    let slot = match self.0.iter_mut().find(|bp| bp.is_none()) {
+      Some(slot) => slot,
+      None => return false,
+    };
+    let original = match read_byte(addr) {
+      Some(b) => b,
+      None => return false,
+    };
+    if !write_byte(addr, INT3) {
+      return false;
+    }
+    *slot = Some(Breakpoint { addr, original });
+    true
+  }
+
+  fn remove(&mut self, addr: u64) -> bool {
+    for slot in self.0.iter_mut() {
+      if let Some(bp) = *slot {
+        if bp.addr == addr {
+          write_byte(bp.addr, bp.original);
+          *slot = None;
+          return true;
+        }
+      }
+    }
+    false
+  }
+
+  fn clear(&mut self) {
+    for i in 0..MAX_BREAKPOINTS {
+      if let Some(bp) = self.0[i] {
+        self.remove(bp.addr);
+      }
+    }
+  }
+}
+
+fn is_canonical(addr: u64) -> bool {
+  ((addr << 16) as i64 >> 16) as u64 == addr
+}
+
+// Only touch memory which is mapped, gdb happily asks for anything
+fn accessible(addr: u64) -> bool {
+  is_canonical(addr) && page_table::mapped(VirtAddr::new(addr))
+}
+
+fn read_byte(addr: u64) -> Option<u8> {
+  if !accessible(addr) {
+    return None;
+  }
+  Some(unsafe { core::ptr::read_volatile(addr as *const u8) })
+}
+
+// Kernel code is mapped read-only, so write protection is turned off
+// while patching in breakpoints.
+fn write_byte(addr: u64, b: u8) -> bool {
+  if !accessible(addr) {
+    return false;
+  }
+  unsafe {
+    let cr0 = Cr0::read();
+    Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+    core::ptr::write_volatile(addr as *mut u8, b);
+    Cr0::write(cr0);
+  }
+  true
+}
+
+fn checksum(data: &[u8]) -> u8 {
+  data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+  match c {
+    b'0'..=b'9' => Some(c - b'0'),
+    b'a'..=b'f' => Some(c - b'a' + 10),
+    b'A'..=b'F' => Some(c - b'A' + 10),
+    _ => None,
+  }
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+  if s.is_empty() || s.len() > 16 {
+    return None;
+  }
+  s.iter()
+    .try_fold(0u64, |n, &c| Some(n << 4 | hex_digit(c)? as u64))
+}
+
+fn parse_hex_byte(s: &[u8]) -> Option<u8> {
+  Some(hex_digit(s[0])? << 4 | hex_digit(s[1])?)
+}
+
+// Registers are sent as little endian hex
+fn parse_hex_le(s: &[u8]) -> Option<u64> {
+  s.chunks(2)
+    .rev()
+    .try_fold(0u64, |n, byte| Some(n << 8 | parse_hex_byte(byte)? as u64))
+}
+
+// Splits "addr,len" style arguments
+fn split_once(s: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+  let i = s.iter().position(|&c| c == sep)?;
+  Some((&s[..i], &s[i + 1..]))
+}
+
+struct Response {
+  buf: [u8; MAX_PACKET_SIZE],
+  len: usize,
+}
+
+impl Response {
+  fn new() -> Self {
+    Self {
+      buf: [0; MAX_PACKET_SIZE],
+      len: 0,
+    }
+  }
+
+  fn as_bytes(&self) -> &[u8] {
+    &self.buf[..self.len]
+  }
+
+  fn push(&mut self, s: &[u8]) {
+    self.buf[self.len..self.len + s.len()].copy_from_slice(s);
+    self.len += s.len();
+  }
+
+  fn push_hex_byte(&mut self, b: u8) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    self.push(&[DIGITS[(b >> 4) as usize], DIGITS[(b & 0xf) as usize]]);
+  }
+
+  fn push_hex_le(&mut self, bytes: &[u8]) {
+    for &b in bytes {
+      self.push_hex_byte(b);
+    }
+  }
+
+  fn error(&mut self, errno: u8) {
+    self.push(b"E");
+    self.push_hex_byte(errno);
+  }
+}
+
+const ERR_INVALID: u8 = 0x16; // EINVAL
+const ERR_FAULT: u8 = 0x0e; // EFAULT
+
+// In the order of the amd64 g packet, followed by eflags and
+// the segment registers as 32 bit values.
+const NUM_REGS: usize = 17;
+
+fn register(frame: &mut TrapFrame, i: usize) -> &mut u64 {
+  match i {
+    0 => &mut frame.rax,
+    1 => &mut frame.rbx,
+    2 => &mut frame.rcx,
+    3 => &mut frame.rdx,
+    4 => &mut frame.rsi,
+    5 => &mut frame.rdi,
+    6 => &mut frame.rbp,
+    7 => &mut frame.frame.stack_ptr,
+    8 => &mut frame.r8,
+    9 => &mut frame.r9,
+    10 => &mut frame.r10,
+    11 => &mut frame.r11,
+    12 => &mut frame.r12,
+    13 => &mut frame.r13,
+    14 => &mut frame.r14,
+    15 => &mut frame.r15,
+    16 => &mut frame.frame.instruction_ptr,
+    _ => unreachable!(),
+  }
+}
+
+fn read_registers(frame: &mut TrapFrame, res: &mut Response) {
+  for i in 0..NUM_REGS {
+    res.push_hex_le(&register(frame, i).to_le_bytes());
+  }
+  res.push_hex_le(&(frame.frame.cpu_flags as u32).to_le_bytes());
+  res.push_hex_le(&(frame.frame.code_segment as u32).to_le_bytes());
+  res.push_hex_le(&(frame.frame.stack_segment as u32).to_le_bytes());
+  // ds, es, fs and gs are unused in long mode
+  for _ in 0..4 {
+    res.push_hex_le(&0u32.to_le_bytes());
+  }
+}
+
+// Segment registers cannot be changed, everything after eflags is ignored
+fn write_registers(frame: &mut TrapFrame, data: &[u8]) -> bool {
+  if data.len() < NUM_REGS * 16 + 8 {
+    return false;
+  }
+  let mut values = [0; NUM_REGS];
+  for (i, value) in values.iter_mut().enumerate() {
+    *value = match parse_hex_le(&data[i * 16..(i + 1) * 16]) {
+      Some(v) => v,
+      None => return false,
+    };
+  }
+  let flags = match parse_hex_le(&data[NUM_REGS * 16..NUM_REGS * 16 + 8]) {
+    Some(v) => v,
+    None => return false,
+  };
+  for (i, &value) in values.iter().enumerate() {
+    *register(frame, i) = value;
+  }
+  frame.frame.cpu_flags = (frame.frame.cpu_flags & !0xffff_ffff) | flags;
+  true
+}
+
+fn read_memory(args: &[u8], res: &mut Response) {
+  let (addr, len) = match split_once(args, b',') {
+    Some((addr, len)) => (parse_hex(addr), parse_hex(len)),
+    None => return res.error(ERR_INVALID),
+  };
+  let (addr, len) = match (addr, len) {
+    (Some(addr), Some(len)) if len as usize <= (MAX_PACKET_SIZE - 4) / 2 => (addr, len),
+    _ => return res.error(ERR_INVALID),
+  };
+  let start = res.len;
+  for a in addr..addr.saturating_add(len) {
+    match read_byte(a) {
+      Some(b) => res.push_hex_byte(b),
+      None => {
+        res.len = start;
+        return res.error(ERR_FAULT);
+      }
+    }
+  }
+}
+
+fn write_memory(args: &[u8], res: &mut Response) {
+  let parsed = split_once(args, b',').and_then(|(addr, rest)| {
+    let (len, data) = split_once(rest, b':')?;
+    Some((parse_hex(addr)?, parse_hex(len)?, data))
+  });
+  let (addr, len, data) = match parsed {
+    Some((addr, len, data)) if len.checked_mul(2) == Some(data.len() as u64) => (addr, len, data),
+    _ => return res.error(ERR_INVALID),
+  };
+  for i in 0..len {
+    let b = match parse_hex_byte(&data[i as usize * 2..]) {
+      Some(b) => b,
+      None => return res.error(ERR_INVALID),
+    };
+    if !write_byte(addr.wrapping_add(i), b) {
+      return res.error(ERR_FAULT);
+    }
+  }
+  res.push(b"OK");
+}
+
+// Z0,addr,kind and z0,addr,kind, other breakpoint types are unsupported
+fn software_breakpoint(insert: bool, args: &[u8], res: &mut Response) {
+  if !args.starts_with(b"0,") {
+    return;
+  }
+  let addr = match split_once(&args[2..], b',') {
+    Some((addr, _kind)) => parse_hex(addr),
+    None => return res.error(ERR_INVALID),
+  };
+  let addr = match addr {
+    Some(addr) => addr,
+    None => return res.error(ERR_INVALID),
+  };
+  let mut breakpoints = BREAKPOINTS.lock();
+  let ok = match insert {
+    true => breakpoints.insert(addr),
+    false => breakpoints.remove(addr),
+  };
+  match ok {
+    true => res.push(b"OK"),
+    false => res.error(ERR_FAULT),
+  }
+}
+
+enum Action {
+  Reply,
+  Resume,
+}
+
+fn handle_packet(
+  frame: &mut TrapFrame,
+  stop_reply: &[u8],
+  packet: &[u8],
+  res: &mut Response,
+) -> Action {
+  let (&cmd, args) = match packet.split_first() {
+    Some(split) => split,
+    None => return Action::Reply,
+  };
+  match cmd {
+    b'?' => res.push(stop_reply),
+    b'g' => read_registers(frame, res),
+    b'G' => match write_registers(frame, args) {
+      true => res.push(b"OK"),
+      false => res.error(ERR_INVALID),
+    },
+    b'm' => read_memory(args, res),
+    b'M' => write_memory(args, res),
+    b'c' | b's' => {
+      if let Some(addr) = parse_hex(args) {
+        frame.frame.instruction_ptr = addr;
+      }
+      match cmd {
+        b's' => frame.frame.cpu_flags |= TRAP_FLAG,
+        _ => frame.frame.cpu_flags &= !TRAP_FLAG,
+      }
+      return Action::Resume;
+    }
+    b'Z' => software_breakpoint(true, args, res),
+    b'z' => software_breakpoint(false, args, res),
+    b'H' => res.push(b"OK"),
+    b'q' if packet.starts_with(b"qSupported") => {
+      res.push(b"PacketSize=");
+      res.push_hex_le(&[(MAX_PACKET_SIZE >> 8) as u8, MAX_PACKET_SIZE as u8]);
+      res.push(b";swbreak+");
+    }
+    b'q' if packet == b"qAttached" => res.push(b"1"),
+    b'D' | b'k' => {
+      BREAKPOINTS.lock().clear();
+      ATTACHED.store(false, Ordering::SeqCst);
+      frame.frame.cpu_flags &= !TRAP_FLAG;
+      if cmd == b'D' {
+        res.push(b"OK");
+        send_packet(res.as_bytes());
+      }
+      return Action::Resume;
+    }
+    _ => {} // an empty response means unsupported
+  }
+  Action::Reply
+}
+
+fn send_packet(data: &[u8]) {
+  loop {
+    serial_port::send(COM2, b'$');
+    for &b in data {
+      serial_port::send(COM2, b);
+    }
+    serial_port::send(COM2, b'#');
+    let mut res = Response::new();
+    res.push_hex_byte(checksum(data));
+    for &b in res.as_bytes() {
+      serial_port::send(COM2, b);
+    }
+    // wait for the ack, resend on a nack
+    loop {
+      match serial_port::read(COM2) {
+        b'+' => return,
+        b'-' => break,
+        _ => {}
+      }
+    }
+  }
+}
+
+// Returns the length of the packet read into buf
+fn receive_packet(buf: &mut [u8; MAX_PACKET_SIZE]) -> usize {
+  loop {
+    while serial_port::read(COM2) != b'$' {}
+    let mut len = 0;
+    let mut overflow = false;
+    loop {
+      match serial_port::read(COM2) {
+        b'#' => break,
+        b if len < buf.len() => {
+          buf[len] = b;
+          len += 1;
+        }
+        _ => overflow = true,
+      }
+    }
+    let sum = [serial_port::read(COM2), serial_port::read(COM2)];
+    if !overflow && parse_hex_byte(&sum) == Some(checksum(&buf[..len])) {
+      serial_port::send(COM2, b'+');
+      return len;
+    }
+    serial_port::send(COM2, b'-');
+  }
+}
+
+// Talks to gdb until it tells us to resume execution
+fn run(frame: &mut TrapFrame, stop_reply: &[u8]) {
+  send_packet(stop_reply);
+  ATTACHED.store(true, Ordering::SeqCst);
+  let mut packet = [0; MAX_PACKET_SIZE];
+  loop {
+    let len = receive_packet(&mut packet);
+    let mut res = Response::new();
+    match handle_packet(frame, stop_reply, &packet[..len], &mut res) {
+      Action::Reply => send_packet(res.as_bytes()),
+      Action::Resume => return,
+    }
+  }
+}
+
+pub fn is_attached() -> bool {
+  ATTACHED.load(Ordering::SeqCst)
+}
+
+// Called on int3. rip points past the int3, if it is one of our
+// breakpoints rewind it so the instruction is executed once gdb has
+// removed the breakpoint.
+pub fn handle_breakpoint(frame: &mut TrapFrame) {
+  let addr = frame.frame.instruction_ptr - 1;
+  if BREAKPOINTS.lock().contains(addr) {
+    frame.frame.instruction_ptr = addr;
+    run(frame, b"T05swbreak:;");
+  } else {
+    run(frame, &[b'S', b'0', b'0' + SIGTRAP]);
+  }
+}
+
+// Called on the debug exception after a single step
+pub fn handle_debug(frame: &mut TrapFrame) {
+  frame.frame.cpu_flags &= !TRAP_FLAG;
+  run(frame, &[b'S', b'0', b'0' + SIGTRAP]);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn handle(frame: &mut TrapFrame, packet: &[u8]) -> Response {
+    let mut res = Response::new();
+    handle_packet(frame, b"S05", packet, &mut res);
+    res
+  }
+
+  #[test_case]
+  fn packet_checksum() {
+    assert_eq!(checksum(b""), 0);
+    assert_eq!(checksum(b"OK"), 0x9a);
+    assert_eq!(checksum(b"qSupported"), 0x37);
+  }
+
+  #[test_case]
+  fn hex_parsing() {
+    assert_eq!(parse_hex(b"ffff80001234"), Some(0xffff80001234));
+    assert_eq!(parse_hex(b"0"), Some(0));
+    assert_eq!(parse_hex(b""), None);
+    assert_eq!(parse_hex(b"12g"), None);
+    assert_eq!(parse_hex(b"11112222333344445"), None);
+    assert_eq!(parse_hex_le(b"3412000000000000"), Some(0x1234));
+    assert_eq!(parse_hex_byte(b"aB"), Some(0xab));
+  }
+
+  #[test_case]
+  fn canonical_addresses() {
+    assert!(is_canonical(0x0000_7fff_ffff_ffff));
+    assert!(is_canonical(0xffff_8000_0000_0000));
+    assert!(!is_canonical(0x0000_8000_0000_0000));
+    assert!(!is_canonical(0x1000_0000_0000_0000));
+  }
+
+  #[test_case]
+  fn register_packets() {
+    let mut frame = TrapFrame {
+      rax: 0x1122,
+      r15: 0xff,
+      ..TrapFrame::default()
+    };
+    frame.frame.instruction_ptr = 0xdead_beef;
+    let res = handle(&mut frame, b"g");
+    let regs = res.as_bytes();
+    assert_eq!(regs.len(), (NUM_REGS * 8 + 7 * 4) * 2);
+    assert_eq!(&regs[..16], b"2211000000000000");
+    assert_eq!(&regs[15 * 16..16 * 16], b"ff00000000000000");
+    assert_eq!(&regs[16 * 16..17 * 16], b"efbeadde00000000");
+
+    let mut regs = [b'0'; (NUM_REGS * 8 + 7 * 4) * 2];
+    regs[2 * 16..2 * 16 + 2].copy_from_slice(b"42");
+    regs[NUM_REGS * 16..NUM_REGS * 16 + 4].copy_from_slice(b"0201");
+    let mut packet = [b'G'; 1 + (NUM_REGS * 8 + 7 * 4) * 2];
+    packet[1..].copy_from_slice(&regs);
+    assert_eq!(handle(&mut frame, &packet).as_bytes(), b"OK");
+    assert_eq!(frame.rax, 0);
+    assert_eq!(frame.rcx, 0x42);
+    assert_eq!(frame.frame.cpu_flags, 0x102);
+    assert_eq!(handle(&mut frame, b"G1234").as_bytes(), b"E16");
+  }
+
+  #[test_case]
+  fn memory_packets() {
+    let mut frame = TrapFrame::default();
+    let mut data = [0x12u8, 0x34, 0x56];
+    let addr = data.as_mut_ptr() as u64;
+    let mut packet = Response::new();
+    packet.push(b"m");
+    packet.push_hex_le(&addr.to_be_bytes());
+    packet.push(b",3");
+    assert_eq!(handle(&mut frame, packet.as_bytes()).as_bytes(), b"123456");
+
+    packet.buf[0] = b'M';
+    packet.push(b":abcd00");
+    assert_eq!(handle(&mut frame, packet.as_bytes()).as_bytes(), b"OK");
+    assert_eq!(data, [0xab, 0xcd, 0x00]);
+
+    assert_eq!(handle(&mut frame, b"m432143210000,4").as_bytes(), b"E0e");
+    assert_eq!(handle(&mut frame, b"m1234").as_bytes(), b"E16");
+  }
+
+  #[test_case]
+  fn breakpoint_packets() {
+    let mut frame = TrapFrame::default();
+    let mut code = [0x90u8; 4];
+    let addr = code.as_mut_ptr() as u64 + 1;
+    let mut packet = Response::new();
+    packet.push(b"Z0,");
+    packet.push_hex_le(&addr.to_be_bytes());
+    packet.push(b",1");
+    assert_eq!(handle(&mut frame, packet.as_bytes()).as_bytes(), b"OK");
+    assert_eq!(code, [0x90, INT3, 0x90, 0x90]);
+    assert!(BREAKPOINTS.lock().contains(addr));
+
+    packet.buf[0] = b'z';
+    assert_eq!(handle(&mut frame, packet.as_bytes()).as_bytes(), b"OK");
+    assert_eq!(code, [0x90; 4]);
+    assert!(!BREAKPOINTS.lock().contains(addr));
+    assert_eq!(handle(&mut frame, packet.as_bytes()).as_bytes(), b"E0e");
+    // hardware breakpoints are unsupported
+    assert_eq!(handle(&mut frame, b"Z1,1000,1").as_bytes(), b"");
+  }
+
+  #[test_case]
+  fn resume_packets() {
+    let mut frame = TrapFrame::default();
+    let mut res = Response::new();
+    assert!(matches!(
+      handle_packet(&mut frame, b"S05", b"s", &mut res),
+      Action::Resume
+    ));
+    assert_ne!(frame.frame.cpu_flags & TRAP_FLAG, 0);
+    assert!(matches!(
+      handle_packet(&mut frame, b"S05", b"c1000", &mut res),
+      Action::Resume
+    ));
+    assert_eq!(frame.frame.cpu_flags & TRAP_FLAG, 0);
+    assert_eq!(frame.frame.instruction_ptr, 0x1000);
+    assert_eq!(handle(&mut frame, b"?").as_bytes(), b"S05");
+    assert_eq!(handle(&mut frame, b"vMustReplyEmpty").as_bytes(), b"");
+  }
+}
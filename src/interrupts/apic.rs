@@ -0,0 +1,126 @@
+#![allow(dead_code)]
+use crate::cpu::regs::IA32_APIC_BASE;
+use crate::mem::{self, PhysAddr};
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/*
+  The local APIC of each cpu, used here to send inter-processor
+  interrupts. External interrupts still come through the 8259 PIC,
+  which is wired to LINT0 of the bootstrap processor. All local APICs
+  are at the same physical address, each cpu sees its own.
+  References:
+  https://wiki.osdev.org/APIC
+  Intel SDM Vol. 3A, 10.4 Local APIC
+*/
+
+const ID: usize = 0x20;
+const EOI: usize = 0xb0;
+const SPURIOUS_INTERRUPT_VECTOR: usize = 0xf0;
+const ERROR_STATUS: usize = 0x280;
+const INTERRUPT_COMMAND_LOW: usize = 0x300;
+const INTERRUPT_COMMAND_HIGH: usize = 0x310;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// Interrupt command register bits
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+// Local vector table entries
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_EXTINT: u32 = 0b111 << 8;
+const LVT_NMI: u32 = 0b100 << 8;
+
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+fn base() -> u64 {
+  let base = BASE.load(Ordering::Relaxed);
+  assert_ne!(base, 0, "the local APIC is not mapped");
+  base
+}
+
+fn read(reg: usize) -> u32 {
+  unsafe { ptr::read_volatile((base() + reg as u64) as *const u32) }
+}
+
+fn write(reg: usize, value: u32) {
+  unsafe { ptr::write_volatile((base() + reg as u64) as *mut u32, value) };
+}
+
+// Maps the registers and enables the local APIC of the bootstrap
+// processor, routing the PIC to LINT0 so that irqs keep working.
+pub fn initialize() {
+  let phys = unsafe { IA32_APIC_BASE.read() } & APIC_BASE_ADDR_MASK;
+  let virt = mem::map_mmio(PhysAddr::new(phys), 0x1000);
+  BASE.store(virt.as_u64(), Ordering::Relaxed);
+  enable();
+  write(LVT_LINT0, LVT_EXTINT);
+  write(LVT_LINT1, LVT_NMI);
+}
+
+// Enables the local APIC of the calling cpu
+pub fn enable() {
+  unsafe {
+    let apic_base = IA32_APIC_BASE.read();
+    IA32_APIC_BASE.write(apic_base | APIC_GLOBAL_ENABLE);
+  }
+  write(
+    SPURIOUS_INTERRUPT_VECTOR,
+    APIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+  );
+  // the error status register has to be written before it is read
+  write(ERROR_STATUS, 0);
+}
+
+pub fn id() -> u8 {
+  (read(ID) >> 24) as u8
+}
+
+pub fn end_of_interrupt() {
+  write(EOI, 0);
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+  write(INTERRUPT_COMMAND_HIGH, (apic_id as u32) << 24);
+  write(INTERRUPT_COMMAND_LOW, command);
+  while read(INTERRUPT_COMMAND_LOW) & DELIVERY_PENDING != 0 {
+    unsafe { asm!("pause") };
+  }
+}
+
+// Resets the cpu, after which it waits for a startup IPI
+pub fn send_init(apic_id: u8) {
+  send_ipi(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+}
+
+// Starts the cpu in real mode at vector * 0x1000
+pub fn send_startup(apic_id: u8, vector: u8) {
+  send_ipi(apic_id, DELIVERY_STARTUP | LEVEL_ASSERT | vector as u32);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::acpi;
+
+  #[test_case]
+  fn local_apic() {
+    if BASE.load(Ordering::Relaxed) == 0 {
+      initialize();
+    }
+    assert_ne!(unsafe { IA32_APIC_BASE.read() } & APIC_GLOBAL_ENABLE, 0);
+    assert_ne!(read(SPURIOUS_INTERRUPT_VECTOR) & APIC_SOFTWARE_ENABLE, 0);
+    assert_eq!(read(LVT_LINT0) & LVT_MASKED, 0);
+    // the bootstrap processor is one of the processors in the MADT
+    let madt = acpi::madt().unwrap();
+    assert!(madt.processors().any(|cpu| cpu.apic_id == id()));
+  }
+}
@@ -1,8 +1,10 @@
+#![allow(dead_code)]
 use super::DescriptorTablePtr;
 use crate::indexable_from_field;
 use core::mem::size_of;
 
 // Reference: https://wiki.osdev.org/TSS
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct TaskSegmentSelector {
   reserved1: u32,
@@ -15,7 +17,7 @@ pub struct TaskSegmentSelector {
 }
 
 impl TaskSegmentSelector {
-  pub fn new() -> Self {
+  pub const fn new() -> Self {
     Self {
       rsp: [0; 3],
       ist: [0; 7],
@@ -28,18 +30,52 @@ impl TaskSegmentSelector {
   }
 
   pub fn set_interrupt_stack(&mut self, i: usize, stack: &'static [u8]) {
-    let stack_ptr = stack.as_ptr() as u64;
-    let stack_size = stack.len() as u64;
-    self.ist[i - 1] = stack_ptr + stack_size;
+    self.set_interrupt_stack_ptr(i, stack_top(stack));
   }
+
+  pub fn set_interrupt_stack_ptr(&mut self, i: usize, stack_top: u64) {
+    assert_eq!(stack_top & 0xf, 0, "misaligned stack");
+    self.ist[i - 1] = stack_top;
+  }
+
+  // Stack loaded when switching from a less privileged ring to ring i
+  pub fn set_privilege_stack(&mut self, ring: usize, stack: &'static [u8]) {
+    self.set_privilege_stack_ptr(ring, stack_top(stack));
+  }
+
+  pub fn set_privilege_stack_ptr(&mut self, ring: usize, stack_top: u64) {
+    assert_eq!(stack_top & 0xf, 0, "misaligned stack");
+    self.rsp[ring] = stack_top;
+  }
+
+  pub fn privilege_stack(&self, ring: usize) -> u64 {
+    self.rsp[ring]
+  }
+}
+
+// The stack grows downwards and has to be 16 byte aligned
+fn stack_top(stack: &'static [u8]) -> u64 {
+  let stack_ptr = stack.as_ptr() as u64;
+  let stack_size = stack.len() as u64;
+  (stack_ptr + stack_size) & !0xf
 }
 
+// Selectors for the segments set up in interrupts::initialize.
+// The order of the kernel code/data and user data/code
+// segments is mandated by the syscall/sysret instructions.
+pub const KERNEL_CODE_SELECTOR: u16 = 1 << 3;
+pub const KERNEL_DATA_SELECTOR: u16 = 2 << 3;
+pub const USER_DATA_SELECTOR: u16 = (3 << 3) | 3;
+pub const USER_CODE_SELECTOR: u16 = (4 << 3) | 3;
+pub const TSS_SELECTOR: u16 = 5 << 3;
+
+#[derive(Clone, Copy)]
 #[repr(C)]
-pub struct GlobalDescriptorTable([u64; 4]);
+pub struct GlobalDescriptorTable([u64; 7]);
 
 impl GlobalDescriptorTable {
-  pub fn new() -> Self {
-    Self([0; 4])
+  pub const fn new() -> Self {
+    Self([0; 7])
   }
 
   // Safe since the GDT is static
@@ -51,8 +87,10 @@ impl GlobalDescriptorTable {
 
 indexable_from_field!(GlobalDescriptorTable, 0, u64);
 
+const WRITABLE: u64 = 1 << 41; // Must be set for data segments.
 const EXECUTABLE: u64 = 1 << 43; // Must be set for code segments.
 const USER_SEGMENT: u64 = 1 << 44; // Must be set for user segments
+const RING_3: u64 = 3 << 45; // Descriptor privilege level 3
 const PRESENT: u64 = 1 << 47; // Must be set for any segment
 const LONG_MODE: u64 = 1 << 53; // Must be set for long mode code segments.
 
@@ -60,6 +98,18 @@ pub fn kernel_code_segment() -> u64 {
   PRESENT | EXECUTABLE | LONG_MODE | USER_SEGMENT
 }
 
+pub fn kernel_data_segment() -> u64 {
+  PRESENT | WRITABLE | USER_SEGMENT
+}
+
+pub fn user_code_segment() -> u64 {
+  kernel_code_segment() | RING_3
+}
+
+pub fn user_data_segment() -> u64 {
+  kernel_data_segment() | RING_3
+}
+
 pub fn null_segment() -> u64 {
   0
 }
@@ -100,6 +150,11 @@ pub unsafe fn set_cs(segment_index: u64) {
   )
 }
 
+// Unsafe since the caller has to provide a valid data segment
+pub unsafe fn set_ss(segment_index: u16) {
+  asm!("mov ss, {:x}", in(reg) segment_index);
+}
+
 pub unsafe fn load_tss(segment_index: u16) {
   asm!("ltr {:x}", in(reg) segment_index);
 }
@@ -112,6 +167,25 @@ mod tests {
   fn size_check() {
     use core::mem::size_of;
     assert_eq!(size_of::<TaskSegmentSelector>(), 104);
-    assert_eq!(size_of::<GlobalDescriptorTable>(), 8 * 4);
+    assert_eq!(size_of::<GlobalDescriptorTable>(), 8 * 7);
+  }
+
+  #[test_case]
+  fn segment_privilege_levels() {
+    let dpl = |segment: u64| (segment >> 45) & 0b11;
+    assert_eq!(dpl(kernel_code_segment()), 0);
+    assert_eq!(dpl(kernel_data_segment()), 0);
+    assert_eq!(dpl(user_code_segment()), 3);
+    assert_eq!(dpl(user_data_segment()), 3);
+    assert_eq!(USER_DATA_SELECTOR & 3, 3);
+    assert_eq!(USER_CODE_SELECTOR & 3, 3);
+  }
+
+  #[test_case]
+  fn sysret_segment_layout() {
+    // sysret loads cs from STAR[63:48] + 16 and ss from STAR[63:48] + 8
+    assert_eq!(KERNEL_DATA_SELECTOR, KERNEL_CODE_SELECTOR + 8);
+    assert_eq!(USER_DATA_SELECTOR, (KERNEL_DATA_SELECTOR + 8) | 3);
+    assert_eq!(USER_CODE_SELECTOR, (KERNEL_DATA_SELECTOR + 16) | 3);
   }
 }
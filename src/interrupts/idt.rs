@@ -1,51 +1,196 @@
 use super::gdt::current_cs;
-use super::DescriptorTablePtr;
-use crate::indexable_from_field;
+use super::{DescriptorTablePtr, InterruptStackFrame};
+use core::marker::PhantomData;
+
+// The cpu pushes an error code for some exceptions and some may never
+// return, so each vector gets a handler type matching what the cpu does.
+// Reference: https://wiki.osdev.org/Exceptions
+pub type HandlerFunc = extern "x86-interrupt" fn(&mut InterruptStackFrame);
+pub type HandlerFuncWithErrCode = extern "x86-interrupt" fn(&mut InterruptStackFrame, u64);
+pub type DivergingHandlerFunc = extern "x86-interrupt" fn(&mut InterruptStackFrame) -> !;
+pub type DivergingHandlerFuncWithErrCode =
+  extern "x86-interrupt" fn(&mut InterruptStackFrame, u64) -> !;
+
+pub trait HandlerFuncType {
+  fn addr(self) -> usize;
+}
+
+macro_rules! impl_handler_func_type {
+  ($($f:ty),*) => {
+    $(impl HandlerFuncType for $f {
+      fn addr(self) -> usize {
+        self as usize
+      }
+    })*
+  };
+}
+
+impl_handler_func_type!(
+  HandlerFunc,
+  HandlerFuncWithErrCode,
+  DivergingHandlerFunc,
+  DivergingHandlerFuncWithErrCode
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum GateType {
+  Interrupt = 0xe, // clears the interrupt flag on entry
+  Trap      = 0xf, // leaves the interrupt flag as is
+}
+
+const PRESENT: u16 = 1 << 15;
+const IST_MASK: u16 = 0b111;
+const GATE_TYPE_MASK: u16 = 0xf << 8;
+const PRIVILEGE_LEVEL_MASK: u16 = 0b11 << 13;
 
 // Reference: https://wiki.osdev.org/Interrupt_Descriptor_Table#IDT_in_IA-32e_Mode_.2864-bit_IDT.29
 #[derive(Clone, Copy)]
 #[repr(C)]
-pub struct IdtEntry {
+pub struct IdtEntry<F> {
   ptr_low:  u16,
   selector: u16,
   options:  u16,
   ptr_mid:  u16,
   ptr_high: u32,
   reserved: u32,
+  handler:  PhantomData<F>,
 }
 
-impl IdtEntry {
+impl<F> IdtEntry<F> {
   fn unimplemented() -> Self {
     Self {
       ptr_low:  0,
       selector: 0,
-      options:  0xe00,
+      options:  (GateType::Interrupt as u16) << 8,
       ptr_mid:  0,
       ptr_high: 0,
       reserved: 0,
+      handler:  PhantomData,
     }
   }
 
-  pub fn set_handler(&mut self, fn_ptr: usize) -> &mut Self {
+  // Unsafe since fn_ptr has to be a function that handles the stack
+  // frame of this vector correctly, e.g an assembly entry stub.
+  pub unsafe fn set_handler_addr(&mut self, fn_ptr: usize) -> &mut Self {
     self.selector = current_cs();
     self.ptr_low = fn_ptr as u16;
     self.ptr_mid = (fn_ptr >> 16) as u16;
     self.ptr_high = (fn_ptr >> 32) as u32;
-    self.options |= 1 << 15;
+    self.set_present(true)
+  }
+
+  // Switch to the given interrupt stack table entry, 1-7, on entry
+  pub fn with_ist(&mut self, stack_index: u16) -> &mut Self {
+    assert!(
+      (1..=7).contains(&stack_index),
+      "invalid IST index {}",
+      stack_index
+    );
+    self.options = (self.options & !IST_MASK) | stack_index;
     self
   }
 
-  pub fn with_ist(&mut self, stack_index: u16) {
-    self.options |= stack_index;
+  pub fn set_gate_type(&mut self, gate_type: GateType) -> &mut Self {
+    self.options = (self.options & !GATE_TYPE_MASK) | (gate_type as u16) << 8;
+    self
+  }
+
+  // The lowest privilege level allowed to invoke the vector with int n
+  pub fn set_privilege_level(&mut self, dpl: u16) -> &mut Self {
+    assert!(dpl <= 3, "invalid privilege level {}", dpl);
+    self.options = (self.options & !PRIVILEGE_LEVEL_MASK) | dpl << 13;
+    self
+  }
+
+  pub fn set_present(&mut self, present: bool) -> &mut Self {
+    self.options &= !PRESENT;
+    self.options |= PRESENT * (present as u16);
+    self
+  }
+}
+
+impl<F: HandlerFuncType> IdtEntry<F> {
+  pub fn set_handler(&mut self, handler: F) -> &mut Self {
+    unsafe { self.set_handler_addr(handler.addr()) }
   }
 }
 
+// The first 32 vectors are reserved for cpu exceptions.
+// Reference: https://wiki.osdev.org/Exceptions
 #[repr(C)]
-pub struct InterruptDescriptorTable([IdtEntry; 256]);
+pub struct InterruptDescriptorTable {
+  pub divide_error: IdtEntry<HandlerFunc>,
+  pub debug: IdtEntry<HandlerFunc>,
+  pub non_maskable_interrupt: IdtEntry<HandlerFunc>,
+  pub breakpoint: IdtEntry<HandlerFunc>,
+  pub overflow: IdtEntry<HandlerFunc>,
+  pub bound_range_exceeded: IdtEntry<HandlerFunc>,
+  pub invalid_opcode: IdtEntry<HandlerFunc>,
+  pub device_not_available: IdtEntry<HandlerFunc>,
+  pub double_fault: IdtEntry<DivergingHandlerFuncWithErrCode>,
+  coprocessor_segment_overrun: IdtEntry<HandlerFunc>,
+  pub invalid_tss: IdtEntry<HandlerFuncWithErrCode>,
+  pub segment_not_present: IdtEntry<HandlerFuncWithErrCode>,
+  pub stack_segment_fault: IdtEntry<HandlerFuncWithErrCode>,
+  pub general_protection_fault: IdtEntry<HandlerFuncWithErrCode>,
+  pub page_fault: IdtEntry<HandlerFuncWithErrCode>,
+  reserved_1: IdtEntry<HandlerFunc>,
+  pub x87_floating_point: IdtEntry<HandlerFunc>,
+  pub alignment_check: IdtEntry<HandlerFuncWithErrCode>,
+  pub machine_check: IdtEntry<DivergingHandlerFunc>,
+  pub simd_floating_point: IdtEntry<HandlerFunc>,
+  pub virtualization: IdtEntry<HandlerFunc>,
+  reserved_2: [IdtEntry<HandlerFunc>; 9],
+  pub security_exception: IdtEntry<HandlerFuncWithErrCode>,
+  reserved_3: IdtEntry<HandlerFunc>,
+  interrupts: [IdtEntry<HandlerFunc>; 256 - 32],
+}
+
+// Offset of the PIC interrupts, see pic::initialize
+const IRQ_OFFSET: u8 = 32;
 
 impl InterruptDescriptorTable {
   pub fn new() -> Self {
-    Self([IdtEntry::unimplemented(); 256])
+    Self {
+      divide_error: IdtEntry::unimplemented(),
+      debug: IdtEntry::unimplemented(),
+      non_maskable_interrupt: IdtEntry::unimplemented(),
+      breakpoint: IdtEntry::unimplemented(),
+      overflow: IdtEntry::unimplemented(),
+      bound_range_exceeded: IdtEntry::unimplemented(),
+      invalid_opcode: IdtEntry::unimplemented(),
+      device_not_available: IdtEntry::unimplemented(),
+      double_fault: IdtEntry::unimplemented(),
+      coprocessor_segment_overrun: IdtEntry::unimplemented(),
+      invalid_tss: IdtEntry::unimplemented(),
+      segment_not_present: IdtEntry::unimplemented(),
+      stack_segment_fault: IdtEntry::unimplemented(),
+      general_protection_fault: IdtEntry::unimplemented(),
+      page_fault: IdtEntry::unimplemented(),
+      reserved_1: IdtEntry::unimplemented(),
+      x87_floating_point: IdtEntry::unimplemented(),
+      alignment_check: IdtEntry::unimplemented(),
+      machine_check: IdtEntry::unimplemented(),
+      simd_floating_point: IdtEntry::unimplemented(),
+      virtualization: IdtEntry::unimplemented(),
+      reserved_2: [IdtEntry::unimplemented(); 9],
+      security_exception: IdtEntry::unimplemented(),
+      reserved_3: IdtEntry::unimplemented(),
+      interrupts: [IdtEntry::unimplemented(); 256 - 32],
+    }
+  }
+
+  // Any of the non-exception vectors, 32-255
+  pub fn interrupt(&mut self, vector: u8) -> &mut IdtEntry<HandlerFunc> {
+    assert!(vector >= 32, "vector {} is reserved for exceptions", vector);
+    &mut self.interrupts[(vector - 32) as usize]
+  }
+
+  // External interrupt from the PIC, irq 0-15
+  pub fn irq(&mut self, irq: u8) -> &mut IdtEntry<HandlerFunc> {
+    assert!(irq < 16, "invalid irq {}", irq);
+    self.interrupt(IRQ_OFFSET + irq)
   }
 
   // Safe since the IDT is static
@@ -55,8 +200,6 @@ impl InterruptDescriptorTable {
   }
 }
 
-indexable_from_field!(InterruptDescriptorTable, 0, IdtEntry);
-
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -64,16 +207,30 @@ mod tests {
   #[test_case]
   fn size_check() {
     use core::mem::size_of;
-    assert_eq!(size_of::<IdtEntry>(), 16);
+    assert_eq!(size_of::<IdtEntry<HandlerFunc>>(), 16);
     assert_eq!(size_of::<InterruptDescriptorTable>(), 256 * 16);
   }
 
+  #[test_case]
+  fn vector_offsets() {
+    let mut idt = InterruptDescriptorTable::new();
+    let base = &idt as *const _ as usize;
+    let offset = |entry: usize| (entry - base) / 16;
+    assert_eq!(offset(&idt.breakpoint as *const _ as usize), 3);
+    assert_eq!(offset(&idt.double_fault as *const _ as usize), 8);
+    assert_eq!(offset(&idt.page_fault as *const _ as usize), 14);
+    assert_eq!(offset(&idt.security_exception as *const _ as usize), 30);
+    assert_eq!(offset(idt.irq(0) as *const _ as usize), 32);
+    assert_eq!(offset(idt.irq(1) as *const _ as usize), 33);
+    assert_eq!(offset(idt.interrupt(255) as *const _ as usize), 255);
+  }
+
   #[test_case]
   fn idt_entry_set_fns() {
-    let mut entry = IdtEntry::unimplemented();
+    let mut entry = IdtEntry::<HandlerFunc>::unimplemented();
     assert_eq!(entry.options, 0xe00);
 
-    entry.set_handler(0x0000111122223333);
+    unsafe { entry.set_handler_addr(0x0000111122223333) };
     assert_eq!(entry.selector, current_cs());
     assert_eq!(entry.options, 0x8e00);
     assert_eq!(entry.ptr_low, 0x3333);
@@ -82,5 +239,35 @@ mod tests {
 
     entry.with_ist(5);
     assert_eq!(entry.options, 0x8e05);
+    entry.with_ist(1);
+    assert_eq!(entry.options, 0x8e01);
+  }
+
+  #[test_case]
+  fn idt_entry_options() {
+    let mut entry = IdtEntry::<HandlerFunc>::unimplemented();
+    entry.set_present(true);
+    assert_eq!(entry.options, 0x8e00);
+    entry.set_privilege_level(3);
+    assert_eq!(entry.options, 0xee00);
+    entry.set_gate_type(GateType::Trap);
+    assert_eq!(entry.options, 0xef00);
+    entry
+      .set_privilege_level(0)
+      .set_gate_type(GateType::Interrupt);
+    assert_eq!(entry.options, 0x8e00);
+    entry.set_present(false);
+    assert_eq!(entry.options, 0x0e00);
+  }
+
+  #[test_case]
+  fn typed_handler() {
+    extern "x86-interrupt" fn handler(_: &mut InterruptStackFrame, _: u64) {}
+    let mut idt = InterruptDescriptorTable::new();
+    idt.page_fault.set_handler(handler);
+    let entry = &idt.page_fault;
+    let addr =
+      entry.ptr_low as usize | (entry.ptr_mid as usize) << 16 | (entry.ptr_high as usize) << 32;
+    assert_eq!(addr, handler as usize);
   }
 }
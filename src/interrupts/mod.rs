@@ -1,15 +1,32 @@
+#![allow(dead_code)]
+use crate::backtrace;
+use crate::cpu::fpu;
+use crate::cpu::regs::RFlags;
+use crate::gdb;
 use crate::hang;
 use crate::io;
 use crate::keyboard;
+use crate::panic_screen;
+use crate::percpu;
+use crate::process::signal::{self, SIGFPE, SIGILL, SIGSEGV, SIGTRAP};
+use crate::random;
+use crate::smp::MAX_CPUS;
+use crate::syscall;
+use crate::task;
+use crate::timer;
 use core::mem::size_of;
 use lazy_static::lazy_static;
 
+pub mod apic;
 pub mod gdt;
 pub mod idt;
 pub mod pic;
+pub mod pit;
+pub mod trap;
 
 use gdt::{GlobalDescriptorTable, TaskSegmentSelector};
 use idt::InterruptDescriptorTable;
+use trap::TrapFrame;
 
 // Used to load the IDT and GDT tables
 #[repr(packed)]
@@ -28,98 +45,193 @@ impl DescriptorTablePtr {
 // References:
 // https://os.phil-opp.com/cpu-exceptions/#the-interrupt-stack-frame
 // https://wiki.osdev.org/Exceptions
-#[derive(Debug)]
+#[derive(Debug, Default, Clone)]
 #[repr(C)]
 pub struct InterruptStackFrame {
-  instruction_ptr: u64,
-  code_segment: u64,
-  cpu_flags: u64,
-  stack_ptr: u64,
-  stack_segment: u64,
+  pub instruction_ptr: u64,
+  pub code_segment: u64,
+  pub cpu_flags: u64,
+  pub stack_ptr: u64,
+  pub stack_segment: u64,
 }
 
-extern "x86-interrupt" fn breakpoint_handler(frame: &mut InterruptStackFrame) {
-  dbg!("breakpoint interrupt!");
-  dbg!("{:x?}", frame);
-  hang();
+impl InterruptStackFrame {
+  pub fn is_user_mode(&self) -> bool {
+    self.code_segment & 3 == 3
+  }
 }
 
-extern "x86-interrupt" fn timer_handler(_: &mut InterruptStackFrame) {
+fn timer_handler() {
   unsafe { pic::end_of_interrupt(0) };
+  timer::tick();
+  random::add_interrupt_entropy(trap::IRQ_TIMER);
+  task::tick(); // last, it may switch to another task
 }
 
-extern "x86-interrupt" fn keyboard_handler(_: &mut InterruptStackFrame) {
+fn keyboard_handler() {
   let scan_code = io::read(0x60);
-  keyboard::handle_keyboard_event(scan_code);
+  keyboard::push_scancode(scan_code);
+  random::add_interrupt_entropy(scan_code as u64);
   unsafe { pic::end_of_interrupt(1) };
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-  frame: &mut InterruptStackFrame,
-  _err_code: u64,
-) -> ! {
-  dbg!("double fault interrupt!");
-  dbg!("{:x?}", frame);
-  hang();
+// Spurious interrupts from the local APIC do not need an EOI
+extern "x86-interrupt" fn spurious_handler(_: &mut InterruptStackFrame) {}
+
+// Called by the assembly entry stubs in trap.rs
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+  match frame.vector {
+    trap::DEBUG if is_user_trap(frame) => exception("debug", SIGTRAP, frame),
+    trap::BREAKPOINT if is_user_trap(frame) => exception("breakpoint", SIGTRAP, frame),
+    trap::DEBUG => gdb::handle_debug(frame),
+    trap::BREAKPOINT => gdb::handle_breakpoint(frame),
+    trap::DEVICE_NOT_AVAILABLE => fpu::handle_device_not_available(),
+    trap::IRQ_TIMER => timer_handler(),
+    trap::IRQ_KEYBOARD => keyboard_handler(),
+    trap::DOUBLE_FAULT => fatal_exception("double fault", frame),
+    trap::DIVIDE_ERROR => exception("divide error", SIGFPE, frame),
+    trap::INVALID_OPCODE => exception("invalid opcode", SIGILL, frame),
+    trap::GENERAL_PROTECTION_FAULT => exception("general protection fault", SIGSEGV, frame),
+    trap::PAGE_FAULT => exception("page fault", SIGSEGV, frame),
+    trap::X87_FLOATING_POINT => exception("x87 floating point exception", SIGFPE, frame),
+    trap::SIMD_FLOATING_POINT => exception("simd floating point exception", SIGFPE, frame),
+    vector => panic!("no trap handler for vector {}", vector),
+  }
+  if frame.frame.is_user_mode() {
+    signal::deliver(frame);
+  }
 }
 
-extern "x86-interrupt" fn page_fault_handler(frame: &mut InterruptStackFrame, err_code: u64) -> ! {
-  dbg!("page fault interrupt!");
-  dbg!("{:x?}", frame);
-  dbg!("errcode {:x}", err_code);
-  hang();
+// Breakpoints and single steps in user mode only go to the gdb stub
+// once gdb is attached, otherwise it would wait for it forever
+fn is_user_trap(frame: &TrapFrame) -> bool {
+  frame.frame.is_user_mode() && !gdb::is_attached()
+}
+
+// Faults of user processes raise a signal, all others are fatal
+fn exception(name: &str, signal: u32, frame: &TrapFrame) {
+  if !frame.frame.is_user_mode() || !signal::force(signal) {
+    fatal_exception(name, frame);
+  }
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-  frame: &mut InterruptStackFrame,
-  err_code: u64,
-) -> ! {
-  dbg!("general protection fault interrupt!");
-  dbg!("{:x?}", frame);
-  dbg!("errcode {:x}", err_code);
+fn fatal_exception(name: &str, frame: &TrapFrame) -> ! {
+  dbg!("{} interrupt!", name);
+  dbg!("{:x?}", frame.frame);
+  if frame.vector != trap::DOUBLE_FAULT {
+    dbg!("errcode {:x}", frame.error_code);
+  }
+  backtrace::print_exception(frame);
+  panic_screen::show_exception(name, frame);
   hang();
 }
 
 lazy_static! {
-  static ref TSS: TaskSegmentSelector = {
-    let mut tss = TaskSegmentSelector::new();
-    // TODO: Allocate this memory instead
-    static mut INTERRUPT_STACK: [u8; 4096] = [0; 4096];
-    tss.set_interrupt_stack(1, unsafe { &INTERRUPT_STACK });
-    tss
-  };
-
-  static ref GDT: GlobalDescriptorTable = {
-    let mut gdt = GlobalDescriptorTable::new();
-    let tss_segment = gdt::tss_segment(&TSS);
-    gdt[0] = gdt::null_segment();
-    gdt[1] = gdt::kernel_code_segment();
-    gdt[2] = tss_segment.0;
-    gdt[3] = tss_segment.1;
-    gdt
-  };
-
   static ref IDT: InterruptDescriptorTable = {
     let mut idt = InterruptDescriptorTable::new();
-    idt[3].set_handler(breakpoint_handler as usize);
-    idt[8].set_handler(double_fault_handler as usize).with_ist(1);
-    idt[13].set_handler(general_protection_fault_handler as usize);
-    idt[14].set_handler(page_fault_handler as usize);
-    idt[32].set_handler(timer_handler as usize);
-    idt[33].set_handler(keyboard_handler as usize);
+    // breakpoints and single steps are handled by the gdb stub,
+    // int3 is allowed from user mode
+    unsafe {
+      idt.breakpoint.set_handler_addr(trap::breakpoint_entry as usize).set_privilege_level(3);
+      idt.debug.set_handler_addr(trap::debug_entry as usize);
+    }
+    // faults dump the full register state if they are fatal, the
+    // ones from user mode become signals
+    unsafe {
+      idt.double_fault.set_handler_addr(trap::double_fault_entry as usize).with_ist(1);
+      idt.divide_error.set_handler_addr(trap::divide_error_entry as usize);
+      idt.invalid_opcode.set_handler_addr(trap::invalid_opcode_entry as usize);
+      idt.general_protection_fault.set_handler_addr(trap::general_protection_fault_entry as usize);
+      idt.page_fault.set_handler_addr(trap::page_fault_entry as usize);
+      idt.x87_floating_point.set_handler_addr(trap::x87_floating_point_entry as usize);
+      idt.simd_floating_point.set_handler_addr(trap::simd_floating_point_entry as usize);
+    }
+    // these can interrupt user mode and use per-cpu data
+    unsafe {
+      idt.device_not_available.set_handler_addr(trap::device_not_available_entry as usize);
+      idt.irq(0).set_handler_addr(trap::timer_entry as usize);
+      idt.irq(1).set_handler_addr(trap::keyboard_entry as usize);
+    }
+    idt.interrupt(apic::SPURIOUS_VECTOR).set_handler(spurious_handler);
     idt
   };
 }
 
-pub fn initialize() {
-  GDT.load();
-  unsafe { gdt::set_cs(8) };
-  unsafe { gdt::load_tss(16) };
+// Every cpu needs its own TSS, and with it its own GDT, the IDT is
+// shared. The stacks of the application processors are set up by smp.
+static mut TSS: [TaskSegmentSelector; MAX_CPUS] = [TaskSegmentSelector::new(); MAX_CPUS];
+static mut GDT: [GlobalDescriptorTable; MAX_CPUS] = [GlobalDescriptorTable::new(); MAX_CPUS];
+static mut BSP_INTERRUPT_STACK: [u8; 4096 * 4] = [0; 4096 * 4];
+static mut BSP_KERNEL_STACK: [u8; 4096 * 4] = [0; 4096 * 4];
+
+fn fill_gdt(gdt: &mut GlobalDescriptorTable, tss: &'static TaskSegmentSelector) {
+  let tss_segment = gdt::tss_segment(tss);
+  gdt[0] = gdt::null_segment();
+  gdt[1] = gdt::kernel_code_segment();
+  gdt[2] = gdt::kernel_data_segment();
+  gdt[3] = gdt::user_data_segment();
+  gdt[4] = gdt::user_code_segment();
+  gdt[5] = tss_segment.0;
+  gdt[6] = tss_segment.1;
+}
+
+fn load_tables(gdt: &'static GlobalDescriptorTable) {
+  gdt.load();
+  unsafe { gdt::set_cs(gdt::KERNEL_CODE_SELECTOR as u64) };
+  unsafe { gdt::set_ss(gdt::KERNEL_DATA_SELECTOR) };
+  unsafe { gdt::load_tss(gdt::TSS_SELECTOR) };
   IDT.load();
+}
+
+// Loads the tables of cpu and sets up everything which depends on
+// them. The stacks in its TSS have to be set.
+unsafe fn initialize_cpu(cpu: usize) {
+  fill_gdt(&mut GDT[cpu], &TSS[cpu]);
+  load_tables(&GDT[cpu]);
+  percpu::initialize(cpu, &mut TSS[cpu]);
+  syscall::initialize();
+}
+
+// Sets the stack the cpu switches to when entering the kernel from
+// ring 3. Unsafe since the stack has to stay valid while in user mode.
+pub unsafe fn set_kernel_stack(stack_top: u64) {
+  (*percpu::tss()).set_privilege_stack_ptr(0, stack_top);
+}
+
+pub fn initialize() {
+  unsafe {
+    TSS[0].set_interrupt_stack(1, &BSP_INTERRUPT_STACK);
+    TSS[0].set_privilege_stack(0, &BSP_KERNEL_STACK);
+    initialize_cpu(0);
+  }
   pic::initialize();
+  pit::initialize();
+  enable();
+}
+
+// Called on each application processor with its index, the top of its
+// kernel stack and of its double fault stack. The PIC only interrupts
+// the bootstrap processor, so there is nothing else to set up.
+// Unsafe since it has to be called once per cpu, on that cpu.
+pub unsafe fn initialize_ap(cpu: usize, kernel_stack_top: u64, interrupt_stack_top: u64) {
+  assert!(cpu > 0 && cpu < MAX_CPUS);
+  TSS[cpu].set_privilege_stack_ptr(0, kernel_stack_top);
+  TSS[cpu].set_interrupt_stack_ptr(1, interrupt_stack_top);
+  initialize_cpu(cpu);
+}
+
+pub fn enabled() -> bool {
+  RFlags::read().contains(RFlags::INTERRUPT_FLAG)
+}
+
+pub fn enable() {
   unsafe { asm!("sti") };
 }
 
+pub fn disable() {
+  unsafe { asm!("cli") };
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -130,4 +242,13 @@ mod tests {
     assert_eq!(size_of::<DescriptorTablePtr>(), 10);
     assert_eq!(size_of::<InterruptStackFrame>(), 40);
   }
+
+  #[test_case]
+  fn interrupt_flag() {
+    assert!(!enabled()); // the test runner disables interrupts
+    enable();
+    assert!(enabled());
+    disable();
+    assert!(!enabled());
+  }
 }
@@ -0,0 +1,16 @@
+use crate::io;
+
+// Programmable interval timer, fires IRQ 0.
+// Reference: https://wiki.osdev.org/Programmable_Interval_Timer
+const CHANNEL0_DATA: u16 = 0x40;
+const MODE_CMD: u16 = 0x43;
+
+const BASE_FREQUENCY: u64 = 1_193_182;
+pub const FREQUENCY: u64 = 1000; // timer interrupts per second
+
+pub fn initialize() {
+  let divisor = (BASE_FREQUENCY / FREQUENCY) as u16;
+  io::send(MODE_CMD, 0x36); // channel 0, lobyte/hibyte, square wave mode
+  io::send(CHANNEL0_DATA, divisor as u8);
+  io::send(CHANNEL0_DATA, (divisor >> 8) as u8);
+}
@@ -0,0 +1,188 @@
+use super::InterruptStackFrame;
+
+/*
+  The x86-interrupt calling convention only gives the handler the
+  interrupt stack frame, the general purpose registers are saved by
+  the compiler where we cannot get at them. Exceptions that need to
+  inspect or modify the full register state, like the debugger, go
+  through an assembly stub which pushes a TrapFrame instead and calls
+  interrupts::trap_handler.
+
+  So do all vectors which can interrupt user mode and touch per-cpu
+  data, since the stub does the swapgs on entry from and return to
+  user mode, see percpu.rs.
+  Reference: https://wiki.osdev.org/Interrupt_Service_Routines
+*/
+
+#[derive(Debug, Default, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+  pub r15: u64,
+  pub r14: u64,
+  pub r13: u64,
+  pub r12: u64,
+  pub r11: u64,
+  pub r10: u64,
+  pub r9: u64,
+  pub r8: u64,
+  pub rbp: u64,
+  pub rdi: u64,
+  pub rsi: u64,
+  pub rdx: u64,
+  pub rcx: u64,
+  pub rbx: u64,
+  pub rax: u64,
+  pub vector: u64,
+  pub error_code: u64, // 0 for vectors without one
+  pub frame: InterruptStackFrame,
+}
+
+// For vectors where the cpu does not push an error code
+macro_rules! trap_entry {
+  ($name:ident, $vector:expr) => {
+    #[naked]
+    pub unsafe extern "C" fn $name() {
+      asm!(
+        "push 0",
+        "push {vector}",
+        "jmp {common}",
+        vector = const $vector,
+        common = sym trap_common,
+        options(noreturn)
+      );
+    }
+  };
+}
+
+macro_rules! trap_entry_with_err_code {
+  ($name:ident, $vector:expr) => {
+    #[naked]
+    pub unsafe extern "C" fn $name() {
+      asm!(
+        "push {vector}",
+        "jmp {common}",
+        vector = const $vector,
+        common = sym trap_common,
+        options(noreturn)
+      );
+    }
+  };
+}
+
+pub const DIVIDE_ERROR: u64 = 0;
+pub const DEBUG: u64 = 1;
+pub const BREAKPOINT: u64 = 3;
+pub const INVALID_OPCODE: u64 = 6;
+pub const DEVICE_NOT_AVAILABLE: u64 = 7;
+pub const DOUBLE_FAULT: u64 = 8;
+pub const GENERAL_PROTECTION_FAULT: u64 = 13;
+pub const PAGE_FAULT: u64 = 14;
+pub const X87_FLOATING_POINT: u64 = 16;
+pub const SIMD_FLOATING_POINT: u64 = 19;
+pub const IRQ_TIMER: u64 = 32;
+pub const IRQ_KEYBOARD: u64 = 33;
+
+trap_entry!(divide_error_entry, DIVIDE_ERROR);
+trap_entry!(debug_entry, DEBUG);
+trap_entry!(breakpoint_entry, BREAKPOINT);
+trap_entry!(invalid_opcode_entry, INVALID_OPCODE);
+trap_entry!(device_not_available_entry, DEVICE_NOT_AVAILABLE);
+trap_entry_with_err_code!(double_fault_entry, DOUBLE_FAULT);
+trap_entry_with_err_code!(general_protection_fault_entry, GENERAL_PROTECTION_FAULT);
+trap_entry_with_err_code!(page_fault_entry, PAGE_FAULT);
+trap_entry!(x87_floating_point_entry, X87_FLOATING_POINT);
+trap_entry!(simd_floating_point_entry, SIMD_FLOATING_POINT);
+trap_entry!(timer_entry, IRQ_TIMER);
+trap_entry!(keyboard_entry, IRQ_KEYBOARD);
+
+// The cpu aligns the stack to 16 bytes before pushing the 5 word
+// interrupt stack frame. With the vector, error code and the 15
+// registers that is 22 words, so rsp is aligned again for the call.
+// The saved cs is 3 words up while the vector and error code are on
+// the stack, its low bits are the privilege level we came from.
+#[naked]
+unsafe extern "C" fn trap_common() {
+  asm!(
+    "
+    test qword ptr [rsp + 24], 3
+    jz 2f
+    swapgs
+    2:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call {handler}
+    mov rdi, rsp
+    jmp {trap_return}
+    ",
+    handler = sym super::trap_handler,
+    trap_return = sym trap_return,
+    options(noreturn)
+  );
+}
+
+// Returns from a trap with the registers in frame, to user or kernel
+// mode depending on the saved cs. Used by sigreturn to go back to where
+// a signal interrupted the thread. Unsafe since interrupts have to be
+// disabled and frame has to be a state the thread can continue from.
+pub unsafe fn resume(frame: &TrapFrame) -> ! {
+  trap_return(frame);
+  unreachable!("trap_return returned");
+}
+
+// Pops the frame at rdi and returns to where it came from
+#[naked]
+unsafe extern "C" fn trap_return(_frame: *const TrapFrame) {
+  asm!(
+    "
+    mov rsp, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    test qword ptr [rsp + 24], 3
+    jz 3f
+    swapgs
+    3:
+    add rsp, 16
+    iretq
+    ",
+    options(noreturn)
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn size_check() {
+    use core::mem::size_of;
+    assert_eq!(size_of::<TrapFrame>(), 22 * 8);
+  }
+}
@@ -0,0 +1,180 @@
+#![allow(dead_code)]
+use super::{close_port, open_port};
+use crate::syscall::Errno;
+
+pub const MAX_HANDLES: usize = 16;
+
+bitflags::bitflags! {
+  // What the holder of a capability may do with the port
+  pub struct Rights: u8 {
+    const SEND    = 1 << 0;
+    const RECEIVE = 1 << 1;
+  }
+}
+
+// The right to use a port. Only the kernel makes them, user programs
+// get handles to the ones in their table. Cloning one opens the port
+// again and dropping it closes it, like a FileRef.
+pub struct Capability {
+  port:   usize,
+  rights: Rights,
+}
+
+impl Capability {
+  // Unsafe since port has to be in use, see create
+  pub(super) unsafe fn new(port: usize, rights: Rights) -> Self {
+    open_port(port, rights);
+    Self::opened(port, rights)
+  }
+
+  // Unsafe since port has to be opened with rights already, the
+  // capability closes it again
+  pub(super) unsafe fn opened(port: usize, rights: Rights) -> Self {
+    Self { port, rights }
+  }
+
+  pub(super) fn port(&self) -> usize {
+    self.port
+  }
+
+  pub fn rights(&self) -> Rights {
+    self.rights
+  }
+
+  // Another capability for the same port, with fewer rights. EINVAL
+  // without any, it would not keep the port alive.
+  pub fn restrict(&self, rights: Rights) -> Result<Capability, Errno> {
+    if rights.is_empty() {
+      return Err(Errno::EINVAL);
+    }
+    if !self.rights.contains(rights) {
+      return Err(Errno::EPERM);
+    }
+    Ok(unsafe { Self::new(self.port, rights) })
+  }
+
+  // Fails with EPERM without the rights
+  pub fn check(&self, rights: Rights) -> Result<(), Errno> {
+    if self.rights.contains(rights) {
+      Ok(())
+    } else {
+      Err(Errno::EPERM)
+    }
+  }
+}
+
+impl Clone for Capability {
+  fn clone(&self) -> Self {
+    unsafe { Self::new(self.port, self.rights) }
+  }
+}
+
+impl Drop for Capability {
+  fn drop(&mut self) {
+    close_port(self.port, self.rights);
+  }
+}
+
+/*
+  The capabilities of a process. A handle is the index of an entry
+  combined with a generation which changes whenever the entry is
+  reused, so a handle which was closed does not silently refer to the
+  next capability put there. A process can only name the capabilities
+  in its own table, which is what makes them unforgeable. Children get
+  a copy of the table of their parent, with the same handles.
+*/
+#[derive(Clone)]
+pub struct HandleTable {
+  entries:     [Option<Capability>; MAX_HANDLES],
+  generations: [u32; MAX_HANDLES],
+}
+
+impl HandleTable {
+  pub fn new() -> Self {
+    const NONE: Option<Capability> = None;
+    Self {
+      entries:     [NONE; MAX_HANDLES],
+      generations: [0; MAX_HANDLES],
+    }
+  }
+
+  // Returns the handle for cap, never 0
+  pub fn insert(&mut self, cap: Capability) -> Result<u64, Errno> {
+    let i = self
+      .entries
+      .iter()
+      .position(Option::is_none)
+      .ok_or(Errno::EMFILE)?;
+    self.generations[i] = self.generations[i].wrapping_add(1).max(1);
+    self.entries[i] = Some(cap);
+    Ok((self.generations[i] as u64) << 8 | i as u64)
+  }
+
+  pub fn get(&self, handle: u64) -> Result<Capability, Errno> {
+    let i = self.index(handle)?;
+    Ok(self.entries[i].clone().unwrap())
+  }
+
+  pub fn close(&mut self, handle: u64) -> Result<(), Errno> {
+    let i = self.index(handle)?;
+    self.entries[i] = None;
+    Ok(())
+  }
+
+  pub fn open_count(&self) -> usize {
+    self.entries.iter().filter(|cap| cap.is_some()).count()
+  }
+
+  pub fn close_all(&mut self) {
+    self.entries.iter_mut().for_each(|cap| *cap = None);
+  }
+
+  fn index(&self, handle: u64) -> Result<usize, Errno> {
+    let (i, generation) = ((handle & 0xff) as usize, handle >> 8);
+    match self.entries.get(i) {
+      Some(Some(_)) if self.generations[i] as u64 == generation => Ok(i),
+      _ => Err(Errno::EBADF),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::create;
+  use super::*;
+
+  #[test_case]
+  fn handles() {
+    let mut table = HandleTable::new();
+    let first = table.insert(create().unwrap()).unwrap();
+    let second = table.insert(create().unwrap()).unwrap();
+    assert_ne!(first, 0);
+    assert_ne!(first, second);
+    let cap = table.get(first).unwrap();
+    assert_eq!(cap.rights(), Rights::SEND | Rights::RECEIVE);
+    let send_only = cap.restrict(Rights::SEND).unwrap();
+    assert!(send_only.restrict(Rights::RECEIVE).is_err());
+    assert_eq!(
+      send_only.restrict(Rights::empty()).err(),
+      Some(Errno::EINVAL)
+    );
+    assert_eq!(send_only.check(Rights::RECEIVE), Err(Errno::EPERM));
+    let third = table.insert(send_only).unwrap();
+    assert_eq!(table.get(third).unwrap().rights(), Rights::SEND);
+    // a closed handle stays invalid when its entry is reused
+    table.close(first).unwrap();
+    assert!(table.get(first).is_err());
+    assert_eq!(table.close(first), Err(Errno::EBADF));
+    let reused = table.insert(cap).unwrap();
+    assert_eq!(reused & 0xff, first & 0xff);
+    assert!(table.get(first).is_err());
+    assert!(table.get(reused).is_ok());
+    assert!(table.get(0).is_err());
+    assert!(table.get(u64::MAX).is_err());
+    let copy = table.clone();
+    table.close_all();
+    assert_eq!(table.open_count(), 0);
+    assert_eq!(copy.open_count(), 3);
+    assert!(copy.get(second).is_ok());
+  }
+}
@@ -0,0 +1,434 @@
+#![allow(dead_code)]
+use crate::mem::frame_allocator::FrameAllocator;
+use crate::mem::PhysAddr;
+use crate::process::signal;
+use crate::sync::{Condvar, Mutex};
+use crate::syscall::Errno;
+use crate::task::{self, TaskId};
+
+pub mod capability;
+
+use capability::{Capability, Rights};
+
+/*
+  Ports for synchronous message passing, the way microkernels talk to
+  their servers. A client calls a port with a message and sleeps until
+  a server which received it replies. A message is a few words and can
+  come with a page, which moves from the sender's address space to the
+  receiver's. Calls queue up in FIFO order until they are received.
+
+  Ports are only reachable through capabilities, which say whether
+  their holder may send to or receive from a port. A port lives as
+  long as there are capabilities for it. Once nobody may receive from
+  it anymore, its pending calls fail with EPIPE, as do calls received
+  by a task which exits without replying. Receiving fails with EPIPE
+  once nothing is queued and nobody may send. A signal ends calling
+  and receiving with EINTR, a call given up that way is withdrawn.
+
+  Reply tokens name a received call. They are only good for the task
+  which received the call, and only until it replies.
+  Reference: https://www.qnx.com/developers/docs/7.1/#com.qnx.doc.neutrino.sys_arch/topic/ipc_Sync_messaging.html
+*/
+
+pub const MESSAGE_WORDS: usize = 8;
+pub const MAX_PORTS: usize = 32;
+const MAX_CALLS: usize = 32; // in flight at the same time
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Message {
+  pub words: [u64; MESSAGE_WORDS],
+}
+
+// A frame which goes along with a message, freed if nobody takes it
+#[derive(Debug)]
+pub struct Page(PhysAddr);
+
+impl Page {
+  // Unsafe since the frame must not be used anywhere else
+  pub unsafe fn new(frame: PhysAddr) -> Self {
+    Self(frame)
+  }
+
+  // The caller takes over the frame
+  pub fn into_frame(self) -> PhysAddr {
+    let frame = self.0;
+    core::mem::forget(self);
+    frame
+  }
+}
+
+impl Drop for Page {
+  fn drop(&mut self) {
+    FrameAllocator::the().free(self.0);
+  }
+}
+
+struct Port {
+  in_use: bool,
+  senders: usize, // capabilities with the right to send
+  receivers: usize,
+  queue: [usize; MAX_CALLS], // the calls waiting to be received
+  head: usize,
+  len: usize,
+}
+
+impl Port {
+  const fn new() -> Self {
+    Self {
+      in_use: false,
+      senders: 0,
+      receivers: 0,
+      queue: [0; MAX_CALLS],
+      head: 0,
+      len: 0,
+    }
+  }
+
+  fn open(&mut self, rights: Rights) {
+    if rights.contains(Rights::SEND) {
+      self.senders += 1;
+    }
+    if rights.contains(Rights::RECEIVE) {
+      self.receivers += 1;
+    }
+  }
+
+  fn push(&mut self, call: usize) {
+    self.queue[(self.head + self.len) % MAX_CALLS] = call;
+    self.len += 1;
+  }
+
+  fn pop(&mut self) -> Option<usize> {
+    if self.len == 0 {
+      return None;
+    }
+    let call = self.queue[self.head];
+    self.head = (self.head + 1) % MAX_CALLS;
+    self.len -= 1;
+    Some(call)
+  }
+
+  // Takes call out of the queue, the others keep their order
+  fn remove(&mut self, call: usize) {
+    let mut kept = 0;
+    for i in 0..self.len {
+      let queued = self.queue[(self.head + i) % MAX_CALLS];
+      if queued != call {
+        self.queue[(self.head + kept) % MAX_CALLS] = queued;
+        kept += 1;
+      }
+    }
+    self.len = kept;
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CallState {
+  Free,
+  Queued,
+  Received(TaskId), // by the task which has to reply
+  Replied,
+  Failed(Errno),
+}
+
+struct Call {
+  state: CallState,
+  port: usize,
+  generation: u32, // changes when the slot is freed, for reply tokens
+  message: Message,
+  page: Option<Page>,
+}
+
+impl Call {
+  const fn new() -> Self {
+    Self {
+      state: CallState::Free,
+      port: 0,
+      generation: 0,
+      message: Message {
+        words: [0; MESSAGE_WORDS],
+      },
+      page: None,
+    }
+  }
+
+  // Wakes the caller, the page is freed
+  fn fail(&mut self, slot: usize, err: Errno) {
+    self.state = CallState::Failed(err);
+    self.page = None;
+    REPLIED[slot].notify_one();
+  }
+}
+
+struct Ipc {
+  ports: [Port; MAX_PORTS],
+  calls: [Call; MAX_CALLS],
+}
+
+impl Ipc {
+  // Fails the calls on port which are still waiting for a reply
+  fn fail_calls(&mut self, port: usize) {
+    while self.ports[port].pop().is_some() {}
+    for (i, call) in self.calls.iter_mut().enumerate() {
+      if call.port == port && matches!(call.state, CallState::Queued | CallState::Received(_)) {
+        call.fail(i, Errno::EPIPE);
+      }
+    }
+  }
+}
+
+static IPC: Mutex<Ipc> = {
+  const FREE_PORT: Port = Port::new();
+  const FREE_CALL: Call = Call::new();
+  Mutex::new(Ipc {
+    ports: [FREE_PORT; MAX_PORTS],
+    calls: [FREE_CALL; MAX_CALLS],
+  })
+};
+
+// Receivers of a port wait on the port's condvar, callers on their call's
+#[allow(clippy::declare_interior_mutable_const)]
+const CONDVAR: Condvar = Condvar::new();
+static QUEUED: [Condvar; MAX_PORTS] = [CONDVAR; MAX_PORTS];
+static REPLIED: [Condvar; MAX_CALLS] = [CONDVAR; MAX_CALLS];
+
+// A new port, returns a capability to send to and receive from it
+pub fn create() -> Result<Capability, Errno> {
+  let mut ipc = IPC.lock();
+  let port = ipc
+    .ports
+    .iter()
+    .position(|port| !port.in_use)
+    .ok_or(Errno::EAGAIN)?;
+  let rights = Rights::SEND | Rights::RECEIVE;
+  ipc.ports[port] = Port::new();
+  ipc.ports[port].in_use = true;
+  // opened before the lock is dropped, a port nobody has open is free
+  ipc.ports[port].open(rights);
+  Ok(unsafe { Capability::opened(port, rights) })
+}
+
+// The number of ports in use
+pub fn count() -> usize {
+  IPC.lock().ports.iter().filter(|port| port.in_use).count()
+}
+
+pub(super) fn open_port(port: usize, rights: Rights) {
+  IPC.lock().ports[port].open(rights);
+}
+
+pub(super) fn close_port(index: usize, rights: Rights) {
+  let mut ipc = IPC.lock();
+  let port = &mut ipc.ports[index];
+  if rights.contains(Rights::SEND) {
+    port.senders -= 1;
+    if port.senders == 0 {
+      QUEUED[index].notify_all();
+    }
+  }
+  if rights.contains(Rights::RECEIVE) {
+    port.receivers -= 1;
+    if port.receivers == 0 {
+      ipc.fail_calls(index);
+    }
+  }
+  let port = &mut ipc.ports[index];
+  if port.senders == 0 && port.receivers == 0 {
+    port.in_use = false;
+  }
+}
+
+// Sends message to the port of cap, which needs the right to send,
+// and waits for the reply. A page sent along is gone even if the call
+// fails. EINTR if a signal arrives before the reply, the call is
+// withdrawn then.
+pub fn call(
+  cap: &Capability,
+  message: Message,
+  page: Option<Page>,
+) -> Result<(Message, Option<Page>), Errno> {
+  cap.check(Rights::SEND)?;
+  let port = cap.port();
+  let mut ipc = IPC.lock();
+  if ipc.ports[port].receivers == 0 {
+    return Err(Errno::EPIPE);
+  }
+  let slot = ipc
+    .calls
+    .iter()
+    .position(|call| call.state == CallState::Free)
+    .ok_or(Errno::EAGAIN)?;
+  let call = &mut ipc.calls[slot];
+  call.state = CallState::Queued;
+  call.port = port;
+  call.message = message;
+  call.page = page;
+  ipc.ports[port].push(slot);
+  QUEUED[port].notify_one();
+  let mut interrupted = false;
+  while !interrupted
+    && matches!(
+      ipc.calls[slot].state,
+      CallState::Queued | CallState::Received(_)
+    )
+  {
+    ipc = match REPLIED[slot].wait_interruptible(ipc, signal::interrupted) {
+      Some(ipc) => ipc,
+      None => {
+        interrupted = true;
+        IPC.lock()
+      }
+    };
+  }
+  // an interrupted call is withdrawn, its reply token stops working
+  ipc.ports[port].remove(slot);
+  let call = &mut ipc.calls[slot];
+  let res = match call.state {
+    CallState::Replied => Ok((call.message, call.page.take())),
+    CallState::Failed(err) => Err(err),
+    _ => Err(Errno::EINTR),
+  };
+  call.state = CallState::Free;
+  call.page = None;
+  call.generation = call.generation.wrapping_add(1);
+  res
+}
+
+// Waits for a call to the port of cap, which needs the right to
+// receive. Returns a token to reply with, the message and its page,
+// or EINTR if a signal arrives first.
+pub fn receive(cap: &Capability) -> Result<(u64, Message, Option<Page>), Errno> {
+  cap.check(Rights::RECEIVE)?;
+  let me = task::current().expect("only tasks can receive");
+  let port = cap.port();
+  let mut ipc = IPC.lock();
+  loop {
+    if let Some(slot) = ipc.ports[port].pop() {
+      let call = &mut ipc.calls[slot];
+      call.state = CallState::Received(me);
+      let token = (call.generation as u64) << 8 | slot as u64;
+      return Ok((token, call.message, call.page.take()));
+    }
+    if ipc.ports[port].senders == 0 {
+      return Err(Errno::EPIPE);
+    }
+    ipc = QUEUED[port]
+      .wait_interruptible(ipc, signal::interrupted)
+      .ok_or(Errno::EINTR)?;
+  }
+}
+
+// Answers the call token stands for, which the current task received
+pub fn reply(token: u64, message: Message, page: Option<Page>) -> Result<(), Errno> {
+  let me = task::current().expect("only tasks can reply");
+  let (slot, generation) = ((token & 0xff) as usize, token >> 8);
+  let mut ipc = IPC.lock();
+  let call = match ipc.calls.get_mut(slot) {
+    Some(call) if call.generation as u64 == generation => call,
+    _ => return Err(Errno::EINVAL),
+  };
+  match call.state {
+    CallState::Received(task) if task == me => {}
+    CallState::Received(_) => return Err(Errno::EPERM),
+    _ => return Err(Errno::EINVAL),
+  }
+  call.state = CallState::Replied;
+  call.message = message;
+  call.page = page;
+  REPLIED[slot].notify_one();
+  Ok(())
+}
+
+// Fails the calls task received but did not reply to, when it exits
+pub fn abandon(task: TaskId) {
+  let mut ipc = IPC.lock();
+  for (i, call) in ipc.calls.iter_mut().enumerate() {
+    if call.state == CallState::Received(task) {
+      call.fail(i, Errno::EPIPE);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn message(first: u64) -> Message {
+    let mut message = Message::default();
+    message.words[0] = first;
+    message
+  }
+
+  static SERVER: Mutex<Option<Capability>> = Mutex::new(None);
+
+  // Replies to calls with the first word doubled until it gets a 0
+  fn double_server() {
+    let cap = SERVER.lock().take().unwrap();
+    loop {
+      let (token, mut message, page) = receive(&cap).unwrap();
+      assert!(page.is_none());
+      if message.words[0] == 0 {
+        return reply(token, message, None).unwrap();
+      }
+      message.words[0] *= 2;
+      reply(token, message, None).unwrap();
+    }
+  }
+
+  #[test_case]
+  fn call_and_reply() {
+    let ports = count();
+    let cap = create().unwrap();
+    *SERVER.lock() = Some(cap.restrict(Rights::RECEIVE).unwrap());
+    let server = task::spawn(double_server).unwrap();
+    let client = cap.restrict(Rights::SEND).unwrap();
+    drop(cap);
+    for i in 1..10 {
+      let (reply, page) = call(&client, message(i), None).unwrap();
+      assert_eq!(reply.words[0], 2 * i);
+      assert!(page.is_none());
+    }
+    assert_eq!(receive(&client).err(), Some(Errno::EPERM));
+    call(&client, message(0), None).unwrap();
+    server.join();
+    // the server dropped its capability when it was done
+    assert_eq!(call(&client, message(1), None).err(), Some(Errno::EPIPE));
+    drop(client);
+    assert_eq!(count(), ports);
+  }
+
+  static PENDING: Mutex<Option<Capability>> = Mutex::new(None);
+
+  #[test_case]
+  fn pages_and_failed_calls() {
+    let before = FrameAllocator::the().allocated();
+    let cap = create().unwrap();
+    *PENDING.lock() = Some(cap.clone());
+    // sends the page back, then exits without replying to the next call
+    let server = task::spawn(|| {
+      let cap = PENDING.lock().clone().unwrap();
+      let (token, _, page) = receive(&cap).unwrap();
+      let frame = page.unwrap().into_frame();
+      unsafe { *frame.to_virt().as_mut_ptr::<u64>() += 1 };
+      let page = unsafe { Page::new(frame) };
+      assert_eq!(reply(token, message(0), Some(page)), Ok(()));
+      // the token is used up
+      assert_eq!(reply(token, message(0), None), Err(Errno::EINVAL));
+      assert_eq!(reply(token + 1, message(0), None), Err(Errno::EINVAL));
+      let (_, message, _) = receive(&cap).unwrap();
+      assert_eq!(message.words[0], 2);
+    })
+    .unwrap();
+    let frame = FrameAllocator::the().calloc().unwrap();
+    unsafe { *frame.to_virt().as_mut_ptr::<u64>() = 42 };
+    let (_, page) = call(&cap, message(1), Some(unsafe { Page::new(frame) })).unwrap();
+    assert_eq!(page.unwrap().into_frame().as_u64(), frame.as_u64());
+    assert_eq!(unsafe { *frame.to_virt().as_ptr::<u64>() }, 43);
+    FrameAllocator::the().free(frame);
+    assert_eq!(call(&cap, message(2), None).err(), Some(Errno::EPIPE));
+    server.join();
+    drop(cap);
+    PENDING.lock().take();
+    assert_eq!(FrameAllocator::the().allocated(), before);
+  }
+}
@@ -1,7 +1,15 @@
 #![allow(unused)]
 mod scan_set_1;
+use crate::process::{self, file, signal::SIGINT};
+use crate::sync::IrqSafeMutex;
+use crate::task::executor::{Stream, StreamExt};
+use core::cell::Cell;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
 use scan_set_1::Key;
 
+const QUEUE_SIZE: usize = 128;
+
 bitflags::bitflags! {
   pub struct KeyModifiers: u16 {
     const CTRL            = 1 << 0;
@@ -59,18 +67,163 @@ impl KeyModifiers {
   }
 }
 
-// safety: keyboard events don't overlap, can safely read/write
-static mut MODIFIERS: KeyModifiers = KeyModifiers::empty();
+// keyboard events are decoded one at a time, can safely read/write
+crate::percpu! {
+  static MODIFIERS: Cell<KeyModifiers> = Cell::new(KeyModifiers::empty());
+}
+
+// Scan codes from the interrupt handler, waiting to be decoded
+struct ScancodeQueue {
+  codes: [u8; QUEUE_SIZE],
+  head:  usize,
+  len:   usize,
+  waker: Option<Waker>,
+}
+
+impl ScancodeQueue {
+  const fn new() -> Self {
+    Self {
+      codes: [0; QUEUE_SIZE],
+      head:  0,
+      len:   0,
+      waker: None,
+    }
+  }
+
+  // Returns false if the queue is full
+  fn push(&mut self, scan_code: u8) -> bool {
+    if self.len == QUEUE_SIZE {
+      return false;
+    }
+    self.codes[(self.head + self.len) % QUEUE_SIZE] = scan_code;
+    self.len += 1;
+    true
+  }
+
+  fn pop(&mut self) -> Option<u8> {
+    if self.len == 0 {
+      return None;
+    }
+    let scan_code = self.codes[self.head];
+    self.head = (self.head + 1) % QUEUE_SIZE;
+    self.len -= 1;
+    Some(scan_code)
+  }
+}
+
+static QUEUE: IrqSafeMutex<ScancodeQueue> = IrqSafeMutex::new(ScancodeQueue::new());
+
+// Called from the keyboard interrupt handler. Scan codes are dropped
+// when nobody reads them fast enough.
+pub fn push_scancode(scan_code: u8) {
+  let waker = {
+    let mut queue = QUEUE.lock();
+    queue.push(scan_code);
+    queue.waker.take()
+  };
+  if let Some(waker) = waker {
+    waker.wake();
+  }
+}
+
+// The scan codes of the keyboard. All streams read from the same
+// queue, so there should only be one.
+pub struct ScancodeStream;
+
+impl Stream for ScancodeStream {
+  type Item = u8;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+    let mut queue = QUEUE.lock();
+    match queue.pop() {
+      Some(scan_code) => Poll::Ready(Some(scan_code)),
+      None => {
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+      }
+    }
+  }
+}
+
+// Echoes typed characters to the debug output and passes them on to
+// the console, runs on the executor
+pub async fn print_keypresses() {
+  let mut scan_codes = ScancodeStream;
+  while let Some(scan_code) = scan_codes.next().await {
+    handle_keyboard_event(scan_code);
+  }
+}
 
 pub fn handle_keyboard_event(scan_code: u8) {
   let (key, pressed) = match scan_set_1::decode_key(scan_code) {
     Some(pair) => pair,
     None => return dbg!("Warn: Invalid scan code {:x}", scan_code),
   };
-  unsafe { MODIFIERS.update(key, pressed) };
+  let modifiers = MODIFIERS.with(|modifiers| {
+    let mut updated = modifiers.get();
+    updated.update(key, pressed);
+    modifiers.set(updated);
+    updated
+  });
+  if pressed && modifiers.ctrl() && key == Key::C {
+    // the console is shared by all processes
+    return process::kill_all(SIGINT);
+  }
   if pressed {
-    if let Some(c) = key.to_ascii(unsafe { MODIFIERS }) {
+    if let Some(c) = key.to_ascii(modifiers) {
       dbg_no_ln!("{}", c);
+      file::console_input(c as u8);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use core::sync::atomic::{AtomicUsize, Ordering};
+
+  #[test_case]
+  fn queue_wraps_around() {
+    let mut queue = ScancodeQueue::new();
+    for round in 0..3 {
+      for i in 0..QUEUE_SIZE {
+        assert!(queue.push((round + i) as u8));
+      }
+      assert!(!queue.push(0));
+      for i in 0..QUEUE_SIZE {
+        assert_eq!(queue.pop(), Some((round + i) as u8));
+      }
+    }
+    assert_eq!(queue.pop(), None);
+  }
+
+  static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+  #[test_case]
+  fn scancode_stream() {
+    // a stream polled by hand, with a waker which counts wakes
+    fn poll(stream: &mut ScancodeStream) -> Poll<Option<u8>> {
+      use core::task::{RawWaker, RawWakerVTable};
+      unsafe fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+      }
+      unsafe fn wake(_: *const ()) {
+        RECEIVED.fetch_add(1, Ordering::SeqCst);
+      }
+      unsafe fn drop_waker(_: *const ()) {}
+      static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop_waker);
+      let waker = unsafe { Waker::from_raw(clone(core::ptr::null())) };
+      Pin::new(stream).poll_next(&mut Context::from_waker(&waker))
     }
+    let mut stream = ScancodeStream;
+    assert_eq!(poll(&mut stream), Poll::Pending);
+    push_scancode(0x1e);
+    push_scancode(0x9e);
+    // the first push woke the stream, the second had no waker left
+    assert_eq!(RECEIVED.load(Ordering::SeqCst), 1);
+    assert_eq!(poll(&mut stream), Poll::Ready(Some(0x1e)));
+    assert_eq!(poll(&mut stream), Poll::Ready(Some(0x9e)));
+    assert_eq!(poll(&mut stream), Poll::Pending);
+    QUEUE.lock().waker = None;
   }
 }
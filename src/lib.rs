@@ -3,18 +3,40 @@
 #![feature(abi_x86_interrupt)]
 #![feature(asm)]
 #![feature(custom_test_frameworks)]
+#![feature(naked_functions)]
+#![feature(panic_info_message)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![allow(clippy::identity_op)]
 #![allow(clippy::missing_safety_doc)] // library lints
 #![allow(clippy::new_without_default)]
+#![allow(unused_unsafe)]
+#![allow(clippy::needless_range_loop)]
 
 #[macro_use]
 pub mod dbg_print;
+pub mod acpi;
+pub mod backtrace;
+pub mod cpu;
+pub mod elf;
+pub mod gdb;
 pub mod interrupts;
 mod io;
+pub mod ipc;
 mod keyboard;
+pub mod mem;
+pub mod panic_screen;
+pub mod percpu;
+pub mod process;
+pub mod random;
 mod serial_port;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod timer;
+pub mod usermode;
+pub mod vga;
 
 pub fn hlt_loop() -> ! {
   loop {
@@ -71,8 +93,10 @@ macro_rules! test_prelude {
   ($($init_fn:expr)?) => {
     #[panic_handler]
     fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+      unsafe { $crate::dbg_print::force_unlock() };
       $crate::dbg!("[failed]");
       $crate::dbg!("Error: {}", info);
+      $crate::backtrace::print();
       $crate::qemu_exit_failure();
     }
 
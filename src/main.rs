@@ -4,28 +4,47 @@
 #![feature(asm)]
 #![feature(alloc_error_handler)]
 #![feature(custom_test_frameworks)]
+#![feature(naked_functions)]
+#![feature(panic_info_message)]
 #![test_runner(ax_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![cfg_attr(test, allow(unused_imports))]
 #![cfg_attr(test, allow(dead_code))]
 #![allow(clippy::identity_op)]
 #![allow(unused_unsafe)]
+#![allow(clippy::needless_range_loop)]
 
 #[macro_use]
 extern crate alloc;
 
-use ax_os::{hang, indexable_from_field};
+use ax_os::{hang, hlt_loop, indexable_from_field};
 use bootloader::BootInfo;
 use core::panic::PanicInfo;
 
 #[macro_use]
 mod dbg_print;
+mod acpi;
 mod allocator;
+mod backtrace;
+mod cpu;
+mod elf;
+mod gdb;
 mod interrupts;
 mod io;
+mod ipc;
 mod keyboard;
 mod mem;
+mod panic_screen;
+mod percpu;
+mod process;
+mod random;
 mod serial_port;
+mod smp;
+mod sync;
+mod syscall;
+mod task;
+mod timer;
+mod usermode;
 mod vga;
 
 use mem::frame_allocator::FrameAllocator;
@@ -33,9 +52,15 @@ use vga::VgaDevice;
 
 fn initialize(info: &'static BootInfo) {
   dbg_print::initialize();
+  gdb::initialize();
+  dbg!("cpu: {}", cpu::info());
   FrameAllocator::initialize(&info.memory_map);
   allocator::initialize();
   interrupts::initialize();
+  cpu::fpu::initialize();
+  smp::initialize();
+  random::initialize();
+  task::initialize();
 }
 
 #[cfg(test)]
@@ -44,8 +69,11 @@ ax_os::test_prelude!(initialize);
 #[cfg(not(test))]
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
+  unsafe { dbg_print::force_unlock() };
   dbg!("Kernel panicked!");
   dbg!("Error: {}", info);
+  backtrace::print();
+  panic_screen::show_panic(info);
   ax_os::hang();
 }
 
@@ -60,5 +88,7 @@ pub fn _start(info: &'static BootInfo) -> ! {
     vga.set_color(unsafe { core::mem::transmute(i as u8 + 1) });
     vga.write_char(i, i, c);
   }
-  ax_os::hlt_loop();
+  task::executor::spawn(keyboard::print_keypresses());
+  // the boot task runs the executor from here on
+  task::executor::run();
 }
@@ -0,0 +1,461 @@
+#![allow(dead_code)]
+use super::frame_allocator::FrameAllocator;
+use super::page_table::{self, PageFlags, PageTableEntry};
+use super::{PhysAddr, VirtAddr};
+use crate::cpu::regs::Cr3;
+use crate::syscall::Errno;
+
+/*
+  An address space for user programs. Each one has its own level four
+  table, but only the entries of the user range belong to it. All other
+  entries are copies of the kernel table's (see page_table.rs), so the
+  kernel is mapped the same way in every address space. Kernel mappings
+  with a new level four entry are copied over again on activation.
+  Pages of an address space are accessed through the physical memory
+  map, it does not have to be active for that. Dropping it frees all
+  frames of the user range, the page tables included.
+*/
+
+// Level four entries 32 to 127, clear of the kernel and of the entries
+// the bootloader picks for the boot stack and boot info. Programs have
+// to be linked to somewhere in this range.
+pub const USER_START: u64 = 0x0000_1000_0000_0000;
+pub const USER_END: u64 = 0x0000_4000_0000_0000;
+const USER_ENTRIES: (usize, usize) = (32, 128);
+
+const PAGE_SIZE: u64 = 0x1000;
+
+pub fn is_user_addr(addr: u64) -> bool {
+  (USER_START..USER_END).contains(&addr)
+}
+
+// Checks that [addr, addr + len) is inside the user range
+pub fn is_user_range(addr: u64, len: u64) -> bool {
+  match addr.checked_add(len) {
+    Some(end) => addr >= USER_START && end <= USER_END,
+    None => false,
+  }
+}
+
+pub struct AddressSpace {
+  level_four: PhysAddr,
+}
+
+impl AddressSpace {
+  pub fn new() -> Result<Self, Errno> {
+    let level_four = FrameAllocator::the().calloc().ok_or(Errno::ENOMEM)?;
+    let space = Self { level_four };
+    space.share_kernel_entries();
+    Ok(space)
+  }
+
+  fn share_kernel_entries(&self) {
+    let kernel = unsafe { page_table::table_at(page_table::kernel_level_four()) };
+    let table = unsafe { page_table::table_at(self.level_four) };
+    let (user_start, user_end) = USER_ENTRIES;
+    for i in (0..user_start).chain(user_end..512) {
+      table[i] = kernel[i];
+    }
+  }
+
+  pub fn level_four(&self) -> PhysAddr {
+    self.level_four
+  }
+
+  pub fn is_active(&self) -> bool {
+    let (active, _) = Cr3::read();
+    active.as_u64() == self.level_four.as_u64()
+  }
+
+  // Unsafe since the user range of the previous address space is gone,
+  // nothing may point into it anymore
+  pub unsafe fn activate(&self) {
+    self.share_kernel_entries();
+    Cr3::write(self.level_four, 0);
+  }
+
+  // Maps addr to a zeroed frame if it is not already mapped, the page
+  // is always user accessible. Returns the frame, or ENOMEM if there
+  // is no memory left for it.
+  pub fn map(&mut self, addr: VirtAddr, flags: PageFlags) -> Result<PhysAddr, Errno> {
+    assert!(
+      is_user_addr(addr.as_u64()),
+      "{:#x} is not a user address",
+      addr.as_u64()
+    );
+    let table = unsafe { page_table::table_at(self.level_four) };
+    page_table::map_page_in(table, addr, None, flags | PageFlags::USER_ACCESSIBLE)?;
+    self.flush(addr);
+    Ok(self.translate(addr).unwrap())
+  }
+
+  // Maps addr to frame, which belongs to the address space from then
+  // on. Returns false if addr is already mapped or there is no memory
+  // for the page tables, the frame is left to the caller then.
+  pub fn map_frame(&mut self, addr: VirtAddr, frame: PhysAddr, flags: PageFlags) -> bool {
+    assert!(
+      is_user_addr(addr.as_u64()),
+      "{:#x} is not a user address",
+      addr.as_u64()
+    );
+    if self.translate(addr).is_some() {
+      return false;
+    }
+    let table = unsafe { page_table::table_at(self.level_four) };
+    let flags = flags | PageFlags::USER_ACCESSIBLE;
+    if page_table::map_page_in(table, addr, Some(frame), flags).is_err() {
+      return false;
+    }
+    self.flush(addr);
+    true
+  }
+
+  // Removes the page at addr and returns its frame, which the caller
+  // has to free. None if there is no page at addr.
+  pub fn unmap(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+    if !is_user_addr(addr.as_u64()) || !addr.is_page_aligned() {
+      return None;
+    }
+    let indexes = addr.page_table_indexes();
+    let mut table = unsafe { page_table::table_at(self.level_four) };
+    for &i in &indexes[..3] {
+      let entry = table[i as usize];
+      if !entry.present() || entry.huge() {
+        return None;
+      }
+      table = unsafe { page_table::table_at(entry.addr()) };
+    }
+    let entry = &mut table[indexes[3] as usize];
+    if !entry.present() {
+      return None;
+    }
+    let frame = entry.addr();
+    entry.clear();
+    self.flush(addr);
+    Some(frame)
+  }
+
+  fn flush(&self, addr: VirtAddr) {
+    if self.is_active() {
+      unsafe { asm!("invlpg [{}]", in(reg) addr.as_u64()) };
+    }
+  }
+
+  pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+    page_table::translate_addr_in(self.level_four, addr)
+  }
+
+  pub fn page_entry(&self, addr: VirtAddr) -> Option<PageTableEntry> {
+    page_table::page_entry_in(self.level_four, addr)
+  }
+
+  // Copies data to addr, returns false if any of the pages is unmapped
+  pub fn write(&mut self, addr: u64, data: &[u8]) -> bool {
+    self.for_each_chunk(addr, data.len(), |chunk, offset| {
+      chunk.copy_from_slice(&data[offset..offset + chunk.len()])
+    })
+  }
+
+  pub fn zero(&mut self, addr: u64, len: usize) -> bool {
+    self.for_each_chunk(addr, len, |chunk, _| chunk.iter_mut().for_each(|b| *b = 0))
+  }
+
+  // Copies from addr into buf, returns false if any of the pages is unmapped
+  pub fn read(&self, addr: u64, buf: &mut [u8]) -> bool {
+    let len = buf.len();
+    self.for_each_chunk(addr, len, |chunk, offset| {
+      buf[offset..offset + chunk.len()].copy_from_slice(chunk)
+    })
+  }
+
+  // A copy of the user range with the same permissions, for fork. The
+  // pages are copied right away, there is no copy on write. If memory
+  // runs out the partial copy is freed and it fails with ENOMEM.
+  pub fn duplicate(&self) -> Result<Self, Errno> {
+    let mut copy = Self::new()?;
+    let mut result = Ok(());
+    self.for_each_page(|addr, entry| {
+      if result.is_err() {
+        return;
+      }
+      let mut flags = PageFlags::empty();
+      flags.set(PageFlags::WRITABLE, entry.writable());
+      flags.set(PageFlags::NON_EXECUTABLE, entry.non_executable());
+      let frame = match copy.map(addr, flags) {
+        Ok(frame) => frame,
+        Err(err) => {
+          result = Err(err);
+          return;
+        }
+      };
+      unsafe {
+        core::ptr::copy_nonoverlapping(
+          entry.addr().to_virt().as_ptr::<u8>(),
+          frame.to_virt().as_mut_ptr::<u8>(),
+          PAGE_SIZE as usize,
+        )
+      };
+    });
+    result.map(|_| copy)
+  }
+
+  // Calls f with the address and level one entry of every mapped page
+  fn for_each_page(&self, mut f: impl FnMut(VirtAddr, PageTableEntry)) {
+    let table = unsafe { page_table::table_at(self.level_four) };
+    let (user_start, user_end) = USER_ENTRIES;
+    for i in user_start..user_end {
+      visit_entry(table[i], 0, (i as u64) << 39, &mut f);
+    }
+  }
+
+  // Calls f with the memory of [addr, addr + len) one page at a time,
+  // along with the offset of each chunk in the range
+  fn for_each_chunk(&self, addr: u64, len: usize, mut f: impl FnMut(&mut [u8], usize)) -> bool {
+    if !is_user_range(addr, len as u64) {
+      return false;
+    }
+    let mut offset = 0;
+    while offset < len {
+      let current = addr + offset as u64;
+      let in_page = ((PAGE_SIZE - current % PAGE_SIZE) as usize).min(len - offset);
+      let phys = match self.translate(VirtAddr::new(current)) {
+        Some(phys) => phys,
+        None => return false,
+      };
+      let ptr = phys.to_virt().as_mut_ptr::<u8>();
+      f(
+        unsafe { core::slice::from_raw_parts_mut(ptr, in_page) },
+        offset,
+      );
+      offset += in_page;
+    }
+    true
+  }
+}
+
+impl Drop for AddressSpace {
+  fn drop(&mut self) {
+    assert!(!self.is_active(), "dropping the active address space");
+    let table = unsafe { page_table::table_at(self.level_four) };
+    let mut allocator = FrameAllocator::the();
+    let (user_start, user_end) = USER_ENTRIES;
+    for i in user_start..user_end {
+      free_entry(&mut allocator, table[i], 0);
+    }
+    allocator.free(self.level_four);
+  }
+}
+
+// Frees the frame entry points to, and everything below it if that
+// is a table. Depth 0 is an entry of the level four table, depth 3 one
+// of a level one table which points to a page.
+fn free_entry(allocator: &mut FrameAllocator, entry: PageTableEntry, depth: usize) {
+  if !entry.present() {
+    return;
+  }
+  if depth < 3 && !entry.huge() {
+    let table = unsafe { page_table::table_at(entry.addr()) };
+    for i in 0..512 {
+      free_entry(allocator, table[i], depth + 1);
+    }
+  }
+  allocator.free(entry.addr());
+}
+
+// Walks the pages below entry, which maps addr, depth like for free_entry
+fn visit_entry<F: FnMut(VirtAddr, PageTableEntry)>(
+  entry: PageTableEntry,
+  depth: usize,
+  addr: u64,
+  f: &mut F,
+) {
+  if !entry.present() {
+    return;
+  }
+  if depth == 3 {
+    f(VirtAddr::new(addr), entry);
+    return;
+  }
+  assert!(!entry.huge(), "huge page in a user address space");
+  let table = unsafe { page_table::table_at(entry.addr()) };
+  let shift = 39 - 9 * (depth as u64 + 1);
+  for i in 0..512 {
+    visit_entry(table[i], depth + 1, addr | (i as u64) << shift, f);
+  }
+}
+
+// Switches back to the kernel table, which has no user mappings
+pub unsafe fn activate_kernel() {
+  Cr3::write(page_table::kernel_level_four(), 0);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const ADDR: u64 = USER_START + 0x1234_5000;
+
+  #[test_case]
+  fn user_range() {
+    assert!(is_user_addr(USER_START));
+    assert!(!is_user_addr(USER_END));
+    assert!(!is_user_addr(super::super::PHYS_MEM_OFFSET));
+    assert!(is_user_range(USER_END - 8, 8));
+    assert!(!is_user_range(USER_END - 8, 9));
+    assert!(!is_user_range(u64::MAX, 2));
+  }
+
+  #[test_case]
+  fn separate_user_mappings() {
+    let mut a = AddressSpace::new().unwrap();
+    let b = AddressSpace::new().unwrap();
+    let frame = a.map(VirtAddr::new(ADDR), PageFlags::WRITABLE).unwrap();
+    assert_eq!(
+      a.translate(VirtAddr::new(ADDR)).unwrap().as_u64(),
+      frame.as_u64()
+    );
+    assert!(b.translate(VirtAddr::new(ADDR)).is_none());
+    assert!(page_table::translate_addr(VirtAddr::new(ADDR)).is_none());
+    let entry = a.page_entry(VirtAddr::new(ADDR)).unwrap();
+    assert!(entry.user_accessible() && entry.writable());
+  }
+
+  #[test_case]
+  fn read_write_across_pages() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map(VirtAddr::new(ADDR), PageFlags::WRITABLE).unwrap();
+    assert!(!space.write(ADDR + 0xffe, b"abcd")); // the next page is unmapped
+    space
+      .map(VirtAddr::new(ADDR + 0x1000), PageFlags::WRITABLE)
+      .unwrap();
+    assert!(space.write(ADDR + 0xffe, b"abcd"));
+    let mut buf = [0; 4];
+    assert!(space.read(ADDR + 0xffe, &mut buf));
+    assert_eq!(&buf, b"abcd");
+    assert!(space.zero(ADDR + 0xfff, 2));
+    assert!(space.read(ADDR + 0xffe, &mut buf));
+    assert_eq!(&buf, b"a\0\0d");
+  }
+
+  #[test_case]
+  fn activate_and_back() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map(VirtAddr::new(ADDR), PageFlags::WRITABLE).unwrap();
+    space.write(ADDR, &1337u64.to_le_bytes());
+    let stack_int = 42u64;
+    unsafe { space.activate() };
+    assert!(space.is_active());
+    // the kernel is still there, and so is the user page
+    assert_eq!(unsafe { *(&stack_int as *const u64) }, 42);
+    assert_eq!(unsafe { *VirtAddr::new(ADDR).as_ptr::<u64>() }, 1337);
+    // kernel mappings made now show up in the kernel table
+    let kernel_addr = VirtAddr::new(0x4321_4322_0000);
+    page_table::page_map_addr(kernel_addr);
+    unsafe { activate_kernel() };
+    assert!(!space.is_active());
+    assert!(page_table::mapped(kernel_addr));
+    assert!(page_table::translate_addr(VirtAddr::new(ADDR)).is_none());
+  }
+
+  #[test_case]
+  fn duplicate_copies_pages() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map(VirtAddr::new(ADDR), PageFlags::WRITABLE).unwrap();
+    space
+      .map(VirtAddr::new(USER_END - 0x1000), PageFlags::NON_EXECUTABLE)
+      .unwrap();
+    space.write(ADDR + 8, b"parent");
+    space.write(USER_END - 0x1000, b"stack");
+    let mut copy = space.duplicate().unwrap();
+    let mut buf = [0; 6];
+    assert!(copy.read(ADDR + 8, &mut buf));
+    assert_eq!(&buf, b"parent");
+    assert!(copy.read(USER_END - 0x1000, &mut buf[..5]));
+    assert_eq!(&buf[..5], b"stack");
+    for &addr in [ADDR, USER_END - 0x1000].iter() {
+      let (a, b) = (
+        space.page_entry(VirtAddr::new(addr)),
+        copy.page_entry(VirtAddr::new(addr)),
+      );
+      let (a, b) = (a.unwrap(), b.unwrap());
+      assert_ne!(a.addr().as_u64(), b.addr().as_u64());
+      assert_eq!(a.writable(), b.writable());
+      assert_eq!(a.non_executable(), b.non_executable());
+    }
+    // nothing else is mapped, and the copies are separate
+    assert!(copy.translate(VirtAddr::new(ADDR + 0x1000)).is_none());
+    copy.write(ADDR + 8, b"child!");
+    assert!(space.read(ADDR + 8, &mut buf));
+    assert_eq!(&buf, b"parent");
+  }
+
+  #[test_case]
+  fn duplicate_out_of_memory() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map(VirtAddr::new(ADDR), PageFlags::WRITABLE).unwrap();
+    space
+      .map(VirtAddr::new(USER_END - 0x1000), PageFlags::WRITABLE)
+      .unwrap();
+    let before = FrameAllocator::the().allocated();
+    // take all free frames, linked through their first word, and give
+    // back enough for the copy's tables of the first page
+    let mut taken = 0;
+    while let Some(frame) = FrameAllocator::the().alloc() {
+      unsafe { *frame.to_virt().as_mut_ptr::<u64>() = taken };
+      taken = frame.as_u64();
+    }
+    let mut give_back = |n| {
+      for _ in 0..n {
+        let frame = PhysAddr::new(taken);
+        taken = unsafe { *frame.to_virt().as_ptr::<u64>() };
+        FrameAllocator::the().free(frame);
+      }
+    };
+    give_back(5);
+    assert_eq!(space.duplicate().err(), Some(Errno::ENOMEM));
+    let still_taken = FrameAllocator::the().allocated() - before;
+    give_back(still_taken);
+    assert_eq!(FrameAllocator::the().allocated(), before);
+  }
+
+  #[test_case]
+  fn move_pages() {
+    let before = FrameAllocator::the().allocated();
+    let (mut a, mut b) = (AddressSpace::new().unwrap(), AddressSpace::new().unwrap());
+    let frame = a.map(VirtAddr::new(ADDR), PageFlags::WRITABLE).unwrap();
+    a.write(ADDR, b"moved");
+    assert!(a.unmap(VirtAddr::new(ADDR + 8)).is_none());
+    assert_eq!(
+      a.unmap(VirtAddr::new(ADDR)).map(|f| f.as_u64()),
+      Some(frame.as_u64())
+    );
+    assert!(a.unmap(VirtAddr::new(ADDR)).is_none());
+    assert!(a.translate(VirtAddr::new(ADDR)).is_none());
+    b.map(VirtAddr::new(ADDR), PageFlags::empty()).unwrap();
+    assert!(!b.map_frame(VirtAddr::new(ADDR), frame, PageFlags::WRITABLE));
+    assert!(b.map_frame(VirtAddr::new(ADDR + 0x1000), frame, PageFlags::WRITABLE));
+    let mut buf = [0; 5];
+    assert!(b.read(ADDR + 0x1000, &mut buf));
+    assert_eq!(&buf, b"moved");
+    drop(a);
+    drop(b);
+    assert_eq!(FrameAllocator::the().allocated(), before);
+  }
+
+  #[test_case]
+  fn drop_frees_all_frames() {
+    let before = FrameAllocator::the().allocated();
+    let mut space = AddressSpace::new().unwrap();
+    // the level four table, two sets of lower tables and three pages
+    space.map(VirtAddr::new(ADDR), PageFlags::WRITABLE).unwrap();
+    space
+      .map(VirtAddr::new(ADDR + 0x1000), PageFlags::empty())
+      .unwrap();
+    space
+      .map(VirtAddr::new(USER_END - 0x1000), PageFlags::WRITABLE)
+      .unwrap();
+    assert_eq!(FrameAllocator::the().allocated(), before + 1 + 3 * 2 + 3);
+    drop(space);
+    assert_eq!(FrameAllocator::the().allocated(), before);
+  }
+}
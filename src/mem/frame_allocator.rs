@@ -1,26 +1,38 @@
 use super::PhysAddr;
+use crate::sync::{IrqSafeMutex, IrqSafeMutexGuard};
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use lazy_static::lazy_static;
-use spin::{Mutex, MutexGuard};
+
+// Memory below 1MiB is kept for things which need frames at low
+// physical addresses, like the real mode trampoline in smp.rs
+const LOW_MEMORY_END: u64 = 0x10_0000;
 
 pub struct FrameAllocator {
   memory_map:    Option<&'static MemoryMap>,
   current_index: usize,
+  low_index:     usize,
+  free_list:     u64, // the last freed frame, 0 if there is none
+  allocated:     usize,
 }
 
 lazy_static! {
-  static ref FRAME_ALLOCATOR: Mutex<FrameAllocator> = {
+  static ref FRAME_ALLOCATOR: IrqSafeMutex<FrameAllocator> = {
     let allocator = FrameAllocator {
       memory_map:    None,
       current_index: 0,
+      low_index:     0,
+      free_list:     0,
+      allocated:     0,
     };
-    Mutex::new(allocator)
+    IrqSafeMutex::new(allocator)
   };
 }
 
-// FIXME: This is just a bump allocator to get us started
+// New frames are handed out in the order of the memory map. Freed
+// frames are kept in a linked list, each one holds the physical
+// address of the next, and are reused before any new ones.
 impl FrameAllocator {
-  pub fn the() -> MutexGuard<'static, FrameAllocator> {
+  pub fn the() -> IrqSafeMutexGuard<'static, FrameAllocator> {
     FRAME_ALLOCATOR.lock()
   }
 
@@ -28,8 +40,8 @@ impl FrameAllocator {
     Self::the().memory_map = Some(memory_map);
   }
 
-  pub fn alloc(&mut self) -> Option<PhysAddr> {
-    let addr = self
+  fn usable_frames(&self) -> impl Iterator<Item = u64> {
+    self
       .memory_map
       .unwrap()
       .iter()
@@ -39,9 +51,46 @@ impl FrameAllocator {
         let end = region.range.end_addr();
         (start..end).step_by(0x1000)
       })
-      .nth(self.current_index)
-      .map(PhysAddr::new);
+  }
+
+  // The nth usable frame above LOW_MEMORY_END, counted region by region
+  // so that allocating does not get slower the more frames are in use
+  fn nth_frame(&self, mut n: usize) -> Option<u64> {
+    let regions = self.memory_map.unwrap().iter();
+    for region in regions.filter(|region| region.region_type == MemoryRegionType::Usable) {
+      let start = region.range.start_addr().max(LOW_MEMORY_END);
+      let end = region.range.end_addr();
+      let frames = (end.saturating_sub(start) as usize + 0xfff) / 0x1000;
+      if n < frames {
+        return Some(start + n as u64 * 0x1000);
+      }
+      n -= frames;
+    }
+    None
+  }
+
+  pub fn alloc(&mut self) -> Option<PhysAddr> {
+    if self.free_list != 0 {
+      let frame = PhysAddr::new(self.free_list);
+      self.free_list = unsafe { *frame.to_virt().as_ptr::<u64>() };
+      self.allocated += 1;
+      return Some(frame);
+    }
+    let addr = self.nth_frame(self.current_index).map(PhysAddr::new);
     self.current_index += 1;
+    self.allocated += addr.is_some() as usize;
+    addr
+  }
+
+  // Allocates a frame below 1MiB, never frame zero
+  pub fn alloc_low(&mut self) -> Option<PhysAddr> {
+    let addr = self
+      .usable_frames()
+      .filter(|&frame| frame != 0 && frame < LOW_MEMORY_END)
+      .nth(self.low_index)
+      .map(PhysAddr::new);
+    self.low_index += 1;
+    self.allocated += addr.is_some() as usize;
     addr
   }
 
@@ -53,9 +102,55 @@ impl FrameAllocator {
     Some(frame_addr)
   }
 
+  // The frame must not be used anymore, there is no check for double frees
   pub fn free(&mut self, frame: PhysAddr) {
-    // TODO: Actually do something here
     assert!(frame.is_page_aligned());
-    unimplemented!();
+    assert_ne!(frame.as_u64(), 0, "frame zero is never allocated");
+    unsafe { *frame.to_virt().as_mut_ptr::<u64>() = self.free_list };
+    self.free_list = frame.as_u64();
+    self.allocated -= 1;
+  }
+
+  // The number of frames which are currently allocated
+  pub fn allocated(&self) -> usize {
+    self.allocated
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn free_frames_are_reused() {
+    let mut allocator = FrameAllocator::the();
+    let before = allocator.allocated();
+    let a = allocator.alloc().unwrap();
+    let b = allocator.calloc().unwrap();
+    assert_ne!(a.as_u64(), b.as_u64());
+    assert_eq!(allocator.allocated(), before + 2);
+    allocator.free(a);
+    allocator.free(b);
+    assert_eq!(allocator.allocated(), before);
+    // last freed, first reused
+    assert_eq!(allocator.alloc().unwrap().as_u64(), b.as_u64());
+    let c = allocator.calloc().unwrap();
+    assert_eq!(c.as_u64(), a.as_u64());
+    // calloc zeroes the reused frame, including the list link
+    let page = unsafe { &*c.to_virt().as_ptr::<[u64; 512]>() };
+    assert!(page.iter().all(|&word| word == 0));
+    allocator.free(c);
+    allocator.free(b);
+  }
+
+  #[test_case]
+  fn frames_in_memory_map_order() {
+    let allocator = FrameAllocator::the();
+    let mut frames = allocator
+      .usable_frames()
+      .filter(|&frame| frame >= LOW_MEMORY_END);
+    for n in 0..1024 {
+      assert_eq!(allocator.nth_frame(n), frames.next());
+    }
   }
 }
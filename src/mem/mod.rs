@@ -1,8 +1,15 @@
 #![allow(dead_code)]
+pub mod address_space;
 pub mod frame_allocator;
 pub mod page_table;
 
+use core::sync::atomic::{AtomicU64, Ordering};
+use page_table::PageFlags;
+
 pub const PHYS_MEM_OFFSET: u64 = 0x20000000000; // specified in Cargo.toml
+const MMIO_START: u64 = 0x5000_0000_0000;
+
+static NEXT_MMIO_ADDR: AtomicU64 = AtomicU64::new(MMIO_START);
 
 #[derive(Clone, Copy, Debug)]
 #[repr(transparent)]
@@ -62,6 +69,21 @@ impl PhysAddr {
   }
 }
 
+// Maps size bytes of device memory at phys as uncached. The physical
+// memory map cannot be used for this since it is cached. Mappings are
+// never removed, this is meant for devices set up once at boot.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> VirtAddr {
+  let offset = phys.as_u64() & 0xfff;
+  let pages = (offset + size + 0xfff) / 0x1000;
+  let start = NEXT_MMIO_ADDR.fetch_add(pages * 0x1000, Ordering::Relaxed);
+  let flags = PageFlags::WRITABLE | PageFlags::NON_EXECUTABLE | PageFlags::DISABLE_CACHE;
+  for page in 0..pages {
+    let frame = PhysAddr::new(phys.as_u64() - offset + page * 0x1000);
+    page_table::page_map_phys(VirtAddr::new(start + page * 0x1000), frame, flags);
+  }
+  VirtAddr::new(start + offset)
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -90,4 +112,15 @@ mod tests {
     let value_at_virt_addr = unsafe { *VirtAddr::new(0xb8000).as_mut_ptr::<u8>() };
     assert_eq!(value_at_virt_addr, 42);
   }
+
+  #[test_case]
+  fn mmio_mapping() {
+    // the VGA buffer again, with an offset into the page
+    let addr = map_mmio(PhysAddr::new(0xb8010), 0x2000);
+    assert_eq!(addr.as_u64() & 0xfff, 0x10);
+    let phys = page_table::translate_addr(VirtAddr::new(addr.as_u64() + 0x1000));
+    assert_eq!(phys.unwrap().as_u64(), 0xb9010);
+    let entry = page_table::page_entry(addr).unwrap();
+    assert!(entry.writable());
+  }
 }
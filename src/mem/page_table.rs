@@ -1,7 +1,12 @@
 #![allow(dead_code)]
+use super::address_space;
 use super::frame_allocator::FrameAllocator;
 use super::{PhysAddr, VirtAddr};
+use crate::cpu::regs::Cr3;
+use crate::cpu::{self, Features};
 use crate::indexable_from_field;
+use crate::syscall::Errno;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 const PRESENT: u64 = 1 << 0;
 const WRITABLE: u64 = 1 << 1;
@@ -16,6 +21,16 @@ const NON_EXECUTABLE: u64 = 1 << 63;
 
 const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
 
+bitflags::bitflags! {
+  // Permissions of a mapped page, see page_map_addr_with_flags
+  pub struct PageFlags: u64 {
+    const WRITABLE        = WRITABLE;
+    const USER_ACCESSIBLE = USER_ACCESSIBLE;
+    const NON_EXECUTABLE  = NON_EXECUTABLE;
+    const DISABLE_CACHE   = DISABLE_CACHE; // for memory mapped io
+  }
+}
+
 // Reference: https://os.phil-opp.com/paging-introduction/#page-table-format
 #[derive(Clone, Copy)]
 #[repr(transparent)]
@@ -37,6 +52,10 @@ impl PageTableEntry {
     self.0 == 0
   }
 
+  pub fn clear(&mut self) {
+    self.0 = 0;
+  }
+
   pub fn present(&self) -> bool {
     self.is_bit_set(PRESENT)
   }
@@ -52,6 +71,9 @@ impl PageTableEntry {
   pub fn dirty(&self) -> bool {
     self.is_bit_set(DIRTY)
   }
+  pub fn huge(&self) -> bool {
+    self.is_bit_set(HUGE)
+  }
   pub fn non_executable(&self) -> bool {
     self.is_bit_set(NON_EXECUTABLE)
   }
@@ -65,6 +87,9 @@ impl PageTableEntry {
   pub fn set_user_accessible(&mut self, b: bool) -> &mut Self {
     self.set_bit(b, USER_ACCESSIBLE)
   }
+  pub fn set_disable_cache(&mut self, b: bool) -> &mut Self {
+    self.set_bit(b, DISABLE_CACHE)
+  }
   pub fn set_non_executable(&mut self, b: bool) -> &mut Self {
     self.set_bit(b, NON_EXECUTABLE)
   }
@@ -91,54 +116,198 @@ impl PageTable {
 
 indexable_from_field!(PageTable, 0, PageTableEntry);
 
-pub fn cr3() -> (u64, u64) {
-  let cr3: u64;
-  unsafe { asm!("mov {}, cr3", out(reg) cr3) };
-  (cr3 & PHYS_ADDR_MASK, cr3 & !PHYS_ADDR_MASK)
+pub fn active_level_four_table() -> &'static mut PageTable {
+  let (level_four_page, _) = Cr3::read();
+  unsafe { table_at(level_four_page) }
 }
 
-pub fn active_level_four_table() -> &'static mut PageTable {
-  let (cr3, _) = cr3();
-  let addr = PhysAddr::new(cr3).to_virt();
-  unsafe { &mut *addr.as_mut_ptr() }
+// The level four table set up by the bootloader. All address spaces
+// share its entries outside of the user range, so kernel mappings are
+// always made in this table, see map_page and address_space.rs.
+static KERNEL_LEVEL_FOUR: AtomicU64 = AtomicU64::new(0);
+
+pub fn kernel_level_four() -> PhysAddr {
+  match KERNEL_LEVEL_FOUR.load(Ordering::Relaxed) {
+    // the first call is during boot, while the kernel table is active
+    0 => {
+      let (active, _) = Cr3::read();
+      KERNEL_LEVEL_FOUR.store(active.as_u64(), Ordering::Relaxed);
+      active
+    }
+    addr => PhysAddr::new(addr),
+  }
+}
+
+// Unsafe since the frame has to hold a page table which is not
+// borrowed anywhere else
+pub(super) unsafe fn table_at(frame: PhysAddr) -> &'static mut PageTable {
+  &mut *frame.to_virt().as_mut_ptr()
 }
 
+// Huge pages map 1GiB on level three and 2MiB on level two
+const PAGE_SIZES: [u64; 4] = [0, 1 << 30, 1 << 21, 1 << 12];
+
 pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
-  let level_four_page = PhysAddr::new(cr3().0);
-  addr
-    .page_table_indexes()
-    .iter()
-    .fold(Some(level_four_page), |table_addr, &index| {
-      let table_ptr = table_addr?.to_virt().as_ptr::<PageTable>();
-      let table = unsafe { &*table_ptr };
-      let entry = table[index as usize];
-      if entry.unused() {
-        return None;
-      }
-      Some(entry.addr())
-    })
-    .map(|res| {
-      let offset = addr.as_u64() & 0xfff;
-      PhysAddr::new(res.as_u64() + offset)
-    })
+  let (level_four, _) = Cr3::read();
+  translate_addr_in(level_four, addr)
+}
+
+// Like translate_addr, but walks the tables starting at level_four
+// instead of the active ones
+pub fn translate_addr_in(level_four: PhysAddr, addr: VirtAddr) -> Option<PhysAddr> {
+  let mut table_addr = level_four;
+  for (level, &index) in addr.page_table_indexes().iter().enumerate() {
+    let table = unsafe { &*table_addr.to_virt().as_ptr::<PageTable>() };
+    let entry = table[index as usize];
+    if entry.unused() {
+      return None;
+    }
+    if entry.huge() || level == 3 {
+      let page_size = PAGE_SIZES[level];
+      let frame = entry.addr().as_u64() & !(page_size - 1);
+      return Some(PhysAddr::new(frame + (addr.as_u64() & (page_size - 1))));
+    }
+    table_addr = entry.addr();
+  }
+  unreachable!()
+}
+
+// Returns the last level entry mapping addr, ignoring huge pages
+pub fn page_entry(addr: VirtAddr) -> Option<PageTableEntry> {
+  let (level_four, _) = Cr3::read();
+  page_entry_in(level_four, addr)
+}
+
+pub fn page_entry_in(level_four: PhysAddr, addr: VirtAddr) -> Option<PageTableEntry> {
+  let mut table_addr = level_four;
+  let mut entry = None;
+  for &index in &addr.page_table_indexes() {
+    let table = unsafe { &*table_addr.to_virt().as_ptr::<PageTable>() };
+    let next = table[index as usize];
+    if !next.present() || next.huge() {
+      return None;
+    }
+    table_addr = next.addr();
+    entry = Some(next);
+  }
+  entry
+}
+
+// Checks that the page containing addr is present, huge pages included
+pub fn mapped(addr: VirtAddr) -> bool {
+  let (mut table_addr, _) = Cr3::read();
+  for &index in &addr.page_table_indexes() {
+    let table = unsafe { &*table_addr.to_virt().as_ptr::<PageTable>() };
+    let entry = table[index as usize];
+    if !entry.present() {
+      return false;
+    }
+    if entry.huge() {
+      break;
+    }
+    table_addr = entry.addr();
+  }
+  true
+}
+
+// Checks that the page containing addr can be accessed from ring 3,
+// i.e all levels of the page table walk have the user bit set.
+pub fn user_accessible(addr: VirtAddr) -> bool {
+  let (mut table_addr, _) = Cr3::read();
+  for &index in &addr.page_table_indexes() {
+    let table = unsafe { &*table_addr.to_virt().as_ptr::<PageTable>() };
+    let entry = table[index as usize];
+    if !entry.present() || !entry.user_accessible() {
+      return false;
+    }
+    if entry.huge() {
+      break;
+    }
+    table_addr = entry.addr();
+  }
+  true
 }
 
+// Maps addr as a writable, non-executable kernel page
 pub fn page_map_addr(addr: VirtAddr) {
+  page_map_addr_with_flags(addr, PageFlags::WRITABLE | PageFlags::NON_EXECUTABLE);
+}
+
+// Maps addr to a zeroed frame if it is not already mapped and sets
+// the page permissions to flags. Intermediate tables are left
+// writable and executable, the last level decides the permissions.
+// User accessible pages need the user bit set on all levels.
+pub fn page_map_addr_with_flags(addr: VirtAddr, flags: PageFlags) {
+  map_page(addr, None, flags);
+}
+
+// Maps addr to the given frame, replacing any existing mapping. Used
+// for memory mapped io and memory at fixed physical addresses.
+pub fn page_map_phys(addr: VirtAddr, frame: PhysAddr, flags: PageFlags) {
+  assert!(frame.is_page_aligned());
+  map_page(addr, Some(frame), flags);
+}
+
+// User range addresses are mapped in the active address space. The
+// rest belongs to the kernel and is mapped in the kernel table, with
+// the level four entry copied to the active table in case it is new.
+fn map_page(addr: VirtAddr, frame: Option<PhysAddr>, flags: PageFlags) {
+  if address_space::is_user_addr(addr.as_u64()) {
+    map_page_in(active_level_four_table(), addr, frame, flags).expect("OOM");
+  } else {
+    let kernel = kernel_level_four();
+    map_page_in(unsafe { table_at(kernel) }, addr, frame, flags).expect("OOM");
+    let i = addr.page_table_indexes()[0] as usize;
+    let entry = unsafe { table_at(kernel) }[i];
+    active_level_four_table()[i] = entry;
+  }
+  unsafe { asm!("invlpg [{}]", in(reg) addr.as_u64()) };
+}
+
+// Maps addr in the tables starting at level_four. The caller has to
+// flush the TLB entry if these tables are active. Fails if there is no
+// frame for a table or the page, the tables allocated so far are kept.
+pub(super) fn map_page_in(
+  level_four: &mut PageTable,
+  addr: VirtAddr,
+  frame: Option<PhysAddr>,
+  flags: PageFlags,
+) -> Result<(), Errno> {
   assert!(addr.is_page_aligned());
-  let mut table = active_level_four_table();
-  for &i in &addr.page_table_indexes() {
+  let user = flags.contains(PageFlags::USER_ACCESSIBLE);
+  // the NX bit is reserved if not supported
+  let nx_supported = cpu::features().contains(Features::NX);
+  let mut table = level_four;
+  let indexes = addr.page_table_indexes();
+  for (level, &i) in indexes.iter().enumerate() {
     let entry = &mut table[i as usize];
-    if entry.unused() {
-      let frame_addr = FrameAllocator::the().calloc().expect("OOM");
-      unsafe { entry.set_addr(frame_addr) }
-        .set_present(true)
-        .set_writable(true)
-        .set_non_executable(true);
+    let last = level == indexes.len() - 1;
+    match frame {
+      Some(frame) if last => {
+        unsafe { entry.set_addr(frame) }.set_present(true);
+      }
+      _ if entry.unused() => {
+        let frame_addr = FrameAllocator::the().calloc().ok_or(Errno::ENOMEM)?;
+        unsafe { entry.set_addr(frame_addr) }
+          .set_present(true)
+          .set_writable(true);
+      }
+      _ => {}
     }
-    let next_addr = entry.addr().to_virt();
-    table = unsafe { &mut *next_addr.as_mut_ptr() };
+    if user {
+      entry.set_user_accessible(true);
+    }
+    if last {
+      entry
+        .set_disable_cache(flags.contains(PageFlags::DISABLE_CACHE))
+        .set_writable(flags.contains(PageFlags::WRITABLE))
+        .set_non_executable(flags.contains(PageFlags::NON_EXECUTABLE) && nx_supported);
+      break;
+    }
+    assert!(!entry.huge(), "{:#x} is inside a huge page", addr.as_u64());
+    table = unsafe { table_at(entry.addr()) };
   }
-  unsafe { asm!("invlpg [{}]", in(reg) addr.as_u64()) };
+  Ok(())
 }
 
 #[cfg(test)]
@@ -178,6 +347,48 @@ mod tests {
     assert_eq!(phys_addr.as_u64(), 0xb8001);
   }
 
+  #[test_case]
+  fn kernel_memory_not_user_accessible() {
+    let stack_int = 0u64;
+    assert!(!user_accessible(VirtAddr::new(
+      &stack_int as *const _ as u64
+    )));
+    assert!(!user_accessible(VirtAddr::new(
+      user_accessible as usize as u64
+    )));
+    assert!(!user_accessible(VirtAddr::new(0x4321_4321_0000)));
+  }
+
+  #[test_case]
+  fn mapped_pages() {
+    let stack_int = 0u64;
+    assert!(mapped(VirtAddr::new(&stack_int as *const _ as u64)));
+    assert!(mapped(VirtAddr::new(mapped as usize as u64)));
+    // the physical memory map may use huge pages
+    assert!(mapped(PhysAddr::new(0x1000).to_virt()));
+    assert!(!mapped(VirtAddr::new(0x4321_4321_2000)));
+  }
+
+  #[test_case]
+  fn user_addr_mapping() {
+    let addr = VirtAddr::new(0x1234_5678_9000); // random unmapped address
+    assert!(!user_accessible(addr));
+    page_map_addr_with_flags(addr, PageFlags::USER_ACCESSIBLE);
+    assert!(user_accessible(addr));
+
+    let entry = page_entry(addr).unwrap();
+    assert!(entry.present());
+    assert!(entry.user_accessible());
+    assert!(!entry.writable());
+    assert!(!entry.non_executable());
+
+    page_map_addr_with_flags(addr, PageFlags::WRITABLE | PageFlags::NON_EXECUTABLE);
+    let entry = page_entry(addr).unwrap();
+    assert!(entry.writable());
+    assert!(entry.non_executable());
+    assert!(user_accessible(addr)); // the user bit is never cleared
+  }
+
   #[test_case]
   fn addr_mapping() {
     let addr = VirtAddr::new(0x4321_4321_1000); // random unmapped address
@@ -193,4 +404,21 @@ mod tests {
       assert_eq!(page[i], 0x1337);
     }
   }
+
+  #[test_case]
+  fn phys_addr_mapping() {
+    let addr = VirtAddr::new(0x4321_4321_3000); // random unmapped address
+    let frame = FrameAllocator::the().calloc().unwrap();
+    page_map_phys(addr, frame, PageFlags::WRITABLE | PageFlags::DISABLE_CACHE);
+    assert_eq!(translate_addr(addr).unwrap().as_u64(), frame.as_u64());
+    unsafe { *addr.as_mut_ptr::<u64>().add(1) = 0x1337 };
+    let aliased = unsafe { *frame.to_virt().as_ptr::<u64>().add(1) };
+    assert_eq!(aliased, 0x1337);
+    // the physical memory map may use huge pages
+    let phys = PhysAddr::new(0x12_3456);
+    assert_eq!(
+      translate_addr(phys.to_virt()).unwrap().as_u64(),
+      phys.as_u64()
+    );
+  }
 }
@@ -0,0 +1,175 @@
+#![allow(dead_code)]
+use crate::backtrace;
+use crate::cpu::regs::{Cr0, Cr2, Cr3, Cr4};
+use crate::interrupts::trap::TrapFrame;
+use crate::vga::{VgaColor, VgaDevice, COLS, ROWS};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+/*
+  Full screen report for panics and fatal exceptions, so there is
+  something to look at even without the serial port connected. The
+  serial output is printed separately by the callers.
+  Reference: https://wiki.osdev.org/Printing_To_Screen
+*/
+
+const REGS_PER_ROW: usize = 3;
+
+fn header(vga: &mut VgaDevice, title: fmt::Arguments) {
+  vga.set_background_color(VgaColor::Black);
+  vga.clear();
+  vga.set_color(VgaColor::White);
+  vga.set_background_color(VgaColor::Red);
+  for col in 0..COLS {
+    vga.write_char(0, col, b' ');
+  }
+  vga.set_cursor(0, 1);
+  let _ = vga.write_fmt(title);
+  vga.set_background_color(VgaColor::Black);
+  vga.set_cursor(2, 0);
+}
+
+fn section(vga: &mut VgaDevice, title: &str) {
+  let (row, _) = vga.cursor();
+  vga.set_cursor(row + 1, 0);
+  vga.set_color(VgaColor::Yellow);
+  let _ = writeln!(vga, "{}", title);
+  vga.set_color(VgaColor::Gray);
+}
+
+fn registers(vga: &mut VgaDevice, regs: &[(&str, u64)]) {
+  for (i, (name, value)) in regs.iter().enumerate() {
+    let _ = write!(vga, "{:>6}={:016x}", name, value);
+    let end_of_row = i % REGS_PER_ROW == REGS_PER_ROW - 1 || i == regs.len() - 1;
+    let _ = vga.write_str(if end_of_row { "\n" } else { "  " });
+  }
+}
+
+fn control_registers() -> [(&'static str, u64); 4] {
+  [
+    ("cr0", Cr0::read_raw()),
+    ("cr2", Cr2::read().as_u64()),
+    ("cr3", Cr3::read_raw()),
+    ("cr4", Cr4::read_raw()),
+  ]
+}
+
+// Fills the rest of the screen with as many frames as fit
+fn backtrace(vga: &mut VgaDevice, first_addr: Option<u64>, rbp: u64) {
+  section(vga, "Backtrace:");
+  backtrace::for_each_frame(first_addr, rbp, |i, addr, symbol| {
+    if vga.cursor().0 >= ROWS {
+      return;
+    }
+    let _ = write!(vga, "{:>4}: {:016x} ", i, addr);
+    match symbol {
+      Some((name, offset)) => {
+        // keep the end of long paths, it is the most specific part
+        let max_len = COLS - vga.cursor().1 - 12;
+        let start = name.len().saturating_sub(max_len);
+        let _ = writeln!(vga, "{}+{:#x}", name.get(start..).unwrap_or(name), offset);
+      }
+      None => {
+        let _ = writeln!(vga, "<unknown>");
+      }
+    }
+  });
+}
+
+#[inline(never)]
+pub fn show_panic(info: &PanicInfo) {
+  let mut vga = VgaDevice::new();
+  header(&mut vga, format_args!("KERNEL PANIC"));
+  vga.set_color(VgaColor::White);
+  if let Some(message) = info.message() {
+    let _ = writeln!(vga, "{}", message);
+  }
+  vga.set_color(VgaColor::Gray);
+  if let Some(location) = info.location() {
+    let _ = writeln!(vga, "at {}", location);
+  }
+  section(&mut vga, "Control registers:");
+  registers(&mut vga, &control_registers());
+  backtrace(&mut vga, None, backtrace::current_rbp());
+}
+
+pub fn show_exception(name: &str, frame: &TrapFrame) {
+  let mut vga = VgaDevice::new();
+  header(
+    &mut vga,
+    format_args!(
+      "FATAL EXCEPTION: {} (vector {}, error code {:#x})",
+      name, frame.vector, frame.error_code
+    ),
+  );
+  vga.set_color(VgaColor::Gray);
+  let mode = if frame.frame.is_user_mode() {
+    "user"
+  } else {
+    "kernel"
+  };
+  let _ = writeln!(vga, "in {} mode", mode);
+  section(&mut vga, "Registers:");
+  #[rustfmt::skip]
+  registers(&mut vga, &[
+    ("rax", frame.rax), ("rbx", frame.rbx), ("rcx", frame.rcx),
+    ("rdx", frame.rdx), ("rsi", frame.rsi), ("rdi", frame.rdi),
+    ("rbp", frame.rbp), ("r8", frame.r8), ("r9", frame.r9),
+    ("r10", frame.r10), ("r11", frame.r11), ("r12", frame.r12),
+    ("r13", frame.r13), ("r14", frame.r14), ("r15", frame.r15),
+    ("rip", frame.frame.instruction_ptr), ("rsp", frame.frame.stack_ptr),
+    ("rflags", frame.frame.cpu_flags), ("cs", frame.frame.code_segment),
+    ("ss", frame.frame.stack_segment),
+  ]);
+  section(&mut vga, "Control registers:");
+  registers(&mut vga, &control_registers());
+  backtrace(&mut vga, Some(frame.frame.instruction_ptr), frame.rbp);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn row_starts_with(vga: &VgaDevice, row: usize, s: &str) -> bool {
+    s.bytes()
+      .enumerate()
+      .all(|(col, c)| vga.read_char(row, col) == c)
+  }
+
+  #[test_case]
+  fn exception_screen() {
+    let mut frame = TrapFrame {
+      rax: 0x1234,
+      vector: 14,
+      error_code: 2,
+      ..TrapFrame::default()
+    };
+    frame.frame.instruction_ptr = show_exception as usize as u64;
+    show_exception("page fault", &frame);
+
+    let vga = VgaDevice::new();
+    assert!(row_starts_with(
+      &vga,
+      0,
+      " FATAL EXCEPTION: page fault (vector 14, error code 0x2)"
+    ));
+    assert!(row_starts_with(&vga, 2, "in kernel mode"));
+    assert!(row_starts_with(&vga, 4, "Registers:"));
+    assert!(row_starts_with(&vga, 5, "   rax=0000000000001234"));
+    // 20 registers over 7 rows, then the control registers
+    assert!(row_starts_with(&vga, 13, "Control registers:"));
+    assert!(row_starts_with(&vga, 14, "   cr0="));
+    assert!(row_starts_with(&vga, 17, "Backtrace:"));
+    assert!(row_starts_with(&vga, 18, "   0: "));
+  }
+
+  #[test_case]
+  fn vga_text_wraps() {
+    let mut vga = VgaDevice::new();
+    vga.clear();
+    vga.set_cursor(ROWS - 1, COLS - 2);
+    let _ = vga.write_str("abcd");
+    assert_eq!(vga.read_char(ROWS - 1, COLS - 1), b'b');
+    assert_eq!(vga.cursor(), (ROWS, 0));
+  }
+}
@@ -0,0 +1,186 @@
+#![allow(dead_code)]
+use crate::cpu::regs::{IA32_GS_BASE, IA32_KERNEL_GS_BASE};
+use crate::interrupts::{self, gdt::TaskSegmentSelector};
+use crate::mem::frame_allocator::FrameAllocator;
+use crate::smp::MAX_CPUS;
+
+/*
+  Every cpu has a CpuLocal block, allocated when it is brought up,
+  and GS_BASE points at it while the cpu is in the kernel. Fields
+  are read with gs relative loads, so getting the cpu id or the
+  current task is a single instruction.
+
+  User mode has its own gs base. The kernel's is kept in
+  KERNEL_GS_BASE while in user mode and every kernel entry from user
+  mode swaps the two with swapgs, as does every return to user mode.
+  See syscall_entry, trap_common and usermode::enter.
+
+  Variables declared with percpu! have one slot per cpu, indexed by
+  the cpu id.
+  References:
+  https://wiki.osdev.org/SWAPGS
+  https://www.felixcloutier.com/x86/swapgs
+*/
+
+#[repr(C)]
+pub struct CpuLocal {
+  this: *mut CpuLocal, // gs:[0] is the block itself
+  id: usize,
+  tss: *mut TaskSegmentSelector,
+  user_rsp: u64, // scratch space for syscall_entry
+  current_task: usize,
+}
+
+// Offsets of the fields, for the assembly entry points
+pub const ID_OFFSET: usize = 8;
+pub const TSS_OFFSET: usize = 16;
+pub const USER_RSP_OFFSET: usize = 24;
+pub const CURRENT_TASK_OFFSET: usize = 32;
+
+// Unsafe since it has to be called once on each cpu, before any
+// per-cpu data is accessed, and the TSS has to be the one it loaded
+pub unsafe fn initialize(cpu: usize, tss: &'static mut TaskSegmentSelector) {
+  assert!(cpu < MAX_CPUS);
+  let frame = FrameAllocator::the().calloc().expect("OOM");
+  let block = frame.to_virt().as_mut_ptr::<CpuLocal>();
+  block.write(CpuLocal {
+    this: block,
+    id: cpu,
+    tss,
+    user_rsp: 0,
+    current_task: 0,
+  });
+  IA32_GS_BASE.write(block as u64);
+  IA32_KERNEL_GS_BASE.write(0); // the user gs base
+}
+
+macro_rules! read_field {
+  ($offset:expr) => {{
+    let value: u64;
+    unsafe { asm!("mov {}, gs:[{}]", out(reg) value, const $offset, options(nostack, readonly)) };
+    value
+  }};
+}
+
+pub fn cpu_id() -> usize {
+  read_field!(ID_OFFSET) as usize
+}
+
+// The TSS loaded on this cpu
+pub fn tss() -> *mut TaskSegmentSelector {
+  read_field!(TSS_OFFSET) as *mut TaskSegmentSelector
+}
+
+// Identifies the task running on this cpu, 0 before the scheduler runs
+pub fn current_task() -> usize {
+  read_field!(CURRENT_TASK_OFFSET) as usize
+}
+
+pub fn set_current_task(task: usize) {
+  unsafe { asm!("mov gs:[{}], {}", const CURRENT_TASK_OFFSET, in(reg) task, options(nostack)) };
+}
+
+pub fn current() -> &'static CpuLocal {
+  let ptr = read_field!(0) as *const CpuLocal;
+  unsafe { &*ptr }
+}
+
+// A variable with one value per cpu, declared with percpu!. The value
+// can also be accessed by interrupt handlers on the same cpu.
+pub struct PerCpu<T> {
+  slots: [T; MAX_CPUS],
+}
+
+// Safe since every cpu only accesses its own slot. The values are
+// created on one cpu and used on the others, so they have to be Send.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+  #[doc(hidden)]
+  pub const fn new(slots: [T; MAX_CPUS]) -> Self {
+    Self { slots }
+  }
+
+  // Runs f with the value of this cpu. Interrupts are disabled until
+  // it returns, so the task can't be moved to another cpu meanwhile
+  // and interrupt handlers can't use the value at the same time.
+  pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+    let interrupts_enabled = interrupts::enabled();
+    interrupts::disable();
+    let result = f(&self.slots[cpu_id()]);
+    if interrupts_enabled {
+      interrupts::enable();
+    }
+    result
+  }
+
+  // The values of all cpus, for values which are safe to share
+  pub fn iter(&self) -> impl Iterator<Item = &T>
+  where
+    T: Sync,
+  {
+    self.slots.iter()
+  }
+}
+
+#[macro_export]
+macro_rules! percpu {
+  ($(#[$attr:meta])* $vis:vis static $name:ident: $T:ty = $init:expr;) => {
+    $(#[$attr])*
+    $vis static $name: $crate::percpu::PerCpu<$T> = {
+      #[allow(clippy::declare_interior_mutable_const)]
+      const INIT: $T = $init;
+      $crate::percpu::PerCpu::new([INIT; $crate::smp::MAX_CPUS])
+    };
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use core::cell::Cell;
+
+  crate::percpu! {
+    static COUNTER: Cell<u64> = Cell::new(0);
+  }
+
+  #[test_case]
+  fn field_offsets() {
+    let local = current();
+    let base = local as *const CpuLocal as usize;
+    assert_eq!(&local.id as *const _ as usize - base, ID_OFFSET);
+    assert_eq!(&local.tss as *const _ as usize - base, TSS_OFFSET);
+    assert_eq!(&local.user_rsp as *const _ as usize - base, USER_RSP_OFFSET);
+    assert_eq!(
+      &local.current_task as *const _ as usize - base,
+      CURRENT_TASK_OFFSET
+    );
+    assert_eq!(local.this as usize, base);
+  }
+
+  #[test_case]
+  fn bootstrap_processor() {
+    assert_eq!(cpu_id(), 0);
+    assert_eq!(unsafe { IA32_GS_BASE.read() }, current() as *const _ as u64);
+    assert_eq!(unsafe { (*tss()).privilege_stack(0) } & 0xf, 0);
+  }
+
+  #[test_case]
+  fn task_field() {
+    let before = current_task();
+    set_current_task(42);
+    assert_eq!(current_task(), 42);
+    set_current_task(before);
+  }
+
+  #[test_case]
+  fn percpu_variable() {
+    COUNTER.with(|counter| counter.set(counter.get() + 1));
+    assert_eq!(COUNTER.with(Cell::get), 1);
+    assert_eq!(COUNTER.slots[1].get(), 0);
+    // interrupts are restored afterwards
+    let enabled = interrupts::enabled();
+    assert!(!COUNTER.with(|_| interrupts::enabled()));
+    assert_eq!(interrupts::enabled(), enabled);
+  }
+}
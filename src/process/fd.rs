@@ -0,0 +1,133 @@
+#![allow(dead_code)]
+use super::file::{Access, FileRef, CONSOLE};
+use crate::syscall::Errno;
+
+pub const MAX_FILES: usize = 16;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+// The open files of a process, indexed by file descriptor. Children
+// get a copy of the table of their parent, which opens all of its
+// files again.
+#[derive(Clone)]
+pub struct FdTable {
+  files: [Option<FileRef>; MAX_FILES],
+}
+
+impl FdTable {
+  pub fn new() -> Self {
+    const NONE: Option<FileRef> = None;
+    Self {
+      files: [NONE; MAX_FILES],
+    }
+  }
+
+  // Standard input, output and error on the console
+  pub fn with_console() -> Self {
+    let mut table = Self::new();
+    for &fd in &[STDIN, STDOUT, STDERR] {
+      table.files[fd] = Some(FileRef::new(&CONSOLE, Access::READ | Access::WRITE));
+    }
+    table
+  }
+
+  // Another reference to the file, which stays open while it is used
+  // even if the descriptor is closed
+  pub fn get(&self, fd: usize) -> Result<FileRef, Errno> {
+    self.slot(fd).cloned().ok_or(Errno::EBADF)
+  }
+
+  // Puts file at the lowest free descriptor
+  pub fn insert(&mut self, file: FileRef) -> Result<usize, Errno> {
+    let fd = self
+      .files
+      .iter()
+      .position(Option::is_none)
+      .ok_or(Errno::EMFILE)?;
+    self.files[fd] = Some(file);
+    Ok(fd)
+  }
+
+  pub fn close(&mut self, fd: usize) -> Result<(), Errno> {
+    self.slot(fd).ok_or(Errno::EBADF)?;
+    self.files[fd] = None;
+    Ok(())
+  }
+
+  // A new descriptor for the file of fd, the lowest free one
+  pub fn dup(&mut self, fd: usize) -> Result<usize, Errno> {
+    let file = self.get(fd)?;
+    self.insert(file)
+  }
+
+  // Makes new refer to the file of old, closing what new referred to
+  pub fn dup2(&mut self, old: usize, new: usize) -> Result<usize, Errno> {
+    let file = self.get(old)?;
+    if new >= MAX_FILES {
+      return Err(Errno::EBADF);
+    }
+    self.files[new] = Some(file);
+    Ok(new)
+  }
+
+  pub fn open_count(&self) -> usize {
+    self.files.iter().filter(|file| file.is_some()).count()
+  }
+
+  pub fn close_all(&mut self) {
+    self.files.iter_mut().for_each(|file| *file = None);
+  }
+
+  fn slot(&self, fd: usize) -> Option<&FileRef> {
+    self.files.get(fd).and_then(Option::as_ref)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::process::pipe;
+
+  #[test_case]
+  fn console_table() {
+    let mut table = FdTable::with_console();
+    assert!(table
+      .get(STDOUT)
+      .unwrap()
+      .same_file(&FileRef::new(&CONSOLE, Access::READ)));
+    assert!(table.get(3).is_err());
+    assert!(table.get(MAX_FILES).is_err());
+    let copy = table.clone();
+    table.close_all();
+    assert_eq!(table.open_count(), 0);
+    assert_eq!(copy.open_count(), 3);
+  }
+
+  #[test_case]
+  fn dup_and_close() {
+    let mut table = FdTable::with_console();
+    let (reader, writer) = pipe::pipe().unwrap();
+    assert_eq!(table.insert(reader), Ok(3));
+    assert_eq!(table.insert(writer), Ok(4));
+    assert_eq!(table.close(STDIN), Ok(()));
+    assert_eq!(table.close(STDIN), Err(Errno::EBADF));
+    assert_eq!(table.dup(4), Ok(STDIN));
+    assert_eq!(table.dup2(3, STDOUT), Ok(STDOUT));
+    assert_eq!(table.dup2(3, MAX_FILES), Err(Errno::EBADF));
+    assert_eq!(table.dup2(8, 9), Err(Errno::EBADF));
+    assert_eq!(table.dup(MAX_FILES), Err(Errno::EBADF));
+    assert_eq!(table.open_count(), 5);
+    // the write end is still open through STDIN
+    table.close(4).unwrap();
+    assert_eq!(table.get(STDIN).unwrap().write(b"hi"), Ok(2));
+    table.close(STDIN).unwrap();
+    let mut buf = [0; 4];
+    assert_eq!(table.get(STDOUT).unwrap().read(&mut buf), Ok(2));
+    assert_eq!(table.get(3).unwrap().read(&mut buf), Ok(0));
+    while table.dup(STDERR).is_ok() {}
+    assert_eq!(table.open_count(), MAX_FILES);
+    assert_eq!(table.dup(STDERR), Err(Errno::EMFILE));
+  }
+}
@@ -0,0 +1,204 @@
+#![allow(dead_code)]
+use super::pipe::Pipe;
+use crate::syscall::Errno;
+
+/*
+  Everything a file descriptor can refer to implements File. Files are
+  statics, there is no heap to allocate them from, so a FileRef is a
+  reference to one together with the access it was opened with, like
+  an open file description on Unix. Cloning a FileRef, e.g. for dup or
+  fork, opens the file again and dropping it closes it, so a file can
+  count its users, a pipe its readers and writers.
+
+  The console writes to the debug output on the serial port and reads
+  the characters typed on the keyboard.
+*/
+
+bitflags::bitflags! {
+  // Who may read or write through a FileRef
+  pub struct Access: u8 {
+    const READ  = 1 << 0;
+    const WRITE = 1 << 1;
+  }
+}
+
+bitflags::bitflags! {
+  // What a file is ready for, the values match Linux's poll
+  pub struct PollFlags: u16 {
+    const IN   = 0x01; // read would not block
+    const OUT  = 0x04; // write would not block
+    const ERR  = 0x08; // write would fail, the other end is closed
+    const HUP  = 0x10; // the other end is closed
+    const NVAL = 0x20; // not an open file descriptor
+  }
+}
+
+pub trait File: Sync {
+  // Blocks until at least one byte can be read, returns 0 at the end
+  fn read(&self, buf: &mut [u8]) -> Result<usize, Errno>;
+  // Blocks until buf has been written, may stop short on errors
+  fn write(&self, buf: &[u8]) -> Result<usize, Errno>;
+  // What read and write would do right now, never blocks
+  fn poll(&self) -> PollFlags;
+  // Called for every FileRef to the file, and when it is dropped
+  fn open(&self, _access: Access) {}
+  fn close(&self, _access: Access) {}
+}
+
+pub struct FileRef {
+  file:   &'static dyn File,
+  access: Access,
+}
+
+impl FileRef {
+  pub fn new(file: &'static dyn File, access: Access) -> Self {
+    file.open(access);
+    Self { file, access }
+  }
+
+  pub fn access(&self) -> Access {
+    self.access
+  }
+
+  pub fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+    if !self.access.contains(Access::READ) {
+      return Err(Errno::EBADF);
+    }
+    self.file.read(buf)
+  }
+
+  pub fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+    if !self.access.contains(Access::WRITE) {
+      return Err(Errno::EBADF);
+    }
+    self.file.write(buf)
+  }
+
+  // Only what the access allows, hang ups are always reported
+  pub fn poll(&self) -> PollFlags {
+    let mut mask = PollFlags::HUP;
+    if self.access.contains(Access::READ) {
+      mask |= PollFlags::IN;
+    }
+    if self.access.contains(Access::WRITE) {
+      mask |= PollFlags::OUT | PollFlags::ERR;
+    }
+    self.file.poll() & mask
+  }
+
+  // Whether both refer to the same file
+  pub fn same_file(&self, other: &FileRef) -> bool {
+    // only the data pointers, the vtables of one type can differ
+    core::ptr::eq(
+      self.file as *const dyn File as *const u8,
+      other.file as *const dyn File as *const u8,
+    )
+  }
+}
+
+impl Clone for FileRef {
+  fn clone(&self) -> Self {
+    Self::new(self.file, self.access)
+  }
+}
+
+impl Drop for FileRef {
+  fn drop(&mut self) {
+    self.file.close(self.access);
+  }
+}
+
+pub struct Console;
+
+pub static CONSOLE: Console = Console;
+
+// Typed characters waiting to be read, dropped when nobody reads them
+static INPUT: Pipe = Pipe::connected();
+
+impl File for Console {
+  fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+    INPUT.read(buf)
+  }
+
+  fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+    crate::dbg_print::write_bytes(buf);
+    Ok(buf.len())
+  }
+
+  fn poll(&self) -> PollFlags {
+    (INPUT.poll() & PollFlags::IN) | PollFlags::OUT
+  }
+}
+
+// Called by the keyboard for every typed character
+pub fn console_input(c: u8) {
+  INPUT.try_write(&[c]);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use core::sync::atomic::{AtomicUsize, Ordering};
+
+  struct Counted(AtomicUsize);
+
+  impl File for Counted {
+    fn read(&self, _: &mut [u8]) -> Result<usize, Errno> {
+      Ok(0)
+    }
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+      Ok(buf.len())
+    }
+    fn poll(&self) -> PollFlags {
+      PollFlags::all()
+    }
+    fn open(&self, _: Access) {
+      self.0.fetch_add(1, Ordering::SeqCst);
+    }
+    fn close(&self, _: Access) {
+      self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+  }
+
+  static COUNTED: Counted = Counted(AtomicUsize::new(0));
+
+  #[test_case]
+  fn references_are_counted() {
+    let file = FileRef::new(&COUNTED, Access::READ);
+    let copy = file.clone();
+    assert_eq!(COUNTED.0.load(Ordering::SeqCst), 2);
+    assert!(copy.same_file(&file));
+    assert!(!copy.same_file(&FileRef::new(&CONSOLE, Access::READ)));
+    drop(file);
+    assert_eq!(COUNTED.0.load(Ordering::SeqCst), 1);
+    drop(copy);
+    assert_eq!(COUNTED.0.load(Ordering::SeqCst), 0);
+  }
+
+  #[test_case]
+  fn access_is_checked() {
+    let read_only = FileRef::new(&COUNTED, Access::READ);
+    assert_eq!(read_only.write(b"x"), Err(Errno::EBADF));
+    assert_eq!(read_only.read(&mut [0]), Ok(0));
+    assert_eq!(read_only.poll(), PollFlags::IN | PollFlags::HUP);
+    let write_only = FileRef::new(&COUNTED, Access::WRITE);
+    assert_eq!(write_only.read(&mut [0]), Err(Errno::EBADF));
+    assert_eq!(write_only.write(b"xy"), Ok(2));
+    assert_eq!(
+      write_only.poll(),
+      PollFlags::OUT | PollFlags::ERR | PollFlags::HUP
+    );
+  }
+
+  #[test_case]
+  fn console_input_is_read() {
+    let console = FileRef::new(&CONSOLE, Access::READ | Access::WRITE);
+    assert_eq!(console.poll(), PollFlags::OUT);
+    console_input(b'a');
+    console_input(b'b');
+    assert_eq!(console.poll(), PollFlags::IN | PollFlags::OUT);
+    let mut buf = [0; 4];
+    assert_eq!(console.read(&mut buf), Ok(2));
+    assert_eq!(&buf[..2], b"ab");
+  }
+}
@@ -0,0 +1,1030 @@
+#![allow(dead_code)]
+use crate::cpu::fpu;
+use crate::elf;
+use crate::interrupts;
+use crate::ipc::capability::HandleTable;
+use crate::mem::address_space::AddressSpace;
+use crate::mem::{PhysAddr, VirtAddr};
+use crate::sync::{Condvar, Mutex};
+use crate::syscall::{self, Errno, SyscallFrame};
+use crate::task::{self, JoinHandle, TaskId};
+use crate::usermode;
+use core::fmt;
+
+pub mod fd;
+pub mod file;
+pub mod pipe;
+pub mod programs;
+pub mod signal;
+
+use fd::FdTable;
+use signal::SignalState;
+
+/*
+  A process is a running program. It owns an address space, a file
+  descriptor table, a table of handles to IPC port capabilities and its
+  threads, the tasks running its code in user mode. spawn loads an
+  executable into a fresh address space and starts the main thread at
+  its entry point. fork starts a child with a copy of the caller's
+  address space, files, handles and registers, whose main thread
+  returns from the same system call. The memory is copied right away,
+  there is no copy on write. exec replaces the program of a process,
+  which keeps its pid, files and handles. There is no way to start more
+  threads yet. Kernel tasks have no files or handles, the processes they
+  start get the console as standard input, output and error.
+
+  When a process exits, or is killed by a signal, its address space,
+  files and handles are freed right away, but it stays in the table as a
+  zombie with its exit status until its parent waits for it. Processes
+  started by kernel tasks have Pid::KERNEL as their parent, any kernel
+  task can wait for them. The children of an exiting process become
+  orphans, nobody waits for those so they are removed as soon as they
+  exit.
+*/
+
+pub const MAX_PROCESSES: usize = 32;
+const MAX_THREADS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pid(usize);
+
+impl Pid {
+  // The parent of processes started by the kernel
+  pub const KERNEL: Pid = Pid(0);
+
+  pub fn from_usize(pid: usize) -> Self {
+    Self(pid)
+  }
+
+  pub fn as_usize(self) -> usize {
+    self.0
+  }
+}
+
+impl fmt::Display for Pid {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+  Exited(i32),   // with the exit code
+  Signaled(u32), // killed by the signal
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+  Running,
+  Zombie(ExitStatus),
+}
+
+struct Process {
+  pid:     Pid,
+  parent:  Option<Pid>, // None for orphans
+  state:   State,
+  space:   Option<AddressSpace>, // None once exited
+  files:   FdTable,
+  handles: HandleTable,
+  threads: [Option<TaskId>; MAX_THREADS],
+  start:   Option<Start>, // taken when the main thread starts
+}
+
+// How the main thread enters user mode
+enum Start {
+  Entry(VirtAddr, VirtAddr), // the entry point and stack pointer
+  Resume(SyscallFrame),      // returning from fork
+}
+
+struct ProcessTable {
+  processes: [Option<Process>; MAX_PROCESSES],
+  last_pid:  usize,
+}
+
+impl ProcessTable {
+  const fn new() -> Self {
+    const NONE: Option<Process> = None;
+    Self {
+      processes: [NONE; MAX_PROCESSES],
+      last_pid:  0,
+    }
+  }
+
+  fn slot_of_thread(&self, thread: TaskId) -> Option<usize> {
+    self.processes.iter().position(
+      |process| matches!(process, Some(process) if process.threads.contains(&Some(thread))),
+    )
+  }
+
+  fn current_slot(&self) -> Option<usize> {
+    task::current().and_then(|task| self.slot_of_thread(task))
+  }
+
+  fn free_slot(&self) -> Result<usize, Errno> {
+    let slot = self.processes.iter().position(Option::is_none);
+    slot.ok_or(Errno::EAGAIN)
+  }
+
+  fn next_pid(&mut self) -> Pid {
+    self.last_pid += 1;
+    Pid(self.last_pid)
+  }
+
+  fn get(&mut self, pid: Pid) -> Option<&mut Process> {
+    self
+      .processes
+      .iter_mut()
+      .flatten()
+      .find(|process| process.pid == pid)
+  }
+
+  // The process of the current task, Pid::KERNEL for kernel tasks
+  fn current_pid(&self) -> Pid {
+    match self.current_slot() {
+      Some(slot) => self.processes[slot].as_ref().unwrap().pid,
+      None => Pid::KERNEL,
+    }
+  }
+
+  // Children of an exiting process become orphans, the zombies among
+  // them are removed right away
+  fn orphan_children(&mut self, parent: Pid) {
+    for slot in self.processes.iter_mut() {
+      let zombie = match slot {
+        Some(process) if process.parent == Some(parent) => {
+          process.parent = None;
+          matches!(process.state, State::Zombie(_))
+        }
+        _ => false,
+      };
+      if zombie {
+        *slot = None;
+      }
+    }
+  }
+}
+
+static PROCESSES: Mutex<ProcessTable> = Mutex::new(ProcessTable::new());
+static CHILD_EXITED: Condvar = Condvar::new();
+
+// Starts the executable at path as a child of the current process.
+// By convention args[0] is the name of the program.
+pub fn spawn(path: &[u8], args: &[&[u8]]) -> Result<Pid, Errno> {
+  let program = programs::find(path).ok_or(Errno::ENOENT)?;
+  let mut image = elf::load(program, args, &[])?;
+  signal::map_trampoline(&mut image.space)?;
+  let mut table = PROCESSES.lock();
+  let slot = table.free_slot()?;
+  let parent = table.current_pid();
+  let (files, handles) = match table.get(parent) {
+    Some(parent) => (parent.files.clone(), parent.handles.clone()),
+    None => (FdTable::with_console(), HandleTable::new()),
+  };
+  let process = Process {
+    pid: table.next_pid(),
+    parent: Some(parent),
+    state: State::Running,
+    space: Some(image.space),
+    files,
+    handles,
+    threads: [None; MAX_THREADS],
+    start: Some(Start::Entry(image.entry, image.stack_pointer)),
+  };
+  let signals = SignalState::new();
+  start_main_thread(&mut table, slot, process, signals, task::spawn_in)
+}
+
+// Starts a copy of the current process, which has to be a user process.
+// frame is the state of the calling thread, the child returns from the
+// system call with 0 and the parent gets the child's pid. Fails with
+// ENOMEM if there is no memory for a copy of the address space.
+pub fn fork(frame: &SyscallFrame) -> Result<Pid, Errno> {
+  let mut table = PROCESSES.lock();
+  let me = table.current_slot().ok_or(Errno::EPERM)?;
+  let slot = table.free_slot()?;
+  let mut resume = frame.clone();
+  resume.rax = 0;
+  let pid = table.next_pid();
+  let parent = table.processes[me].as_ref().unwrap();
+  let space = parent.space.as_ref().unwrap().duplicate()?;
+  let process = Process {
+    pid,
+    parent: Some(parent.pid),
+    state: State::Running,
+    space: Some(space),
+    files: parent.files.clone(),
+    handles: parent.handles.clone(),
+    threads: [None; MAX_THREADS],
+    start: Some(Start::Resume(resume)),
+  };
+  let signals = signal::with_slot(me, |signals| signals.fork()).unwrap();
+  start_main_thread(&mut table, slot, process, signals, task::fork_in)
+}
+
+// Puts process into slot and starts its main thread with spawner. The
+// thread waits for the table lock before looking itself up, and the
+// address space is only freed after it has switched away.
+fn start_main_thread(
+  table: &mut ProcessTable,
+  slot: usize,
+  process: Process,
+  signals: SignalState,
+  spawner: unsafe fn(fn(), PhysAddr) -> Option<JoinHandle>,
+) -> Result<Pid, Errno> {
+  let (pid, level_four) = (process.pid, process.space.as_ref().unwrap().level_four());
+  table.processes[slot] = Some(process);
+  match unsafe { spawner(run_main_thread, level_four) } {
+    Some(handle) => {
+      // dropping the handle detaches the task
+      table.processes[slot].as_mut().unwrap().threads[0] = Some(handle.id());
+      signal::attach(slot, handle.id(), signals);
+      Ok(pid)
+    }
+    None => {
+      table.processes[slot] = None;
+      Err(Errno::EAGAIN)
+    }
+  }
+}
+
+fn run_main_thread() {
+  let start = {
+    let mut table = PROCESSES.lock();
+    let slot = table.slot_of_thread(task::current().unwrap());
+    let process = table.processes[slot.expect("thread without a process")].as_mut();
+    process
+      .unwrap()
+      .start
+      .take()
+      .expect("main thread started twice")
+  };
+  // an interrupt between the swapgs and the return to user mode would
+  // run the kernel with the user gs base
+  interrupts::disable();
+  match start {
+    Start::Entry(entry, stack_pointer) => unsafe { usermode::enter(entry, stack_pointer) },
+    Start::Resume(frame) => unsafe { syscall::return_to_user(&frame) },
+  }
+}
+
+// Replaces the program of the current process, a user process, with
+// the executable at path. Only returns on errors, which leave the
+// process as it was.
+pub fn exec(path: &[u8], args: &[&[u8]], env: &[&[u8]]) -> Errno {
+  let program = match programs::find(path) {
+    Some(program) => program,
+    None => return Errno::ENOENT,
+  };
+  // args and env may point into the old image, they are copied here
+  let mut image = match elf::load(program, args, env) {
+    Ok(image) => image,
+    Err(err) => return err.into(),
+  };
+  if let Err(err) = signal::map_trampoline(&mut image.space) {
+    return err;
+  }
+  {
+    let mut table = PROCESSES.lock();
+    let slot = match table.current_slot() {
+      Some(slot) => slot,
+      None => return Errno::EPERM,
+    };
+    unsafe { task::set_address_space(Some(image.space.level_four())) };
+    // the old address space is freed now that it is inactive
+    table.processes[slot].as_mut().unwrap().space = Some(image.space);
+    signal::with_slot(slot, SignalState::exec);
+  }
+  interrupts::disable();
+  unsafe {
+    fpu::reset_current();
+    usermode::enter(image.entry, image.stack_pointer)
+  }
+}
+
+// Ends the current process, which has to be a user process
+pub fn exit(code: i32) -> ! {
+  terminate(ExitStatus::Exited(code))
+}
+
+// Ends the current process like the default action of signal
+pub fn exit_by_signal(signal: u32) -> ! {
+  terminate(ExitStatus::Signaled(signal))
+}
+
+fn terminate(status: ExitStatus) -> ! {
+  let me = task::current().expect("exit without a task");
+  {
+    let mut table = PROCESSES.lock();
+    let slot = table.slot_of_thread(me).expect("exit outside of a process");
+    // off the address space before it is freed
+    unsafe { task::set_address_space(None) };
+    let process = table.processes[slot].as_mut().unwrap();
+    process.threads = [None; MAX_THREADS];
+    process.state = State::Zombie(status);
+    process.space = None;
+    process.files.close_all();
+    process.handles.close_all();
+    signal::detach(slot);
+    let (pid, orphan) = (process.pid, process.parent.is_none());
+    table.orphan_children(pid);
+    if orphan {
+      table.processes[slot] = None;
+    }
+  }
+  CHILD_EXITED.notify_all();
+  task::exit();
+}
+
+// Waits until a child of the current process has exited, any child
+// if pid is None, and removes it. Returns its pid and exit status, or
+// EINTR if a signal arrives first.
+pub fn wait(pid: Option<Pid>) -> Result<(Pid, ExitStatus), Errno> {
+  let mut table = PROCESSES.lock();
+  let me = table.current_pid();
+  loop {
+    match find_child(&table, me, pid) {
+      Some((slot, State::Zombie(status))) => {
+        let child = table.processes[slot].take().unwrap();
+        return Ok((child.pid, status));
+      }
+      Some(_) => {
+        table = CHILD_EXITED
+          .wait_interruptible(table, signal::interrupted)
+          .ok_or(Errno::EINTR)?
+      }
+      None => return Err(Errno::ECHILD),
+    }
+  }
+}
+
+// A matching child, a zombie if there is one
+fn find_child(table: &ProcessTable, parent: Pid, pid: Option<Pid>) -> Option<(usize, State)> {
+  let mut found = None;
+  for (slot, process) in table.processes.iter().enumerate() {
+    match process {
+      Some(process)
+        if process.parent == Some(parent) && pid.unwrap_or(process.pid) == process.pid =>
+      {
+        found = Some((slot, process.state));
+        if process.state != State::Running {
+          break;
+        }
+      }
+      _ => {}
+    }
+  }
+  found
+}
+
+// Sends signal to the process, which is delivered the next time its
+// thread returns to user mode. A blocking system call it is in fails
+// with EINTR. Signal 0 only checks that it exists.
+pub fn kill(pid: Pid, signal: u32) -> Result<(), Errno> {
+  if signal != 0 && !signal::is_valid(signal) {
+    return Err(Errno::EINVAL);
+  }
+  let table = PROCESSES.lock();
+  let slot = table
+    .processes
+    .iter()
+    .position(|process| matches!(process, Some(process) if process.pid == pid))
+    .ok_or(Errno::ESRCH)?;
+  // zombies have no signal state, signals to them have no effect
+  if signal != 0 {
+    signal::send(slot, signal);
+  }
+  Ok(())
+}
+
+// Sends signal to every process, like Ctrl-C on the console which all
+// of them share
+pub fn kill_all(signal: u32) {
+  for slot in 0..MAX_PROCESSES {
+    signal::send(slot, signal);
+  }
+}
+
+// Runs f with the file descriptor table of the current process,
+// kernel tasks have none
+pub fn with_files<R>(f: impl FnOnce(&mut FdTable) -> R) -> Result<R, Errno> {
+  let mut table = PROCESSES.lock();
+  let slot = table.current_slot().ok_or(Errno::EBADF)?;
+  Ok(f(&mut table.processes[slot].as_mut().unwrap().files))
+}
+
+// Like with_files, for the IPC handles
+pub fn with_handles<R>(f: impl FnOnce(&mut HandleTable) -> R) -> Result<R, Errno> {
+  let mut table = PROCESSES.lock();
+  let slot = table.current_slot().ok_or(Errno::EBADF)?;
+  Ok(f(&mut table.processes[slot].as_mut().unwrap().handles))
+}
+
+// Runs f with the address space of the current process
+pub fn with_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Result<R, Errno> {
+  let mut table = PROCESSES.lock();
+  let slot = table.current_slot().ok_or(Errno::EPERM)?;
+  let process = table.processes[slot].as_mut().unwrap();
+  Ok(f(process.space.as_mut().unwrap()))
+}
+
+pub fn current() -> Pid {
+  PROCESSES.lock().current_pid()
+}
+
+// The number of processes, zombies included
+pub fn count() -> usize {
+  PROCESSES.lock().processes.iter().flatten().count()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::elf::test_elf::{self, SIZE};
+  use crate::ipc;
+  use crate::mem::frame_allocator::FrameAllocator;
+  use crate::syscall::{SYS_CLOCK_GETTIME, SYS_NANOSLEEP};
+  use crate::syscall::{
+    SYS_CLOSE, SYS_DUP2, SYS_PIPE, SYS_READ, SYS_SIGACTION, SYS_WAIT, SYS_WRITE,
+  };
+  use crate::syscall::{SYS_EXECVE, SYS_EXIT, SYS_FORK, SYS_GETPID, SYS_KILL};
+  use crate::syscall::{SYS_PORT_CALL, SYS_PORT_CREATE, SYS_PORT_RECEIVE, SYS_PORT_REPLY};
+  use lazy_static::lazy_static;
+  use signal::{SIGFPE, SIGILL, SIGINT, SIGKILL, SIGPIPE, SIGSEGV, SIGTRAP, SIGUSR1};
+
+  lazy_static! {
+    static ref EXIT_42: [u8; SIZE] = test_elf::build_with_code(&[
+      0xbf, 42, 0, 0, 0,             // mov edi, 42
+      0xb8, SYS_EXIT as u8, 0, 0, 0, // mov eax, SYS_EXIT
+      0x0f, 0x05,                    // syscall
+      0x0f, 0x0b,                    // ud2
+    ]);
+    static ref EXIT_ARGC: [u8; SIZE] = test_elf::build_with_code(&[
+      0x48, 0x8b, 0x3c, 0x24,        // mov rdi, [rsp]
+      0xb8, SYS_EXIT as u8, 0, 0, 0, // mov eax, SYS_EXIT
+      0x0f, 0x05,                    // syscall
+      0x0f, 0x0b,                    // ud2
+    ]);
+    // The child exits with 1 + the value its parent stored before the
+    // fork, the parent with the child's code + its own, unchanged copy
+    static ref FORK: [u8; SIZE] = test_elf::build_with_code(&[
+      0x48, 0x83, 0xec, 0x10,                         // sub rsp, 16
+      0x48, 0xc7, 0x44, 0x24, 0x08, 5, 0, 0, 0,       // mov qword [rsp+8], 5
+      0xb8, SYS_FORK as u8, 0, 0, 0,                  // mov eax, SYS_FORK
+      0x0f, 0x05,                                     // syscall
+      0x48, 0x85, 0xc0,                               // test rax, rax
+      0x74, 0x1a,                                     // jz child
+      0x48, 0x89, 0xc7,                               // mov rdi, rax
+      0x48, 0x89, 0xe6,                               // mov rsi, rsp
+      0xb8, SYS_WAIT as u8, 0, 0, 0,                  // mov eax, SYS_WAIT
+      0x0f, 0x05,                                     // syscall
+      0x8b, 0x3c, 0x24,                               // mov edi, [rsp]
+      0xc1, 0xef, 0x08,                               // shr edi, 8
+      0x48, 0x03, 0x7c, 0x24, 0x08,                   // add rdi, [rsp+8]
+      0xeb, 0x0f,                                     // jmp exit
+      0x48, 0x8b, 0x7c, 0x24, 0x08,                   // child: mov rdi, [rsp+8]
+      0x48, 0x83, 0x44, 0x24, 0x08, 100,              // add qword [rsp+8], 100
+      0x48, 0x83, 0xc7, 0x01,                         // add rdi, 1
+      0xb8, SYS_EXIT as u8, 0, 0, 0,                  // exit: mov eax, SYS_EXIT
+      0x0f, 0x05,                                     // syscall
+      0x0f, 0x0b,                                     // ud2
+    ]);
+    static ref EXEC_EXIT_ARGC: [u8; SIZE] =
+      test_elf::build_with_code(&exec_code(b"/bin/exit_argc"));
+    static ref EXEC_MISSING: [u8; SIZE] =
+      test_elf::build_with_code(&exec_code(b"/bin/not_there"));
+    static ref SEGFAULT: [u8; SIZE] = test_elf::build_with_code(&[
+      0xc6, 0x04, 0x25, 0, 0, 0, 0, 0, // mov byte [0], 0
+    ]);
+    static ref ILLEGAL: [u8; SIZE] = test_elf::build_with_code(&[
+      0x0f, 0x0b, // ud2
+    ]);
+    static ref DIVIDE_BY_ZERO: [u8; SIZE] = test_elf::build_with_code(&[
+      0x31, 0xc9, // xor ecx, ecx
+      0xf7, 0xf1, // div ecx
+    ]);
+    static ref BREAKPOINT: [u8; SIZE] = test_elf::build_with_code(&[
+      0xcc, // int3
+    ]);
+    static ref SPIN: [u8; SIZE] = test_elf::build_with_code(&[
+      0xeb, 0xfe, // jmp $
+    ]);
+    static ref SIGNAL_SELF: [u8; SIZE] = test_elf::build_with_code(&signal_self_code());
+    // Exits from a SIGSEGV handler with 100 + the signal
+    static ref CATCH_SEGFAULT: [u8; SIZE] = test_elf::build_with_code(&[
+      0xbf, SIGSEGV as u8, 0, 0, 0,       // mov edi, SIGSEGV
+      0x48, 0x8d, 0x35, 0x13, 0, 0, 0,    // lea rsi, [rip + handler]
+      0x31, 0xd2,                         // xor edx, edx
+      0xb8, SYS_SIGACTION as u8, 0, 0, 0, // mov eax, SYS_SIGACTION
+      0x0f, 0x05,                         // syscall
+      0xc6, 0x04, 0x25, 0, 0, 0, 0, 0,    // mov byte [0], 0
+      0x0f, 0x0b,                         // ud2
+      0x83, 0xc7, 100,                    // handler: add edi, 100
+      0xb8, SYS_EXIT as u8, 0, 0, 0,      // mov eax, SYS_EXIT
+      0x0f, 0x05,                         // syscall
+      0x0f, 0x0b,                         // ud2
+    ]);
+    // Like `echo hello | cat`, the child writes to its standard output
+    // which is a pipe to the parent. The parent exits with the number
+    // of bytes it read + the first one.
+    static ref PIPELINE: [u8; SIZE] = test_elf::build_with_code(&[
+      0x48, 0x83, 0xec, 0x10,                         // sub rsp, 16
+      0x48, 0x89, 0xe7,                               // mov rdi, rsp
+      0xb8, SYS_PIPE as u8, 0, 0, 0,                  // mov eax, SYS_PIPE
+      0x0f, 0x05,                                     // syscall
+      0xb8, SYS_FORK as u8, 0, 0, 0,                  // mov eax, SYS_FORK
+      0x0f, 0x05,                                     // syscall
+      0x48, 0x85, 0xc0,                               // test rax, rax
+      0x75, 0x3e,                                     // jnz parent
+      0x8b, 0x7c, 0x24, 0x04,                         // mov edi, [rsp+4]
+      0xbe, 1, 0, 0, 0,                               // mov esi, 1
+      0xb8, SYS_DUP2 as u8, 0, 0, 0,                  // mov eax, SYS_DUP2
+      0x0f, 0x05,                                     // syscall
+      0x48, 0xb8, b'h', b'e', b'l', b'l', b'o', 0, 0, 0, // mov rax, "hello"
+      0x48, 0x89, 0x44, 0x24, 0x08,                   // mov [rsp+8], rax
+      0xbf, 1, 0, 0, 0,                               // mov edi, 1
+      0x48, 0x8d, 0x74, 0x24, 0x08,                   // lea rsi, [rsp+8]
+      0xba, 5, 0, 0, 0,                               // mov edx, 5
+      0xb8, SYS_WRITE as u8, 0, 0, 0,                 // mov eax, SYS_WRITE
+      0x0f, 0x05,                                     // syscall
+      0x89, 0xc7,                                     // mov edi, eax
+      0xb8, SYS_EXIT as u8, 0, 0, 0,                  // mov eax, SYS_EXIT
+      0x0f, 0x05,                                     // syscall
+      0x8b, 0x7c, 0x24, 0x04,                         // parent: mov edi, [rsp+4]
+      0xb8, SYS_CLOSE as u8, 0, 0, 0,                 // mov eax, SYS_CLOSE
+      0x0f, 0x05,                                     // syscall
+      0x31, 0xdb,                                     // xor ebx, ebx
+      0x8b, 0x3c, 0x24,                               // read: mov edi, [rsp]
+      0x48, 0x8d, 0x74, 0x24, 0x08,                   // lea rsi, [rsp+8]
+      0xba, 8, 0, 0, 0,                               // mov edx, 8
+      0xb8, SYS_READ as u8, 0, 0, 0,                  // mov eax, SYS_READ
+      0x0f, 0x05,                                     // syscall
+      0x01, 0xc3,                                     // add ebx, eax
+      0x85, 0xc0,                                     // test eax, eax
+      0x7f, 0xe6,                                     // jg read
+      0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff,       // mov rdi, -1
+      0x31, 0xf6,                                     // xor esi, esi
+      0xb8, SYS_WAIT as u8, 0, 0, 0,                  // mov eax, SYS_WAIT
+      0x0f, 0x05,                                     // syscall
+      0x0f, 0xb6, 0x7c, 0x24, 0x08,                   // movzx edi, byte [rsp+8]
+      0x01, 0xdf,                                     // add edi, ebx
+      0xb8, SYS_EXIT as u8, 0, 0, 0,                  // mov eax, SYS_EXIT
+      0x0f, 0x05,                                     // syscall
+      0x0f, 0x0b,                                     // ud2
+    ]);
+    // Writes to a pipe after closing its read end
+    static ref BROKEN_PIPE: [u8; SIZE] = test_elf::build_with_code(&[
+      0x48, 0x83, 0xec, 0x10,         // sub rsp, 16
+      0x48, 0x89, 0xe7,               // mov rdi, rsp
+      0xb8, SYS_PIPE as u8, 0, 0, 0,  // mov eax, SYS_PIPE
+      0x0f, 0x05,                     // syscall
+      0x8b, 0x3c, 0x24,               // mov edi, [rsp]
+      0xb8, SYS_CLOSE as u8, 0, 0, 0, // mov eax, SYS_CLOSE
+      0x0f, 0x05,                     // syscall
+      0x8b, 0x7c, 0x24, 0x04,         // mov edi, [rsp+4]
+      0x48, 0x89, 0xe6,               // mov rsi, rsp
+      0xba, 1, 0, 0, 0,               // mov edx, 1
+      0xb8, SYS_WRITE as u8, 0, 0, 0, // mov eax, SYS_WRITE
+      0x0f, 0x05,                     // syscall
+      0x89, 0xc7,                     // mov edi, eax
+      0xb8, SYS_EXIT as u8, 0, 0, 0,  // mov eax, SYS_EXIT
+      0x0f, 0x05,                     // syscall
+      0x0f, 0x0b,                     // ud2
+    ]);
+    // Reads from a pipe nobody writes to, exits with what read returned
+    static ref READ_EMPTY_PIPE: [u8; SIZE] = test_elf::build_with_code(&[
+      0x48, 0x83, 0xec, 0x10,         // sub rsp, 16
+      0x48, 0x89, 0xe7,               // mov rdi, rsp
+      0xb8, SYS_PIPE as u8, 0, 0, 0,  // mov eax, SYS_PIPE
+      0x0f, 0x05,                     // syscall
+      0x8b, 0x3c, 0x24,               // mov edi, [rsp]
+      0x48, 0x89, 0xe6,               // mov rsi, rsp
+      0xba, 1, 0, 0, 0,               // mov edx, 1
+      0xb8, SYS_READ as u8, 0, 0, 0,  // mov eax, SYS_READ
+      0x0f, 0x05,                     // syscall
+      0x89, 0xc7,                     // mov edi, eax
+      0xb8, SYS_EXIT as u8, 0, 0, 0,  // mov eax, SYS_EXIT
+      0x0f, 0x05,                     // syscall
+      0x0f, 0x0b,                     // ud2
+    ]);
+    // Sleeps for 10s, exits with what nanosleep returned
+    static ref SLEEP_LONG: [u8; SIZE] = test_elf::build_with_code(&[
+      0x48, 0x83, 0xec, 0x10,                         // sub rsp, 16
+      0x48, 0xc7, 0x04, 0x24, 10, 0, 0, 0,            // mov qword [rsp], 10
+      0x48, 0xc7, 0x44, 0x24, 0x08, 0, 0, 0, 0,       // mov qword [rsp+8], 0
+      0x48, 0x89, 0xe7,                               // mov rdi, rsp
+      0x31, 0xf6,                                     // xor esi, esi
+      0xb8, SYS_NANOSLEEP as u8, 0, 0, 0,             // mov eax, SYS_NANOSLEEP
+      0x0f, 0x05,                                     // syscall
+      0x89, 0xc7,                                     // mov edi, eax
+      0xb8, SYS_EXIT as u8, 0, 0, 0,                  // mov eax, SYS_EXIT
+      0x0f, 0x05,                                     // syscall
+      0x0f, 0x0b,                                     // ud2
+    ]);
+    // Receives from a port only it could call, exits with what
+    // port_receive returned
+    static ref RECEIVE_EMPTY_PORT: [u8; SIZE] = test_elf::build_with_code(&[
+      0x48, 0x83, 0xec, 0x50,                 // sub rsp, 80
+      0xb8, SYS_PORT_CREATE as u8, 0, 0, 0,   // mov eax, SYS_PORT_CREATE
+      0x0f, 0x05,                             // syscall
+      0x48, 0x89, 0xc7,                       // mov rdi, rax
+      0x48, 0x89, 0xe6,                       // mov rsi, rsp
+      0x31, 0xd2,                             // xor edx, edx
+      0xb8, SYS_PORT_RECEIVE as u8, 0, 0, 0,  // mov eax, SYS_PORT_RECEIVE
+      0x0f, 0x05,                             // syscall
+      0x89, 0xc7,                             // mov edi, eax
+      0xb8, SYS_EXIT as u8, 0, 0, 0,          // mov eax, SYS_EXIT
+      0x0f, 0x05,                             // syscall
+      0x0f, 0x0b,                             // ud2
+    ]);
+    static ref IPC_PAGE: [u8; SIZE] = test_elf::build_with_code(&ipc_page_code());
+    // Sleeps for 5ms and exits with the milliseconds which passed on
+    // the monotonic clock
+    static ref SLEEP: [u8; SIZE] = test_elf::build_with_code(&[
+      0x48, 0x83, 0xec, 0x20,                         // sub rsp, 32
+      0xbf, 1, 0, 0, 0,                               // mov edi, CLOCK_MONOTONIC
+      0x48, 0x89, 0xe6,                               // mov rsi, rsp
+      0xb8, SYS_CLOCK_GETTIME as u8, 0, 0, 0,         // mov eax, SYS_CLOCK_GETTIME
+      0x0f, 0x05,                                     // syscall
+      0x48, 0xc7, 0x44, 0x24, 0x10, 0, 0, 0, 0,       // mov qword [rsp+16], 0
+      0x48, 0xc7, 0x44, 0x24, 0x18, 0x40, 0x4b, 0x4c, 0, // mov qword [rsp+24], 5000000
+      0x48, 0x8d, 0x7c, 0x24, 0x10,                   // lea rdi, [rsp+16]
+      0x31, 0xf6,                                     // xor esi, esi
+      0xb8, SYS_NANOSLEEP as u8, 0, 0, 0,             // mov eax, SYS_NANOSLEEP
+      0x0f, 0x05,                                     // syscall
+      0xbf, 1, 0, 0, 0,                               // mov edi, CLOCK_MONOTONIC
+      0x48, 0x8d, 0x74, 0x24, 0x10,                   // lea rsi, [rsp+16]
+      0xb8, SYS_CLOCK_GETTIME as u8, 0, 0, 0,         // mov eax, SYS_CLOCK_GETTIME
+      0x0f, 0x05,                                     // syscall
+      0x48, 0x8b, 0x44, 0x24, 0x10,                   // mov rax, [rsp+16]
+      0x48, 0x2b, 0x04, 0x24,                         // sub rax, [rsp]
+      0x48, 0x69, 0xc0, 0x00, 0xca, 0x9a, 0x3b,       // imul rax, rax, 1000000000
+      0x48, 0x03, 0x44, 0x24, 0x18,                   // add rax, [rsp+24]
+      0x48, 0x2b, 0x44, 0x24, 0x08,                   // sub rax, [rsp+8]
+      0x31, 0xd2,                                     // xor edx, edx
+      0xb9, 0x40, 0x42, 0x0f, 0,                      // mov ecx, 1000000
+      0x48, 0xf7, 0xf1,                               // div rcx
+      0x89, 0xc7,                                     // mov edi, eax
+      0xb8, SYS_EXIT as u8, 0, 0, 0,                  // mov eax, SYS_EXIT
+      0x0f, 0x05,                                     // syscall
+      0x0f, 0x0b,                                     // ud2
+    ]);
+  }
+
+  // Sends itself SIGUSR1, whose handler stores the signal number in the
+  // data segment and clobbers r12. Exits with the stored number plus r12
+  // as it was before the signal.
+  #[rustfmt::skip]
+  fn signal_self_code() -> [u8; 92] {
+    let data = test_elf::data_addr().to_le_bytes();
+    let mut code = [0; 92];
+    code[..49].copy_from_slice(&[
+      0xbf, SIGUSR1 as u8, 0, 0, 0,       // mov edi, SIGUSR1
+      0x48, 0x8d, 0x35, 0x3d, 0, 0, 0,    // lea rsi, [rip + handler]
+      0x31, 0xd2,                         // xor edx, edx
+      0xb8, SYS_SIGACTION as u8, 0, 0, 0, // mov eax, SYS_SIGACTION
+      0x0f, 0x05,                         // syscall
+      0xb8, SYS_GETPID as u8, 0, 0, 0,    // mov eax, SYS_GETPID
+      0x0f, 0x05,                         // syscall
+      0x48, 0x89, 0xc7,                   // mov rdi, rax
+      0xbe, SIGUSR1 as u8, 0, 0, 0,       // mov esi, SIGUSR1
+      0x41, 0xbc, 7, 0, 0, 0,             // mov r12d, 7
+      0xb8, SYS_KILL as u8, 0, 0, 0,      // mov eax, SYS_KILL
+      0x0f, 0x05,                         // syscall
+    ]);
+    code[49..51].copy_from_slice(&[0x48, 0xb8]); // mov rax, data
+    code[51..59].copy_from_slice(&data);
+    code[59..73].copy_from_slice(&[
+      0x8b, 0x38,                         // mov edi, [rax]
+      0x44, 0x01, 0xe7,                   // add edi, r12d
+      0xb8, SYS_EXIT as u8, 0, 0, 0,      // mov eax, SYS_EXIT
+      0x0f, 0x05,                         // syscall
+      0x0f, 0x0b,                         // ud2
+    ]);
+    code[73..75].copy_from_slice(&[0x48, 0xb8]); // handler: mov rax, data
+    code[75..83].copy_from_slice(&data);
+    code[83..92].copy_from_slice(&[
+      0x89, 0x38,                         // mov [rax], edi
+      0x41, 0xbc, 0xe8, 0x03, 0, 0,       // mov r12d, 1000
+      0xc3,                               // ret
+    ]);
+    code
+  }
+
+  // Where IPC_PAGE maps the page it receives
+  const MAP_AT: u64 = test_elf::DATA + 0x10_0000;
+
+  // Creates a port and forks. The child stores 33 in its data segment
+  // and sends the page along with a call, then exits with the first
+  // word of the reply. The parent replies with the stored number + 1,
+  // read from where it mapped the page, and exits with the child's code.
+  #[rustfmt::skip]
+  fn ipc_page_code() -> [u8; 179] {
+    let mut code = [0; 179];
+    code[..26].copy_from_slice(&[
+      0x48, 0x83, 0xec, 0x60,                   // sub rsp, 96
+      0xb8, SYS_PORT_CREATE as u8, 0, 0, 0,     // mov eax, SYS_PORT_CREATE
+      0x0f, 0x05,                               // syscall
+      0x48, 0x89, 0xc3,                         // mov rbx, rax
+      0xb8, SYS_FORK as u8, 0, 0, 0,            // mov eax, SYS_FORK
+      0x0f, 0x05,                               // syscall
+      0x48, 0x85, 0xc0,                         // test rax, rax
+      0x75, 0x38,                               // jnz parent
+    ]);
+    code[26..28].copy_from_slice(&[0x48, 0xb8]); // mov rax, data
+    code[28..36].copy_from_slice(&test_elf::data_addr().to_le_bytes());
+    code[36..44].copy_from_slice(&[
+      0xc7, 0x00, 33, 0, 0, 0,                  // mov dword [rax], 33
+      0x48, 0xb8,                               // mov rax, DATA
+    ]);
+    code[44..52].copy_from_slice(&test_elf::DATA.to_le_bytes());
+    code[52..88].copy_from_slice(&[
+      0x48, 0x89, 0x44, 0x24, 0x40,             // mov [rsp+64], rax
+      0x48, 0x89, 0xdf,                         // mov rdi, rbx
+      0x48, 0x89, 0xe6,                         // mov rsi, rsp
+      0x31, 0xd2,                               // xor edx, edx
+      0xb8, SYS_PORT_CALL as u8, 0, 0, 0,       // mov eax, SYS_PORT_CALL
+      0x0f, 0x05,                               // syscall
+      0x8b, 0x3c, 0x24,                         // mov edi, [rsp]
+      0xb8, SYS_EXIT as u8, 0, 0, 0,            // mov eax, SYS_EXIT
+      0x0f, 0x05,                               // syscall
+      0x48, 0x89, 0xdf,                         // parent: mov rdi, rbx
+      0x48, 0x89, 0xe6,                         // mov rsi, rsp
+    ]);
+    code[88..90].copy_from_slice(&[0x48, 0xba]); // mov rdx, MAP_AT
+    code[90..98].copy_from_slice(&MAP_AT.to_le_bytes());
+    code[98..].copy_from_slice(&[
+      0xb8, SYS_PORT_RECEIVE as u8, 0, 0, 0,    // mov eax, SYS_PORT_RECEIVE
+      0x0f, 0x05,                               // syscall
+      0x48, 0x89, 0xc7,                         // mov rdi, rax
+      0x48, 0x8b, 0x44, 0x24, 0x40,             // mov rax, [rsp+64]
+      0x8b, 0x80, 0x00, 0x02, 0, 0,             // mov eax, [rax+0x200]
+      0xff, 0xc0,                               // inc eax
+      0x48, 0x89, 0x04, 0x24,                   // mov [rsp], rax
+      0x48, 0xc7, 0x44, 0x24, 0x40, 0, 0, 0, 0, // mov qword [rsp+64], 0
+      0x48, 0x89, 0xe6,                         // mov rsi, rsp
+      0xb8, SYS_PORT_REPLY as u8, 0, 0, 0,      // mov eax, SYS_PORT_REPLY
+      0x0f, 0x05,                               // syscall
+      0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff, // mov rdi, -1
+      0x48, 0x8d, 0x74, 0x24, 0x50,             // lea rsi, [rsp+80]
+      0xb8, SYS_WAIT as u8, 0, 0, 0,            // mov eax, SYS_WAIT
+      0x0f, 0x05,                               // syscall
+      0x8b, 0x7c, 0x24, 0x50,                   // mov edi, [rsp+80]
+      0xc1, 0xef, 0x08,                         // shr edi, 8
+      0xb8, SYS_EXIT as u8, 0, 0, 0,            // mov eax, SYS_EXIT
+      0x0f, 0x05,                               // syscall
+      0x0f, 0x0b,                               // ud2
+    ]);
+    code
+  }
+
+  // Execs path with itself as both arguments, exits with 100 if that fails
+  #[rustfmt::skip]
+  fn exec_code(path: &[u8; 14]) -> [u8; 68] {
+    let mut code = [0; 68];
+    code[..54].copy_from_slice(&[
+      0x48, 0x8d, 0x3d, 0x2f, 0, 0, 0,  // lea rdi, [rip + path]
+      0x6a, 14,                         // push 14
+      0x57,                             // push rdi
+      0x6a, 14,                         // push 14
+      0x57,                             // push rdi
+      0xbe, 14, 0, 0, 0,                // mov esi, 14
+      0x48, 0x89, 0xe2,                 // mov rdx, rsp
+      0x41, 0xba, 2, 0, 0, 0,           // mov r10d, 2
+      0x45, 0x31, 0xc0,                 // xor r8d, r8d
+      0x45, 0x31, 0xc9,                 // xor r9d, r9d
+      0xb8, SYS_EXECVE as u8, 0, 0, 0,  // mov eax, SYS_EXECVE
+      0x0f, 0x05,                       // syscall
+      0xbf, 100, 0, 0, 0,               // mov edi, 100
+      0xb8, SYS_EXIT as u8, 0, 0, 0,    // mov eax, SYS_EXIT
+      0x0f, 0x05,                       // syscall
+      0x0f, 0x0b,                       // ud2
+    ]);
+    code[54..].copy_from_slice(path);
+    code
+  }
+
+  fn register_programs() {
+    assert!(programs::register("/bin/exit42", &*EXIT_42));
+    assert!(programs::register("/bin/exit_argc", &*EXIT_ARGC));
+    assert!(programs::register("/bin/garbage", b"\x7fELF garbage"));
+    assert!(programs::register("/bin/fork", &*FORK));
+    assert!(programs::register("/bin/exec_exit_argc", &*EXEC_EXIT_ARGC));
+    assert!(programs::register("/bin/exec_missing", &*EXEC_MISSING));
+    assert!(programs::register("/bin/segfault", &*SEGFAULT));
+    assert!(programs::register("/bin/illegal", &*ILLEGAL));
+    assert!(programs::register("/bin/divide_by_zero", &*DIVIDE_BY_ZERO));
+    assert!(programs::register("/bin/breakpoint", &*BREAKPOINT));
+    assert!(programs::register("/bin/spin", &*SPIN));
+    assert!(programs::register("/bin/signal_self", &*SIGNAL_SELF));
+    assert!(programs::register("/bin/catch_segfault", &*CATCH_SEGFAULT));
+    assert!(programs::register("/bin/pipeline", &*PIPELINE));
+    assert!(programs::register("/bin/broken_pipe", &*BROKEN_PIPE));
+    assert!(programs::register(
+      "/bin/read_empty_pipe",
+      &*READ_EMPTY_PIPE
+    ));
+    assert!(programs::register("/bin/sleep_long", &*SLEEP_LONG));
+    assert!(programs::register(
+      "/bin/receive_empty_port",
+      &*RECEIVE_EMPTY_PORT
+    ));
+    assert!(programs::register("/bin/ipc_page", &*IPC_PAGE));
+    assert!(programs::register("/bin/sleep", &*SLEEP));
+  }
+
+  #[test_case]
+  fn spawn_and_wait() {
+    register_programs();
+    let pid = spawn(b"/bin/exit42", &[b"exit42"]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Exited(42))));
+    // it was removed
+    assert_eq!(wait(Some(pid)), Err(Errno::ECHILD));
+    assert_eq!(count(), 0);
+  }
+
+  #[test_case]
+  fn arguments_reach_the_program() {
+    register_programs();
+    let args: [&[u8]; 3] = [b"exit_argc", b"a", b"b"];
+    let pid = spawn(b"/bin/exit_argc", &args).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Exited(3))));
+  }
+
+  #[test_case]
+  fn wait_for_any_child() {
+    register_programs();
+    let a = spawn(b"/bin/exit42", &[]).unwrap();
+    let b = spawn(b"/bin/exit_argc", &[b"one"]).unwrap();
+    assert_ne!(a, b);
+    let (first, _) = wait(None).unwrap();
+    let (second, _) = wait(None).unwrap();
+    assert!((first, second) == (a, b) || (first, second) == (b, a));
+    assert_eq!(wait(None), Err(Errno::ECHILD));
+  }
+
+  #[test_case]
+  fn spawn_errors() {
+    register_programs();
+    assert_eq!(spawn(b"/bin/missing", &[]), Err(Errno::ENOENT));
+    assert_eq!(spawn(b"/bin/garbage", &[]), Err(Errno::ENOEXEC));
+    assert_eq!(count(), 0);
+  }
+
+  #[test_case]
+  fn fork_returns_twice() {
+    register_programs();
+    let pid = spawn(b"/bin/fork", &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Exited(6 + 5))));
+    // the child was waited for by its parent
+    assert_eq!(count(), 0);
+  }
+
+  #[test_case]
+  fn exec_keeps_the_pid() {
+    register_programs();
+    let pid = spawn(b"/bin/exec_exit_argc", &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Exited(2))));
+    let pid = spawn(b"/bin/exec_missing", &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Exited(100))));
+  }
+
+  #[test_case]
+  fn fork_errors() {
+    let frame = SyscallFrame::default();
+    assert_eq!(fork(&frame), Err(Errno::EPERM));
+    assert_eq!(exec(b"/bin/exit42", &[], &[]), Errno::EPERM);
+  }
+
+  #[test_case]
+  fn faults_raise_signals() {
+    register_programs();
+    let faults = [
+      (&b"/bin/segfault"[..], SIGSEGV),
+      (b"/bin/illegal", SIGILL),
+      (b"/bin/divide_by_zero", SIGFPE),
+      (b"/bin/breakpoint", SIGTRAP),
+    ];
+    for &(path, signal) in faults.iter() {
+      let pid = spawn(path, &[]).unwrap();
+      assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Signaled(signal))));
+    }
+  }
+
+  #[test_case]
+  fn signal_handlers() {
+    register_programs();
+    // from a system call, the handler returns through sigreturn
+    let pid = spawn(b"/bin/signal_self", &[]).unwrap();
+    let status = ExitStatus::Exited(SIGUSR1 as i32 + 7);
+    assert_eq!(wait(Some(pid)), Ok((pid, status)));
+    // from an exception
+    let pid = spawn(b"/bin/catch_segfault", &[]).unwrap();
+    let status = ExitStatus::Exited(SIGSEGV as i32 + 100);
+    assert_eq!(wait(Some(pid)), Ok((pid, status)));
+  }
+
+  #[test_case]
+  fn kill_processes() {
+    register_programs();
+    let pid = spawn(b"/bin/spin", &[]).unwrap();
+    assert_eq!(kill(pid, 0), Ok(()));
+    assert_eq!(kill(pid, SIGKILL), Ok(()));
+    assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Signaled(SIGKILL))));
+    assert_eq!(kill(pid, SIGKILL), Err(Errno::ESRCH));
+    // like Ctrl-C on the console
+    let pid = spawn(b"/bin/spin", &[]).unwrap();
+    kill_all(SIGINT);
+    assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Signaled(SIGINT))));
+    assert_eq!(kill(Pid::KERNEL, 64), Err(Errno::EINVAL));
+  }
+
+  #[test_case]
+  fn pipes_between_processes() {
+    register_programs();
+    let pipes = pipe::count();
+    let pid = spawn(b"/bin/pipeline", &[]).unwrap();
+    let status = ExitStatus::Exited(5 + b'h' as i32);
+    assert_eq!(wait(Some(pid)), Ok((pid, status)));
+    let pid = spawn(b"/bin/broken_pipe", &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Signaled(SIGPIPE))));
+    assert_eq!(pipe::count(), pipes);
+  }
+
+  #[test_case]
+  fn signals_interrupt_blocking_calls() {
+    register_programs();
+    let (pipes, ports) = (pipe::count(), ipc::count());
+    let programs: [&[u8]; 3] = [
+      b"/bin/read_empty_pipe",
+      b"/bin/sleep_long",
+      b"/bin/receive_empty_port",
+    ];
+    for &program in programs.iter() {
+      for &signal in [SIGKILL, SIGINT].iter() {
+        let pid = spawn(program, &[]).unwrap();
+        // long enough for it to block
+        task::sleep(core::time::Duration::from_millis(20));
+        let start = crate::timer::uptime();
+        kill(pid, signal).unwrap();
+        assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Signaled(signal))));
+        assert!(crate::timer::uptime() - start < core::time::Duration::from_secs(1));
+      }
+    }
+    assert_eq!(pipe::count(), pipes);
+    assert_eq!(ipc::count(), ports);
+  }
+
+  #[test_case]
+  fn ports_between_processes() {
+    register_programs();
+    let ports = ipc::count();
+    let pid = spawn(b"/bin/ipc_page", &[]).unwrap();
+    assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Exited(34))));
+    assert_eq!(ipc::count(), ports);
+  }
+
+  #[test_case]
+  fn sleep_and_clock() {
+    register_programs();
+    let pid = spawn(b"/bin/sleep", &[]).unwrap();
+    let (_, status) = wait(Some(pid)).unwrap();
+    // at least 5ms, and not much longer since nothing else runs
+    assert!(matches!(status, ExitStatus::Exited(5..=20)), "{:?}", status);
+  }
+
+  #[test_case]
+  fn no_leaks() {
+    register_programs();
+    // the first runs map the stacks of the task slots
+    for _ in 0..4 {
+      let pid = spawn(b"/bin/exit42", &[]).unwrap();
+      wait(Some(pid)).unwrap();
+    }
+    let before = FrameAllocator::the().allocated();
+    for i in 0..100 {
+      let pid = spawn(b"/bin/exit_argc", &[b"exit_argc"]).unwrap();
+      assert_eq!(
+        wait(Some(pid)),
+        Ok((pid, ExitStatus::Exited(1))),
+        "run {}",
+        i
+      );
+    }
+    for _ in 0..20 {
+      for &path in [
+        &b"/bin/fork"[..],
+        b"/bin/exec_exit_argc",
+        b"/bin/signal_self",
+        b"/bin/pipeline",
+        b"/bin/ipc_page",
+      ]
+      .iter()
+      {
+        let pid = spawn(path, &[]).unwrap();
+        wait(Some(pid)).unwrap();
+      }
+    }
+    assert_eq!(FrameAllocator::the().allocated(), before);
+    assert_eq!(count(), 0);
+  }
+}
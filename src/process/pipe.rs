@@ -0,0 +1,332 @@
+#![allow(dead_code)]
+use super::file::{Access, File, FileRef, PollFlags};
+use super::signal;
+use crate::sync::{Condvar, Mutex};
+use crate::syscall::Errno;
+
+/*
+  Anonymous pipes, a bounded buffer with a read and a write end.
+  Readers sleep while the buffer is empty and writers while it is
+  full. Once all writers are gone reads return 0, the end of the file,
+  and once all readers are gone writes fail with EPIPE. Writes of up
+  to PIPE_SIZE bytes are atomic, they wait until there is room for all
+  of it so they are never interleaved with other writes. A signal
+  for the process interrupts them, with EINTR if nothing was
+  transferred yet.
+  The pipes are a fixed pool, a pipe is free again once both of its
+  ends are closed.
+  Reference: https://man7.org/linux/man-pages/man7/pipe.7.html
+*/
+
+pub const PIPE_SIZE: usize = 4096;
+pub const MAX_PIPES: usize = 16;
+
+struct Buffer {
+  bytes:   [u8; PIPE_SIZE],
+  head:    usize,
+  len:     usize,
+  readers: usize,
+  writers: usize,
+  in_use:  bool,
+}
+
+impl Buffer {
+  const fn new() -> Self {
+    Self {
+      bytes:   [0; PIPE_SIZE],
+      head:    0,
+      len:     0,
+      readers: 0,
+      writers: 0,
+      in_use:  false,
+    }
+  }
+
+  fn free(&self) -> usize {
+    PIPE_SIZE - self.len
+  }
+
+  // Returns how many bytes fit
+  fn push(&mut self, bytes: &[u8]) -> usize {
+    let count = bytes.len().min(self.free());
+    for (i, &b) in bytes[..count].iter().enumerate() {
+      self.bytes[(self.head + self.len + i) % PIPE_SIZE] = b;
+    }
+    self.len += count;
+    count
+  }
+
+  // Returns how many bytes there were
+  fn pop(&mut self, buf: &mut [u8]) -> usize {
+    let count = buf.len().min(self.len);
+    for (i, b) in buf[..count].iter_mut().enumerate() {
+      *b = self.bytes[(self.head + i) % PIPE_SIZE];
+    }
+    self.head = (self.head + count) % PIPE_SIZE;
+    self.len -= count;
+    count
+  }
+}
+
+pub struct Pipe {
+  buffer:  Mutex<Buffer>,
+  changed: Condvar,
+}
+
+static PIPES: [Pipe; MAX_PIPES] = {
+  #[allow(clippy::declare_interior_mutable_const)]
+  const FREE: Pipe = Pipe::new();
+  [FREE; MAX_PIPES]
+};
+
+// Creates a pipe, returns its read and write end
+pub fn pipe() -> Result<(FileRef, FileRef), Errno> {
+  let pipe = PIPES
+    .iter()
+    .find(|pipe| pipe.claim())
+    .ok_or(Errno::ENFILE)?;
+  Ok((
+    FileRef::new(pipe, Access::READ),
+    FileRef::new(pipe, Access::WRITE),
+  ))
+}
+
+// The number of pipes with open ends
+pub fn count() -> usize {
+  PIPES
+    .iter()
+    .filter(|pipe| pipe.buffer.lock().in_use)
+    .count()
+}
+
+impl Pipe {
+  const fn new() -> Self {
+    Self {
+      buffer:  Mutex::new(Buffer::new()),
+      changed: Condvar::new(),
+    }
+  }
+
+  // A pipe with a reader and a writer which are never closed, for
+  // kernel code which uses it directly instead of through FileRefs
+  pub const fn connected() -> Self {
+    let buffer = Buffer {
+      readers: 1,
+      writers: 1,
+      in_use: true,
+      ..Buffer::new()
+    };
+    Self {
+      buffer:  Mutex::new(buffer),
+      changed: Condvar::new(),
+    }
+  }
+
+  // Takes the pipe if it is free
+  fn claim(&self) -> bool {
+    let mut buffer = self.buffer.lock();
+    if buffer.in_use {
+      return false;
+    }
+    *buffer = Buffer::new();
+    buffer.in_use = true;
+    true
+  }
+
+  // Writes what fits without blocking, returns how much that was
+  pub fn try_write(&self, buf: &[u8]) -> usize {
+    let count = self.buffer.lock().push(buf);
+    if count > 0 {
+      self.changed.notify_all();
+    }
+    count
+  }
+}
+
+impl File for Pipe {
+  fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    let mut buffer = self.buffer.lock();
+    while buffer.len == 0 && buffer.writers > 0 {
+      buffer = self
+        .changed
+        .wait_interruptible(buffer, signal::interrupted)
+        .ok_or(Errno::EINTR)?;
+    }
+    let count = buffer.pop(buf);
+    if count > 0 {
+      self.changed.notify_all();
+    }
+    Ok(count)
+  }
+
+  fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+    let mut written = 0;
+    let mut buffer = self.buffer.lock();
+    while written < buf.len() {
+      let wanted = if buf.len() <= PIPE_SIZE { buf.len() } else { 1 };
+      while buffer.readers > 0 && buffer.free() < wanted {
+        buffer = match self.changed.wait_interruptible(buffer, signal::interrupted) {
+          Some(buffer) => buffer,
+          None if written > 0 => return Ok(written),
+          None => return Err(Errno::EINTR),
+        };
+      }
+      if buffer.readers == 0 {
+        return if written > 0 {
+          Ok(written)
+        } else {
+          Err(Errno::EPIPE)
+        };
+      }
+      written += buffer.push(&buf[written..]);
+      self.changed.notify_all();
+    }
+    Ok(written)
+  }
+
+  fn poll(&self) -> PollFlags {
+    let buffer = self.buffer.lock();
+    let mut flags = PollFlags::empty();
+    if buffer.len > 0 || buffer.writers == 0 {
+      flags |= PollFlags::IN;
+    }
+    if buffer.writers == 0 {
+      flags |= PollFlags::HUP;
+    }
+    if buffer.free() > 0 || buffer.readers == 0 {
+      flags |= PollFlags::OUT;
+    }
+    if buffer.readers == 0 {
+      flags |= PollFlags::ERR;
+    }
+    flags
+  }
+
+  fn open(&self, access: Access) {
+    let mut buffer = self.buffer.lock();
+    if access.contains(Access::READ) {
+      buffer.readers += 1;
+    }
+    if access.contains(Access::WRITE) {
+      buffer.writers += 1;
+    }
+  }
+
+  // The last reader or writer wakes those waiting on the other end
+  fn close(&self, access: Access) {
+    let mut buffer = self.buffer.lock();
+    if access.contains(Access::READ) {
+      buffer.readers -= 1;
+    }
+    if access.contains(Access::WRITE) {
+      buffer.writers -= 1;
+    }
+    if buffer.readers == 0 || buffer.writers == 0 {
+      self.changed.notify_all();
+    }
+    if buffer.readers == 0 && buffer.writers == 0 {
+      buffer.in_use = false;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::task;
+
+  #[test_case]
+  fn buffer_wraps_around() {
+    let mut buffer = Buffer::new();
+    let mut out = [0; PIPE_SIZE];
+    for round in 0..3 {
+      let bytes = [round as u8 + 1; PIPE_SIZE / 3 * 2];
+      assert_eq!(buffer.push(&bytes), bytes.len());
+      assert_eq!(buffer.pop(&mut out[..bytes.len() - 1]), bytes.len() - 1);
+      assert_eq!(buffer.pop(&mut out), 1);
+      assert_eq!(out[0], round as u8 + 1);
+    }
+    assert_eq!(buffer.push(&[7; PIPE_SIZE + 1]), PIPE_SIZE);
+    assert_eq!(buffer.push(&[7]), 0);
+    assert_eq!(buffer.pop(&mut [0; 0]), 0);
+  }
+
+  #[test_case]
+  fn end_of_file_and_broken_pipe() {
+    let before = count();
+    let (reader, writer) = pipe().unwrap();
+    assert_eq!(count(), before + 1);
+    assert_eq!(writer.poll(), PollFlags::OUT);
+    assert_eq!(writer.write(b"hello"), Ok(5));
+    assert_eq!(reader.poll(), PollFlags::IN);
+    assert_eq!(reader.write(b"x"), Err(Errno::EBADF));
+    let mut buf = [0; 8];
+    assert_eq!(reader.read(&mut buf[..2]), Ok(2));
+    drop(writer);
+    assert_eq!(reader.poll(), PollFlags::IN | PollFlags::HUP);
+    assert_eq!(reader.read(&mut buf), Ok(3));
+    assert_eq!(&buf[..3], b"llo");
+    assert_eq!(reader.read(&mut buf), Ok(0));
+    drop(reader);
+    assert_eq!(count(), before);
+
+    let (reader, writer) = pipe().unwrap();
+    drop(reader);
+    assert_eq!(writer.poll(), PollFlags::OUT | PollFlags::ERR);
+    assert_eq!(writer.write(b"hello"), Err(Errno::EPIPE));
+  }
+
+  #[test_case]
+  fn pool_is_bounded() {
+    let before = count();
+    const NONE: Option<(FileRef, FileRef)> = None;
+    let mut pipes = [NONE; MAX_PIPES];
+    for pipe in pipes.iter_mut().skip(before) {
+      *pipe = Some(super::pipe().unwrap());
+    }
+    assert!(matches!(super::pipe(), Err(Errno::ENFILE)));
+    pipes[MAX_PIPES - 1] = None;
+    assert!(super::pipe().is_ok());
+  }
+
+  static WRITER: Mutex<Option<FileRef>> = Mutex::new(None);
+
+  #[test_case]
+  fn readers_and_writers_block() {
+    const TOTAL: usize = PIPE_SIZE * 3 + 5;
+    let (reader, writer) = pipe().unwrap();
+    *WRITER.lock() = Some(writer);
+    // fills the pipe more than three times, then closes its end
+    let task = task::spawn(|| {
+      let writer = WRITER.lock().take().unwrap();
+      let mut bytes = [0; 1000];
+      for start in (0..TOTAL).step_by(bytes.len()) {
+        let count = bytes.len().min(TOTAL - start);
+        for (i, b) in bytes[..count].iter_mut().enumerate() {
+          *b = (start + i) as u8;
+        }
+        assert_eq!(writer.write(&bytes[..count]), Ok(count));
+      }
+    })
+    .unwrap();
+    let mut buf = [0; 700];
+    let mut received = 0;
+    loop {
+      match reader.read(&mut buf) {
+        Ok(0) => break,
+        Ok(count) => {
+          for (i, &b) in buf[..count].iter().enumerate() {
+            assert_eq!(b, (received + i) as u8);
+          }
+          received += count;
+        }
+        Err(err) => panic!("read failed: {:?}", err),
+      }
+    }
+    assert_eq!(received, TOTAL);
+    task.join();
+  }
+}
@@ -0,0 +1,50 @@
+#![allow(dead_code)]
+use crate::sync::IrqSafeMutex;
+
+// Executables the kernel knows by path, until there is a file system
+// to load them from. The files are kept in kernel memory for good.
+const MAX_PROGRAMS: usize = 32;
+
+type Program = (&'static str, &'static [u8]);
+
+static PROGRAMS: IrqSafeMutex<[Option<Program>; MAX_PROGRAMS]> =
+  IrqSafeMutex::new([None; MAX_PROGRAMS]);
+
+// Adds an executable, replacing any other one at path. False if
+// there is no room left.
+pub fn register(path: &'static str, elf: &'static [u8]) -> bool {
+  let mut programs = PROGRAMS.lock();
+  let existing = programs
+    .iter()
+    .position(|program| matches!(program, Some((p, _)) if *p == path));
+  match existing.or_else(|| programs.iter().position(Option::is_none)) {
+    Some(i) => {
+      programs[i] = Some((path, elf));
+      true
+    }
+    None => false,
+  }
+}
+
+pub fn find(path: &[u8]) -> Option<&'static [u8]> {
+  PROGRAMS
+    .lock()
+    .iter()
+    .flatten()
+    .find(|(p, _)| p.as_bytes() == path)
+    .map(|&(_, elf)| elf)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn register_and_find() {
+    assert!(register("/test/programs", b"first"));
+    assert_eq!(find(b"/test/programs"), Some(&b"first"[..]));
+    assert!(register("/test/programs", b"second"));
+    assert_eq!(find(b"/test/programs"), Some(&b"second"[..]));
+    assert_eq!(find(b"/test/program"), None);
+  }
+}
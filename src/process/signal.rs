@@ -0,0 +1,517 @@
+use super::MAX_PROCESSES;
+use crate::cpu::fpu::{self, FpuState};
+use crate::cpu::regs::RFlags;
+use crate::elf;
+use crate::interrupts::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::interrupts::trap::{self, TrapFrame};
+use crate::interrupts::{self, InterruptStackFrame};
+use crate::mem::address_space::{self, AddressSpace};
+use crate::mem::page_table::PageFlags;
+use crate::mem::VirtAddr;
+use crate::sync::IrqSafeMutex;
+use crate::syscall::{self, Errno, SyscallFrame, SYS_SIGRETURN};
+use crate::task::{self, TaskId};
+use core::mem::size_of;
+use core::ptr;
+
+/*
+  POSIX style signals. Every process has a set of pending signals, a
+  mask of blocked ones and an action for each signal: the default,
+  ignoring it or running a handler. A pending signal which is not
+  blocked is delivered when the thread next returns to user mode, from
+  a system call or an interrupt. Sending a signal wakes the thread, so
+  a blocking system call it is in fails with EINTR and the signal is
+  delivered on the way out. Calls are not restarted after a handler.
+  There is no job control, so no stopping and continuing.
+
+  A handler runs on the user stack, below the red zone, on top of a
+  signal frame with the interrupted registers, FPU state and signal
+  mask. It gets the signal number in rdi and the address of the frame
+  in rdx, and returns to a trampoline mapped into every process which
+  makes the sigreturn system call. sigreturn restores the frame, after
+  checking that it does not lead anywhere but user mode. The signal and
+  the handler's mask are blocked until then.
+
+  Faults in user mode raise SIGSEGV, SIGILL or SIGFPE. Those cannot be
+  ignored or blocked, the process is killed instead.
+  References:
+  https://man7.org/linux/man-pages/man7/signal.7.html
+  https://man7.org/linux/man-pages/man2/sigreturn.2.html
+*/
+
+// Signal numbers, the same as on Linux. Valid signals are 1 to NSIG - 1.
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGURG: u32 = 23;
+pub const SIGWINCH: u32 = 28;
+pub const NSIG: u32 = 32;
+
+// Handler values of sigaction besides function addresses
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+// How sigprocmask changes the mask
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+const ALL_SIGNALS: u64 = (1 << (NSIG - 1)) - 1;
+const UNBLOCKABLE: u64 = 1 << (SIGKILL - 1);
+
+// Below the stack, with a guard page in between
+pub const TRAMPOLINE: u64 = elf::STACK_TOP - elf::STACK_SIZE - 0x2000;
+const TRAMPOLINE_CODE: [u8; 9] = [
+  0xb8,
+  SYS_SIGRETURN as u8,
+  0,
+  0,
+  0, // mov eax, SYS_SIGRETURN
+  0x0f,
+  0x05, // syscall
+  0x0f,
+  0x0b, // ud2
+];
+
+// Handlers must not touch the 128 bytes below the interrupted stack
+// pointer, leaf functions may keep data there
+const RED_ZONE: u64 = 128;
+
+// The flags a signal frame may change, the others are the kernel's
+const USER_FLAGS: u64 = RFlags::CARRY_FLAG.bits()
+  | RFlags::PARITY_FLAG.bits()
+  | RFlags::AUXILIARY_CARRY_FLAG.bits()
+  | RFlags::ZERO_FLAG.bits()
+  | RFlags::SIGN_FLAG.bits()
+  | RFlags::TRAP_FLAG.bits()
+  | RFlags::DIRECTION_FLAG.bits()
+  | RFlags::OVERFLOW_FLAG.bits()
+  | RFlags::ALIGNMENT_CHECK.bits();
+
+pub fn is_valid(signal: u32) -> bool {
+  signal > 0 && signal < NSIG
+}
+
+fn bit(signal: u32) -> u64 {
+  1 << (signal - 1)
+}
+
+// The default action is to end the process, except for these
+fn ignored_by_default(signal: u32) -> bool {
+  matches!(signal, SIGCHLD | SIGCONT | SIGURG | SIGWINCH)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+  Default,
+  Ignore,
+  // a function in user mode, run with mask blocked
+  Handler { addr: u64, mask: u64 },
+}
+
+#[derive(Clone)]
+pub struct SignalState {
+  pending: u64,
+  blocked: u64,
+  actions: [Action; NSIG as usize], // indexed by signal, 0 is unused
+}
+
+impl SignalState {
+  pub const fn new() -> Self {
+    Self {
+      pending: 0,
+      blocked: 0,
+      actions: [Action::Default; NSIG as usize],
+    }
+  }
+
+  // The state of a forked child, which has no pending signals
+  pub fn fork(&self) -> Self {
+    Self {
+      pending: 0,
+      ..self.clone()
+    }
+  }
+
+  // Handlers are gone with the old program, the rest is kept
+  pub fn exec(&mut self) {
+    for action in self.actions.iter_mut() {
+      if let Action::Handler { .. } = action {
+        *action = Action::Default;
+      }
+    }
+  }
+
+  pub fn pending(&self) -> u64 {
+    self.pending
+  }
+
+  pub fn blocked(&self) -> u64 {
+    self.blocked
+  }
+
+  // True if a pending signal is not blocked
+  fn deliverable(&self) -> bool {
+    self.pending & !self.blocked != 0
+  }
+
+  fn ignores(&self, signal: u32) -> bool {
+    match self.actions[signal as usize] {
+      Action::Ignore => true,
+      Action::Default => ignored_by_default(signal),
+      Action::Handler { .. } => false,
+    }
+  }
+
+  // Makes signal pending, unless it would be ignored anyway
+  pub fn raise(&mut self, signal: u32) {
+    if !self.ignores(signal) {
+      self.pending |= bit(signal);
+    }
+  }
+
+  // Raises a signal which has to be handled, since the thread cannot
+  // continue otherwise. If it is blocked or ignored it gets the default
+  // action, which ends the process.
+  pub fn force(&mut self, signal: u32) {
+    if self.blocked & bit(signal) != 0 || self.actions[signal as usize] == Action::Ignore {
+      self.blocked &= !bit(signal);
+      self.actions[signal as usize] = Action::Default;
+    }
+    self.pending |= bit(signal);
+  }
+
+  // Takes the lowest pending signal which is not blocked. Returns it
+  // with its action and the mask to restore after the handler, which
+  // is blocked from here on.
+  pub fn dequeue(&mut self) -> Option<(u32, Action, u64)> {
+    let deliverable = self.pending & !self.blocked;
+    if deliverable == 0 {
+      return None;
+    }
+    let signal = deliverable.trailing_zeros() + 1;
+    self.pending &= !bit(signal);
+    let (action, blocked) = (self.actions[signal as usize], self.blocked);
+    if let Action::Handler { mask, .. } = action {
+      self.blocked |= (mask | bit(signal)) & ALL_SIGNALS & !UNBLOCKABLE;
+    }
+    Some((signal, action, blocked))
+  }
+
+  // Returns the previous action. SIGKILL cannot be changed.
+  pub fn set_action(&mut self, signal: u32, action: Action) -> Result<Action, Errno> {
+    if !is_valid(signal) || signal == SIGKILL {
+      return Err(Errno::EINVAL);
+    }
+    let old = core::mem::replace(&mut self.actions[signal as usize], action);
+    if self.ignores(signal) {
+      self.pending &= !bit(signal);
+    }
+    Ok(old)
+  }
+
+  // Changes the mask like sigprocmask, returns the previous one
+  pub fn set_blocked(&mut self, how: u64, set: u64) -> Result<u64, Errno> {
+    let old = self.blocked;
+    let blocked = match how {
+      SIG_BLOCK => old | set,
+      SIG_UNBLOCK => old & !set,
+      SIG_SETMASK => set,
+      _ => return Err(Errno::EINVAL),
+    };
+    self.blocked = blocked & ALL_SIGNALS & !UNBLOCKABLE;
+    Ok(old)
+  }
+}
+
+// The signal state of each process, at the same slot as in the process
+// table, along with its thread. Kept apart from the process table since
+// interrupt handlers need it.
+static SIGNALS: IrqSafeMutex<[Option<(TaskId, SignalState)>; MAX_PROCESSES]> = {
+  const NONE: Option<(TaskId, SignalState)> = None;
+  IrqSafeMutex::new([NONE; MAX_PROCESSES])
+};
+
+pub(super) fn attach(slot: usize, thread: TaskId, state: SignalState) {
+  SIGNALS.lock()[slot] = Some((thread, state));
+}
+
+pub(super) fn detach(slot: usize) {
+  SIGNALS.lock()[slot] = None;
+}
+
+// Makes signal pending for the process in slot, if it has not exited,
+// and wakes its thread if the signal can be delivered
+pub(super) fn send(slot: usize, signal: u32) {
+  if let Some((thread, state)) = SIGNALS.lock()[slot].as_mut() {
+    state.raise(signal);
+    if state.deliverable() {
+      task::wake(*thread);
+    }
+  }
+}
+
+pub(super) fn with_slot<R>(slot: usize, f: impl FnOnce(&mut SignalState) -> R) -> Option<R> {
+  SIGNALS.lock()[slot].as_mut().map(|(_, state)| f(state))
+}
+
+// Runs f with the signal state of the current process, None if the
+// current task is no user thread
+pub fn with_current<R>(f: impl FnOnce(&mut SignalState) -> R) -> Option<R> {
+  let me = task::current()?;
+  let mut signals = SIGNALS.lock();
+  let entry = signals
+    .iter_mut()
+    .flatten()
+    .find(|(thread, _)| *thread == me);
+  entry.map(|(_, state)| f(state))
+}
+
+// True if the current thread has a signal to deliver, which blocking
+// system calls check to fail with EINTR. Always false for kernel tasks.
+pub fn interrupted() -> bool {
+  with_current(|state| state.deliverable()).unwrap_or(false)
+}
+
+// For faults in user mode, returns false if the current task is no
+// user thread
+pub fn force(signal: u32) -> bool {
+  with_current(|state| state.force(signal)).is_some()
+}
+
+// Maps the trampoline handlers return to, the page has to be free
+pub fn map_trampoline(space: &mut AddressSpace) -> Result<(), Errno> {
+  let page = VirtAddr::new(TRAMPOLINE);
+  if space.translate(page).is_some() {
+    return Err(Errno::ENOEXEC);
+  }
+  space.map(page, PageFlags::empty())?;
+  space.write(TRAMPOLINE, &TRAMPOLINE_CODE);
+  Ok(())
+}
+
+#[repr(C)]
+struct SignalFrame {
+  signal:  u64,
+  blocked: u64, // restored by sigreturn
+  context: TrapFrame,
+  fpu:     FpuState,
+}
+
+// Delivers the next signal to the current thread, which is about to
+// return to user mode with the registers in frame. Either changes the
+// frame to run the handler or ends the process. Interrupts are left
+// disabled if there was a handler to run.
+pub fn deliver(frame: &mut TrapFrame) {
+  let (signal, action, blocked) = match with_current(SignalState::dequeue) {
+    Some(Some(next)) => next,
+    _ => return,
+  };
+  match action {
+    Action::Ignore => {}
+    Action::Default if ignored_by_default(signal) => {}
+    Action::Default => super::exit_by_signal(signal),
+    Action::Handler { addr, .. } => {
+      if !push_frame(frame, signal, addr, blocked) {
+        super::exit_by_signal(SIGSEGV);
+      }
+    }
+  }
+}
+
+// Like deliver, for threads returning from a system call
+pub fn deliver_syscall(frame: &mut SyscallFrame) {
+  // sysret leaves the return address in rcx and the flags in r11
+  let mut trap_frame = TrapFrame {
+    r15: frame.r15,
+    r14: frame.r14,
+    r13: frame.r13,
+    r12: frame.r12,
+    r11: frame.rflags,
+    r10: frame.r10,
+    r9: frame.r9,
+    r8: frame.r8,
+    rbp: frame.rbp,
+    rdi: frame.rdi,
+    rsi: frame.rsi,
+    rdx: frame.rdx,
+    rcx: frame.rip,
+    rbx: frame.rbx,
+    rax: frame.rax,
+    vector: 0,
+    error_code: 0,
+    frame: InterruptStackFrame {
+      instruction_ptr: frame.rip,
+      code_segment: USER_CODE_SELECTOR as u64,
+      cpu_flags: frame.rflags,
+      stack_ptr: frame.rsp,
+      stack_segment: USER_DATA_SELECTOR as u64,
+    },
+  };
+  deliver(&mut trap_frame);
+  // a handler is called with the argument registers set
+  frame.rdi = trap_frame.rdi;
+  frame.rsi = trap_frame.rsi;
+  frame.rdx = trap_frame.rdx;
+  frame.rip = trap_frame.frame.instruction_ptr;
+  frame.rflags = trap_frame.frame.cpu_flags;
+  frame.rsp = trap_frame.frame.stack_ptr;
+}
+
+// Puts a signal frame and the return address of the trampoline on the
+// user stack and points frame to the handler. False if the stack is
+// not writable.
+fn push_frame(frame: &mut TrapFrame, signal: u32, handler: u64, blocked: u64) -> bool {
+  let size = size_of::<SignalFrame>() as u64;
+  let addr = match frame.frame.stack_ptr.checked_sub(RED_ZONE + size + 8) {
+    Some(bottom) => (bottom + 8) & !63,
+    None => return false,
+  };
+  let stack_pointer = addr - 8;
+  let stack = match syscall::user_slice_mut(stack_pointer, 8 + size) {
+    Ok(stack) => stack,
+    Err(_) => return false,
+  };
+  let mut signal_frame = SignalFrame {
+    signal: signal as u64,
+    blocked,
+    context: frame.clone(),
+    fpu: FpuState::new(),
+  };
+  interrupts::disable();
+  unsafe { fpu::copy_current(&mut signal_frame.fpu) };
+  let bytes =
+    unsafe { core::slice::from_raw_parts(&signal_frame as *const _ as *const u8, size as usize) };
+  stack[..8].copy_from_slice(&TRAMPOLINE.to_le_bytes());
+  stack[8..].copy_from_slice(bytes);
+  // the stack is 8 off a multiple of 16 like after a call
+  frame.frame.instruction_ptr = handler;
+  frame.frame.stack_ptr = stack_pointer;
+  frame.frame.cpu_flags &= !(RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG).bits();
+  frame.rdi = signal as u64;
+  frame.rsi = 0;
+  frame.rdx = addr;
+  true
+}
+
+// Returns from a handler to where the signal interrupted the thread,
+// with the signal frame at addr
+pub fn sigreturn(addr: u64) -> ! {
+  let bytes = match syscall::user_slice(addr, size_of::<SignalFrame>() as u64) {
+    Ok(bytes) => bytes,
+    Err(_) => super::exit_by_signal(SIGSEGV),
+  };
+  let mut signal_frame = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const SignalFrame) };
+  let context = &mut signal_frame.context;
+  if !address_space::is_user_addr(context.frame.instruction_ptr) {
+    super::exit_by_signal(SIGSEGV);
+  }
+  context.frame.code_segment = USER_CODE_SELECTOR as u64;
+  context.frame.stack_segment = USER_DATA_SELECTOR as u64;
+  let kernel_flags = RFlags::INTERRUPT_FLAG | RFlags::RESERVED;
+  context.frame.cpu_flags = (context.frame.cpu_flags & USER_FLAGS) | kernel_flags.bits();
+  signal_frame.fpu.sanitize();
+  let blocked = signal_frame.blocked & ALL_SIGNALS & !UNBLOCKABLE;
+  with_current(|state| state.blocked = blocked);
+  interrupts::disable();
+  unsafe { fpu::load_current(&signal_frame.fpu) };
+  // signals unblocked by the restored mask are delivered right away
+  deliver(&mut signal_frame.context);
+  unsafe { trap::resume(&signal_frame.context) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const HANDLER: Action = Action::Handler {
+    addr: 0x1000,
+    mask: 1 << (SIGUSR2 - 1),
+  };
+
+  #[test_case]
+  fn delivery_order_and_masks() {
+    let mut state = SignalState::new();
+    state.raise(SIGTERM);
+    state.raise(SIGINT);
+    state.raise(SIGCHLD); // ignored by default
+    assert_eq!(state.pending(), bit(SIGTERM) | bit(SIGINT));
+    assert_eq!(state.set_blocked(SIG_BLOCK, bit(SIGINT)), Ok(0));
+    assert_eq!(
+      state.dequeue(),
+      Some((SIGTERM, Action::Default, bit(SIGINT)))
+    );
+    assert_eq!(state.dequeue(), None);
+    state.set_blocked(SIG_UNBLOCK, bit(SIGINT)).unwrap();
+    assert_eq!(state.dequeue(), Some((SIGINT, Action::Default, 0)));
+    // SIGKILL can be neither blocked nor caught
+    state.set_blocked(SIG_SETMASK, u64::MAX).unwrap();
+    assert_eq!(state.blocked(), ALL_SIGNALS & !bit(SIGKILL));
+    assert_eq!(
+      state.set_action(SIGKILL, Action::Ignore),
+      Err(Errno::EINVAL)
+    );
+    assert_eq!(state.set_blocked(3, 0), Err(Errno::EINVAL));
+    state.raise(SIGKILL);
+    assert_eq!(
+      state.dequeue(),
+      Some((SIGKILL, Action::Default, state.blocked()))
+    );
+  }
+
+  #[test_case]
+  fn handlers_block_signals() {
+    let mut state = SignalState::new();
+    assert_eq!(state.set_action(SIGUSR1, HANDLER), Ok(Action::Default));
+    state.raise(SIGUSR1);
+    assert_eq!(state.dequeue(), Some((SIGUSR1, HANDLER, 0)));
+    assert_eq!(state.blocked(), bit(SIGUSR1) | bit(SIGUSR2));
+    // exec resets handlers but keeps the mask, fork clears pending
+    state.raise(SIGUSR1);
+    state.exec();
+    assert_eq!(
+      state.set_action(SIGUSR1, Action::Ignore),
+      Ok(Action::Default)
+    );
+    assert_eq!(state.pending(), 0);
+    state.raise(SIGUSR2);
+    assert_eq!(state.fork().pending(), 0);
+    assert_eq!(state.fork().blocked(), state.blocked());
+    assert_eq!(state.set_action(0, Action::Ignore), Err(Errno::EINVAL));
+    assert_eq!(state.set_action(NSIG, Action::Ignore), Err(Errno::EINVAL));
+  }
+
+  #[test_case]
+  fn forced_signals() {
+    let mut state = SignalState::new();
+    state.set_action(SIGSEGV, Action::Ignore).unwrap();
+    state.set_blocked(SIG_BLOCK, bit(SIGFPE)).unwrap();
+    state.force(SIGSEGV);
+    state.force(SIGFPE);
+    assert_eq!(state.blocked(), 0);
+    assert_eq!(state.dequeue(), Some((SIGFPE, Action::Default, 0)));
+    assert_eq!(state.dequeue(), Some((SIGSEGV, Action::Default, 0)));
+  }
+
+  #[test_case]
+  fn frame_layout() {
+    // the fpu state is aligned by the frame's alignment
+    assert_eq!(size_of::<SignalFrame>() % 64, 0);
+    assert_eq!(TRAMPOLINE % 0x1000, 0);
+    assert!(address_space::is_user_addr(TRAMPOLINE));
+  }
+}
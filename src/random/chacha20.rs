@@ -0,0 +1,82 @@
+// The ChaCha20 block function, as specified in RFC 8439. Each block
+// is 64 bytes of keystream for the given key, counter and nonce.
+// Reference: https://datatracker.ietf.org/doc/html/rfc8439#section-2.3
+
+pub const KEY_SIZE: usize = 32;
+pub const BLOCK_SIZE: usize = 64;
+
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+// Named like in the RFC
+#[allow(clippy::many_single_char_names)]
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+  s[a] = s[a].wrapping_add(s[b]);
+  s[d] = (s[d] ^ s[a]).rotate_left(16);
+  s[c] = s[c].wrapping_add(s[d]);
+  s[b] = (s[b] ^ s[c]).rotate_left(12);
+  s[a] = s[a].wrapping_add(s[b]);
+  s[d] = (s[d] ^ s[a]).rotate_left(8);
+  s[c] = s[c].wrapping_add(s[d]);
+  s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+pub fn block(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; 12]) -> [u8; BLOCK_SIZE] {
+  let word = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+  let mut state = [0; 16];
+  state[..4].copy_from_slice(&CONSTANTS);
+  for (i, chunk) in key.chunks(4).enumerate() {
+    state[4 + i] = word(chunk);
+  }
+  state[12] = counter;
+  for (i, chunk) in nonce.chunks(4).enumerate() {
+    state[13 + i] = word(chunk);
+  }
+
+  let mut working = state;
+  for _ in 0..10 {
+    // column rounds
+    quarter_round(&mut working, 0, 4, 8, 12);
+    quarter_round(&mut working, 1, 5, 9, 13);
+    quarter_round(&mut working, 2, 6, 10, 14);
+    quarter_round(&mut working, 3, 7, 11, 15);
+    // diagonal rounds
+    quarter_round(&mut working, 0, 5, 10, 15);
+    quarter_round(&mut working, 1, 6, 11, 12);
+    quarter_round(&mut working, 2, 7, 8, 13);
+    quarter_round(&mut working, 3, 4, 9, 14);
+  }
+
+  let mut out = [0; BLOCK_SIZE];
+  for (i, chunk) in out.chunks_mut(4).enumerate() {
+    chunk.copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn rfc8439_block() {
+    // test vector from section 2.3.2
+    let mut key = [0; KEY_SIZE];
+    for (i, b) in key.iter_mut().enumerate() {
+      *b = i as u8;
+    }
+    let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+    let out = block(&key, 1, &nonce);
+    #[rustfmt::skip]
+    assert_eq!(out[..16], [
+      0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15,
+      0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20, 0x71, 0xc4,
+    ]);
+    assert_eq!(out[60..], [0xa2, 0x50, 0x3c, 0x4e]);
+  }
+
+  #[test_case]
+  fn zero_key_block() {
+    let out = block(&[0; KEY_SIZE], 0, &[0; 12]);
+    assert_eq!(out[..8], [0x76, 0xb8, 0xe0, 0xad, 0xa0, 0xf1, 0x3d, 0x90]);
+  }
+}
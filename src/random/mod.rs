@@ -0,0 +1,277 @@
+#![allow(dead_code)]
+use crate::cpu::{self, Features};
+use crate::sync::IrqSafeMutex;
+use chacha20::{BLOCK_SIZE, KEY_SIZE};
+
+mod chacha20;
+
+/*
+  Kernel random numbers. Entropy from RDSEED/RDRAND, when the cpu has
+  them, jitter of the timestamp counter and the timing of interrupts
+  is mixed into a pool. The pool seeds a ChaCha20 generator which is
+  reseeded from it once enough new samples have come in. The key is
+  replaced after every request ("fast key erasure"), so earlier output
+  cannot be reconstructed from the state.
+  References:
+  https://blog.cr.yp.to/20170723-random.html
+  https://www.felixcloutier.com/x86/rdrand
+  https://www.felixcloutier.com/x86/rdseed
+*/
+
+const RDRAND_RETRIES: usize = 10;
+const JITTER_SAMPLES: usize = 256;
+const RESEED_SAMPLES: u64 = 64;
+
+fn rdrand() -> Option<u64> {
+  for _ in 0..RDRAND_RETRIES {
+    let (value, ok): (u64, u8);
+    unsafe {
+      asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack))
+    };
+    if ok == 1 {
+      return Some(value);
+    }
+  }
+  None
+}
+
+fn rdseed() -> Option<u64> {
+  for _ in 0..RDRAND_RETRIES {
+    let (value, ok): (u64, u8);
+    unsafe {
+      asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack))
+    };
+    if ok == 1 {
+      return Some(value);
+    }
+    unsafe { asm!("pause") };
+  }
+  None
+}
+
+// A hardware random number, None if the cpu has no generator
+pub fn hardware_u64() -> Option<u64> {
+  let features = cpu::features();
+  if features.contains(Features::RDSEED) {
+    if let Some(value) = rdseed() {
+      return Some(value);
+    }
+  }
+  if features.contains(Features::RDRAND) {
+    return rdrand();
+  }
+  None
+}
+
+// Samples are mixed in with SipHash rounds. The pool is only ever read
+// through ChaCha20, so it does not need to be a cryptographic hash.
+struct Pool {
+  state: [u64; 4],
+  new_samples: u64,
+}
+
+impl Pool {
+  const fn new() -> Self {
+    // the SipHash initialization constants
+    Self {
+      state: [
+        0x736f_6d65_7073_6575,
+        0x646f_7261_6e64_6f6d,
+        0x6c79_6765_6e65_7261,
+        0x7465_6462_7974_6573,
+      ],
+      new_samples: 0,
+    }
+  }
+
+  fn round(&mut self) {
+    let [v0, v1, v2, v3] = &mut self.state;
+    *v0 = v0.wrapping_add(*v1);
+    *v1 = v1.rotate_left(13) ^ *v0;
+    *v0 = v0.rotate_left(32);
+    *v2 = v2.wrapping_add(*v3);
+    *v3 = v3.rotate_left(16) ^ *v2;
+    *v0 = v0.wrapping_add(*v3);
+    *v3 = v3.rotate_left(21) ^ *v0;
+    *v2 = v2.wrapping_add(*v1);
+    *v1 = v1.rotate_left(17) ^ *v2;
+    *v2 = v2.rotate_left(32);
+  }
+
+  fn mix(&mut self, sample: u64) {
+    self.state[3] ^= sample;
+    self.round();
+    self.round();
+    self.state[0] ^= sample;
+    self.new_samples += 1;
+  }
+
+  fn bytes(&self) -> [u8; 32] {
+    let mut out = [0; 32];
+    for (chunk, word) in out.chunks_mut(8).zip(self.state.iter()) {
+      chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    out
+  }
+}
+
+struct Rng {
+  key:    [u8; KEY_SIZE],
+  seeded: bool,
+}
+
+impl Rng {
+  const fn new() -> Self {
+    Self {
+      key:    [0; KEY_SIZE],
+      seeded: false,
+    }
+  }
+
+  // Mixes the pool into the key through ChaCha20
+  fn reseed(&mut self, pool: &mut Pool) {
+    for (k, p) in self.key.iter_mut().zip(pool.bytes().iter()) {
+      *k ^= p;
+    }
+    let block = chacha20::block(&self.key, 0, &[0xff; 12]);
+    self.key.copy_from_slice(&block[..KEY_SIZE]);
+    pool.new_samples = 0;
+    self.seeded = true;
+  }
+
+  fn fill(&mut self, buf: &mut [u8]) {
+    let nonce = [0; 12];
+    // block 0 becomes the next key, the output starts at block 1
+    for (i, chunk) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+      let block = chacha20::block(&self.key, i as u32 + 1, &nonce);
+      chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+    let next = chacha20::block(&self.key, 0, &nonce);
+    self.key.copy_from_slice(&next[..KEY_SIZE]);
+  }
+}
+
+static POOL: IrqSafeMutex<Pool> = IrqSafeMutex::new(Pool::new());
+static RNG: IrqSafeMutex<Rng> = IrqSafeMutex::new(Rng::new());
+
+// The timing of the work in between two reads of the timestamp
+// counter varies slightly with caches, interrupts and the like
+fn collect_jitter(pool: &mut Pool) {
+  let mut prev = cpu::rdtsc();
+  for i in 0..JITTER_SAMPLES {
+    let mut x = prev ^ i as u64;
+    for _ in 0..(prev & 0xf) {
+      x = x.rotate_left(7).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+    let now = cpu::rdtsc();
+    pool.mix(now.wrapping_sub(prev) ^ x);
+    prev = now;
+  }
+}
+
+fn seed(pool: &mut Pool) -> bool {
+  let mut hardware = false;
+  for _ in 0..4 {
+    if let Some(value) = hardware_u64() {
+      pool.mix(value);
+      hardware = true;
+    }
+  }
+  collect_jitter(pool);
+  hardware
+}
+
+// Seeds the generator, it is seeded on first use otherwise
+pub fn initialize() {
+  let mut pool = POOL.lock();
+  let hardware = seed(&mut pool);
+  RNG.lock().reseed(&mut pool);
+  let source = if hardware {
+    "rdseed/rdrand"
+  } else {
+    "no hardware rng"
+  };
+  dbg!("random: seeded, {} and tsc jitter", source);
+}
+
+// Called from interrupt handlers, the arrival time of interrupts is
+// hard to predict. Samples are dropped if the pool is busy.
+pub fn add_interrupt_entropy(source: u64) {
+  if POOL.is_locked() {
+    return;
+  }
+  POOL.lock().mix(cpu::rdtsc() ^ (source << 56));
+}
+
+pub fn fill(buf: &mut [u8]) {
+  let mut rng = RNG.lock();
+  let mut pool = POOL.lock();
+  if !rng.seeded {
+    seed(&mut pool);
+    rng.reseed(&mut pool);
+  } else if pool.new_samples >= RESEED_SAMPLES {
+    rng.reseed(&mut pool);
+  }
+  drop(pool);
+  rng.fill(buf);
+}
+
+pub fn u64() -> u64 {
+  let mut buf = [0; 8];
+  fill(&mut buf);
+  u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn fill_buffers() {
+    let (mut a, mut b) = ([0u8; 100], [0u8; 100]);
+    fill(&mut a);
+    fill(&mut b);
+    assert_ne!(a[..], b[..]);
+    assert_ne!(a[..], [0; 100][..]);
+    assert_ne!(u64(), u64());
+    fill(&mut []);
+  }
+
+  #[test_case]
+  fn fast_key_erasure() {
+    let mut rng = Rng::new();
+    let key = rng.key;
+    let mut buf = [0; BLOCK_SIZE + 1];
+    rng.fill(&mut buf);
+    assert_ne!(rng.key, key);
+    // the output does not reveal the next key
+    assert_ne!(buf[..KEY_SIZE], rng.key[..]);
+    assert_eq!(buf[..BLOCK_SIZE], chacha20::block(&key, 1, &[0; 12])[..]);
+    // the last byte spills into the next block
+    assert_eq!(buf[BLOCK_SIZE], chacha20::block(&key, 2, &[0; 12])[0]);
+  }
+
+  #[test_case]
+  fn pool_mixing() {
+    let (mut a, mut b) = (Pool::new(), Pool::new());
+    a.mix(1);
+    b.mix(2);
+    assert_ne!(a.bytes(), b.bytes());
+    // every bit of the sample affects the whole state
+    let diff = a
+      .state
+      .iter()
+      .zip(b.state.iter())
+      .map(|(x, y)| (x ^ y).count_ones())
+      .sum::<u32>();
+    assert!(diff > 64);
+    assert_eq!(a.new_samples, 1);
+  }
+
+  #[test_case]
+  fn hardware_rng() {
+    let features = cpu::features();
+    let available = features.intersects(Features::RDRAND | Features::RDSEED);
+    assert_eq!(hardware_u64().is_some(), available);
+  }
+}
@@ -0,0 +1,295 @@
+#![allow(dead_code)]
+use crate::acpi;
+use crate::cpu::fpu;
+use crate::cpu::regs::{Cr0Flags, Cr3, Cr4, Cr4Flags, Efer, EferFlags};
+use crate::hlt_loop;
+use crate::interrupts::{self, apic, gdt};
+use crate::mem::frame_allocator::FrameAllocator;
+use crate::mem::page_table::{self, PageFlags};
+use crate::mem::{PhysAddr, VirtAddr};
+use crate::timer;
+use core::ptr;
+use core::sync::atomic::{self, AtomicUsize, Ordering};
+
+/*
+  Application processors start in real mode after an INIT and a
+  startup IPI, at the page given by the startup vector. The trampoline
+  below is copied to such a page below 1MiB, followed by a block of
+  parameters. It loads a temporary GDT, the page table and control
+  registers of the bootstrap processor and enables protected mode and
+  paging at once, which puts it straight into long mode. From there it
+  calls ap_entry on its own stack. The processors are started one at a
+  time since they share the trampoline and its parameters.
+  References:
+  https://wiki.osdev.org/SMP
+  https://wiki.osdev.org/Entering_Long_Mode_Directly
+  Intel SDM Vol. 3A, 8.4 Multiple-Processor (MP) Initialization
+*/
+
+pub const MAX_CPUS: usize = 16;
+
+// Each cpu gets a boot stack, which becomes its idle stack, a double
+// fault stack and a stack for entering the kernel from user mode, all
+// with a guard page below them
+const STACKS_START: u64 = 0x6000_0000_0000;
+const STACK_SIZE: u64 = 4096 * 4;
+const STACK_STRIDE: u64 = STACK_SIZE + 4096;
+const STACKS_PER_CPU: u64 = 3;
+
+const INIT_DELAY_MS: u64 = 10;
+const STARTUP_DELAY_MS: u64 = 1;
+const ONLINE_TIMEOUT_MS: u64 = 100;
+
+// The bootstrap processor is online from the start
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+// The code is well below this, the parameters follow it
+const TRAMPOLINE_CODE_SIZE: usize = 0x200;
+const PARAMS: usize = TRAMPOLINE_CODE_SIZE;
+
+// Offsets into the parameter block
+const PARAM_GDT: usize = 0; // three entries
+const PARAM_GDTR: usize = 24; // limit and base
+const PARAM_LONG_MODE_JUMP: usize = 32; // far pointer, filled in by the
+                                        // trampoline
+const PARAM_CR3: usize = 40;
+const PARAM_CR4: usize = 44;
+const PARAM_EFER: usize = 48;
+const PARAM_STACK: usize = 56;
+const PARAM_CPU: usize = 64;
+const PARAM_ENTRY: usize = 72;
+
+const CR0_LONG_MODE: u64 =
+  Cr0Flags::PROTECTED_MODE_ENABLE.bits() | Cr0Flags::WRITE_PROTECT.bits() | Cr0Flags::PAGING.bits();
+
+// Never called, only copied. Memory operands in the 16 bit part are
+// relative to ds, which is set to our segment, in the 64 bit part
+// they are relative to rbx, the physical address of the trampoline.
+#[naked]
+unsafe extern "C" fn ap_trampoline() {
+  asm!(
+    ".code16",
+    "2:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "movzx ebx, ax",
+    "shl ebx, 4",
+    "lgdt [{gdtr}]",
+    "mov eax, dword ptr [{cr4}]",
+    "mov cr4, eax",
+    "mov eax, dword ptr [{cr3}]",
+    "mov cr3, eax",
+    "mov ecx, {efer_msr}",
+    "rdmsr",
+    "or eax, dword ptr [{efer}]",
+    "wrmsr",
+    "mov eax, cr0",
+    "or eax, {cr0}",
+    "mov cr0, eax",
+    // now in compatibility mode, a far jump loads the 64 bit cs. The
+    // offset of the 64 bit part has to be a constant, and the jump goes
+    // through si since a far jump to an absolute address would be
+    // assembled as a near one.
+    ".set ap_long_mode_offset, 3f - 2b",
+    "mov eax, offset ap_long_mode_offset",
+    "add eax, ebx",
+    "mov si, {long_mode_jump}",
+    "mov dword ptr [si], eax",
+    "jmp fword ptr [si]",
+    ".code64",
+    "3:",
+    "mov ax, {data_selector}",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor eax, eax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov rsp, [rbx + {stack}]",
+    "mov rdi, [rbx + {cpu}]",
+    "call qword ptr [rbx + {entry}]",
+    "ud2",
+    gdtr = const PARAMS + PARAM_GDTR,
+    cr3 = const PARAMS + PARAM_CR3,
+    cr4 = const PARAMS + PARAM_CR4,
+    efer = const PARAMS + PARAM_EFER,
+    efer_msr = const 0xc000_0080u32,
+    cr0 = const CR0_LONG_MODE,
+    long_mode_jump = const PARAMS + PARAM_LONG_MODE_JUMP,
+    data_selector = const gdt::KERNEL_DATA_SELECTOR,
+    stack = const PARAMS + PARAM_STACK,
+    cpu = const PARAMS + PARAM_CPU,
+    entry = const PARAMS + PARAM_ENTRY,
+    options(noreturn)
+  );
+}
+
+struct Trampoline {
+  frame: PhysAddr,
+}
+
+impl Trampoline {
+  fn new(frame: PhysAddr) -> Self {
+    // the cpu enables paging while executing the trampoline, so it
+    // has to be identity mapped as well
+    let identity = VirtAddr::new(frame.as_u64());
+    if page_table::translate_addr(identity).map(|addr| addr.as_u64()) != Some(frame.as_u64()) {
+      page_table::page_map_phys(identity, frame, PageFlags::WRITABLE);
+    }
+    unsafe {
+      let code = ap_trampoline as usize as *const u8;
+      ptr::copy_nonoverlapping(code, frame.to_virt().as_mut_ptr(), TRAMPOLINE_CODE_SIZE);
+    }
+    let trampoline = Self { frame };
+    let (cr3, _) = Cr3::read();
+    assert!(cr3.as_u64() < 1 << 32, "the page table is above 4GiB");
+    let cr4 = Cr4::read()
+      & (Cr4Flags::PHYSICAL_ADDRESS_EXTENSION
+        | Cr4Flags::PAGE_SIZE_EXTENSION
+        | Cr4Flags::PAGE_GLOBAL);
+    let efer = Efer::read()
+      & (EferFlags::SYSTEM_CALL_EXTENSIONS
+        | EferFlags::LONG_MODE_ENABLE
+        | EferFlags::NO_EXECUTE_ENABLE);
+    let gdt_addr = frame.as_u64() + (PARAMS + PARAM_GDT) as u64;
+    unsafe {
+      trampoline.write(PARAM_GDT, gdt::null_segment());
+      trampoline.write(PARAM_GDT + 8, gdt::kernel_code_segment());
+      trampoline.write(PARAM_GDT + 16, gdt::kernel_data_segment());
+      trampoline.write(PARAM_GDTR, 23u16);
+      trampoline.write(PARAM_GDTR + 2, gdt_addr as u32);
+      trampoline.write(PARAM_LONG_MODE_JUMP + 4, gdt::KERNEL_CODE_SELECTOR);
+      trampoline.write(PARAM_CR3, cr3.as_u64() as u32);
+      trampoline.write(PARAM_CR4, cr4.bits() as u32);
+      trampoline.write(PARAM_EFER, efer.bits() as u32);
+      trampoline.write(PARAM_ENTRY, ap_entry as usize as u64);
+    }
+    trampoline
+  }
+
+  unsafe fn write<T>(&self, param: usize, value: T) {
+    let addr = self.frame.to_virt().as_u64() + (PARAMS + param) as u64;
+    ptr::write_unaligned(addr as *mut T, value);
+  }
+
+  // Starts the cpu and waits for it to come online
+  fn start(&self, cpu: usize, apic_id: u8) -> bool {
+    let online = ONLINE.load(Ordering::SeqCst);
+    unsafe {
+      self.write(PARAM_STACK, stack_top(cpu, 0));
+      self.write(PARAM_CPU, cpu as u64);
+    }
+    atomic::fence(Ordering::SeqCst);
+    let vector = (self.frame.as_u64() >> 12) as u8;
+    apic::send_init(apic_id);
+    wait_ms(INIT_DELAY_MS, || false);
+    // a second startup IPI is sent in case the first one is missed
+    for _ in 0..2 {
+      apic::send_startup(apic_id, vector);
+      wait_ms(STARTUP_DELAY_MS, || false);
+    }
+    wait_ms(ONLINE_TIMEOUT_MS, || ONLINE.load(Ordering::SeqCst) > online)
+  }
+}
+
+// Waits until done returns true or ms milliseconds have passed.
+// Relies on the timer interrupt.
+fn wait_ms(ms: u64, done: impl Fn() -> bool) -> bool {
+  let deadline = timer::ticks() + timer::millis_to_ticks(ms) + 1;
+  while timer::ticks() < deadline {
+    if done() {
+      return true;
+    }
+    unsafe { asm!("pause") };
+  }
+  done()
+}
+
+fn stack_bottom(cpu: usize, i: u64) -> u64 {
+  let cpu_start = STACKS_START + cpu as u64 * STACKS_PER_CPU * STACK_STRIDE;
+  cpu_start + i * STACK_STRIDE + 4096
+}
+
+fn stack_top(cpu: usize, i: u64) -> u64 {
+  stack_bottom(cpu, i) + STACK_SIZE
+}
+
+fn map_stacks(cpu: usize) {
+  for i in 0..STACKS_PER_CPU {
+    let bottom = stack_bottom(cpu, i);
+    for page in (bottom..bottom + STACK_SIZE).step_by(4096) {
+      page_table::page_map_addr(VirtAddr::new(page));
+    }
+  }
+}
+
+// Called from the trampoline on the boot stack
+extern "C" fn ap_entry(cpu: u64) -> ! {
+  let cpu = cpu as usize;
+  unsafe { interrupts::initialize_ap(cpu, stack_top(cpu, 2), stack_top(cpu, 1)) };
+  fpu::enable();
+  apic::enable();
+  ONLINE.fetch_add(1, Ordering::SeqCst);
+  interrupts::enable();
+  hlt_loop();
+}
+
+// Starts all processors listed in the MADT. Has to be called on the
+// bootstrap processor after interrupts::initialize.
+pub fn initialize() {
+  let madt = match acpi::madt() {
+    Some(madt) => madt,
+    None => {
+      dbg!("smp: no MADT, only using the bootstrap processor");
+      return;
+    }
+  };
+  apic::initialize();
+  let bsp_id = apic::id();
+  let frame = FrameAllocator::the()
+    .alloc_low()
+    .expect("no low memory for the trampoline");
+  let trampoline = Trampoline::new(frame);
+  let mut cpu = 1;
+  for processor in madt.processors().filter(|p| p.apic_id != bsp_id) {
+    if cpu == MAX_CPUS {
+      dbg!("smp: ignoring cpus after the first {}", MAX_CPUS);
+      break;
+    }
+    map_stacks(cpu);
+    if trampoline.start(cpu, processor.apic_id) {
+      cpu += 1;
+    } else {
+      dbg!("smp: cpu with apic id {} did not start", processor.apic_id);
+    }
+  }
+  dbg!("smp: {} CPUs online", cpu_count());
+}
+
+pub fn cpu_count() -> usize {
+  ONLINE.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn stack_layout() {
+    // guard pages between all stacks
+    assert_eq!(stack_bottom(0, 0), STACKS_START + 4096);
+    assert_eq!(stack_bottom(0, 1), stack_top(0, 0) + 4096);
+    assert_eq!(stack_bottom(1, 0), stack_top(0, STACKS_PER_CPU - 1) + 4096);
+    assert_eq!(stack_top(1, 2) & 0xf, 0);
+  }
+
+  #[test_case]
+  fn trampoline_code() {
+    let code = unsafe { core::slice::from_raw_parts(ap_trampoline as usize as *const u8, 2) };
+    assert_eq!(code, [0xfa, 0xfc]); // cli, cld
+    assert_eq!(CR0_LONG_MODE, 0x8001_0001);
+    assert_eq!(PARAM_GDTR, PARAM_GDT + 24);
+  }
+}
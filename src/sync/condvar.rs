@@ -0,0 +1,144 @@
+#![allow(dead_code)]
+use super::{MutexGuard, WaitQueue};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+  A condition variable for the sleeping Mutex. A notification is a
+  wake token for a task which is waiting at the time, handed out in
+  FIFO order, so notifying without waiters does nothing. The waiter is
+  queued before the mutex is unlocked, so a notification by a task
+  which locked the mutex afterwards is never missed. As usual, waiters
+  have to check their condition again after waking up.
+*/
+pub struct Condvar {
+  tokens:  AtomicUsize, // never more than there are waiters
+  waiters: WaitQueue,
+}
+
+impl Condvar {
+  pub const fn new() -> Self {
+    Self {
+      tokens:  AtomicUsize::new(0),
+      waiters: WaitQueue::new(),
+    }
+  }
+
+  // Unlocks the mutex and sleeps until notified, then locks it again
+  pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    let mutex = guard.mutex;
+    self
+      .waiters
+      .wait_until_with(|| self.take_token(), || drop(guard));
+    mutex.lock()
+  }
+
+  // Like wait, but gives up once interrupted returns true, see
+  // WaitQueue::wait_until_interruptible. None if it did, the mutex is
+  // not locked again then.
+  pub fn wait_interruptible<'a, T>(
+    &self,
+    guard: MutexGuard<'a, T>,
+    interrupted: impl FnMut() -> bool,
+  ) -> Option<MutexGuard<'a, T>> {
+    let mutex = guard.mutex;
+    let notified =
+      self
+        .waiters
+        .wait_until_interruptible(|| self.take_token(), || drop(guard), interrupted);
+    if !notified {
+      // a notification this task did not take goes to the others
+      self.waiters.wake_one_with(|_, waiting| {
+        let tokens = self.tokens.load(Ordering::Relaxed);
+        self.tokens.store(tokens.min(waiting), Ordering::Relaxed);
+      });
+      return None;
+    }
+    Some(mutex.lock())
+  }
+
+  fn take_token(&self) -> bool {
+    let tokens = self.tokens.load(Ordering::Relaxed);
+    if tokens == 0 {
+      return false;
+    }
+    self.tokens.store(tokens - 1, Ordering::Relaxed);
+    true
+  }
+
+  pub fn notify_one(&self) {
+    self.waiters.wake_one_with(|_, waiting| {
+      if self.tokens.load(Ordering::Relaxed) < waiting {
+        self.tokens.fetch_add(1, Ordering::Relaxed);
+      }
+    });
+  }
+
+  // The first waiter is woken right away, the others one after the
+  // other as each one gets through
+  pub fn notify_all(&self) {
+    self
+      .waiters
+      .wake_one_with(|_, waiting| self.tokens.store(waiting, Ordering::Relaxed));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::sync::Mutex;
+  use crate::task;
+
+  static FLAG: Mutex<bool> = Mutex::new(false);
+  static CONDVAR: Condvar = Condvar::new();
+
+  #[test_case]
+  fn notify_without_waiters() {
+    let condvar = Condvar::new();
+    condvar.notify_one();
+    condvar.notify_all();
+    assert_eq!(condvar.tokens.load(Ordering::Relaxed), 0);
+  }
+
+  static TURN: Mutex<usize> = Mutex::new(0);
+  static TURN_CHANGED: Condvar = Condvar::new();
+
+  #[test_case]
+  fn ping_pong() {
+    // two tasks taking turns, each waits for the other to notify it.
+    // A single missed notification leaves both waiting forever.
+    fn player(me: usize) {
+      for _ in 0..100 {
+        let mut turn = TURN.lock();
+        while *turn != me {
+          turn = TURN_CHANGED.wait(turn);
+        }
+        *turn = 1 - me;
+        TURN_CHANGED.notify_one();
+      }
+    }
+    let ping = task::spawn(|| player(0)).unwrap();
+    let pong = task::spawn(|| player(1)).unwrap();
+    ping.join();
+    pong.join();
+    assert_eq!(*TURN.lock(), 0);
+  }
+
+  #[test_case]
+  fn notify_all_wakes_everyone() {
+    fn waiter() {
+      let mut flag = FLAG.lock();
+      while !*flag {
+        flag = CONDVAR.wait(flag);
+      }
+    }
+    let a = task::spawn(waiter).unwrap();
+    let b = task::spawn(waiter).unwrap();
+    let c = task::spawn(waiter).unwrap();
+    task::yield_now();
+    *FLAG.lock() = true;
+    CONDVAR.notify_all();
+    a.join();
+    b.join();
+    c.join();
+  }
+}
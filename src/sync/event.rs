@@ -0,0 +1,71 @@
+#![allow(dead_code)]
+use super::WaitQueue;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// A one-shot event. Tasks wait until it is set, which wakes all of
+// them, and once set it stays set.
+pub struct Event {
+  set:     AtomicBool,
+  waiters: WaitQueue,
+}
+
+impl Event {
+  pub const fn new() -> Self {
+    Self {
+      set:     AtomicBool::new(false),
+      waiters: WaitQueue::new(),
+    }
+  }
+
+  pub fn wait(&self) {
+    self.waiters.wait_until(|| self.is_set());
+  }
+
+  // Also safe from interrupt handlers
+  pub fn set(&self) {
+    self
+      .waiters
+      .wake_one_with(|_, _| self.set.store(true, Ordering::Release));
+  }
+
+  pub fn is_set(&self) -> bool {
+    self.set.load(Ordering::Acquire)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::task;
+  use core::sync::atomic::AtomicUsize;
+
+  #[test_case]
+  fn set_before_wait() {
+    let event = Event::new();
+    event.set();
+    event.wait();
+    event.wait();
+    assert!(event.is_set());
+  }
+
+  static EVENT: Event = Event::new();
+  static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+  #[test_case]
+  fn wakes_all_waiters() {
+    fn waiter() {
+      EVENT.wait();
+      WOKEN.fetch_add(1, Ordering::SeqCst);
+    }
+    let a = task::spawn(waiter).unwrap();
+    let b = task::spawn(waiter).unwrap();
+    let c = task::spawn(waiter).unwrap();
+    task::yield_now();
+    assert_eq!(WOKEN.load(Ordering::SeqCst), 0);
+    EVENT.set();
+    a.join();
+    b.join();
+    c.join();
+    assert_eq!(WOKEN.load(Ordering::SeqCst), 3);
+  }
+}
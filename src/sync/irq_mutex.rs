@@ -0,0 +1,173 @@
+use crate::interrupts;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/*
+  A spinlock which disables interrupts while it is held. An
+  interrupt handler can therefore never spin on a lock held
+  by the code it interrupted, which would deadlock the kernel.
+  The interrupt flag (RFLAGS.IF) is restored when the guard is
+  dropped, so nested critical sections work as expected. Debug builds
+  also remember which cpu holds it and panic when that cpu locks it
+  again, which would spin forever otherwise.
+  Reference: https://wiki.osdev.org/Synchronization_Primitives
+*/
+pub struct IrqSafeMutex<T> {
+  locked: AtomicBool,
+  // APIC id + 1 of the cpu holding the lock, 0 if unlocked
+  #[cfg(debug_assertions)]
+  owner:  AtomicU32,
+  data:   UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for IrqSafeMutex<T> {}
+unsafe impl<T: Send> Send for IrqSafeMutex<T> {}
+
+pub struct IrqSafeMutexGuard<'a, T> {
+  mutex: &'a IrqSafeMutex<T>,
+  interrupts_enabled: bool,
+}
+
+impl<T> IrqSafeMutex<T> {
+  pub const fn new(data: T) -> Self {
+    Self {
+      locked: AtomicBool::new(false),
+      #[cfg(debug_assertions)]
+      owner: AtomicU32::new(0),
+      data: UnsafeCell::new(data),
+    }
+  }
+
+  pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+    let interrupts_enabled = interrupts::enabled();
+    interrupts::disable();
+    #[cfg(debug_assertions)]
+    assert_ne!(
+      self.owner.load(Ordering::Relaxed),
+      current_cpu(),
+      "IrqSafeMutex locked twice on the same cpu"
+    );
+    while self
+      .locked
+      .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+      .is_err()
+    {
+      while self.locked.load(Ordering::Relaxed) {
+        unsafe { asm!("pause") };
+      }
+    }
+    #[cfg(debug_assertions)]
+    self.owner.store(current_cpu(), Ordering::Relaxed);
+    IrqSafeMutexGuard {
+      mutex: self,
+      interrupts_enabled,
+    }
+  }
+
+  pub fn is_locked(&self) -> bool {
+    self.locked.load(Ordering::Relaxed)
+  }
+
+  // Unsafe since any existing guard is invalidated.
+  // Only meant for panic handlers which need to print.
+  pub unsafe fn force_unlock(&self) {
+    #[cfg(debug_assertions)]
+    self.owner.store(0, Ordering::Relaxed);
+    self.locked.store(false, Ordering::Release);
+  }
+}
+
+impl<'a, T> Deref for IrqSafeMutexGuard<'a, T> {
+  type Target = T;
+  fn deref(&self) -> &T {
+    unsafe { &*self.mutex.data.get() }
+  }
+}
+
+impl<'a, T> DerefMut for IrqSafeMutexGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.mutex.data.get() }
+  }
+}
+
+impl<'a, T> Drop for IrqSafeMutexGuard<'a, T> {
+  fn drop(&mut self) {
+    #[cfg(debug_assertions)]
+    self.mutex.owner.store(0, Ordering::Relaxed);
+    self.mutex.locked.store(false, Ordering::Release);
+    if self.interrupts_enabled {
+      interrupts::enable();
+    }
+  }
+}
+
+// Initial APIC id from cpuid, unique per cpu. Reference:
+// https://wiki.osdev.org/Detecting_CPU_Topology_(80x86)#Using_CPUID
+#[cfg(debug_assertions)]
+fn current_cpu() -> u32 {
+  let info = unsafe { core::arch::x86_64::__cpuid(1) };
+  (info.ebx >> 24) + 1
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn lock_and_mutate() {
+    let mutex = IrqSafeMutex::new(0);
+    *mutex.lock() += 1;
+    *mutex.lock() += 1;
+    assert_eq!(*mutex.lock(), 2);
+    assert!(!mutex.is_locked());
+  }
+
+  #[test_case]
+  fn interrupts_disabled_while_locked() {
+    let mutex = IrqSafeMutex::new(());
+    interrupts::enable();
+    {
+      let _guard = mutex.lock();
+      assert!(!interrupts::enabled());
+      assert!(mutex.is_locked());
+    }
+    assert!(interrupts::enabled());
+    interrupts::disable();
+    {
+      let _guard = mutex.lock();
+      assert!(!interrupts::enabled());
+    }
+    assert!(!interrupts::enabled());
+  }
+
+  #[test_case]
+  fn nested_different_locks() {
+    let a = IrqSafeMutex::new(1);
+    let b = IrqSafeMutex::new(2);
+    interrupts::enable();
+    {
+      let guard_a = a.lock();
+      {
+        let guard_b = b.lock();
+        assert_eq!(*guard_a + *guard_b, 3);
+      }
+      // inner guard must not re-enable interrupts
+      assert!(!interrupts::enabled());
+    }
+    assert!(interrupts::enabled());
+    interrupts::disable();
+  }
+
+  #[test_case]
+  fn force_unlock() {
+    let mutex = IrqSafeMutex::new(0);
+    core::mem::forget(mutex.lock());
+    assert!(mutex.is_locked());
+    unsafe { mutex.force_unlock() };
+    assert!(!mutex.is_locked());
+    *mutex.lock() = 1;
+  }
+}
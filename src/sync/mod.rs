@@ -0,0 +1,15 @@
+mod condvar;
+mod event;
+mod irq_mutex;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use event::Event;
+pub use irq_mutex::{IrqSafeMutex, IrqSafeMutexGuard};
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use wait_queue::WaitQueue;
@@ -0,0 +1,166 @@
+#![allow(dead_code)]
+use super::WaitQueue;
+use crate::task;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+  A mutex which puts the task to sleep while it is locked by another
+  task, unlike IrqSafeMutex which spins. It cannot be used from
+  interrupt handlers. Unlocking hands the mutex directly to the first
+  waiter, so it is granted in the order it was asked for and a task
+  which keeps locking it cannot starve the others.
+*/
+pub struct Mutex<T> {
+  owner:   AtomicUsize, // the id of the task holding it, 0 if unlocked
+  waiters: WaitQueue,
+  data:    UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+  pub(super) mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+  pub const fn new(data: T) -> Self {
+    Self {
+      owner:   AtomicUsize::new(0),
+      waiters: WaitQueue::new(),
+      data:    UnsafeCell::new(data),
+    }
+  }
+
+  pub fn lock(&self) -> MutexGuard<'_, T> {
+    let me = task::current()
+      .expect("only tasks can lock a Mutex")
+      .as_usize();
+    assert_ne!(
+      self.owner.load(Ordering::Relaxed),
+      me,
+      "Mutex locked twice by the same task"
+    );
+    self.waiters.wait_until(|| {
+      let owner = self.owner.load(Ordering::Acquire);
+      owner == me || (owner == 0 && self.try_take(me))
+    });
+    MutexGuard { mutex: self }
+  }
+
+  // Never blocks, but also takes the mutex if others are waiting for it
+  pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+    let me = task::current()
+      .expect("only tasks can lock a Mutex")
+      .as_usize();
+    if self.try_take(me) {
+      Some(MutexGuard { mutex: self })
+    } else {
+      None
+    }
+  }
+
+  fn try_take(&self, me: usize) -> bool {
+    self
+      .owner
+      .compare_exchange(0, me, Ordering::Acquire, Ordering::Relaxed)
+      .is_ok()
+  }
+
+  pub fn is_locked(&self) -> bool {
+    self.owner.load(Ordering::Relaxed) != 0
+  }
+
+  fn unlock(&self) {
+    self.waiters.wake_one_with(|first, _| {
+      let next = first.map_or(0, |task| task.as_usize());
+      self.owner.store(next, Ordering::Release);
+    });
+  }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+  type Target = T;
+  fn deref(&self) -> &T {
+    unsafe { &*self.mutex.data.get() }
+  }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.mutex.data.get() }
+  }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+  fn drop(&mut self) {
+    self.mutex.unlock();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::sync::IrqSafeMutex;
+
+  #[test_case]
+  fn lock_and_mutate() {
+    let mutex = Mutex::new(0);
+    *mutex.lock() += 1;
+    {
+      let mut guard = mutex.lock();
+      *guard += 1;
+      assert!(mutex.is_locked());
+      assert!(mutex.try_lock().is_none());
+    }
+    assert!(!mutex.is_locked());
+    assert_eq!(*mutex.try_lock().unwrap(), 2);
+  }
+
+  static MUTEX: Mutex<u64> = Mutex::new(0);
+  static LOG: IrqSafeMutex<([u8; 4], usize)> = IrqSafeMutex::new(([0; 4], 0));
+
+  #[test_case]
+  fn granted_in_order() {
+    fn worker(c: u8) {
+      let _guard = MUTEX.lock();
+      let mut log = LOG.lock();
+      let i = log.1;
+      log.0[i] = c;
+      log.1 += 1;
+    }
+    let guard = MUTEX.lock();
+    let a = task::spawn(|| worker(b'a')).unwrap();
+    let b = task::spawn(|| worker(b'b')).unwrap();
+    let c = task::spawn(|| worker(b'c')).unwrap();
+    task::yield_now(); // all of them block on the mutex
+    drop(guard);
+    // the mutex went to a, the boot task has to wait behind c now
+    assert!(MUTEX.try_lock().is_none());
+    a.join();
+    b.join();
+    c.join();
+    let log = LOG.lock();
+    assert_eq!(&log.0[..log.1], b"abc");
+  }
+
+  #[test_case]
+  fn contended_counter() {
+    fn worker() {
+      for _ in 0..100 {
+        let mut guard = MUTEX.lock();
+        let value = *guard;
+        task::yield_now(); // with the mutex held
+        *guard = value + 1;
+      }
+    }
+    *MUTEX.lock() = 0;
+    let a = task::spawn(worker).unwrap();
+    let b = task::spawn(worker).unwrap();
+    a.join();
+    b.join();
+    assert_eq!(*MUTEX.lock(), 200);
+  }
+}
@@ -0,0 +1,166 @@
+#![allow(dead_code)]
+use super::WaitQueue;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+  A sleeping reader-writer lock. Readers and writers queue up in the
+  same FIFO order, so a waiting writer keeps new readers out until it
+  has had its turn and writers cannot be starved by a steady stream of
+  readers. Consecutive readers at the front of the queue all get in.
+*/
+pub struct RwLock<T> {
+  state:   AtomicUsize, // the number of readers, or WRITER
+  waiters: WaitQueue,
+  data:    UnsafeCell<T>,
+}
+
+const WRITER: usize = usize::MAX;
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Send> Send for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T> {
+  lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+  lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+  pub const fn new(data: T) -> Self {
+    Self {
+      state:   AtomicUsize::new(0),
+      waiters: WaitQueue::new(),
+      data:    UnsafeCell::new(data),
+    }
+  }
+
+  pub fn read(&self) -> RwLockReadGuard<'_, T> {
+    self.waiters.wait_until(|| {
+      let readers = self.state.load(Ordering::Acquire);
+      readers != WRITER && self.take(readers, readers + 1)
+    });
+    RwLockReadGuard { lock: self }
+  }
+
+  pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+    self.waiters.wait_until(|| self.take(0, WRITER));
+    RwLockWriteGuard { lock: self }
+  }
+
+  fn take(&self, current: usize, new: usize) -> bool {
+    self
+      .state
+      .compare_exchange(current, new, Ordering::Acquire, Ordering::Relaxed)
+      .is_ok()
+  }
+
+  pub fn readers(&self) -> usize {
+    match self.state.load(Ordering::Relaxed) {
+      WRITER => 0,
+      readers => readers,
+    }
+  }
+
+  pub fn is_write_locked(&self) -> bool {
+    self.state.load(Ordering::Relaxed) == WRITER
+  }
+
+  fn read_unlock(&self) {
+    self.waiters.wake_one_with(|_, _| {
+      self.state.fetch_sub(1, Ordering::Release);
+    });
+  }
+
+  fn write_unlock(&self) {
+    self.waiters.wake_one_with(|_, _| {
+      self.state.store(0, Ordering::Release);
+    });
+  }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+  type Target = T;
+  fn deref(&self) -> &T {
+    unsafe { &*self.lock.data.get() }
+  }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+  fn drop(&mut self) {
+    self.lock.read_unlock();
+  }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+  type Target = T;
+  fn deref(&self) -> &T {
+    unsafe { &*self.lock.data.get() }
+  }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.lock.data.get() }
+  }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+  fn drop(&mut self) {
+    self.lock.write_unlock();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::task;
+  use core::sync::atomic::AtomicBool;
+
+  #[test_case]
+  fn readers_share() {
+    let lock = RwLock::new(1);
+    {
+      let a = lock.read();
+      let b = lock.read();
+      assert_eq!(*a + *b, 2);
+      assert_eq!(lock.readers(), 2);
+    }
+    *lock.write() += 1;
+    assert_eq!(*lock.read(), 2);
+    assert_eq!(lock.readers(), 0);
+    assert!(!lock.is_write_locked());
+  }
+
+  static LOCK: RwLock<u64> = RwLock::new(0);
+  static WRITTEN: AtomicBool = AtomicBool::new(false);
+  static LATE_READER_SAW: AtomicUsize = AtomicUsize::new(0);
+
+  #[test_case]
+  fn writer_not_starved() {
+    let guard = LOCK.read();
+    let writer = task::spawn(|| {
+      *LOCK.write() = 1;
+      WRITTEN.store(true, Ordering::SeqCst);
+    })
+    .unwrap();
+    task::yield_now(); // the writer queues up
+    assert!(!WRITTEN.load(Ordering::SeqCst));
+    // a reader arriving now waits behind the writer, even though the
+    // lock is only held for reading
+    let reader = task::spawn(|| {
+      let value = *LOCK.read();
+      LATE_READER_SAW.store(value as usize, Ordering::SeqCst);
+    })
+    .unwrap();
+    task::yield_now();
+    assert_eq!(LOCK.readers(), 1);
+    drop(guard);
+    writer.join();
+    reader.join();
+    assert_eq!(LATE_READER_SAW.load(Ordering::SeqCst), 1);
+  }
+}
@@ -0,0 +1,104 @@
+#![allow(dead_code)]
+use super::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// A counting semaphore, tasks sleep in acquire while there are no
+// permits left. Permits are handed out in the order they were asked for.
+pub struct Semaphore {
+  permits: AtomicUsize,
+  waiters: WaitQueue,
+}
+
+impl Semaphore {
+  pub const fn new(permits: usize) -> Self {
+    Self {
+      permits: AtomicUsize::new(permits),
+      waiters: WaitQueue::new(),
+    }
+  }
+
+  pub fn acquire(&self) {
+    self.waiters.wait_until(|| self.try_take());
+  }
+
+  // Returns false instead of sleeping if there is no permit
+  pub fn try_acquire(&self) -> bool {
+    self.waiters.is_empty() && self.try_take()
+  }
+
+  fn try_take(&self) -> bool {
+    let permits = self.permits.load(Ordering::Acquire);
+    permits > 0
+      && self
+        .permits
+        .compare_exchange(permits, permits - 1, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
+  }
+
+  pub fn release(&self) {
+    self.waiters.wake_one_with(|_, _| {
+      self.permits.fetch_add(1, Ordering::Release);
+    });
+  }
+
+  pub fn permits(&self) -> usize {
+    self.permits.load(Ordering::Relaxed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::task;
+
+  #[test_case]
+  fn counting() {
+    let semaphore = Semaphore::new(2);
+    semaphore.acquire();
+    assert!(semaphore.try_acquire());
+    assert!(!semaphore.try_acquire());
+    semaphore.release();
+    assert_eq!(semaphore.permits(), 1);
+    semaphore.acquire();
+    semaphore.release();
+    semaphore.release();
+    assert_eq!(semaphore.permits(), 2);
+  }
+
+  static SEMAPHORE: Semaphore = Semaphore::new(2);
+  static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+  static MAX_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+  #[test_case]
+  fn limits_concurrency() {
+    fn worker() {
+      for _ in 0..10 {
+        SEMAPHORE.acquire();
+        let active = ACTIVE.fetch_add(1, Ordering::SeqCst) + 1;
+        MAX_ACTIVE.fetch_max(active, Ordering::SeqCst);
+        task::yield_now();
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+        SEMAPHORE.release();
+      }
+    }
+    let a = task::spawn(worker).unwrap();
+    let b = task::spawn(worker).unwrap();
+    let c = task::spawn(worker).unwrap();
+    let d = task::spawn(worker).unwrap();
+    a.join();
+    b.join();
+    c.join();
+    d.join();
+    assert_eq!(MAX_ACTIVE.load(Ordering::SeqCst), 2);
+    assert_eq!(SEMAPHORE.permits(), 2);
+  }
+
+  #[test_case]
+  fn release_before_acquire() {
+    // a release without a waiter is not lost
+    let semaphore = Semaphore::new(0);
+    semaphore.release();
+    semaphore.acquire();
+    assert_eq!(semaphore.permits(), 0);
+  }
+}
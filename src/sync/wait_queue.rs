@@ -0,0 +1,312 @@
+#![allow(dead_code)]
+use super::IrqSafeMutex;
+use crate::task::{self, TaskId, MAX_TASKS};
+use crate::timer;
+
+/*
+  Tasks waiting for a condition, in FIFO order. Only the first waiter
+  checks the condition when woken, and a new caller only gets through
+  without queueing if nobody is waiting, so nobody can overtake the
+  tasks which are already waiting. Once the first waiter is through,
+  the next one is woken to check its own condition.
+
+  Conditions are checked with the queue locked and anything which can
+  make them true has to change the state through wake_one_with, with
+  the queue locked as well. A task which has checked its condition is
+  therefore always queued before the change and gets woken, and a wake
+  which arrives before it actually blocks makes task::block return.
+  This is what the sleeping primitives in this module are built on.
+
+  A waiter which gives up, at its deadline or because it was
+  interrupted, leaves the queue, and if it was the first one it wakes
+  the next, which may be able to go now.
+  Reference: https://wiki.osdev.org/Synchronization_Primitives
+*/
+
+struct Waiters {
+  tasks: [Option<TaskId>; MAX_TASKS],
+  head:  usize,
+  len:   usize,
+}
+
+impl Waiters {
+  const fn new() -> Self {
+    Self {
+      tasks: [None; MAX_TASKS],
+      head:  0,
+      len:   0,
+    }
+  }
+
+  // A task waits on one queue at a time, so there is always room
+  fn push(&mut self, task: TaskId) {
+    assert!(self.len < MAX_TASKS);
+    self.tasks[(self.head + self.len) % MAX_TASKS] = Some(task);
+    self.len += 1;
+  }
+
+  fn front(&self) -> Option<TaskId> {
+    if self.len == 0 {
+      return None;
+    }
+    self.tasks[self.head]
+  }
+
+  fn pop(&mut self) -> Option<TaskId> {
+    let task = self.front()?;
+    self.tasks[self.head] = None;
+    self.head = (self.head + 1) % MAX_TASKS;
+    self.len -= 1;
+    Some(task)
+  }
+
+  // Takes task out of the queue wherever it is, the ones behind it
+  // move up
+  fn remove(&mut self, task: TaskId) {
+    let head = self.head;
+    let index = |i: usize| (head + i) % MAX_TASKS;
+    let pos = match (0..self.len).find(|&i| self.tasks[index(i)] == Some(task)) {
+      Some(pos) => pos,
+      None => return,
+    };
+    for i in pos..self.len - 1 {
+      self.tasks[index(i)] = self.tasks[index(i + 1)];
+    }
+    self.tasks[index(self.len - 1)] = None;
+    self.len -= 1;
+  }
+}
+
+pub struct WaitQueue {
+  waiters: IrqSafeMutex<Waiters>,
+}
+
+impl WaitQueue {
+  pub const fn new() -> Self {
+    Self {
+      waiters: IrqSafeMutex::new(Waiters::new()),
+    }
+  }
+
+  // Blocks the current task until condition returns true. The
+  // condition runs with interrupts disabled and must not block.
+  pub fn wait_until(&self, condition: impl FnMut() -> bool) {
+    self.wait_until_with(condition, || {});
+  }
+
+  // Like wait_until, but calls queued once the task is in the queue
+  // and before it first blocks, so a wake caused by queued, e.g. by
+  // unlocking a mutex, is not missed
+  pub fn wait_until_with(&self, condition: impl FnMut() -> bool, queued: impl FnOnce()) {
+    self.wait(condition, queued, None, || false);
+  }
+
+  // Like wait_until, but gives up once timer::ticks() reaches deadline.
+  // False if it did, the condition is checked before that though.
+  pub fn wait_until_timeout(&self, condition: impl FnMut() -> bool, deadline: u64) -> bool {
+    self.wait(condition, || {}, Some(deadline), || false)
+  }
+
+  // Like wait_until_with, but gives up once interrupted returns true.
+  // It is checked whenever the task wakes up, so whatever makes it
+  // true has to wake the task. False if it gave up.
+  pub fn wait_until_interruptible(
+    &self,
+    condition: impl FnMut() -> bool,
+    queued: impl FnOnce(),
+    interrupted: impl FnMut() -> bool,
+  ) -> bool {
+    self.wait(condition, queued, None, interrupted)
+  }
+
+  fn wait(
+    &self,
+    mut condition: impl FnMut() -> bool,
+    queued: impl FnOnce(),
+    deadline: Option<u64>,
+    mut interrupted: impl FnMut() -> bool,
+  ) -> bool {
+    let me = task::current().expect("only tasks can wait");
+    let expired = || matches!(deadline, Some(deadline) if timer::ticks() >= deadline);
+    {
+      let interrupted = interrupted();
+      let mut waiters = self.waiters.lock();
+      if waiters.len == 0 && condition() {
+        return true;
+      }
+      if expired() || interrupted {
+        return false;
+      }
+      waiters.push(me);
+    }
+    queued();
+    loop {
+      match deadline {
+        Some(deadline) => {
+          task::block_until(deadline);
+        }
+        None => task::block(),
+      }
+      let interrupted = interrupted();
+      let mut waiters = self.waiters.lock();
+      let first = waiters.front() == Some(me);
+      if first && condition() {
+        waiters.pop();
+        if let Some(next) = waiters.front() {
+          task::wake(next);
+        }
+        return true;
+      }
+      if expired() || interrupted {
+        waiters.remove(me);
+        if let Some(next) = waiters.front().filter(|_| first) {
+          task::wake(next);
+        }
+        return false;
+      }
+    }
+  }
+
+  // Runs update with the first waiter, if any, and the number of
+  // waiters, then wakes the first one. The queue is locked while
+  // update runs, see the comment at the top.
+  pub fn wake_one_with<R>(&self, update: impl FnOnce(Option<TaskId>, usize) -> R) -> R {
+    let waiters = self.waiters.lock();
+    let first = waiters.front();
+    let res = update(first, waiters.len);
+    if let Some(task) = first {
+      task::wake(task);
+    }
+    res
+  }
+
+  pub fn wake_one(&self) {
+    self.wake_one_with(|_, _| {});
+  }
+
+  pub fn len(&self) -> usize {
+    self.waiters.lock().len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+  #[test_case]
+  fn waiters_fifo() {
+    let mut waiters = Waiters::new();
+    for round in 0..3 {
+      for i in 0..MAX_TASKS {
+        waiters.push(TaskId::from_usize(round * MAX_TASKS + i));
+      }
+      for i in 0..MAX_TASKS {
+        assert_eq!(
+          waiters.pop(),
+          Some(TaskId::from_usize(round * MAX_TASKS + i))
+        );
+      }
+    }
+    assert_eq!(waiters.pop(), None);
+  }
+
+  #[test_case]
+  fn waiters_remove() {
+    let mut waiters = Waiters::new();
+    let id = TaskId::from_usize;
+    // wrapped around the end of the array
+    for i in 0..MAX_TASKS - 2 {
+      waiters.push(id(i));
+      waiters.pop();
+    }
+    for i in 0..5 {
+      waiters.push(id(i));
+    }
+    waiters.remove(id(2));
+    waiters.remove(id(0));
+    waiters.remove(id(4));
+    waiters.remove(id(42));
+    assert_eq!(waiters.len, 2);
+    assert_eq!(waiters.pop(), Some(id(1)));
+    assert_eq!(waiters.pop(), Some(id(3)));
+    assert_eq!(waiters.pop(), None);
+  }
+
+  #[test_case]
+  fn no_wait_if_true() {
+    let queue = WaitQueue::new();
+    let mut checked = 0;
+    queue.wait_until(|| {
+      checked += 1;
+      true
+    });
+    assert_eq!(checked, 1);
+    assert!(queue.is_empty());
+  }
+
+  #[test_case]
+  fn interrupted_wait() {
+    let queue = WaitQueue::new();
+    assert!(!queue.wait_until_interruptible(|| false, || {}, || true));
+    assert!(queue.is_empty());
+    // the condition comes first
+    assert!(queue.wait_until_interruptible(|| true, || {}, || true));
+  }
+
+  static QUEUE: WaitQueue = WaitQueue::new();
+  static READY: AtomicBool = AtomicBool::new(false);
+  static ORDER: AtomicUsize = AtomicUsize::new(0);
+
+  #[test_case]
+  fn wake_in_order() {
+    fn waiter(n: usize) {
+      // the condition runs with the queue locked, so the order it
+      // succeeds in is the order the waiters get through
+      QUEUE.wait_until(|| {
+        let ready = READY.load(Ordering::SeqCst);
+        if ready {
+          assert_eq!(ORDER.fetch_add(1, Ordering::SeqCst), n);
+        }
+        ready
+      });
+    }
+    let a = task::spawn(|| waiter(0)).unwrap();
+    let b = task::spawn(|| waiter(1)).unwrap();
+    let c = task::spawn(|| waiter(2)).unwrap();
+    task::yield_now();
+    assert_eq!(QUEUE.len(), 3);
+    QUEUE.wake_one_with(|first, waiting| {
+      assert_eq!((first, waiting), (Some(a.id()), 3));
+      READY.store(true, Ordering::SeqCst);
+    });
+    a.join();
+    b.join();
+    c.join();
+    assert_eq!(ORDER.load(Ordering::SeqCst), 3);
+    assert!(QUEUE.is_empty());
+  }
+
+  static TIMEOUT_QUEUE: WaitQueue = WaitQueue::new();
+  static OPEN: AtomicBool = AtomicBool::new(false);
+
+  #[test_case]
+  fn wait_with_timeout() {
+    let queue = WaitQueue::new();
+    let deadline = timer::ticks() + 3;
+    assert!(!queue.wait_until_timeout(|| false, deadline));
+    assert!(timer::ticks() >= deadline);
+    assert!(queue.is_empty());
+    assert!(queue.wait_until_timeout(|| true, 0));
+    // the waiter behind this one is only woken because it gives up
+    OPEN.store(true, Ordering::SeqCst);
+    let waiter = task::spawn(|| TIMEOUT_QUEUE.wait_until(|| OPEN.load(Ordering::SeqCst))).unwrap();
+    assert!(!TIMEOUT_QUEUE.wait_until_timeout(|| false, timer::ticks() + 5));
+    waiter.join();
+    assert!(TIMEOUT_QUEUE.is_empty());
+  }
+}
@@ -0,0 +1,37 @@
+// Error numbers returned from system calls, negated in rax.
+// The values match Linux so existing tooling understands them.
+// Reference: https://man7.org/linux/man-pages/man3/errno.3.html
+#[allow(dead_code, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+  EPERM   = 1,
+  ENOENT  = 2,
+  ESRCH   = 3,
+  EINTR   = 4,
+  EIO     = 5,
+  E2BIG   = 7,
+  ENOEXEC = 8,
+  EBADF   = 9,
+  ECHILD  = 10,
+  EAGAIN  = 11,
+  ENOMEM  = 12,
+  EFAULT  = 14,
+  EBUSY   = 16,
+  EEXIST  = 17,
+  EINVAL  = 22,
+  ENFILE  = 23,
+  EMFILE  = 24,
+  EPIPE   = 32,
+  ENOSYS  = 38,
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+// Errors are returned as -errno, which is never a valid return value
+pub fn encode(res: SyscallResult) -> u64 {
+  match res {
+    Ok(value) => value,
+    Err(errno) => -(errno as i64) as u64,
+  }
+}
@@ -0,0 +1,99 @@
+use super::{user_slice, user_slice_mut, Errno, SyscallFrame, SyscallResult};
+use crate::process::fd::MAX_FILES;
+use crate::process::file::PollFlags;
+use crate::process::signal::{self, SIGPIPE};
+use crate::process::{self, pipe};
+
+// read(fd, buf, len) -> the number of bytes read, 0 at the end of the
+// file. Blocks until there is something to read.
+pub fn sys_read(frame: &mut SyscallFrame) -> SyscallResult {
+  let file = process::with_files(|files| files.get(frame.arg(0) as usize))??;
+  let buf = user_slice_mut(frame.arg(1), frame.arg(2))?;
+  Ok(file.read(buf)? as u64)
+}
+
+// write(fd, buf, len) -> the number of bytes written. Writing to a pipe
+// without readers raises SIGPIPE, or fails with EPIPE if it is ignored.
+pub fn sys_write(frame: &mut SyscallFrame) -> SyscallResult {
+  let file = process::with_files(|files| files.get(frame.arg(0) as usize))??;
+  let buf = user_slice(frame.arg(1), frame.arg(2))?;
+  match file.write(buf) {
+    Err(Errno::EPIPE) => {
+      signal::with_current(|state| state.raise(SIGPIPE));
+      Err(Errno::EPIPE)
+    }
+    res => Ok(res? as u64),
+  }
+}
+
+// close(fd)
+pub fn sys_close(frame: &mut SyscallFrame) -> SyscallResult {
+  process::with_files(|files| files.close(frame.arg(0) as usize))??;
+  Ok(0)
+}
+
+// pipe(fds) stores the descriptors of the read and the write end of a
+// new pipe in fds, two 32 bit integers
+pub fn sys_pipe(frame: &mut SyscallFrame) -> SyscallResult {
+  let fds = user_slice_mut(frame.arg(0), 8)?;
+  let (reader, writer) = pipe::pipe()?;
+  let (read_fd, write_fd) = process::with_files(|files| {
+    let read_fd = files.insert(reader)?;
+    match files.insert(writer) {
+      Ok(write_fd) => Ok((read_fd, write_fd)),
+      Err(err) => {
+        files.close(read_fd).unwrap();
+        Err(err)
+      }
+    }
+  })??;
+  fds[..4].copy_from_slice(&(read_fd as u32).to_le_bytes());
+  fds[4..].copy_from_slice(&(write_fd as u32).to_le_bytes());
+  Ok(0)
+}
+
+// dup(fd) -> the lowest free descriptor, which refers to the same file
+pub fn sys_dup(frame: &mut SyscallFrame) -> SyscallResult {
+  let fd = process::with_files(|files| files.dup(frame.arg(0) as usize))??;
+  Ok(fd as u64)
+}
+
+// dup2(old, new) -> new, which refers to the file of old afterwards.
+// What new referred to before is closed.
+pub fn sys_dup2(frame: &mut SyscallFrame) -> SyscallResult {
+  let (old, new) = (frame.arg(0) as usize, frame.arg(1) as usize);
+  let fd = process::with_files(|files| files.dup2(old, new))??;
+  Ok(fd as u64)
+}
+
+// poll(fds, count) -> the number of ready descriptors. fds points to
+// count Linux struct pollfds, (fd: i32, events: i16, revents: i16).
+// Never blocks, it only reports what the files are ready for now.
+// Negative descriptors are skipped.
+pub fn sys_poll(frame: &mut SyscallFrame) -> SyscallResult {
+  match frame.arg(1) {
+    0 => return Ok(0),
+    count if count > MAX_FILES as u64 => return Err(Errno::EINVAL),
+    _ => {}
+  }
+  let fds = user_slice_mut(frame.arg(0), frame.arg(1) * 8)?;
+  let mut ready = 0;
+  for start in (0..fds.len()).step_by(8) {
+    let pollfd = &mut fds[start..start + 8];
+    let fd = i32::from_le_bytes([pollfd[0], pollfd[1], pollfd[2], pollfd[3]]);
+    let events = PollFlags::from_bits_truncate(u16::from_le_bytes([pollfd[4], pollfd[5]]));
+    let revents = if fd < 0 {
+      PollFlags::empty()
+    } else {
+      match process::with_files(|files| files.get(fd as usize))? {
+        Ok(file) => file.poll() & (events | PollFlags::ERR | PollFlags::HUP),
+        Err(_) => PollFlags::NVAL,
+      }
+    };
+    pollfd[6..].copy_from_slice(&revents.bits().to_le_bytes());
+    if !revents.is_empty() {
+      ready += 1;
+    }
+  }
+  Ok(ready)
+}
@@ -0,0 +1,126 @@
+use super::{read_u64, user_slice_mut, Errno, SyscallFrame, SyscallResult};
+use crate::ipc::capability::Rights;
+use crate::ipc::{self, Message, Page, MESSAGE_WORDS};
+use crate::mem::address_space;
+use crate::mem::page_table::PageFlags;
+use crate::mem::VirtAddr;
+use crate::process;
+
+// Messages in user memory are the words followed by the address of a
+// page. For sending it is the page to move along, 0 for none, and on
+// receiving it is where the page was mapped, 0 if there was none.
+const MESSAGE_SIZE: u64 = (MESSAGE_WORDS as u64 + 1) * 8;
+
+// port_create() -> a handle to a new port, with all rights
+pub fn sys_port_create(_: &mut SyscallFrame) -> SyscallResult {
+  let cap = ipc::create()?;
+  process::with_handles(|handles| handles.insert(cap))?
+}
+
+// port_call(handle, message, map_at) sends the message and waits for
+// the reply, which is stored in its place. A page which comes with the
+// reply is mapped at map_at, or dropped if that is 0 or taken.
+pub fn sys_port_call(frame: &mut SyscallFrame) -> SyscallResult {
+  let cap = process::with_handles(|handles| handles.get(frame.arg(0)))??;
+  cap.check(Rights::SEND)?;
+  let map_at = check_map_at(frame.arg(2))?;
+  let (request, page) = read_message(frame.arg(1))?;
+  let (reply, page) = ipc::call(&cap, request, page)?;
+  // EFAULT if the message was in the page which was sent
+  write_message(frame.arg(1), &reply, page, map_at)?;
+  Ok(0)
+}
+
+// port_receive(handle, message, map_at) -> a reply token. Waits for a
+// call and stores its message, a page with it is mapped at map_at like
+// for port_call.
+pub fn sys_port_receive(frame: &mut SyscallFrame) -> SyscallResult {
+  let cap = process::with_handles(|handles| handles.get(frame.arg(0)))??;
+  cap.check(Rights::RECEIVE)?;
+  let map_at = check_map_at(frame.arg(2))?;
+  user_slice_mut(frame.arg(1), MESSAGE_SIZE)?;
+  let (token, message, page) = ipc::receive(&cap)?;
+  write_message(frame.arg(1), &message, page, map_at)?;
+  Ok(token)
+}
+
+// port_reply(token, message) answers a received call
+pub fn sys_port_reply(frame: &mut SyscallFrame) -> SyscallResult {
+  let (message, page) = read_message(frame.arg(1))?;
+  ipc::reply(frame.arg(0), message, page)?;
+  Ok(0)
+}
+
+// handle_dup(handle, rights) -> a new handle for the same port with
+// some of the rights, at least one. Rights::SEND is 1 and
+// Rights::RECEIVE 2.
+pub fn sys_handle_dup(frame: &mut SyscallFrame) -> SyscallResult {
+  let rights = match frame.arg(1) {
+    bits if bits <= u8::MAX as u64 => Rights::from_bits(bits as u8),
+    _ => None,
+  };
+  // without any rights the handle would not keep the port alive
+  let rights = rights.filter(|rights| !rights.is_empty());
+  let rights = rights.ok_or(Errno::EINVAL)?;
+  let cap = process::with_handles(|handles| handles.get(frame.arg(0)))??;
+  let cap = cap.restrict(rights)?;
+  process::with_handles(|handles| handles.insert(cap))?
+}
+
+// handle_close(handle)
+pub fn sys_handle_close(frame: &mut SyscallFrame) -> SyscallResult {
+  process::with_handles(|handles| handles.close(frame.arg(0)))??;
+  Ok(0)
+}
+
+fn check_map_at(addr: u64) -> Result<Option<VirtAddr>, Errno> {
+  match addr {
+    0 => Ok(None),
+    addr if address_space::is_user_addr(addr) && addr % 0x1000 == 0 => {
+      Ok(Some(VirtAddr::new(addr)))
+    }
+    _ => Err(Errno::EINVAL),
+  }
+}
+
+// Reads a message and takes the page named in it out of the address
+// space of the current process
+fn read_message(ptr: u64) -> Result<(Message, Option<Page>), Errno> {
+  let bytes = user_slice_mut(ptr, MESSAGE_SIZE)?;
+  let mut message = Message::default();
+  for (i, word) in message.words.iter_mut().enumerate() {
+    *word = read_u64(bytes, i * 8);
+  }
+  let page = match read_u64(bytes, MESSAGE_WORDS * 8) {
+    0 => None,
+    addr if addr % 0x1000 == 0 => {
+      let frame = process::with_space(|space| space.unmap(VirtAddr::new(addr)))?;
+      Some(unsafe { Page::new(frame.ok_or(Errno::EFAULT)?) })
+    }
+    _ => return Err(Errno::EINVAL),
+  };
+  Ok((message, page))
+}
+
+// Stores message at ptr and maps page at map_at
+fn write_message(
+  ptr: u64,
+  message: &Message,
+  page: Option<Page>,
+  map_at: Option<VirtAddr>,
+) -> Result<(), Errno> {
+  let mut mapped = 0;
+  if let (Some(page), Some(addr)) = (page, map_at) {
+    let flags = PageFlags::WRITABLE | PageFlags::NON_EXECUTABLE;
+    let frame = page.into_frame();
+    match process::with_space(|space| space.map_frame(addr, frame, flags)) {
+      Ok(true) => mapped = addr.as_u64(),
+      _ => drop(unsafe { Page::new(frame) }),
+    }
+  }
+  let bytes = user_slice_mut(ptr, MESSAGE_SIZE)?;
+  for (i, word) in message.words.iter().chain(Some(&mapped)).enumerate() {
+    bytes[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+  }
+  Ok(())
+}
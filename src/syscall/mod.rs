@@ -0,0 +1,446 @@
+#![allow(dead_code)]
+use crate::cpu::regs::{Efer, EferFlags, RFlags, IA32_FMASK, IA32_LSTAR, IA32_STAR};
+use crate::interrupts::gdt;
+use crate::mem::page_table;
+use crate::mem::VirtAddr;
+use crate::percpu;
+use crate::process::signal as signals;
+use crate::timer;
+
+mod errno;
+mod file;
+mod ipc;
+mod process;
+mod sched;
+mod signal;
+mod time;
+
+pub use errno::{Errno, SyscallResult};
+
+/*
+  System calls are entered via the syscall instruction. The number
+  is passed in rax and up to six arguments in rdi, rsi, rdx, r10, r8
+  and r9, like on Linux. The result is returned in rax, negative
+  values are errors (see errno.rs). All other registers are preserved.
+  References:
+  https://wiki.osdev.org/SYSENTER#AMD:_SYSCALL.2FSYSRET
+  https://www.felixcloutier.com/x86/syscall
+  https://www.felixcloutier.com/x86/sysret
+*/
+
+// syscall numbers, index into SYSCALL_TABLE
+pub const SYS_DEBUG_WRITE: u64 = 0;
+pub const SYS_TICKS: u64 = 1;
+pub const SYS_SPAWN: u64 = 2;
+pub const SYS_EXIT: u64 = 3;
+pub const SYS_WAIT: u64 = 4;
+pub const SYS_FORK: u64 = 5;
+pub const SYS_EXECVE: u64 = 6;
+pub const SYS_KILL: u64 = 7;
+pub const SYS_SIGACTION: u64 = 8;
+pub const SYS_SIGPROCMASK: u64 = 9;
+pub const SYS_SIGRETURN: u64 = 10;
+pub const SYS_GETPID: u64 = 11;
+pub const SYS_READ: u64 = 12;
+pub const SYS_WRITE: u64 = 13;
+pub const SYS_CLOSE: u64 = 14;
+pub const SYS_PIPE: u64 = 15;
+pub const SYS_DUP: u64 = 16;
+pub const SYS_DUP2: u64 = 17;
+pub const SYS_POLL: u64 = 18;
+pub const SYS_PORT_CREATE: u64 = 19;
+pub const SYS_PORT_CALL: u64 = 20;
+pub const SYS_PORT_RECEIVE: u64 = 21;
+pub const SYS_PORT_REPLY: u64 = 22;
+pub const SYS_HANDLE_DUP: u64 = 23;
+pub const SYS_HANDLE_CLOSE: u64 = 24;
+pub const SYS_NICE: u64 = 25;
+pub const SYS_SET_RT_PRIORITY: u64 = 26;
+pub const SYS_GET_RT_PRIORITY: u64 = 27;
+pub const SYS_CLOCK_GETTIME: u64 = 28;
+pub const SYS_NANOSLEEP: u64 = 29;
+
+type SyscallHandler = fn(&mut SyscallFrame) -> SyscallResult;
+
+static SYSCALL_TABLE: [SyscallHandler; 30] = [
+  sys_debug_write,
+  sys_ticks,
+  process::sys_spawn,
+  process::sys_exit,
+  process::sys_wait,
+  process::sys_fork,
+  process::sys_execve,
+  signal::sys_kill,
+  signal::sys_sigaction,
+  signal::sys_sigprocmask,
+  signal::sys_sigreturn,
+  process::sys_getpid,
+  file::sys_read,
+  file::sys_write,
+  file::sys_close,
+  file::sys_pipe,
+  file::sys_dup,
+  file::sys_dup2,
+  file::sys_poll,
+  ipc::sys_port_create,
+  ipc::sys_port_call,
+  ipc::sys_port_receive,
+  ipc::sys_port_reply,
+  ipc::sys_handle_dup,
+  ipc::sys_handle_close,
+  sched::sys_nice,
+  sched::sys_set_rt_priority,
+  sched::sys_get_rt_priority,
+  time::sys_clock_gettime,
+  time::sys_nanosleep,
+];
+
+// Register state of the calling thread, pushed by syscall_entry
+#[derive(Debug, Default, Clone)]
+#[repr(C)]
+pub struct SyscallFrame {
+  pub r15:    u64,
+  pub r14:    u64,
+  pub r13:    u64,
+  pub r12:    u64,
+  pub r10:    u64,
+  pub r9:     u64,
+  pub r8:     u64,
+  pub rbp:    u64,
+  pub rdi:    u64,
+  pub rsi:    u64,
+  pub rdx:    u64,
+  pub rbx:    u64,
+  pub rax:    u64,
+  pub rflags: u64, // saved in r11 by the cpu
+  pub rip:    u64, // saved in rcx by the cpu
+  pub rsp:    u64,
+}
+
+impl SyscallFrame {
+  pub fn arg(&self, i: usize) -> u64 {
+    match i {
+      0 => self.rdi,
+      1 => self.rsi,
+      2 => self.rdx,
+      3 => self.r10,
+      4 => self.r8,
+      5 => self.r9,
+      _ => panic!("syscalls take at most 6 arguments"),
+    }
+  }
+}
+
+// rflags bits cleared on entry
+const SYSCALL_RFLAGS_MASK: u64 =
+  RFlags::TRAP_FLAG.bits() | RFlags::INTERRUPT_FLAG.bits() | RFlags::DIRECTION_FLAG.bits();
+
+// Has to be called on every cpu, after percpu::initialize
+pub fn initialize() {
+  let kernel_stack = unsafe { (*percpu::tss()).privilege_stack(0) };
+  assert_ne!(kernel_stack, 0, "no kernel stack in the TSS");
+  unsafe {
+    Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    // sysret adds 16 for cs and 8 for ss, see gdt.rs
+    let sysret_base = (gdt::KERNEL_DATA_SELECTOR | 3) as u64;
+    let syscall_base = gdt::KERNEL_CODE_SELECTOR as u64;
+    IA32_STAR.write((sysret_base << 48) | (syscall_base << 32));
+    IA32_LSTAR.write(syscall_entry as usize as u64);
+    IA32_FMASK.write(SYSCALL_RFLAGS_MASK);
+  }
+}
+
+// The cpu does not switch stacks on syscall so we have to do it
+// ourselves, before pushing the SyscallFrame. The kernel stack is
+// TSS.rsp[0] of this cpu, found through the per-cpu block once
+// swapgs has made it reachable. Interrupts are masked by SFMASK
+// until we are on the kernel stack.
+#[naked]
+unsafe extern "C" fn syscall_entry() {
+  asm!(
+    "
+    swapgs
+    mov gs:[{user_rsp}], rsp
+    mov rsp, gs:[{tss}]
+    mov rsp, [rsp + 4]
+    push qword ptr gs:[{user_rsp}]
+    push rcx
+    push r11
+    push rax
+    push rbx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    sti
+    call {dispatch}
+    cli
+    mov rdi, rsp
+    jmp {sysret}
+    ",
+    user_rsp = const percpu::USER_RSP_OFFSET,
+    tss = const percpu::TSS_OFFSET,
+    dispatch = sym dispatch,
+    sysret = sym sysret_frame,
+    options(noreturn)
+  );
+}
+
+// Returns to user mode with the registers in frame, like the end of
+// a system call. For threads which continue where another one made a
+// system call, i.e forked processes. Unsafe since frame has to be the
+// state of a user thread and interrupts have to be disabled.
+pub unsafe fn return_to_user(frame: &SyscallFrame) -> ! {
+  sysret_frame(frame);
+  unreachable!("sysret_frame returned");
+}
+
+// Pops the frame at rdi and swaps the user gs base back in
+#[naked]
+unsafe extern "C" fn sysret_frame(_frame: *const SyscallFrame) {
+  asm!(
+    "
+    mov rsp, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rbx
+    pop rax
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
+    ",
+    options(noreturn)
+  );
+}
+
+extern "C" fn dispatch(frame: &mut SyscallFrame) {
+  let res = match SYSCALL_TABLE.get(frame.rax as usize) {
+    Some(handler) => handler(frame),
+    None => Err(Errno::ENOSYS),
+  };
+  frame.rax = errno::encode(res);
+  signals::deliver_syscall(frame);
+}
+
+// Validates that [ptr, ptr+len) is user memory before the kernel touches it
+pub fn user_slice<'a>(ptr: u64, len: u64) -> Result<&'a [u8], Errno> {
+  let end = ptr.checked_add(len).ok_or(Errno::EFAULT)?;
+  let first_page = ptr & !0xfff;
+  let pages_accessible = (first_page..end)
+    .step_by(0x1000)
+    .all(|page| page_table::user_accessible(VirtAddr::new(page)));
+  if ptr == 0 || !pages_accessible {
+    return Err(Errno::EFAULT);
+  }
+  Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+// Like user_slice, but the pages also have to be writable
+pub fn user_slice_mut<'a>(ptr: u64, len: u64) -> Result<&'a mut [u8], Errno> {
+  user_slice(ptr, len)?;
+  let first_page = ptr & !0xfff;
+  let pages_writable = (first_page..ptr + len).step_by(0x1000).all(
+    |page| matches!(page_table::page_entry(VirtAddr::new(page)), Some(entry) if entry.writable()),
+  );
+  if !pages_writable {
+    return Err(Errno::EFAULT);
+  }
+  Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) })
+}
+
+// The little endian u64 at offset, e.g. in a user slice
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+  let mut buf = [0; 8];
+  buf.copy_from_slice(&bytes[offset..offset + 8]);
+  u64::from_le_bytes(buf)
+}
+
+fn sys_debug_write(frame: &mut SyscallFrame) -> SyscallResult {
+  let bytes = user_slice(frame.arg(0), frame.arg(1))?;
+  let s = core::str::from_utf8(bytes).map_err(|_| Errno::EINVAL)?;
+  dbg_no_ln!("{}", s);
+  Ok(bytes.len() as u64)
+}
+
+fn sys_ticks(_: &mut SyscallFrame) -> SyscallResult {
+  Ok(timer::ticks())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn call(nr: u64, args: &[u64]) -> u64 {
+    let arg = |i: usize| args.get(i).copied().unwrap_or(0);
+    let mut frame = SyscallFrame {
+      rax: nr,
+      rdi: arg(0),
+      rsi: arg(1),
+      rdx: arg(2),
+      r10: arg(3),
+      r8: arg(4),
+      r9: arg(5),
+      ..SyscallFrame::default()
+    };
+    dispatch(&mut frame);
+    frame.rax
+  }
+
+  #[test_case]
+  fn size_check() {
+    use core::mem::size_of;
+    assert_eq!(size_of::<SyscallFrame>(), 16 * 8);
+  }
+
+  #[test_case]
+  fn errno_encoding() {
+    assert_eq!(errno::encode(Ok(42)), 42);
+    assert_eq!(errno::encode(Err(Errno::ENOSYS)) as i64, -38);
+    assert_eq!(errno::encode(Err(Errno::EFAULT)) as i64, -14);
+  }
+
+  #[test_case]
+  fn unknown_syscall() {
+    assert_eq!(call(SYSCALL_TABLE.len() as u64, &[]) as i64, -38);
+    assert_eq!(call(u64::MAX, &[]) as i64, -38);
+  }
+
+  #[test_case]
+  fn dispatch_ticks() {
+    let before = timer::ticks();
+    assert!(call(SYS_TICKS, &[]) >= before);
+  }
+
+  #[test_case]
+  fn debug_write_rejects_kernel_memory() {
+    let msg = b"kernel memory";
+    let res = call(SYS_DEBUG_WRITE, &[msg.as_ptr() as u64, msg.len() as u64]);
+    assert_eq!(res as i64, -14);
+    assert_eq!(call(SYS_DEBUG_WRITE, &[0, 1]) as i64, -14);
+    assert_eq!(call(SYS_DEBUG_WRITE, &[u64::MAX, 2]) as i64, -14);
+  }
+
+  #[test_case]
+  fn process_syscalls_check_arguments() {
+    let path = b"/bin/anything";
+    let res = call(SYS_SPAWN, &[path.as_ptr() as u64, path.len() as u64]);
+    assert_eq!(res as i64, -14);
+    let res = call(SYS_SPAWN, &[0, 0, 0, 1000]);
+    assert_eq!(res as i64, -14); // the path is checked first
+                                 // the boot task is no process, so it waits for
+                                 // the kernel's children
+    assert_eq!(call(SYS_WAIT, &[-1i64 as u64, 0]) as i64, -10);
+    assert_eq!(call(SYS_WAIT, &[0, 0]) as i64, -22);
+    // kernel tasks cannot fork
+    assert_eq!(call(SYS_FORK, &[]) as i64, -1);
+    assert_eq!(call(SYS_EXECVE, &[0, 0]) as i64, -14);
+    assert_eq!(call(SYS_GETPID, &[]), 0);
+  }
+
+  #[test_case]
+  fn signal_syscalls_check_arguments() {
+    assert_eq!(call(SYS_KILL, &[0, 9]) as i64, -22);
+    assert_eq!(call(SYS_KILL, &[1, 32]) as i64, -22);
+    assert_eq!(call(SYS_KILL, &[12345, 0]) as i64, -3);
+    assert_eq!(call(SYS_SIGACTION, &[10, 0x1234]) as i64, -14);
+    // kernel tasks have no signal state
+    assert_eq!(call(SYS_SIGACTION, &[10, 1]) as i64, -1);
+    assert_eq!(call(SYS_SIGPROCMASK, &[0, 0]) as i64, -1);
+    assert_eq!(call(SYS_SIGRETURN, &[]) as i64, -1);
+  }
+
+  #[test_case]
+  fn file_syscalls_check_arguments() {
+    let mut buf = [0u8; 8];
+    let ptr = buf.as_mut_ptr() as u64;
+    // kernel tasks have no files
+    assert_eq!(call(SYS_READ, &[0, ptr, 8]) as i64, -9);
+    assert_eq!(call(SYS_WRITE, &[1, ptr, 8]) as i64, -9);
+    assert_eq!(call(SYS_CLOSE, &[0]) as i64, -9);
+    assert_eq!(call(SYS_DUP, &[0]) as i64, -9);
+    assert_eq!(call(SYS_DUP2, &[0, 1]) as i64, -9);
+    assert_eq!(call(SYS_PIPE, &[ptr]) as i64, -14);
+    assert_eq!(call(SYS_POLL, &[ptr, 1]) as i64, -14);
+    assert_eq!(call(SYS_POLL, &[ptr, 1000]) as i64, -22);
+    assert_eq!(call(SYS_POLL, &[0, 0]), 0);
+  }
+
+  #[test_case]
+  fn ipc_syscalls_check_arguments() {
+    let ports = crate::ipc::count();
+    let mut message = [0u64; 9];
+    let ptr = message.as_mut_ptr() as u64;
+    // kernel tasks have no handles
+    assert_eq!(call(SYS_PORT_CREATE, &[]) as i64, -9);
+    assert_eq!(crate::ipc::count(), ports);
+    assert_eq!(call(SYS_PORT_CALL, &[0x101, ptr, 0]) as i64, -9);
+    assert_eq!(call(SYS_PORT_RECEIVE, &[0x101, ptr, 0]) as i64, -9);
+    assert_eq!(call(SYS_PORT_REPLY, &[0x101, ptr]) as i64, -14);
+    assert_eq!(call(SYS_HANDLE_DUP, &[0x101, 1]) as i64, -9);
+    assert_eq!(call(SYS_HANDLE_DUP, &[0x101, 4]) as i64, -22);
+    assert_eq!(call(SYS_HANDLE_DUP, &[0x101, 0]) as i64, -22);
+    assert_eq!(call(SYS_HANDLE_CLOSE, &[0x101]) as i64, -9);
+  }
+
+  #[test_case]
+  fn sched_syscalls() {
+    let me = crate::task::current().unwrap();
+    assert_eq!(call(SYS_NICE, &[0]), 20);
+    assert_eq!(call(SYS_NICE, &[3]), 17);
+    assert_eq!(call(SYS_NICE, &[100]), 1);
+    // the priority can only be lowered
+    assert_eq!(call(SYS_NICE, &[-1i64 as u64]) as i64, -1);
+    assert_eq!(call(SYS_NICE, &[0]), 1);
+    assert_eq!(call(SYS_GET_RT_PRIORITY, &[]), 0);
+    assert_eq!(call(SYS_SET_RT_PRIORITY, &[100]) as i64, -22);
+    assert_eq!(call(SYS_SET_RT_PRIORITY, &[50]) as i64, -1);
+    crate::task::set_rt_priority(me, 50);
+    assert_eq!(call(SYS_SET_RT_PRIORITY, &[51]) as i64, -1);
+    assert_eq!(call(SYS_SET_RT_PRIORITY, &[10]), 0);
+    assert_eq!(call(SYS_GET_RT_PRIORITY, &[]), 10);
+    assert_eq!(call(SYS_SET_RT_PRIORITY, &[0]), 0);
+    assert_eq!(call(SYS_GET_RT_PRIORITY, &[]), 0);
+    crate::task::set_nice(me, 0);
+  }
+
+  #[test_case]
+  fn time_syscalls_check_arguments() {
+    let timespec = [0u64, 2_000_000];
+    let ptr = timespec.as_ptr() as u64;
+    assert_eq!(call(SYS_CLOCK_GETTIME, &[0, 0]) as i64, -22);
+    assert_eq!(
+      call(SYS_CLOCK_GETTIME, &[time::CLOCK_MONOTONIC, ptr]) as i64,
+      -14
+    );
+    assert_eq!(call(SYS_NANOSLEEP, &[ptr, 0]) as i64, -14);
+  }
+
+  #[test_case]
+  fn msrs_configured() {
+    assert!(Efer::read().contains(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    unsafe {
+      assert_eq!(IA32_LSTAR.read(), syscall_entry as usize as u64);
+      assert_eq!(IA32_STAR.read() >> 32, 0x0013_0008);
+      assert_eq!(IA32_FMASK.read(), 0x700);
+    }
+  }
+}
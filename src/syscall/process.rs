@@ -0,0 +1,102 @@
+use super::{read_u64, user_slice, user_slice_mut, Errno, SyscallFrame, SyscallResult};
+use crate::process::{self, ExitStatus, Pid};
+
+// The most arguments or environment variables a program can get
+const MAX_ARGS: usize = 16;
+
+// spawn(path, path_len, args, arg_count) -> pid. args points to
+// arg_count (pointer, length) pairs.
+pub fn sys_spawn(frame: &mut SyscallFrame) -> SyscallResult {
+  let path = user_slice(frame.arg(0), frame.arg(1))?;
+  let mut args: [&[u8]; MAX_ARGS] = [&[]; MAX_ARGS];
+  let arg_count = user_strings(frame.arg(2), frame.arg(3), &mut args)?;
+  let pid = process::spawn(path, &args[..arg_count])?;
+  Ok(pid.as_usize() as u64)
+}
+
+// fork() -> pid, 0 in the child
+pub fn sys_fork(frame: &mut SyscallFrame) -> SyscallResult {
+  let pid = process::fork(frame)?;
+  Ok(pid.as_usize() as u64)
+}
+
+// execve(path, path_len, args, arg_count, env, env_count), only returns
+// on errors. args and env are (pointer, length) pairs like for spawn.
+pub fn sys_execve(frame: &mut SyscallFrame) -> SyscallResult {
+  let path = user_slice(frame.arg(0), frame.arg(1))?;
+  let mut args: [&[u8]; MAX_ARGS] = [&[]; MAX_ARGS];
+  let arg_count = user_strings(frame.arg(2), frame.arg(3), &mut args)?;
+  let mut env: [&[u8]; MAX_ARGS] = [&[]; MAX_ARGS];
+  let env_count = user_strings(frame.arg(4), frame.arg(5), &mut env)?;
+  Err(process::exec(path, &args[..arg_count], &env[..env_count]))
+}
+
+// Reads count (pointer, length) pairs from ptr into strings, returns
+// the count
+fn user_strings(ptr: u64, count: u64, strings: &mut [&[u8]; MAX_ARGS]) -> Result<usize, Errno> {
+  if count > MAX_ARGS as u64 {
+    return Err(Errno::E2BIG);
+  }
+  let count = count as usize;
+  if count > 0 {
+    let pairs = user_slice(ptr, count as u64 * 16)?;
+    for (i, string) in strings.iter_mut().take(count).enumerate() {
+      *string = user_slice(read_u64(pairs, i * 16), read_u64(pairs, i * 16 + 8))?;
+    }
+  }
+  Ok(count)
+}
+
+// exit(code), never returns
+pub fn sys_exit(frame: &mut SyscallFrame) -> SyscallResult {
+  process::exit(frame.arg(0) as i32)
+}
+
+// getpid() -> pid, 0 for kernel tasks
+pub fn sys_getpid(_: &mut SyscallFrame) -> SyscallResult {
+  Ok(process::current().as_usize() as u64)
+}
+
+// wait(pid, status) -> pid. Waits for any child if pid is -1. The
+// status is stored like on Linux if the pointer is not null.
+pub fn sys_wait(frame: &mut SyscallFrame) -> SyscallResult {
+  let pid = match frame.arg(0) as i64 {
+    -1 => None,
+    pid if pid > 0 => Some(Pid::from_usize(pid as usize)),
+    _ => return Err(Errno::EINVAL),
+  };
+  let status_ptr = frame.arg(1);
+  // checked up front, a child must not be removed without reporting it
+  if status_ptr != 0 {
+    user_slice_mut(status_ptr, 4)?;
+  }
+  let (pid, status) = process::wait(pid)?;
+  if status_ptr != 0 {
+    let status = wait_status(status);
+    user_slice_mut(status_ptr, 4)?.copy_from_slice(&status.to_le_bytes());
+  }
+  Ok(pid.as_usize() as u64)
+}
+
+// The exit code is in bits 8 to 15 of a normal exit's status, the
+// signal in the low 7 bits if the process was killed by one
+// Reference: https://man7.org/linux/man-pages/man2/waitpid.2.html
+fn wait_status(status: ExitStatus) -> i32 {
+  match status {
+    ExitStatus::Exited(code) => (code & 0xff) << 8,
+    ExitStatus::Signaled(signal) => (signal & 0x7f) as i32,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn status_encoding() {
+    assert_eq!(wait_status(ExitStatus::Exited(0)), 0);
+    assert_eq!(wait_status(ExitStatus::Exited(42)), 42 << 8);
+    assert_eq!(wait_status(ExitStatus::Exited(-1)), 0xff << 8);
+    assert_eq!(wait_status(ExitStatus::Signaled(9)), 9);
+  }
+}
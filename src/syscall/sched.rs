@@ -0,0 +1,48 @@
+use super::{Errno, SyscallFrame, SyscallResult};
+use crate::task::{self, TaskId, MAX_NICE, MAX_RT_PRIORITY, MIN_NICE};
+
+// The scheduling settings are those of the calling task. A task may
+// only lower its priority, since a busy one with a higher priority
+// could starve everything else, even the keyboard and Ctrl-C. Only the
+// kernel raises priorities.
+
+// nice(increment) -> 20 - the new nice value, between 1 and 40 like
+// getpriority on Linux since negative results are errors. nice(0)
+// only returns it, a negative increment fails with EPERM.
+pub fn sys_nice(frame: &mut SyscallFrame) -> SyscallResult {
+  let me = current()?;
+  let increment = frame.arg(0) as i64;
+  if increment < 0 {
+    return Err(Errno::EPERM);
+  }
+  let nice = task::nice(me).ok_or(Errno::ESRCH)? as i64 + increment.min(40);
+  let nice = nice.clamp(MIN_NICE as i64, MAX_NICE as i64) as i8;
+  task::set_nice(me, nice);
+  Ok((20 - nice as i64) as u64)
+}
+
+// set_rt_priority(priority) lowers the priority of a real-time task,
+// or makes it fair again with 0. Raising it, or making a fair task
+// real-time, fails with EPERM.
+pub fn sys_set_rt_priority(frame: &mut SyscallFrame) -> SyscallResult {
+  let me = current()?;
+  let priority = frame.arg(0);
+  if priority > MAX_RT_PRIORITY as u64 {
+    return Err(Errno::EINVAL);
+  }
+  if priority > task::rt_priority(me).ok_or(Errno::ESRCH)? as u64 {
+    return Err(Errno::EPERM);
+  }
+  task::set_rt_priority(me, priority as u8);
+  Ok(0)
+}
+
+// get_rt_priority() -> the real-time priority, 0 for fair tasks
+pub fn sys_get_rt_priority(_: &mut SyscallFrame) -> SyscallResult {
+  let priority = task::rt_priority(current()?).ok_or(Errno::ESRCH)?;
+  Ok(priority as u64)
+}
+
+fn current() -> Result<TaskId, Errno> {
+  task::current().ok_or(Errno::ESRCH)
+}
@@ -0,0 +1,60 @@
+use super::{Errno, SyscallFrame, SyscallResult};
+use crate::mem::address_space;
+use crate::process::signal::{self, Action, SIG_DFL, SIG_IGN};
+use crate::process::{self, Pid};
+
+// kill(pid, signal). Signal 0 only checks that the process exists.
+pub fn sys_kill(frame: &mut SyscallFrame) -> SyscallResult {
+  let pid = match frame.arg(0) as i64 {
+    pid if pid > 0 => Pid::from_usize(pid as usize),
+    _ => return Err(Errno::EINVAL),
+  };
+  let signal = frame.arg(1);
+  if signal >= signal::NSIG as u64 {
+    return Err(Errno::EINVAL);
+  }
+  process::kill(pid, signal as u32)?;
+  Ok(0)
+}
+
+// sigaction(signal, handler, mask) -> the previous handler. handler is
+// SIG_DFL, SIG_IGN or a function taking the signal number, which runs
+// with the signals in mask blocked.
+pub fn sys_sigaction(frame: &mut SyscallFrame) -> SyscallResult {
+  let signal = frame.arg(0);
+  if signal >= signal::NSIG as u64 {
+    return Err(Errno::EINVAL);
+  }
+  let action = match frame.arg(1) {
+    SIG_DFL => Action::Default,
+    SIG_IGN => Action::Ignore,
+    addr if address_space::is_user_addr(addr) => Action::Handler {
+      addr,
+      mask: frame.arg(2),
+    },
+    _ => return Err(Errno::EFAULT),
+  };
+  let old = signal::with_current(|state| state.set_action(signal as u32, action));
+  match old.ok_or(Errno::EPERM)?? {
+    Action::Default => Ok(SIG_DFL),
+    Action::Ignore => Ok(SIG_IGN),
+    Action::Handler { addr, .. } => Ok(addr),
+  }
+}
+
+// sigprocmask(how, set) -> the previous mask. how is SIG_BLOCK,
+// SIG_UNBLOCK or SIG_SETMASK, signal n is bit n - 1 of the set.
+pub fn sys_sigprocmask(frame: &mut SyscallFrame) -> SyscallResult {
+  let (how, set) = (frame.arg(0), frame.arg(1));
+  let old = signal::with_current(|state| state.set_blocked(how, set));
+  old.ok_or(Errno::EPERM)?
+}
+
+// sigreturn(), made by the trampoline handlers return to. Does not
+// return to the caller but to where the signal interrupted the thread.
+pub fn sys_sigreturn(frame: &mut SyscallFrame) -> SyscallResult {
+  if signal::with_current(|_| ()).is_none() {
+    return Err(Errno::EPERM);
+  }
+  signal::sigreturn(frame.rsp)
+}
@@ -0,0 +1,52 @@
+use super::{read_u64, user_slice, user_slice_mut, Errno, SyscallFrame, SyscallResult};
+use crate::process::signal;
+use crate::task;
+use crate::timer;
+use core::time::Duration;
+
+// Clocks for clock_gettime, the numbers are those of Linux. There is
+// no wall clock yet, only the time since boot.
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+// Linux struct timespec, (seconds: i64, nanoseconds: i64)
+const TIMESPEC_SIZE: u64 = 16;
+
+// clock_gettime(clock, timespec) stores the time of the clock
+pub fn sys_clock_gettime(frame: &mut SyscallFrame) -> SyscallResult {
+  if frame.arg(0) != CLOCK_MONOTONIC {
+    return Err(Errno::EINVAL);
+  }
+  let timespec = user_slice_mut(frame.arg(1), TIMESPEC_SIZE)?;
+  let uptime = timer::uptime();
+  timespec[..8].copy_from_slice(&uptime.as_secs().to_le_bytes());
+  timespec[8..].copy_from_slice(&(uptime.subsec_nanos() as u64).to_le_bytes());
+  Ok(0)
+}
+
+// nanosleep(timespec, remaining) sleeps for at least the given time.
+// A signal ends it early with EINTR, and the time which was left is
+// stored in remaining unless it is null.
+pub fn sys_nanosleep(frame: &mut SyscallFrame) -> SyscallResult {
+  let timespec = user_slice(frame.arg(0), TIMESPEC_SIZE)?;
+  let (secs, nanos) = (read_u64(timespec, 0) as i64, read_u64(timespec, 8) as i64);
+  if secs < 0 || !(0..1_000_000_000).contains(&nanos) {
+    return Err(Errno::EINVAL);
+  }
+  let ticks = timer::duration_to_ticks(Duration::new(secs as u64, nanos as u32));
+  // plus one since the current tick is already partly over, as in task::sleep
+  let deadline = timer::ticks().saturating_add(ticks.saturating_add(1));
+  loop {
+    if signal::interrupted() {
+      if frame.arg(1) != 0 {
+        let remaining = user_slice_mut(frame.arg(1), TIMESPEC_SIZE)?;
+        let left = timer::ticks_to_duration(deadline.saturating_sub(timer::ticks()));
+        remaining[..8].copy_from_slice(&left.as_secs().to_le_bytes());
+        remaining[8..].copy_from_slice(&(left.subsec_nanos() as u64).to_le_bytes());
+      }
+      return Err(Errno::EINTR);
+    }
+    if !task::block_until(deadline) {
+      return Ok(0);
+    }
+  }
+}
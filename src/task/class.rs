@@ -0,0 +1,361 @@
+use super::MAX_TASKS;
+use crate::timer;
+
+/*
+  Scheduling classes, which decide what runs next. Real-time tasks
+  have a fixed priority from 1 to MAX_RT_PRIORITY and always run
+  before the others, the highest priority first and round-robin among
+  equal ones. All other tasks are fair: they share the cpu by their
+  virtual runtime, the time they ran scaled by the weight of their
+  nice value, and the one which ran least is next. A task which slept
+  has little virtual runtime, so an interactive task runs right away
+  when it wakes up, even while others keep the cpu busy. The running
+  fair task is preempted once it got ahead of the next one by more
+  than GRANULARITY, so the time slice only matters for real-time ones.
+
+  The queues are indexed by task slot and picking a task scans them,
+  which is cheap enough for MAX_TASKS tasks.
+  References:
+  https://docs.kernel.org/scheduler/sched-design-CFS.html
+  https://man7.org/linux/man-pages/man7/sched.7.html
+*/
+
+pub const MAX_RT_PRIORITY: u8 = 99;
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+const NICE_0_WEIGHT: u64 = 1024;
+const TICK_NANOS: u64 = 1_000_000_000 / timer::TICKS_PER_SECOND;
+// How much virtual runtime a waking task may be behind the others
+const SLEEPER_CREDIT: u64 = 10 * 1_000_000;
+// How far a task may get ahead of another one before that one runs
+const GRANULARITY: u64 = 1_000_000;
+
+// The weights of nice values, each step is about 10% of cpu time.
+// Taken from Linux, kernel/sched/core.c.
+#[rustfmt::skip]
+const WEIGHTS: [u64; 40] = [
+  /* -20 */ 88761, 71755, 56483, 46273, 36291,
+  /* -15 */ 29154, 23254, 18705, 14949, 11916,
+  /* -10 */  9548,  7620,  6100,  4904,  3906,
+  /*  -5 */  3121,  2501,  1991,  1586,  1277,
+  /*   0 */  1024,   820,   655,   526,   423,
+  /*   5 */   335,   272,   215,   172,   137,
+  /*  10 */   110,    87,    70,    56,    45,
+  /*  15 */    36,    29,    23,    18,    15,
+];
+
+// The scheduling state of a task
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Entity {
+  pub rt_priority: u8, // 0 for fair tasks
+  pub nice: i8,
+  pub vruntime: u64, // in weighted nanoseconds
+}
+
+impl Entity {
+  pub fn is_real_time(&self) -> bool {
+    self.rt_priority > 0
+  }
+
+  fn weight(&self) -> u64 {
+    WEIGHTS[(self.nice - MIN_NICE) as usize]
+  }
+}
+
+pub trait SchedClass {
+  // Queues a runnable task
+  fn enqueue(&mut self, slot: usize, entity: &mut Entity);
+  fn dequeue(&mut self, slot: usize);
+  // Takes the task to run next out of the queue
+  fn pick_next(&mut self) -> Option<usize>;
+  fn is_empty(&self) -> bool;
+  // Charges a tick to the running task, true if it should make room
+  // for a queued one
+  fn tick(&mut self, entity: &mut Entity) -> bool;
+  // Called before a yielding task is queued, so it runs after the
+  // others which are queued
+  fn yield_task(&mut self, entity: &mut Entity);
+  // True if the woken task should run instead of the running one
+  fn preempts(&self, woken: &Entity, running: &Entity) -> bool;
+}
+
+// Queued slots with a key each, the smallest key is next and equal
+// ones go in the order they were queued
+struct Queue {
+  keys:     [Option<(u64, u64)>; MAX_TASKS],
+  next_seq: u64,
+}
+
+impl Queue {
+  const fn new() -> Self {
+    Self {
+      keys:     [None; MAX_TASKS],
+      next_seq: 0,
+    }
+  }
+
+  fn push(&mut self, slot: usize, key: u64) {
+    assert!(self.keys[slot].is_none(), "task queued twice");
+    self.keys[slot] = Some((key, self.next_seq));
+    self.next_seq += 1;
+  }
+
+  fn remove(&mut self, slot: usize) {
+    self.keys[slot] = None;
+  }
+
+  fn first(&self) -> Option<(usize, u64)> {
+    let (slot, (key, _)) = self
+      .keys
+      .iter()
+      .enumerate()
+      .filter_map(|(slot, key)| key.map(|key| (slot, key)))
+      .min_by_key(|&(_, key)| key)?;
+    Some((slot, key))
+  }
+
+  fn max_key(&self) -> Option<u64> {
+    self.keys.iter().flatten().map(|&(key, _)| key).max()
+  }
+
+  fn is_empty(&self) -> bool {
+    self.keys.iter().all(Option::is_none)
+  }
+}
+
+pub struct RealTime {
+  queue: Queue,
+}
+
+impl RealTime {
+  pub const fn new() -> Self {
+    Self {
+      queue: Queue::new(),
+    }
+  }
+}
+
+impl SchedClass for RealTime {
+  fn enqueue(&mut self, slot: usize, entity: &mut Entity) {
+    self
+      .queue
+      .push(slot, (MAX_RT_PRIORITY - entity.rt_priority) as u64);
+  }
+
+  fn dequeue(&mut self, slot: usize) {
+    self.queue.remove(slot);
+  }
+
+  fn pick_next(&mut self) -> Option<usize> {
+    let (slot, _) = self.queue.first()?;
+    self.queue.remove(slot);
+    Some(slot)
+  }
+
+  fn is_empty(&self) -> bool {
+    self.queue.is_empty()
+  }
+
+  // Round-robin only needs the time slice
+  fn tick(&mut self, _: &mut Entity) -> bool {
+    false
+  }
+
+  fn yield_task(&mut self, _: &mut Entity) {}
+
+  fn preempts(&self, woken: &Entity, running: &Entity) -> bool {
+    woken.rt_priority > running.rt_priority
+  }
+}
+
+pub struct Fair {
+  queue: Queue,
+  // Never decreases, where new and waking tasks are placed
+  min_vruntime: u64,
+}
+
+impl Fair {
+  pub const fn new() -> Self {
+    Self {
+      queue: Queue::new(),
+      min_vruntime: 0,
+    }
+  }
+
+  pub fn min_vruntime(&self) -> u64 {
+    self.min_vruntime
+  }
+
+  fn update_min_vruntime(&mut self, vruntime: u64) {
+    let first = self.queue.first().map_or(vruntime, |(_, key)| key);
+    self.min_vruntime = self.min_vruntime.max(vruntime.min(first));
+  }
+}
+
+impl SchedClass for Fair {
+  // A task which slept gets a little credit, but not the whole time
+  // it slept
+  fn enqueue(&mut self, slot: usize, entity: &mut Entity) {
+    let earliest = self.min_vruntime.saturating_sub(SLEEPER_CREDIT);
+    entity.vruntime = entity.vruntime.max(earliest);
+    self.queue.push(slot, entity.vruntime);
+  }
+
+  fn dequeue(&mut self, slot: usize) {
+    self.queue.remove(slot);
+  }
+
+  fn pick_next(&mut self) -> Option<usize> {
+    let (slot, vruntime) = self.queue.first()?;
+    self.queue.remove(slot);
+    self.min_vruntime = self.min_vruntime.max(vruntime);
+    Some(slot)
+  }
+
+  fn is_empty(&self) -> bool {
+    self.queue.is_empty()
+  }
+
+  fn tick(&mut self, entity: &mut Entity) -> bool {
+    entity.vruntime += TICK_NANOS * NICE_0_WEIGHT / entity.weight();
+    self.update_min_vruntime(entity.vruntime);
+    match self.queue.first() {
+      Some((_, first)) => entity.vruntime > first + GRANULARITY,
+      None => false,
+    }
+  }
+
+  fn yield_task(&mut self, entity: &mut Entity) {
+    let last = self.queue.max_key().unwrap_or(0);
+    entity.vruntime = entity.vruntime.max(last);
+  }
+
+  fn preempts(&self, woken: &Entity, running: &Entity) -> bool {
+    woken.vruntime + GRANULARITY < running.vruntime
+  }
+}
+
+// The classes in the order they are asked for a task
+pub struct Classes {
+  pub real_time: RealTime,
+  pub fair:      Fair,
+}
+
+impl Classes {
+  pub const fn new() -> Self {
+    Self {
+      real_time: RealTime::new(),
+      fair:      Fair::new(),
+    }
+  }
+
+  // The class of a task
+  pub fn of(&mut self, entity: &Entity) -> &mut dyn SchedClass {
+    if entity.is_real_time() {
+      &mut self.real_time
+    } else {
+      &mut self.fair
+    }
+  }
+
+  pub fn pick_next(&mut self) -> Option<usize> {
+    self.real_time.pick_next().or_else(|| self.fair.pick_next())
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.real_time.is_empty() && self.fair.is_empty()
+  }
+
+  // Real-time tasks preempt fair ones, within a class it decides
+  pub fn preempts(&self, woken: &Entity, running: &Entity) -> bool {
+    match (woken.is_real_time(), running.is_real_time()) {
+      (true, false) => true,
+      (false, true) => false,
+      (true, true) => self.real_time.preempts(woken, running),
+      (false, false) => self.fair.preempts(woken, running),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn real_time(rt_priority: u8) -> Entity {
+    Entity {
+      rt_priority,
+      ..Entity::default()
+    }
+  }
+
+  #[test_case]
+  fn real_time_order() {
+    let mut classes = Classes::new();
+    let mut fair = Entity::default();
+    classes.of(&fair).enqueue(0, &mut fair);
+    for (slot, &priority) in [5, 50, 5, 99, 50].iter().enumerate() {
+      let mut entity = real_time(priority);
+      classes.of(&entity).enqueue(slot + 1, &mut entity);
+    }
+    classes.real_time.dequeue(2);
+    let mut order = [0; 5];
+    for slot in order.iter_mut() {
+      *slot = classes.pick_next().unwrap();
+    }
+    // fair tasks come last
+    assert_eq!(order, [4, 5, 1, 3, 0]);
+    assert!(classes.is_empty());
+    assert_eq!(classes.pick_next(), None);
+    assert!(classes.preempts(&real_time(1), &fair));
+    assert!(!classes.preempts(&fair, &real_time(1)));
+    assert!(classes.preempts(&real_time(2), &real_time(1)));
+    assert!(!classes.preempts(&real_time(2), &real_time(2)));
+  }
+
+  #[test_case]
+  fn fair_shares_by_nice() {
+    // runs two tasks for a while, picking one every tick
+    let mut fair = Fair::new();
+    let mut entities = [Entity::default(), Entity::default()];
+    entities[1].nice = 5;
+    let mut ran = [0; 2];
+    fair.enqueue(0, &mut entities[0]);
+    fair.enqueue(1, &mut entities[1]);
+    for _ in 0..1000 {
+      let slot = fair.pick_next().unwrap();
+      fair.tick(&mut entities[slot]);
+      ran[slot] += 1;
+      fair.enqueue(slot, &mut entities[slot]);
+    }
+    // by their weights, 1024 to 335
+    assert!((740..=770).contains(&ran[0]), "{:?}", ran);
+    assert_eq!(ran[0] + ran[1], 1000);
+  }
+
+  #[test_case]
+  fn sleepers_run_first() {
+    let mut fair = Fair::new();
+    let mut busy = Entity::default();
+    let mut sleeper = Entity::default();
+    fair.enqueue(0, &mut busy);
+    assert_eq!(fair.pick_next(), Some(0));
+    for _ in 0..100 {
+      fair.tick(&mut busy);
+    }
+    // the sleeper did not run, but only gets a little credit for it
+    fair.enqueue(1, &mut sleeper);
+    assert_eq!(sleeper.vruntime, fair.min_vruntime() - SLEEPER_CREDIT);
+    assert!(fair.preempts(&sleeper, &busy));
+    assert!(!fair.preempts(&busy, &sleeper));
+    assert!(fair.tick(&mut busy));
+    fair.enqueue(0, &mut busy);
+    assert_eq!(fair.pick_next(), Some(1));
+    // yielding puts it behind the busy one
+    fair.yield_task(&mut sleeper);
+    fair.enqueue(1, &mut sleeper);
+    assert_eq!(fair.pick_next(), Some(0));
+    assert_eq!(fair.pick_next(), Some(1));
+    assert!(fair.is_empty());
+  }
+}
@@ -0,0 +1,293 @@
+use super::TaskId;
+use crate::sync::IrqSafeMutex;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::mem::{align_of, size_of, MaybeUninit};
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+/*
+  A cooperative executor for kernel futures, run by one task. Futures
+  are stored in a fixed number of slots, each large enough for the
+  state of a typical async fn, and never move once spawned. A waker
+  sets the bit of its slot in a ready mask and wakes the executor
+  task. Both are safe from interrupt handlers, which is how drivers
+  hand events to async code without doing the work in the handler.
+  When nothing is ready the executor task blocks, and once no other
+  task can run the idle task halts the cpu until the next interrupt.
+  Reference: https://os.phil-opp.com/async-await/
+*/
+
+pub const MAX_FUTURES: usize = 64; // one bit each in READY
+const FUTURE_SIZE: usize = 1024;
+const FUTURE_ALIGN: usize = 16;
+
+#[repr(C, align(16))]
+struct Storage(UnsafeCell<MaybeUninit<[u8; FUTURE_SIZE]>>);
+
+// Safe since a slot is only written by spawn while it is free and
+// only accessed by the executor task while it is taken
+unsafe impl Sync for Storage {}
+
+// The type erased future in a slot
+#[derive(Clone, Copy)]
+struct Slot {
+  poll: unsafe fn(*mut u8, &mut Context) -> Poll<()>,
+  drop: unsafe fn(*mut u8),
+}
+
+static SLOTS: IrqSafeMutex<[Option<Slot>; MAX_FUTURES]> = IrqSafeMutex::new([None; MAX_FUTURES]);
+static STORAGE: [Storage; MAX_FUTURES] = {
+  #[allow(clippy::declare_interior_mutable_const)]
+  const EMPTY: Storage = Storage(UnsafeCell::new(MaybeUninit::uninit()));
+  [EMPTY; MAX_FUTURES]
+};
+static READY: AtomicU64 = AtomicU64::new(0);
+// The task running the executor, 0 if it is not running
+static EXECUTOR: AtomicUsize = AtomicUsize::new(0);
+
+unsafe fn poll_future<F: Future<Output = ()>>(ptr: *mut u8, cx: &mut Context) -> Poll<()> {
+  Pin::new_unchecked(&mut *(ptr as *mut F)).poll(cx)
+}
+
+unsafe fn drop_future<F>(ptr: *mut u8) {
+  ptr::drop_in_place(ptr as *mut F);
+}
+
+fn storage_ptr(slot: usize) -> *mut u8 {
+  STORAGE[slot].0.get() as *mut u8
+}
+
+// Runs future on the executor. Returns false if all slots are taken.
+pub fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) -> bool {
+  assert!(
+    size_of::<F>() <= FUTURE_SIZE && align_of::<F>() <= FUTURE_ALIGN,
+    "future too large: {} bytes",
+    size_of::<F>()
+  );
+  let slot = {
+    let mut slots = SLOTS.lock();
+    let slot = match slots.iter().position(Option::is_none) {
+      Some(slot) => slot,
+      None => return false,
+    };
+    unsafe { ptr::write(storage_ptr(slot) as *mut F, future) };
+    slots[slot] = Some(Slot {
+      poll: poll_future::<F>,
+      drop: drop_future::<F>,
+    });
+    slot
+  };
+  mark_ready(slot);
+  true
+}
+
+fn mark_ready(slot: usize) {
+  READY.fetch_or(1 << slot, Ordering::SeqCst);
+  let executor = EXECUTOR.load(Ordering::SeqCst);
+  if executor != 0 {
+    super::wake(TaskId(executor));
+  }
+}
+
+// Wakers are just the slot number, stale ones can only cause an extra
+// poll of whatever future is in the slot later on
+const WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake, wake, drop_waker);
+
+fn raw_waker(slot: usize) -> RawWaker {
+  RawWaker::new(slot as *const (), &WAKER_VTABLE)
+}
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+  raw_waker(data as usize)
+}
+
+unsafe fn wake(data: *const ()) {
+  mark_ready(data as usize);
+}
+
+unsafe fn drop_waker(_: *const ()) {}
+
+fn poll_slot(slot: usize) {
+  let future = match SLOTS.lock()[slot] {
+    Some(future) => future,
+    None => return,
+  };
+  let waker = unsafe { Waker::from_raw(raw_waker(slot)) };
+  let mut cx = Context::from_waker(&waker);
+  let ptr = storage_ptr(slot);
+  if unsafe { (future.poll)(ptr, &mut cx) }.is_ready() {
+    unsafe { (future.drop)(ptr) };
+    SLOTS.lock()[slot] = None;
+  }
+}
+
+// Polls futures as they become ready, forever. Has to be called from
+// a task, and only from one.
+pub fn run() -> ! {
+  let me = super::current().expect("the executor has to run in a task");
+  let registered = EXECUTOR.compare_exchange(0, me.0, Ordering::SeqCst, Ordering::SeqCst);
+  assert!(registered.is_ok(), "the executor is already running");
+  loop {
+    let ready = READY.swap(0, Ordering::SeqCst);
+    if ready == 0 {
+      // a wake between the swap and here makes block return at once
+      super::block();
+      continue;
+    }
+    for slot in (0..MAX_FUTURES).filter(|slot| ready & 1 << slot != 0) {
+      poll_slot(slot);
+    }
+  }
+}
+
+// An asynchronous sequence of values, like an Iterator
+pub trait Stream {
+  type Item;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>>;
+}
+
+pub trait StreamExt: Stream {
+  // The next value, None once the stream has ended
+  fn next(&mut self) -> Next<'_, Self>
+  where
+    Self: Unpin,
+  {
+    Next { stream: self }
+  }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+pub struct Next<'a, S: ?Sized> {
+  stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+  type Output = Option<S::Item>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    Pin::new(&mut *self.stream).poll_next(cx)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{interrupts, task, timer};
+  use core::sync::atomic::AtomicBool;
+
+  fn start_executor() {
+    static STARTED: AtomicBool = AtomicBool::new(false);
+    if !STARTED.swap(true, Ordering::SeqCst) {
+      drop(task::spawn(|| run()).unwrap());
+    }
+  }
+
+  // Interrupts are enabled while waiting, for the timer
+  fn wait_for(flag: &AtomicBool) {
+    interrupts::enable();
+    while !flag.load(Ordering::SeqCst) {
+      task::yield_now();
+    }
+    interrupts::disable();
+  }
+
+  #[test_case]
+  fn raw_waker_roundtrip() {
+    let waker = unsafe { Waker::from_raw(raw_waker(5)) };
+    let clone = waker.clone();
+    assert!(waker.will_wake(&clone));
+    READY.fetch_and(!(1 << 5), Ordering::SeqCst);
+    clone.wake();
+    assert_ne!(READY.load(Ordering::SeqCst) & 1 << 5, 0);
+  }
+
+  static DONE: AtomicBool = AtomicBool::new(false);
+
+  #[test_case]
+  fn run_async_block() {
+    start_executor();
+    async fn add(a: u64, b: u64) -> u64 {
+      a + b
+    }
+    assert!(spawn(async {
+      let sum = add(1, 2).await;
+      DONE.store(sum == 3, Ordering::SeqCst);
+    }));
+    wait_for(&DONE);
+  }
+
+  // Pending until WOKEN is set, registers its waker in WAKER
+  struct WaitForTimer;
+
+  static WAKER: IrqSafeMutex<Option<Waker>> = IrqSafeMutex::new(None);
+  static WOKEN: AtomicBool = AtomicBool::new(false);
+  static TIMER_DONE: AtomicBool = AtomicBool::new(false);
+
+  impl Future for WaitForTimer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+      let mut waker = WAKER.lock();
+      if WOKEN.load(Ordering::SeqCst) {
+        return Poll::Ready(());
+      }
+      *waker = Some(cx.waker().clone());
+      Poll::Pending
+    }
+  }
+
+  #[test_case]
+  fn wake_from_interrupt() {
+    start_executor();
+    assert!(spawn(async {
+      WaitForTimer.await;
+      TIMER_DONE.store(true, Ordering::SeqCst);
+    }));
+    // the timer callback runs in the timer interrupt
+    timer::schedule_in(2, || {
+      let mut waker = WAKER.lock();
+      WOKEN.store(true, Ordering::SeqCst);
+      if let Some(waker) = waker.take() {
+        waker.wake();
+      }
+    })
+    .unwrap();
+    wait_for(&TIMER_DONE);
+  }
+
+  struct Countdown(u32);
+
+  impl Stream for Countdown {
+    type Item = u32;
+
+    fn poll_next(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<Option<u32>> {
+      if self.0 == 0 {
+        return Poll::Ready(None);
+      }
+      self.0 -= 1;
+      Poll::Ready(Some(self.0))
+    }
+  }
+
+  static SUM: AtomicUsize = AtomicUsize::new(0);
+  static STREAM_DONE: AtomicBool = AtomicBool::new(false);
+
+  #[test_case]
+  fn stream_next() {
+    start_executor();
+    assert!(spawn(async {
+      let mut countdown = Countdown(5);
+      while let Some(n) = countdown.next().await {
+        SUM.fetch_add(n as usize, Ordering::SeqCst);
+      }
+      STREAM_DONE.store(true, Ordering::SeqCst);
+    }));
+    wait_for(&STREAM_DONE);
+    assert_eq!(SUM.load(Ordering::SeqCst), 4 + 3 + 2 + 1);
+  }
+}
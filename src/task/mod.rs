@@ -0,0 +1,913 @@
+#![allow(dead_code)]
+use crate::cpu::fpu::{self, FpuState};
+use crate::cpu::regs::Cr3;
+use crate::interrupts;
+use crate::ipc;
+use crate::mem::page_table;
+use crate::mem::{PhysAddr, VirtAddr};
+use crate::percpu;
+use crate::sync::IrqSafeMutex;
+use crate::timer;
+use class::{Classes, Entity};
+use core::fmt;
+use core::time::Duration;
+
+mod class;
+pub mod executor;
+mod switch;
+
+pub use class::{MAX_NICE, MAX_RT_PRIORITY, MIN_NICE};
+
+/*
+  Kernel threads, called tasks, and a preemptive scheduler. Every
+  task has its own stack, saved registers and FPU state. Runnable
+  tasks are queued in their scheduling class, see class.rs, which
+  picks the next one. The running task is queued again when it yields,
+  when its time slice is used up at a timer tick, when its class says
+  so, or at the next tick after a task which should run instead woke
+  up. The idle task runs when nothing is runnable and halts until the
+  next interrupt. A task can block with a deadline, it is woken at the
+  first tick at or after it, which is what sleep is built on.
+
+  The task table has a fixed number of slots, each with a stack which
+  is mapped the first time it is used and then kept for the next task
+  in the slot. Task ids contain a generation count, so a stale id
+  never refers to a newer task in the same slot.
+
+  Tasks running user code have an address space, which is switched to
+  along with the task, the others run on the kernel page table. The
+  stack of a task is also where the cpu switches to when it enters the
+  kernel from user mode.
+
+  Tasks only run on the bootstrap processor for now, it is the only
+  one getting timer interrupts. The code which was running when the
+  scheduler was initialized becomes the boot task.
+  New tasks get the scheduling class and nice value of the task which
+  spawned them.
+  Reference: https://wiki.osdev.org/Scheduling_Algorithms
+*/
+
+pub const MAX_TASKS: usize = 64;
+const STACKS_START: u64 = 0x6100_0000_0000;
+const STACK_SIZE: u64 = 4096 * 4;
+const STACK_STRIDE: u64 = STACK_SIZE + 4096; // with a guard page
+const TIME_SLICE_MS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskId(usize);
+
+impl TaskId {
+  fn slot(self) -> usize {
+    self.0 % MAX_TASKS
+  }
+
+  // For ids stored as plain integers, like the current task of a cpu
+  pub fn from_usize(id: usize) -> Self {
+    Self(id)
+  }
+
+  pub fn as_usize(self) -> usize {
+    self.0
+  }
+}
+
+impl fmt::Display for TaskId {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+  Runnable,
+  Running,
+  Blocked,
+  Exited,
+}
+
+struct Task {
+  id: TaskId,
+  state: State,
+  rsp: u64, // saved while not running
+  entry: Option<fn()>,
+  fpu: FpuState,
+  woken: bool,          // a wake arrived while the task was not blocked
+  wake_at: Option<u64>, // the deadline of a timed block, in ticks
+  joiner: Option<TaskId>,
+  detached: bool,
+  level_four: Option<PhysAddr>, // None for the kernel page table
+  kernel_stack: u64,            // the top of the stack
+  entity: Entity,
+}
+
+struct Scheduler {
+  tasks: [Option<Task>; MAX_TASKS],
+  generations: [usize; MAX_TASKS],
+  classes: Classes,
+  running: bool,
+  current: usize,
+  idle: usize,
+  slice_left: u64,
+  // a task which became runnable should preempt the current one
+  need_resched: bool,
+  // no blocked task has an earlier deadline
+  next_wakeup: u64,
+  // an exited task whose slot is freed once we are off its stack
+  reap: Option<usize>,
+}
+
+impl Scheduler {
+  const fn new() -> Self {
+    const NONE: Option<Task> = None;
+    Self {
+      tasks: [NONE; MAX_TASKS],
+      generations: [0; MAX_TASKS],
+      classes: Classes::new(),
+      running: false,
+      current: 0,
+      idle: 0,
+      slice_left: 0,
+      need_resched: false,
+      next_wakeup: u64::MAX,
+      reap: None,
+    }
+  }
+
+  fn task(&mut self, id: TaskId) -> Option<&mut Task> {
+    self.tasks[id.slot()].as_mut().filter(|task| task.id == id)
+  }
+
+  fn current_task(&mut self) -> &mut Task {
+    self.tasks[self.current].as_mut().expect("no current task")
+  }
+
+  // Takes a free slot for a task which has not run yet
+  fn insert(&mut self, entry: Option<fn()>) -> Option<usize> {
+    let slot = self.tasks.iter().position(Option::is_none)?;
+    self.generations[slot] += 1;
+    self.tasks[slot] = Some(Task {
+      id: TaskId(self.generations[slot] * MAX_TASKS + slot),
+      state: State::Runnable,
+      rsp: 0,
+      entry,
+      fpu: FpuState::new(),
+      woken: false,
+      wake_at: None,
+      joiner: None,
+      detached: false,
+      level_four: None,
+      kernel_stack: stack_bottom(slot) + STACK_SIZE,
+      entity: Entity::default(),
+    });
+    Some(slot)
+  }
+
+  // Puts a runnable task into the queue of its class
+  fn enqueue(&mut self, slot: usize) {
+    let task = self.tasks[slot].as_mut().unwrap();
+    self
+      .classes
+      .of(&task.entity)
+      .enqueue(slot, &mut task.entity);
+  }
+
+  // Lets a task which became runnable preempt the current one at the
+  // next tick, if its class says so
+  fn check_preempt(&mut self, slot: usize) {
+    if !self.running || self.current == self.idle {
+      return;
+    }
+    let woken = &self.tasks[slot].as_ref().unwrap().entity;
+    let running = &self.tasks[self.current].as_ref().unwrap().entity;
+    if self.classes.preempts(woken, running) {
+      self.need_resched = true;
+    }
+  }
+
+  // Gives the task in slot a fresh stack which starts at start
+  fn prepare_stack(&mut self, slot: usize, start: extern "C" fn() -> !) {
+    let bottom = stack_bottom(slot);
+    for page in (bottom..bottom + STACK_SIZE).step_by(4096) {
+      page_table::page_map_addr(VirtAddr::new(page));
+    }
+    let task = self.tasks[slot].as_mut().unwrap();
+    task.rsp = unsafe { switch::initial_stack(bottom + STACK_SIZE, start) };
+  }
+
+  fn spawn(&mut self, entry: fn(), level_four: Option<PhysAddr>) -> Option<TaskId> {
+    let slot = self.insert(Some(entry))?;
+    let mut entity = if self.running {
+      self.current_task().entity
+    } else {
+      Entity::default()
+    };
+    entity.vruntime = self.classes.fair.min_vruntime();
+    let task = self.tasks[slot].as_mut().unwrap();
+    task.level_four = level_four;
+    task.entity = entity;
+    self.prepare_stack(slot, task_start);
+    self.enqueue(slot);
+    Some(self.tasks[slot].as_ref().unwrap().id)
+  }
+
+  // Changes the scheduling class or nice value of a task. False if the
+  // task is gone.
+  fn set_entity(&mut self, id: TaskId, f: impl FnOnce(&mut Entity)) -> bool {
+    let slot = id.slot();
+    let task = match self.tasks[slot].as_mut().filter(|task| task.id == id) {
+      Some(task) => task,
+      None => return false,
+    };
+    let queued = task.state == State::Runnable;
+    if queued {
+      self.classes.of(&task.entity).dequeue(slot);
+    }
+    f(&mut task.entity);
+    if queued {
+      self.enqueue(slot);
+    }
+    // another task may have to run now, or this one
+    self.need_resched = true;
+    true
+  }
+
+  fn free(&mut self, slot: usize) {
+    let task = self.tasks[slot].take().expect("freeing a free slot");
+    assert_eq!(task.state, State::Exited);
+  }
+
+  // Makes a blocked task runnable, or lets its next block return
+  // right away if it is not blocked. False if the task is gone.
+  fn wake(&mut self, id: TaskId) -> bool {
+    let task = match self.task(id) {
+      Some(task) => task,
+      None => return false,
+    };
+    match task.state {
+      State::Blocked => self.unblock(id.slot()),
+      State::Exited => return false,
+      State::Runnable | State::Running => task.woken = true,
+    }
+    true
+  }
+
+  fn unblock(&mut self, slot: usize) {
+    let task = self.tasks[slot].as_mut().unwrap();
+    task.state = State::Runnable;
+    task.wake_at = None;
+    self.enqueue(slot);
+    self.check_preempt(slot);
+  }
+
+  // True if the current task has to be switched away from. It does
+  // not block if the deadline, if any, has passed.
+  fn block_current(&mut self, deadline: Option<u64>) -> bool {
+    let task = self.current_task();
+    if task.woken {
+      task.woken = false;
+      return false;
+    }
+    if let Some(deadline) = deadline {
+      if deadline <= timer::ticks() {
+        return false;
+      }
+      self.next_wakeup = self.next_wakeup.min(deadline);
+    }
+    let task = self.current_task();
+    task.state = State::Blocked;
+    task.wake_at = deadline;
+    true
+  }
+
+  // Wakes the blocked tasks whose deadline has come
+  fn wake_sleepers(&mut self, now: u64) {
+    if now < self.next_wakeup {
+      return;
+    }
+    self.next_wakeup = u64::MAX;
+    for slot in 0..MAX_TASKS {
+      let deadline = match &self.tasks[slot] {
+        Some(task) if task.state == State::Blocked => task.wake_at,
+        _ => None,
+      };
+      match deadline {
+        Some(deadline) if deadline <= now => self.unblock(slot),
+        Some(deadline) => self.next_wakeup = self.next_wakeup.min(deadline),
+        None => {}
+      }
+    }
+  }
+
+  // Picks the next task and returns where to save the stack pointer
+  // of the current one and the stack pointer to switch to, if any
+  fn switch_from_current(&mut self) -> Option<(*mut u64, u64)> {
+    if !self.running {
+      return None;
+    }
+    let prev = self.current;
+    let (idle, prev_state) = (self.idle, self.current_task().state);
+    if prev_state == State::Running && prev != idle {
+      self.current_task().state = State::Runnable;
+      self.enqueue(prev);
+    }
+    let next = self.classes.pick_next().unwrap_or(idle);
+    self.current = next;
+    self.slice_left = timer::millis_to_ticks(TIME_SLICE_MS);
+    self.need_resched = false;
+    let task = self.current_task();
+    task.state = State::Running;
+    if next == prev {
+      return None;
+    }
+    percpu::set_current_task(task.id.0);
+    unsafe { fpu::switch_to(&mut task.fpu) };
+    unsafe { switch_address_space(task.level_four) };
+    unsafe { interrupts::set_kernel_stack(task.kernel_stack) };
+    let new_rsp = task.rsp;
+    let prev_rsp = &mut self.tasks[prev].as_mut().unwrap().rsp as *mut u64;
+    Some((prev_rsp, new_rsp))
+  }
+
+  // Called every timer tick, true if the current task is preempted
+  fn tick(&mut self) -> bool {
+    if !self.running {
+      return false;
+    }
+    self.wake_sleepers(timer::ticks());
+    if self.current == self.idle {
+      return !self.classes.is_empty();
+    }
+    let task = self.tasks[self.current].as_mut().unwrap();
+    let make_room = self.classes.of(&task.entity).tick(&mut task.entity);
+    self.slice_left = self.slice_left.saturating_sub(1);
+    let slice_used = self.slice_left == 0 && !self.classes.is_empty();
+    self.need_resched || make_room || slice_used
+  }
+
+  // Makes the current task go behind the others of its class
+  fn yield_current(&mut self) {
+    let task = self.tasks[self.current].as_mut().unwrap();
+    self.classes.of(&task.entity).yield_task(&mut task.entity);
+  }
+}
+
+static SCHEDULER: IrqSafeMutex<Scheduler> = IrqSafeMutex::new(Scheduler::new());
+
+fn stack_bottom(slot: usize) -> u64 {
+  STACKS_START + slot as u64 * STACK_STRIDE + 4096
+}
+
+// Unsafe since the level four table has to map the kernel, like the
+// ones of address_space.rs do
+unsafe fn switch_address_space(level_four: Option<PhysAddr>) {
+  let level_four = level_four.unwrap_or_else(page_table::kernel_level_four);
+  let (active, _) = Cr3::read();
+  if active.as_u64() != level_four.as_u64() {
+    Cr3::write(level_four, 0);
+  }
+}
+
+// Switches to the next task, if there is another one to run
+fn schedule() {
+  let interrupts_enabled = interrupts::enabled();
+  interrupts::disable();
+  let switch = SCHEDULER.lock().switch_from_current();
+  if let Some((prev_rsp, new_rsp)) = switch {
+    unsafe { switch::switch_stacks(prev_rsp, new_rsp) };
+    finish_switch();
+  }
+  if interrupts_enabled {
+    interrupts::enable();
+  }
+}
+
+// Runs on the new stack after every switch
+fn finish_switch() {
+  let mut scheduler = SCHEDULER.lock();
+  if let Some(slot) = scheduler.reap.take() {
+    scheduler.free(slot);
+  }
+}
+
+// Where new tasks start, with interrupts disabled
+extern "C" fn task_start() -> ! {
+  finish_switch();
+  let entry = SCHEDULER.lock().current_task().entry;
+  interrupts::enable();
+  (entry.expect("task without entry"))();
+  exit();
+}
+
+extern "C" fn idle_task() -> ! {
+  finish_switch();
+  loop {
+    interrupts::disable();
+    if SCHEDULER.lock().classes.is_empty() {
+      // sti only takes effect after the next instruction, so an
+      // interrupt cannot slip in between and be waited for
+      unsafe { asm!("sti; hlt", options(nomem, nostack)) };
+    } else {
+      schedule();
+    }
+  }
+}
+
+// Turns the caller into the boot task and starts scheduling. Has to
+// be called once, on the bootstrap processor.
+pub fn initialize() {
+  let mut scheduler = SCHEDULER.lock();
+  assert!(!scheduler.running);
+  let boot = scheduler.insert(None).expect("no slot for the boot task");
+  let idle = scheduler.insert(None).expect("no slot for the idle task");
+  scheduler.prepare_stack(idle, idle_task);
+  scheduler.current = boot;
+  scheduler.idle = idle;
+  scheduler.slice_left = timer::millis_to_ticks(TIME_SLICE_MS);
+  scheduler.running = true;
+  let task = scheduler.current_task();
+  task.state = State::Running;
+  // nobody joins it, and it does not run on a stack from the task table
+  task.detached = true;
+  task.kernel_stack = unsafe { (*percpu::tss()).privilege_stack(0) };
+  percpu::set_current_task(task.id.0);
+  unsafe { fpu::switch_to(&mut task.fpu) };
+}
+
+// Called from the timer interrupt handler, after the EOI
+pub fn tick() {
+  let preempt = SCHEDULER.lock().tick();
+  if preempt {
+    schedule();
+  }
+}
+
+pub fn current() -> Option<TaskId> {
+  match percpu::current_task() {
+    0 => None,
+    id => Some(TaskId(id)),
+  }
+}
+
+// Runs entry in a new task. Returns None if there are too many tasks.
+pub fn spawn(entry: fn()) -> Option<JoinHandle> {
+  let id = SCHEDULER.lock().spawn(entry, None)?;
+  Some(JoinHandle { id })
+}
+
+// Like spawn, but the task runs in the address space with the given
+// level four table. Unsafe since that has to stay valid while the
+// task uses it, see set_address_space.
+pub unsafe fn spawn_in(entry: fn(), level_four: PhysAddr) -> Option<JoinHandle> {
+  let id = SCHEDULER.lock().spawn(entry, Some(level_four))?;
+  Some(JoinHandle { id })
+}
+
+// Like spawn_in, but the task starts with a copy of the current task's
+// FPU registers, for a forked process. Unsafe like spawn_in.
+pub unsafe fn fork_in(entry: fn(), level_four: PhysAddr) -> Option<JoinHandle> {
+  let mut scheduler = SCHEDULER.lock();
+  let id = scheduler.spawn(entry, Some(level_four))?;
+  // interrupts are off while the lock is held
+  fpu::copy_current(&mut scheduler.task(id).unwrap().fpu);
+  Some(JoinHandle { id })
+}
+
+// Switches the current task to another address space, or to the
+// kernel page table with None, right away and whenever it runs again.
+// Unsafe like spawn_in.
+pub unsafe fn set_address_space(level_four: Option<PhysAddr>) {
+  let mut scheduler = SCHEDULER.lock();
+  scheduler.current_task().level_four = level_four;
+  switch_address_space(level_four);
+}
+
+// Lets the other runnable tasks of the same class run before
+// continuing. Tasks of a lower class still have to wait.
+pub fn yield_now() {
+  {
+    let mut scheduler = SCHEDULER.lock();
+    if scheduler.running {
+      scheduler.yield_current();
+    }
+  }
+  schedule();
+}
+
+// Ends the current task. The IPC calls it received but did not reply
+// to fail, nobody else could answer them.
+pub fn exit() -> ! {
+  if let Some(me) = current() {
+    ipc::abandon(me);
+  }
+  interrupts::disable();
+  {
+    let mut scheduler = SCHEDULER.lock();
+    let slot = scheduler.current;
+    assert_ne!(slot, scheduler.idle, "the idle task cannot exit");
+    let task = scheduler.current_task();
+    task.state = State::Exited;
+    fpu::release(&mut task.fpu);
+    let (joiner, detached) = (task.joiner.take(), task.detached);
+    if let Some(joiner) = joiner {
+      scheduler.wake(joiner);
+    }
+    if detached {
+      scheduler.reap = Some(slot);
+    }
+  }
+  schedule();
+  unreachable!("an exited task was scheduled");
+}
+
+// Takes the current task off the cpu until wake is called with its
+// id. A wake which arrives before the task blocks is remembered and
+// makes block return right away, so a task can check a condition,
+// register itself to be woken and then block without missing it.
+// Spurious wakeups are possible, callers have to check again.
+pub fn block() {
+  let blocked = SCHEDULER.lock().block_current(None);
+  if blocked {
+    schedule();
+  }
+}
+
+// Like block, but also returns at the first tick at or after deadline,
+// in timer ticks. False if the deadline has passed.
+pub fn block_until(deadline: u64) -> bool {
+  let blocked = SCHEDULER.lock().block_current(Some(deadline));
+  if blocked {
+    schedule();
+  }
+  timer::ticks() < deadline
+}
+
+// Blocks until timer::ticks() reaches deadline
+pub fn sleep_until(deadline: u64) {
+  while block_until(deadline) {}
+}
+
+// Blocks for at least duration. It is rounded up to whole ticks, plus
+// one since the current tick is already partly over.
+pub fn sleep(duration: Duration) {
+  let ticks = timer::duration_to_ticks(duration).saturating_add(1);
+  sleep_until(timer::ticks().saturating_add(ticks));
+}
+
+// Wakes a blocked task. Safe to call from interrupt handlers.
+// Returns false if the task has exited.
+pub fn wake(id: TaskId) -> bool {
+  SCHEDULER.lock().wake(id)
+}
+
+// The nice value of a task, None if it is gone
+pub fn nice(id: TaskId) -> Option<i8> {
+  SCHEDULER.lock().task(id).map(|task| task.entity.nice)
+}
+
+// Sets the nice value of a task, clamped to MIN_NICE..=MAX_NICE. It
+// only matters while the task is fair. False if the task is gone.
+pub fn set_nice(id: TaskId, nice: i8) -> bool {
+  let nice = nice.clamp(MIN_NICE, MAX_NICE);
+  SCHEDULER.lock().set_entity(id, |entity| entity.nice = nice)
+}
+
+// The real-time priority of a task, 0 if it is fair. None if the task
+// is gone.
+pub fn rt_priority(id: TaskId) -> Option<u8> {
+  SCHEDULER
+    .lock()
+    .task(id)
+    .map(|task| task.entity.rt_priority)
+}
+
+// Makes a task real-time with the given priority, up to
+// MAX_RT_PRIORITY, or fair again with 0. False if the task is gone.
+pub fn set_rt_priority(id: TaskId, priority: u8) -> bool {
+  assert!(priority <= MAX_RT_PRIORITY, "invalid real-time priority");
+  SCHEDULER
+    .lock()
+    .set_entity(id, |entity| entity.rt_priority = priority)
+}
+
+// Dropping the handle detaches the task, it is then cleaned up as
+// soon as it exits
+pub struct JoinHandle {
+  id: TaskId,
+}
+
+impl JoinHandle {
+  pub fn id(&self) -> TaskId {
+    self.id
+  }
+
+  // Blocks until the task has exited
+  pub fn join(self) {
+    assert_ne!(Some(self.id), current(), "a task cannot join itself");
+    loop {
+      {
+        let mut scheduler = SCHEDULER.lock();
+        let me = TaskId(percpu::current_task());
+        let task = scheduler.task(self.id).expect("joined a detached task");
+        if task.state == State::Exited {
+          scheduler.free(self.id.slot());
+          return;
+        }
+        task.joiner = Some(me);
+      }
+      block();
+    }
+  }
+}
+
+impl Drop for JoinHandle {
+  fn drop(&mut self) {
+    let mut scheduler = SCHEDULER.lock();
+    if let Some(task) = scheduler.task(self.id) {
+      if task.state == State::Exited {
+        scheduler.free(self.id.slot());
+      } else {
+        task.detached = true;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+  #[test_case]
+  fn stale_ids() {
+    let mut scheduler = Scheduler::new();
+    let slot = scheduler.insert(None).unwrap();
+    let first = scheduler.tasks[slot].as_ref().unwrap().id;
+    scheduler.tasks[slot].as_mut().unwrap().state = State::Exited;
+    scheduler.free(slot);
+    assert_eq!(scheduler.insert(None), Some(slot));
+    let second = scheduler.tasks[slot].as_ref().unwrap().id;
+    assert_ne!(first, second);
+    assert!(scheduler.task(first).is_none());
+    assert!(!scheduler.wake(first));
+  }
+
+  static RAN: AtomicBool = AtomicBool::new(false);
+
+  #[test_case]
+  fn spawn_and_join() {
+    RAN.store(false, Ordering::SeqCst);
+    let handle = spawn(|| {
+      assert!(interrupts::enabled());
+      RAN.store(true, Ordering::SeqCst);
+    })
+    .unwrap();
+    assert_ne!(Some(handle.id()), current());
+    handle.join();
+    assert!(RAN.load(Ordering::SeqCst));
+    // the slot was freed, spawning reuses it
+    assert!(spawn(|| {}).is_some());
+  }
+
+  static LOG: IrqSafeMutex<([u8; 8], usize)> = IrqSafeMutex::new(([0; 8], 0));
+
+  fn log(c: u8) {
+    let mut log = LOG.lock();
+    let i = log.1;
+    log.0[i] = c;
+    log.1 += 1;
+  }
+
+  #[test_case]
+  fn yield_interleaves() {
+    fn task(c: u8) {
+      for _ in 0..3 {
+        log(c);
+        yield_now();
+      }
+    }
+    let a = spawn(|| task(b'a')).unwrap();
+    let b = spawn(|| task(b'b')).unwrap();
+    a.join();
+    b.join();
+    let log = LOG.lock();
+    assert_eq!(&log.0[..log.1], b"ababab");
+  }
+
+  static SPINNER_DONE: AtomicBool = AtomicBool::new(false);
+  static OTHER_RAN: AtomicBool = AtomicBool::new(false);
+
+  #[test_case]
+  fn preemption() {
+    // the first task never yields, the second only gets to run if
+    // the first one is preempted
+    let spinner = spawn(|| {
+      while !OTHER_RAN.load(Ordering::SeqCst) {
+        unsafe { asm!("pause") };
+      }
+      SPINNER_DONE.store(true, Ordering::SeqCst);
+    })
+    .unwrap();
+    let other = spawn(|| OTHER_RAN.store(true, Ordering::SeqCst)).unwrap();
+    spinner.join();
+    other.join();
+    assert!(SPINNER_DONE.load(Ordering::SeqCst));
+  }
+
+  #[test_case]
+  fn wake_before_block() {
+    wake(current().unwrap());
+    block(); // returns right away
+  }
+
+  static SLEEPER: AtomicUsize = AtomicUsize::new(0);
+
+  #[test_case]
+  fn idle_until_woken() {
+    // everything is blocked until the timer callback, so the idle
+    // task runs in between
+    let sleeper = spawn(|| {
+      SLEEPER.store(current().unwrap().0, Ordering::SeqCst);
+      block();
+    })
+    .unwrap();
+    let deadline = timer::ticks() + 5;
+    timer::schedule(deadline, || {
+      wake(TaskId(SLEEPER.load(Ordering::SeqCst)));
+    })
+    .unwrap();
+    sleeper.join();
+    assert!(timer::ticks() >= deadline);
+  }
+
+  static SEEN: AtomicUsize = AtomicUsize::new(0);
+
+  #[test_case]
+  fn address_space_follows_the_task() {
+    use crate::mem::address_space::{AddressSpace, USER_START};
+    use crate::mem::page_table::PageFlags;
+    let mut space = AddressSpace::new().unwrap();
+    space
+      .map(VirtAddr::new(USER_START), PageFlags::WRITABLE)
+      .unwrap();
+    space.write(USER_START, &1337usize.to_le_bytes());
+    let kernel_stack_before = unsafe { (*percpu::tss()).privilege_stack(0) };
+    let handle = unsafe {
+      spawn_in(
+        || {
+          // the kernel stack for entries from user mode is this one
+          let stack_int = 0u64;
+          let top = (*percpu::tss()).privilege_stack(0);
+          let addr = &stack_int as *const _ as u64;
+          assert!(addr < top && addr >= top - STACK_SIZE);
+          let value = *VirtAddr::new(USER_START).as_ptr::<usize>();
+          SEEN.store(value, Ordering::SeqCst);
+        },
+        space.level_four(),
+      )
+    }
+    .unwrap();
+    handle.join();
+    assert_eq!(SEEN.load(Ordering::SeqCst), 1337);
+    assert!(!space.is_active());
+    assert_eq!(
+      unsafe { (*percpu::tss()).privilege_stack(0) },
+      kernel_stack_before
+    );
+  }
+
+  #[test_case]
+  fn detached_tasks_are_reaped() {
+    for _ in 0..MAX_TASKS * 2 {
+      drop(spawn(|| {}).unwrap());
+      yield_now();
+    }
+    assert!(SCHEDULER.lock().classes.is_empty());
+  }
+
+  #[test_case]
+  fn priorities() {
+    let me = current().unwrap();
+    assert_eq!(nice(me), Some(0));
+    assert!(set_nice(me, 100));
+    assert_eq!(nice(me), Some(MAX_NICE));
+    // new tasks get the nice value of their parent
+    let child = spawn(|| {}).unwrap();
+    let id = child.id();
+    assert_eq!(nice(id), Some(MAX_NICE));
+    assert_eq!(rt_priority(id), Some(0));
+    assert!(set_nice(me, 0));
+    child.join();
+    assert_eq!(nice(id), None);
+    assert!(!set_nice(id, 0));
+    assert!(!set_rt_priority(id, 1));
+  }
+
+  static FIRST: AtomicUsize = AtomicUsize::new(0);
+
+  #[test_case]
+  fn real_time_runs_first() {
+    FIRST.store(0, Ordering::SeqCst);
+    let fair = spawn(|| {
+      let _ = FIRST.compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst);
+    })
+    .unwrap();
+    let real_time = spawn(|| {
+      let _ = FIRST.compare_exchange(0, 2, Ordering::SeqCst, Ordering::SeqCst);
+    })
+    .unwrap();
+    // it was queued after the fair one
+    assert!(set_rt_priority(real_time.id(), 10));
+    assert_eq!(rt_priority(real_time.id()), Some(10));
+    fair.join();
+    real_time.join();
+    assert_eq!(FIRST.load(Ordering::SeqCst), 2);
+  }
+
+  static STOP: AtomicBool = AtomicBool::new(false);
+  static INTERACTIVE: AtomicUsize = AtomicUsize::new(0);
+  static WORST_LATENCY: AtomicU64 = AtomicU64::new(0);
+
+  #[test_case]
+  fn interactive_latency_under_load() {
+    const SPINNERS: usize = 4;
+    STOP.store(false, Ordering::SeqCst);
+    let mut spinners: [Option<JoinHandle>; SPINNERS] = [None, None, None, None];
+    for spinner in spinners.iter_mut() {
+      *spinner = spawn(|| {
+        while !STOP.load(Ordering::SeqCst) {
+          unsafe { asm!("pause") };
+        }
+      });
+    }
+    // sleeps a few ticks at a time, like a task waiting for keys, and
+    // measures how long it takes to run again after the timer fired
+    let interactive = spawn(|| {
+      INTERACTIVE.store(current().unwrap().0, Ordering::SeqCst);
+      let mut worst = 0;
+      for _ in 0..20 {
+        let deadline = timer::ticks() + 3;
+        timer::schedule(deadline, || {
+          wake(TaskId(INTERACTIVE.load(Ordering::SeqCst)));
+        })
+        .unwrap();
+        while timer::ticks() < deadline {
+          block();
+        }
+        worst = worst.max(timer::ticks() - deadline);
+      }
+      WORST_LATENCY.store(worst, Ordering::SeqCst);
+    })
+    .unwrap();
+    interactive.join();
+    STOP.store(true, Ordering::SeqCst);
+    for spinner in spinners.iter_mut() {
+      spinner.take().unwrap().join();
+    }
+    // round-robin would make it wait for the time slices of the
+    // spinners, up to SPINNERS * TIME_SLICE_MS
+    let worst = WORST_LATENCY.load(Ordering::SeqCst);
+    assert!(worst <= 2, "waited {} ticks", worst);
+  }
+
+  #[test_case]
+  fn timed_block() {
+    let deadline = timer::ticks() + 3;
+    assert!(!block_until(deadline));
+    assert!(timer::ticks() >= deadline);
+    // a wake ends it early
+    wake(current().unwrap());
+    assert!(block_until(timer::ticks() + 1000));
+    assert!(!block_until(0));
+    let start = timer::ticks();
+    sleep(Duration::from_millis(5));
+    assert!(timer::ticks() > start + timer::millis_to_ticks(5));
+  }
+
+  static NEXT_AWAKE: AtomicUsize = AtomicUsize::new(0);
+  static AWAKE: [AtomicUsize; 3] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+  ];
+
+  #[test_case]
+  fn sleepers_wake_by_deadline() {
+    fn sleeper(i: usize, ms: u64) {
+      sleep(Duration::from_millis(ms));
+      AWAKE[i].store(NEXT_AWAKE.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+    }
+    NEXT_AWAKE.store(0, Ordering::SeqCst);
+    let a = spawn(|| sleeper(0, 30)).unwrap();
+    let b = spawn(|| sleeper(1, 10)).unwrap();
+    let c = spawn(|| sleeper(2, 20)).unwrap();
+    a.join();
+    b.join();
+    c.join();
+    let order = [
+      AWAKE[0].load(Ordering::SeqCst),
+      AWAKE[1].load(Ordering::SeqCst),
+      AWAKE[2].load(Ordering::SeqCst),
+    ];
+    assert_eq!(order, [2, 0, 1]);
+  }
+}
@@ -0,0 +1,74 @@
+/*
+  A task which is not running is just its saved stack pointer.
+  switch_stacks pushes the callee saved registers, the only ones the
+  System V ABI requires a call to preserve, saves the stack pointer
+  and pops the registers of the other task from its own stack. The
+  return then continues where that task called switch_stacks, or in
+  its start function if it never ran before.
+  Reference: https://wiki.osdev.org/Kernel_Multitasking
+*/
+
+// rbp, rbx and r12-r15
+const CALLEE_SAVED: u64 = 6;
+
+#[naked]
+pub unsafe extern "C" fn switch_stacks(_old_rsp: *mut u64, _new_rsp: u64) {
+  asm!(
+    "
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+    ",
+    options(noreturn)
+  );
+}
+
+// Prepares the stack ending at top so that switching to the returned
+// stack pointer jumps to start. The stack then looks as if start was
+// called from address 0, with rbp 0, which ends backtraces.
+// Unsafe since the stack has to be mapped and unused.
+pub unsafe fn initial_stack(top: u64, start: extern "C" fn() -> !) -> u64 {
+  assert_eq!(top & 0xf, 0, "unaligned stack");
+  let stack = top as *mut u64;
+  stack.sub(1).write(0);
+  stack.sub(2).write(start as usize as u64);
+  for i in 0..CALLEE_SAVED as usize {
+    stack.sub(3 + i).write(0);
+  }
+  top - (2 + CALLEE_SAVED) * 8
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test_case]
+  fn stack_alignment() {
+    extern "C" fn start() -> ! {
+      unreachable!();
+    }
+    let mut stack = [0u64; 16];
+    let top = (stack.as_mut_ptr() as u64 + 16 * 8) & !0xf;
+    let rsp = unsafe { initial_stack(top, start) };
+    // after popping the registers and returning, rsp is 8 mod 16 as
+    // it would be right after a call
+    let after_ret = rsp + CALLEE_SAVED * 8 + 8;
+    assert_eq!(after_ret % 16, 8);
+    assert_eq!(
+      unsafe { *((after_ret - 8) as *const u64) },
+      start as usize as u64
+    );
+  }
+}
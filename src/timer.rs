@@ -0,0 +1,292 @@
+#![allow(dead_code)]
+use crate::interrupts::pit;
+use crate::sync::IrqSafeMutex;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+
+/*
+  Deferred callbacks driven by the PIT tick. Pending timers
+  are kept in a fixed size binary min-heap ordered by deadline,
+  expired callbacks are run from the timer interrupt.
+  Reference: https://en.wikipedia.org/wiki/Binary_heap
+*/
+
+pub const TICKS_PER_SECOND: u64 = pit::FREQUENCY;
+const MAX_TIMERS: usize = 128;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Copy)]
+struct Timer {
+  deadline: u64,
+  id: u64,
+  callback: fn(),
+}
+
+impl Timer {
+  // ties are broken by id so timers with equal deadlines run in FIFO order
+  fn before(&self, other: &Timer) -> bool {
+    (self.deadline, self.id) < (other.deadline, other.id)
+  }
+}
+
+struct TimerQueue {
+  heap: [Timer; MAX_TIMERS],
+  len:  usize,
+}
+
+impl TimerQueue {
+  fn new() -> Self {
+    fn unused() {}
+    let timer = Timer {
+      deadline: 0,
+      id: 0,
+      callback: unused,
+    };
+    Self {
+      heap: [timer; MAX_TIMERS],
+      len:  0,
+    }
+  }
+
+  fn push(&mut self, timer: Timer) -> bool {
+    if self.len == MAX_TIMERS {
+      return false;
+    }
+    self.heap[self.len] = timer;
+    self.len += 1;
+    self.sift_up(self.len - 1);
+    true
+  }
+
+  fn pop_expired(&mut self, now: u64) -> Option<Timer> {
+    if self.len == 0 || self.heap[0].deadline > now {
+      return None;
+    }
+    Some(self.remove_at(0))
+  }
+
+  fn remove(&mut self, id: u64) -> bool {
+    match self.heap[..self.len].iter().position(|t| t.id == id) {
+      Some(i) => {
+        self.remove_at(i);
+        true
+      }
+      None => false,
+    }
+  }
+
+  fn remove_at(&mut self, i: usize) -> Timer {
+    let timer = self.heap[i];
+    self.len -= 1;
+    if i != self.len {
+      self.heap[i] = self.heap[self.len];
+      self.sift_down(i);
+      self.sift_up(i);
+    }
+    timer
+  }
+
+  fn sift_up(&mut self, mut i: usize) {
+    while i > 0 {
+      let parent = (i - 1) / 2;
+      if !self.heap[i].before(&self.heap[parent]) {
+        break;
+      }
+      self.heap.swap(i, parent);
+      i = parent;
+    }
+  }
+
+  fn sift_down(&mut self, mut i: usize) {
+    loop {
+      let mut smallest = i;
+      for &child in &[2 * i + 1, 2 * i + 2] {
+        if child < self.len && self.heap[child].before(&self.heap[smallest]) {
+          smallest = child;
+        }
+      }
+      if smallest == i {
+        break;
+      }
+      self.heap.swap(i, smallest);
+      i = smallest;
+    }
+  }
+}
+
+lazy_static! {
+  static ref TIMERS: IrqSafeMutex<TimerQueue> = IrqSafeMutex::new(TimerQueue::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle(u64);
+
+impl TimerHandle {
+  // Returns false if the timer already fired or was cancelled
+  pub fn cancel(self) -> bool {
+    TIMERS.lock().remove(self.0)
+  }
+}
+
+pub fn ticks() -> u64 {
+  TICKS.load(Ordering::Relaxed)
+}
+
+pub fn millis_to_ticks(ms: u64) -> u64 {
+  (ms * TICKS_PER_SECOND + 999) / 1000
+}
+
+// Rounded up, u64::MAX if it does not fit
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+  let ticks = (duration.as_nanos() * TICKS_PER_SECOND as u128 + 999_999_999) / 1_000_000_000;
+  ticks.min(u64::MAX as u128) as u64
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+  let nanos = (ticks % TICKS_PER_SECOND) * 1_000_000_000 / TICKS_PER_SECOND;
+  Duration::new(ticks / TICKS_PER_SECOND, nanos as u32)
+}
+
+// The time since the timer was started, with the resolution of a tick
+pub fn uptime() -> Duration {
+  ticks_to_duration(ticks())
+}
+
+// Runs callback from the timer interrupt once ticks() >= deadline.
+// Returns None if too many timers are already pending.
+pub fn schedule(deadline: u64, callback: fn()) -> Option<TimerHandle> {
+  let id = NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed);
+  let timer = Timer {
+    deadline,
+    id,
+    callback,
+  };
+  if TIMERS.lock().push(timer) {
+    Some(TimerHandle(id))
+  } else {
+    None
+  }
+}
+
+pub fn schedule_in(ticks_from_now: u64, callback: fn()) -> Option<TimerHandle> {
+  schedule(ticks() + ticks_from_now, callback)
+}
+
+// Called from the timer interrupt handler. Callbacks are run
+// without holding the lock so that they can (re)schedule timers.
+pub fn tick() {
+  let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+  loop {
+    let expired = TIMERS.lock().pop_expired(now);
+    match expired {
+      Some(timer) => (timer.callback)(),
+      None => break,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use core::sync::atomic::AtomicUsize;
+
+  fn timer(deadline: u64, id: u64) -> Timer {
+    Timer {
+      deadline,
+      id,
+      callback: || {},
+    }
+  }
+
+  #[test_case]
+  fn queue_pops_in_deadline_order() {
+    let mut queue = TimerQueue::new();
+    for (id, &deadline) in [50, 10, 40, 10, 30, 20].iter().enumerate() {
+      assert!(queue.push(timer(deadline, id as u64)));
+    }
+    assert!(queue.pop_expired(5).is_none());
+    let mut order = [0; 6];
+    for slot in order.iter_mut() {
+      *slot = queue.pop_expired(100).unwrap().id;
+    }
+    assert_eq!(order, [1, 3, 5, 4, 2, 0]);
+    assert!(queue.pop_expired(100).is_none());
+  }
+
+  #[test_case]
+  fn queue_only_pops_expired() {
+    let mut queue = TimerQueue::new();
+    queue.push(timer(10, 0));
+    queue.push(timer(20, 1));
+    assert_eq!(queue.pop_expired(15).map(|t| t.id), Some(0));
+    assert!(queue.pop_expired(15).is_none());
+    assert_eq!(queue.pop_expired(20).map(|t| t.id), Some(1));
+  }
+
+  #[test_case]
+  fn queue_remove() {
+    let mut queue = TimerQueue::new();
+    for id in 0..10 {
+      queue.push(timer(100 - id, id));
+    }
+    assert!(queue.remove(3));
+    assert!(queue.remove(9));
+    assert!(!queue.remove(9));
+    assert!(!queue.remove(42));
+    let mut prev = 0;
+    while let Some(t) = queue.pop_expired(1000) {
+      assert!(t.id != 3 && t.id != 9);
+      assert!(t.deadline >= prev);
+      prev = t.deadline;
+    }
+  }
+
+  #[test_case]
+  fn queue_full() {
+    let mut queue = TimerQueue::new();
+    for id in 0..MAX_TIMERS as u64 {
+      assert!(queue.push(timer(id, id)));
+    }
+    assert!(!queue.push(timer(0, 1337)));
+  }
+
+  #[test_case]
+  fn conversions() {
+    assert_eq!(
+      duration_to_ticks(Duration::from_millis(5)),
+      millis_to_ticks(5)
+    );
+    assert_eq!(duration_to_ticks(Duration::from_nanos(1)), 1);
+    assert_eq!(duration_to_ticks(Duration::from_secs(0)), 0);
+    assert_eq!(duration_to_ticks(Duration::MAX), u64::MAX);
+    let before = ticks();
+    let uptime = duration_to_ticks(uptime());
+    assert!(uptime >= before && uptime <= ticks());
+  }
+
+  static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+  #[test_case]
+  fn schedule_and_cancel() {
+    fn callback() {
+      FIRED.fetch_add(1, Ordering::SeqCst);
+    }
+    FIRED.store(0, Ordering::SeqCst);
+    let now = ticks();
+    schedule(now + 1, callback).unwrap();
+    schedule(now + 2, callback).unwrap();
+    let cancelled = schedule(now + 2, callback).unwrap();
+    assert!(cancelled.cancel());
+    assert!(!cancelled.cancel());
+
+    tick();
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    tick();
+    assert_eq!(FIRED.load(Ordering::SeqCst), 2);
+    tick();
+    assert_eq!(FIRED.load(Ordering::SeqCst), 2);
+  }
+}
@@ -0,0 +1,41 @@
+#![allow(dead_code)]
+use crate::cpu::regs::RFlags;
+use crate::interrupts::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::mem::VirtAddr;
+
+/*
+  There is no instruction to simply jump to ring 3. Instead we push
+  the stack frame the cpu would have pushed on an interrupt from user
+  mode and "return" to it with iretq. The code and stack have to be
+  mapped with PageFlags::USER_ACCESSIBLE and the kernel stack in the
+  TSS has to be valid, it is used on the next interrupt or exception.
+  Like every return to user mode it swaps in the user gs base, see
+  percpu.rs.
+  Reference: https://wiki.osdev.org/Getting_to_Ring_3
+*/
+
+// Unsafe since the caller has to make sure entry and stack_top are
+// mapped user accessible pages.
+pub unsafe fn enter(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+  assert!(
+    entry.as_u64() < 0x0000_8000_0000_0000,
+    "entry not in user space"
+  );
+  asm!(
+    "
+    push {ss}
+    push {rsp}
+    push {rflags}
+    push {cs}
+    push {rip}
+    swapgs
+    iretq
+    ",
+    ss = in(reg) USER_DATA_SELECTOR as u64,
+    rsp = in(reg) stack_top.as_u64(),
+    rflags = in(reg) (RFlags::INTERRUPT_FLAG | RFlags::RESERVED).bits(),
+    cs = in(reg) USER_CODE_SELECTOR as u64,
+    rip = in(reg) entry.as_u64(),
+    options(noreturn)
+  );
+}
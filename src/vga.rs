@@ -1,7 +1,8 @@
 #![allow(unused)]
+use core::fmt;
 
-const ROWS: usize = 25;
-const COLS: usize = 80;
+pub const ROWS: usize = 25;
+pub const COLS: usize = 80;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
@@ -72,4 +73,53 @@ impl VgaDevice {
       color: self.color,
     };
   }
+
+  pub fn clear(&mut self) {
+    for row in 0..ROWS {
+      for col in 0..COLS {
+        self.write_char(row, col, b' ');
+      }
+    }
+    self.set_cursor(0, 0);
+  }
+
+  // Where the next character is written by write_byte
+  pub fn set_cursor(&mut self, row: usize, col: usize) {
+    self.row = row;
+    self.col = col;
+  }
+
+  pub fn cursor(&self) -> (usize, usize) {
+    (self.row, self.col)
+  }
+
+  // Writes at the cursor, wrapping at the end of the line.
+  // Anything past the last row is dropped.
+  pub fn write_byte(&mut self, c: u8) {
+    if c == b'\n' {
+      self.set_cursor(self.row + 1, 0);
+      return;
+    }
+    if self.col == COLS {
+      self.set_cursor(self.row + 1, 0);
+    }
+    if self.row < ROWS {
+      self.write_char(self.row, self.col, c);
+      self.col += 1;
+    }
+  }
+
+  pub fn read_char(&self, row: usize, col: usize) -> u8 {
+    self.buf[row][col].c
+  }
+}
+
+impl fmt::Write for VgaDevice {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    for c in s.chars() {
+      // non-ascii characters are shown as a block
+      self.write_byte(if c.is_ascii() { c as u8 } else { 0xfe });
+    }
+    Ok(())
+  }
 }
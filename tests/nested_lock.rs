@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ax_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// This test verifies that locking an IrqSafeMutex twice on the same
+// cpu panics in debug builds instead of spinning forever. The panic
+// is the expected outcome, so it needs its own panic handler.
+
+use ax_os::dbg;
+use ax_os::sync::IrqSafeMutex;
+use core::panic::PanicInfo;
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+  unsafe { ax_os::dbg_print::force_unlock() };
+  dbg!("[success]");
+  dbg!("Panicked with: {}", info);
+  ax_os::qemu_exit_success();
+}
+
+#[allow(unreachable_code)]
+#[no_mangle]
+pub extern "C" fn _start(_: &'static bootloader::BootInfo) -> ! {
+  test_main();
+  unreachable!();
+}
+
+#[test_case]
+fn nested_lock() {
+  // release builds do not track the owner and would hang
+  if !cfg!(debug_assertions) {
+    ax_os::qemu_exit_success();
+  }
+  let mutex = IrqSafeMutex::new(0);
+  let _guard = mutex.lock();
+  let _nested = mutex.lock();
+  dbg!("[failed]");
+  dbg!("Error: locked twice without panicking");
+  ax_os::qemu_exit_failure();
+}
@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ax_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// This test starts the application processors and checks that all
+// of them came online. It has to run with -smp 4, see the test-args
+// in Cargo.toml.
+
+use ax_os::mem::frame_allocator::FrameAllocator;
+use ax_os::{acpi, interrupts, smp};
+
+fn initialize(info: &'static bootloader::BootInfo) {
+  FrameAllocator::initialize(&info.memory_map);
+  interrupts::initialize();
+  smp::initialize();
+}
+
+ax_os::test_prelude!(initialize);
+
+#[test_case]
+fn all_cpus_online() {
+  let cpus = acpi::madt().unwrap().processors().count();
+  assert_eq!(cpus, 4);
+  assert_eq!(smp::cpu_count(), cpus);
+}
@@ -15,7 +15,7 @@ use gdt::{GlobalDescriptorTable, TaskSegmentSelector};
 use idt::InterruptDescriptorTable;
 use lazy_static::lazy_static;
 
-extern "x86-interrupt" fn double_fault(_: &mut InterruptStackFrame, _: u64) {
+extern "x86-interrupt" fn double_fault(_: &mut InterruptStackFrame, _: u64) -> ! {
   dbg!("[success]");
   ax_os::qemu_exit_success();
 }
@@ -38,7 +38,7 @@ lazy_static! {
   };
   static ref IDT: InterruptDescriptorTable = {
     let mut idt = InterruptDescriptorTable::new();
-    idt[0x08].set_handler(double_fault as usize).with_ist(1);
+    idt.double_fault.set_handler(double_fault).with_ist(1);
     idt
   };
 }
@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(ax_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// This test verifies that we can drop to ring 3 and that user code
+// traps back into the kernel. The user program makes a system call,
+// which has to return to ring 3, and then executes the privileged
+// hlt instruction which raises a general protection fault. Both
+// rely on the kernel stack in the TaskSegmentSelector.
+
+use ax_os::interrupts::{gdt, idt, pic, InterruptStackFrame};
+use ax_os::mem::frame_allocator::FrameAllocator;
+use ax_os::mem::page_table::{self, PageFlags};
+use ax_os::mem::VirtAddr;
+use ax_os::{dbg, percpu, syscall, usermode};
+use gdt::{GlobalDescriptorTable, TaskSegmentSelector};
+use idt::InterruptDescriptorTable;
+use lazy_static::lazy_static;
+
+const USER_CODE: u64 = 0x1000_0000_0000;
+const USER_STACK: u64 = 0x1000_0001_0000;
+
+#[rustfmt::skip]
+static USER_PROGRAM: [u8; 8] = [
+  0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, SYS_TICKS
+  0x0f, 0x05,                   // syscall
+  0xf4,                         // hlt
+];
+const HLT_OFFSET: u64 = 7;
+
+extern "x86-interrupt" fn general_protection_fault(frame: &mut InterruptStackFrame, _: u64) {
+  assert!(frame.is_user_mode());
+  assert_eq!(frame.code_segment, gdt::USER_CODE_SELECTOR as u64);
+  assert_eq!(frame.instruction_ptr, USER_CODE + HLT_OFFSET);
+  dbg!("[success]");
+  ax_os::qemu_exit_success();
+}
+
+extern "x86-interrupt" fn unexpected_fault(frame: &mut InterruptStackFrame, err_code: u64) {
+  panic!("unexpected fault {:x?}, errcode {:x}", frame, err_code);
+}
+
+extern "x86-interrupt" fn timer(_: &mut InterruptStackFrame) {
+  unsafe { pic::end_of_interrupt(0) };
+}
+
+extern "x86-interrupt" fn keyboard(_: &mut InterruptStackFrame) {
+  unsafe { pic::end_of_interrupt(1) };
+}
+
+static mut TSS: TaskSegmentSelector = TaskSegmentSelector::new();
+static mut KERNEL_STACK: [u8; 4096 * 4] = [0; 4096 * 4];
+
+lazy_static! {
+  static ref GDT: GlobalDescriptorTable = {
+    let mut gdt = GlobalDescriptorTable::new();
+    let tss_segment = gdt::tss_segment(unsafe { &TSS });
+    gdt[0] = gdt::null_segment();
+    gdt[1] = gdt::kernel_code_segment();
+    gdt[2] = gdt::kernel_data_segment();
+    gdt[3] = gdt::user_data_segment();
+    gdt[4] = gdt::user_code_segment();
+    gdt[5] = tss_segment.0;
+    gdt[6] = tss_segment.1;
+    gdt
+  };
+  static ref IDT: InterruptDescriptorTable = {
+    let mut idt = InterruptDescriptorTable::new();
+    idt
+      .general_protection_fault
+      .set_handler(general_protection_fault);
+    idt.page_fault.set_handler(unexpected_fault);
+    idt.irq(0).set_handler(timer);
+    idt.irq(1).set_handler(keyboard);
+    idt
+  };
+}
+
+fn initialize(info: &'static bootloader::BootInfo) {
+  FrameAllocator::initialize(&info.memory_map);
+  unsafe { TSS.set_privilege_stack(0, &KERNEL_STACK) };
+  GDT.load();
+  unsafe { gdt::set_cs(gdt::KERNEL_CODE_SELECTOR as u64) };
+  unsafe { gdt::set_ss(gdt::KERNEL_DATA_SELECTOR) };
+  unsafe { gdt::load_tss(gdt::TSS_SELECTOR) };
+  IDT.load();
+  pic::initialize();
+  // the syscall entry finds the kernel stack through the per-cpu block
+  unsafe { percpu::initialize(0, &mut TSS) };
+  syscall::initialize();
+}
+
+ax_os::test_prelude!(initialize);
+
+#[test_case]
+fn ring_3() {
+  let code = VirtAddr::new(USER_CODE);
+  let code_flags = PageFlags::USER_ACCESSIBLE | PageFlags::WRITABLE;
+  page_table::page_map_addr_with_flags(code, code_flags);
+  let code_ptr = code.as_mut_ptr::<[u8; 8]>();
+  unsafe { *code_ptr = USER_PROGRAM };
+
+  let stack = VirtAddr::new(USER_STACK);
+  let stack_flags = code_flags | PageFlags::NON_EXECUTABLE;
+  page_table::page_map_addr_with_flags(stack, stack_flags);
+
+  unsafe { usermode::enter(code, VirtAddr::new(USER_STACK + 0x1000)) };
+}
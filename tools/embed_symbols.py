@@ -0,0 +1,111 @@
+#!/usr/bin/env python3
+"""
+Writes the function symbols of a kernel ELF binary into its KSYMS
+table, which the kernel uses to symbolize backtraces. The table is
+reserved in src/backtrace.rs, which is compiled into both the library
+and the kernel binary, so a binary can contain a copy of each and all
+of them are filled in. The layout has to match src/backtrace.rs:
+
+  magic   b"KSYM"
+  count   u32
+  entries [u64 addr, u32 size, u32 name offset] * count, sorted by addr
+  names   utf-8 strings, referenced by offset from the start of names
+
+Usage: embed_symbols.py <kernel elf>
+"""
+import re
+import struct
+import sys
+
+TABLE = "backtrace::KSYMS"
+MAGIC = b"KSYM"
+STT_OBJECT = 1
+STT_FUNC = 2
+
+
+def sections(elf):
+  shoff, = struct.unpack_from("<Q", elf, 0x28)
+  shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3a)
+  headers = []
+  for i in range(shnum):
+    name, _, _, addr, offset, size = struct.unpack_from("<IIQQQQ", elf, shoff + i * shentsize)
+    headers.append((name, addr, offset, size))
+  strtab_offset = headers[shstrndx][2]
+  for name, addr, offset, size in headers:
+    end = elf.index(b"\0", strtab_offset + name)
+    yield bytes(elf[strtab_offset + name:end]), addr, offset, size
+
+
+def demangle(name):
+  # legacy rust mangling: _ZN 3foo 3bar 17h<hash> E
+  if not name.startswith("_ZN") or not name.endswith("E"):
+    return name
+  rest, parts = name[3:-1], []
+  while rest:
+    m = re.match(r"(\d+)", rest)
+    if not m:
+      return name
+    length = int(m.group(1))
+    start = len(m.group(1))
+    parts.append(rest[start:start + length])
+    rest = rest[start + length:]
+  if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+    parts.pop()
+  escapes = {"$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">", "$LP$": "(", "$RP$": ")", "$C$": ","}
+
+  def unescape(part):
+    if part.startswith("_$"):
+      part = part[1:]
+    for k, v in escapes.items():
+      part = part.replace(k, v)
+    part = re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), part)
+    return part.replace("..", "::")
+
+  return "::".join(unescape(p) for p in parts)
+
+
+def main(path):
+  with open(path, "rb") as f:
+    elf = bytearray(f.read())
+  headers = list(sections(elf))
+  found = {name: (offset, size) for name, _, offset, size in headers}
+  symtab_offset, symtab_size = found[b".symtab"]
+  strtab_offset, _ = found[b".strtab"]
+
+  symbols, tables = {}, []
+  for i in range(symtab_size // 24):
+    name, info, _, shndx, value, size = struct.unpack_from("<IBBHQQ", elf, symtab_offset + i * 24)
+    if info & 0xf not in (STT_OBJECT, STT_FUNC) or value == 0:
+      continue
+    end = elf.index(b"\0", strtab_offset + name)
+    name = demangle(elf[strtab_offset + name:end].decode())
+    if info & 0xf == STT_FUNC:
+      symbols[value] = (size, name)
+    elif name.endswith(TABLE):
+      _, section_addr, section_offset, _ = headers[shndx]
+      tables.append((section_offset + value - section_addr, size))
+  if not tables:
+    sys.exit("embed_symbols: no {} in {}".format(TABLE, path))
+
+  out_size = min(size for _, size in tables)
+  entries, names = bytearray(), bytearray()
+  count = 0
+  for addr in sorted(symbols):
+    size, name = symbols[addr]
+    encoded = name.encode() + b"\0"
+    if 8 + len(entries) + 16 + len(names) + len(encoded) > out_size:
+      print("embed_symbols: table full, dropped {} symbols".format(len(symbols) - count))
+      break
+    entries += struct.pack("<QII", addr, size, len(names))
+    names += encoded
+    count += 1
+
+  blob = MAGIC + struct.pack("<I", count) + entries + names
+  for offset, size in tables:
+    elf[offset:offset + size] = blob.ljust(size, b"\0")
+  with open(path, "wb") as f:
+    f.write(elf)
+
+
+if __name__ == "__main__":
+  main(sys.argv[1])
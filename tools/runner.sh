@@ -0,0 +1,6 @@
+#!/bin/sh
+# Cargo runner for kernel binaries, embeds the symbol
+# table used for backtraces before booting the kernel.
+set -e
+python3 "$(dirname "$0")/embed_symbols.py" "$1"
+exec bootimage runner --quiet "$@"